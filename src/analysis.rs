@@ -0,0 +1,732 @@
+use std::cmp::{min, max};
+use std::hash::hash;
+use collections::hashmap::{HashMap, HashSet};
+use operators::{Operator, Nop, Sub, Skip, Loop, DoSub, DoSkip, DoLoop, Checkpoint, Shebang};
+use operators::{Get, Prev, Next, PrevN, NextN, Move, SetAt, SetCell, Random};
+use operators::{Incr, Decr, IncrN, DecrN, AddAt, OffsetWrite, Put, Negate, HostCall};
+use ast::{Ast, Span};
+use sourcemap::{SourceMap, SourceNode};
+use rewrite::is_clear;
+
+
+/**
+A fragment of code occurring more than once in a program.
+
+Fragments are normalized: comments are dropped, and loops are
+spelled out as their `Skip`, body and `Loop` operators (`DoSkip`
+and `DoLoop` for do-while loops), so that identical code compares
+equal regardless of how it was formatted.
+*/
+pub struct Repeat {
+	/// The repeated operators, normalized.
+	pub fragment: ~[Operator],
+	/// How many times the fragment occurs, without overlapping.
+	pub count: uint,
+	/**
+	How many operators would be saved by factoring the fragment
+	out, assuming it is kept once as a definition and each of its
+	occurrences is replaced by a single call operator.
+	*/
+	pub saved: uint,
+	/// Where each occurrence lies in the original source.
+	pub spans: ~[Span],
+}
+
+
+/**
+Finds the repeated fragments of a program.
+
+Reports every distinct fragment of at least `min_len` operators
+which occurs two or more times. Only fragments with balanced
+brackets are considered, as those are the ones which could be
+factored out; a repeated loop is therefore found as a whole.
+
+The search hashes every window of `min_len` operators, then grows
+each group of identical windows to the right for as long as all
+of its occurrences agree. Fragments whose every occurrence lies in
+an occurrence of a longer fragment are not reported.
+
+Occurrences never overlap. They are picked greedily from the left,
+so in `++++` the fragment `++` is counted twice, and in `+++` only
+once (hence not reported at all).
+
+Results are sorted by operators saved, most first.
+*/
+pub fn find_repeats(ast: &Ast, min_len: uint) -> Vec<Repeat> {
+	let mut ops = Vec::new();
+	let mut offsets = Vec::new();
+	normalize(ast, 0, &mut ops, &mut offsets);
+	if min_len == 0 || ops.len() < min_len {
+		return Vec::new();
+	}
+
+	// Keep track of the nesting depth before each operator, so
+	// that it is cheap to tell whether a window is balanced.
+	let mut depths = Vec::with_capacity(ops.len() + 1);
+	let mut depth = 0;
+	depths.push(depth);
+	for op in ops.iter() {
+		match *op {
+			Skip | DoSkip => depth += 1,
+			Loop | DoLoop => depth -= 1,
+			_ => {}
+		}
+		depths.push(depth);
+	}
+	let ops = ops.as_slice();
+	let depths = depths.as_slice();
+
+	// Group the starts of balanced windows by their contents' hash.
+	let mut windows: HashMap<u64, Vec<uint>> = HashMap::new();
+	for start in range(0, ops.len() - min_len + 1) {
+		if !is_balanced(depths, start, start + min_len) { continue }
+		let key = hash(&ops.slice(start, start + min_len));
+		windows.find_or_insert_with(key, |_| Vec::new()).push(start);
+	}
+
+	let mut found: Vec<(uint, Vec<uint>)> = Vec::new();
+	for (_, starts) in windows.iter() {
+		// Hashes may collide, so split each group into windows
+		// which are actually identical.
+		let mut rest = starts.clone();
+		while !rest.is_empty() {
+			let first = *rest.get(0);
+			let window = ops.slice(first, first + min_len);
+			let (same, other) = rest.partition(|&start| {
+				ops.slice(start, start + min_len) == window
+			});
+			rest = other;
+
+			let chosen = non_overlapping(same.as_slice(), min_len);
+			if chosen.len() < 2 { continue }
+			let len = extend(ops, depths, chosen.as_slice(), min_len);
+			found.push((len, chosen));
+		}
+	}
+
+	// Drop the fragments which are just part of a longer one. With
+	// every occurrence sorted by start, longest first, one lies in
+	// another if any of those before it ends no sooner.
+	let mut occurrences = Vec::new();
+	for (i, &(len, ref starts)) in found.iter().enumerate() {
+		for &start in starts.iter() {
+			occurrences.push((start, start + len, i));
+		}
+	}
+	occurrences.as_mut_slice().sort_by(|&(a_start, a_end, _), &(b_start, b_end, _)| {
+		match a_start.cmp(&b_start) {
+			Equal => b_end.cmp(&a_end),
+			order => order,
+		}
+	});
+	let mut outside = Vec::from_elem(found.len(), false);
+	let mut reach = 0;
+	for &(_, end, i) in occurrences.iter() {
+		if reach < end { *outside.get_mut(i) = true }
+		reach = max(reach, end);
+	}
+
+	let mut repeats = Vec::new();
+	for (i, &(len, ref starts)) in found.iter().enumerate() {
+		if !*outside.get(i) { continue }
+
+		let count = starts.len();
+		let spans = starts.iter().map(|&s| {
+			let last = s + len - 1;
+			Span { start: *offsets.get(s), end: *offsets.get(last) + ops[last].source_len() }
+		}).collect();
+		repeats.push(Repeat {
+			fragment: ops.slice(*starts.get(0), *starts.get(0) + len).to_owned(),
+			count: count,
+			saved: if count * len > len + count { count * len - len - count } else { 0 },
+			spans: spans,
+		});
+	}
+
+	repeats.as_mut_slice().sort_by(|a, b| {
+		match b.saved.cmp(&a.saved) {
+			Equal => a.spans[0].start.cmp(&b.spans[0].start),
+			order => order,
+		}
+	});
+	repeats
+}
+
+/**
+Flattens an AST into normalized operators and the source offset of
+each of them. Returns the offset right past the end of the AST.
+*/
+fn normalize(ast: &Ast, offset: uint, ops: &mut Vec<Operator>, offsets: &mut Vec<uint>) -> uint {
+	let &Ast(ref body) = ast;
+	let mut pos = offset;
+	for op in body.iter() {
+		match *op {
+			// Comments, checkpoints and shebang lines do not matter.
+			Nop(_) | Shebang(_) | Checkpoint(_) => {}
+			// Spell out the loop with its brackets.
+			Sub(ref sub) => {
+				ops.push(Skip);
+				offsets.push(pos);
+				let end = normalize(sub, pos + 1, ops, offsets);
+				ops.push(Loop);
+				offsets.push(end);
+			}
+			DoSub(ref sub) => {
+				ops.push(DoSkip);
+				offsets.push(pos);
+				let end = normalize(sub, pos + 1, ops, offsets);
+				ops.push(DoLoop);
+				offsets.push(end);
+			}
+			ref other => {
+				ops.push(other.clone());
+				offsets.push(pos);
+			}
+		}
+		pos += op.source_len();
+	}
+	pos
+}
+
+/**
+Whether the operators between `start` and `end` have balanced
+brackets, given the nesting depth before each operator.
+*/
+fn is_balanced(depths: &[int], start: uint, end: uint) -> bool {
+	let base = depths[start];
+	depths[end] == base && depths.slice(start, end).iter().all(|&d| d >= base)
+}
+
+/**
+Picks the occurrences of a `len` long fragment which do not overlap,
+greedily from the left. Starts are expected to be sorted.
+*/
+fn non_overlapping(starts: &[uint], len: uint) -> Vec<uint> {
+	let mut chosen: Vec<uint> = Vec::new();
+	for &start in starts.iter() {
+		match chosen.last() {
+			Some(&last) if start < last + len => continue,
+			_ => {}
+		}
+		chosen.push(start);
+	}
+	chosen
+}
+
+/**
+Grows a fragment to the right for as long as all of its occurrences
+agree and do not run into each other. Returns the longest balanced
+length reached.
+*/
+fn extend(ops: &[Operator], depths: &[int], starts: &[uint], min_len: uint) -> uint {
+	let first = starts[0];
+	let mut len = min_len;
+	let mut best = min_len;
+	loop {
+		let agree = starts.iter().enumerate().all(|(k, &start)| {
+			let next = start + len;
+			let fits = match starts.get(k + 1) {
+				Some(&following) => next < following,
+				None => next < ops.len(),
+			};
+			fits && ops[next] == ops[first + len]
+		});
+		if !agree { break }
+		len += 1;
+		if is_balanced(depths, first, first + len) { best = len }
+	}
+	best
+}
+
+/**
+The reads of a program whose outcome may change its control flow,
+depending on what `Get` does at the end of input, see `semantics::Eof`.
+*/
+#[deriving(Clone, Eq, Show)]
+pub struct EofReport {
+	/// Where each such read lies in the source, in order.
+	pub reads: ~[Span],
+}
+
+/**
+Finds the reads of a program which the condition of a loop may check
+before the cell read is overwritten, so that reading zero, minus one
+or nothing at the end of input may decide whether the loop runs.
+
+The analysis follows the head through straight-line code, so that a
+read is only flagged when a loop may check its very cell, and adding
+to the cell still counts as depending on it. Setting or clearing the
+cell forgets about the read. It is approximate where the head can not
+be followed, e.g. after a loop moving it, and then flags any read not
+known to be overwritten: there may be false positives, but no read
+directly checked by a loop, as in `,[`, is ever missed.
+*/
+pub fn eof_sensitivity(ast: &Ast) -> EofReport {
+	let mut reads = Reads { head: 0, pending: Vec::new() };
+	let mut flagged = Vec::new();
+	let map = SourceMap::of(ast);
+	let SourceMap(ref nodes) = map;
+	let &Ast(ref ops) = ast;
+	follow_reads(ops.as_slice(), nodes.as_slice(), &mut reads, &mut flagged);
+	let mut spans: ~[Span] = flagged.move_iter().collect();
+	spans.sort_by(|a, b| a.start.cmp(&b.start));
+	spans.dedup();
+	EofReport { reads: spans }
+}
+
+/**
+The reads not yet overwritten while following a program.
+*/
+#[deriving(Clone)]
+struct Reads {
+	/// Where the head is at, relative to where it was last known.
+	head: int,
+	/// Each read, at the position it went to, if still known.
+	pending: Vec<(Option<int>, Span)>,
+}
+
+impl Reads {
+	/// Forgets about the reads known to be at a position.
+	fn overwrite(&mut self, pos: int) {
+		self.pending.retain(|&(at, _)| at != Some(pos));
+	}
+
+	/// Flags the reads which may be at the head, as checked by a loop.
+	fn check(&self, flagged: &mut Vec<Span>) {
+		for &(at, span) in self.pending.iter() {
+			if at.is_none() || at == Some(self.head) { flagged.push(span) }
+		}
+	}
+
+	/**
+	Takes in the reads of another way the program may have gone.
+	Unless the head ended up at the same place both ways, where
+	reads went is not known anymore.
+	*/
+	fn merge(&mut self, other: &Reads) {
+		let lost = other.head != self.head;
+		let mut pending = Vec::new();
+		for &(at, span) in self.pending.iter().chain(other.pending.iter()) {
+			let read = (if lost { None } else { at }, span);
+			if !pending.contains(&read) { pending.push(read) }
+		}
+		if lost { self.head = 0 }
+		self.pending = pending;
+	}
+}
+
+/**
+Follows the reads of a sequence of operators, flagging those checked
+by loops.
+*/
+fn follow_reads(ops: &[Operator], nodes: &[SourceNode], reads: &mut Reads, flagged: &mut Vec<Span>) {
+	for (op, node) in ops.iter().zip(nodes.iter()) {
+		let head = reads.head;
+		match *op {
+			Get => {
+				reads.overwrite(head);
+				reads.pending.push((Some(head), node.span));
+			}
+			Prev => reads.head -= 1,
+			Next => reads.head += 1,
+			PrevN(n) => reads.head -= n as int,
+			NextN(n) => reads.head += n as int,
+			Move(offset) => reads.head += offset,
+			SetAt(offset, _) => reads.overwrite(head + offset),
+			Random | SetCell(_) => reads.overwrite(head),
+			Sub(_) if is_clear(op) => reads.overwrite(head),
+			Sub(Ast(ref body)) | DoSub(Ast(ref body)) => {
+				let &SourceNode { body: SourceMap(ref body_nodes), .. } = node;
+				let skipped = reads.clone();
+				let do_while = match *op { DoSub(_) => true, _ => false };
+				if !do_while { reads.check(flagged) }
+				// Go around twice, so that reads from the end of the
+				// body reach the start of it.
+				for _ in range(0, 2) {
+					follow_reads(body.as_slice(), body_nodes.as_slice(), reads, flagged);
+					reads.check(flagged);
+					let entered = reads.clone();
+					*reads = skipped.clone();
+					reads.merge(&entered);
+				}
+				// Loops only ever end on a zero cell.
+				let head = reads.head;
+				reads.overwrite(head);
+			}
+			// Anything else keeps the cell depending on what was read.
+			_ => {}
+		}
+	}
+}
+
+/**
+What the straight-line start of a program does, folded: the top-level
+operators before its first loop, or its first read of input or random
+value, see `Ast::estimate_trip_counts`.
+*/
+#[deriving(Clone, Eq, Show)]
+pub struct PrefixSummary {
+	/// How many top-level operators the prefix is made of.
+	pub len: uint,
+	/// Where the head is after the prefix.
+	pub head: int,
+	/// The cycles the prefix takes.
+	pub cycles: uint,
+	/// The value of every cell the prefix changed, by position.
+	pub cells: ~[(int, u8)],
+}
+
+impl PrefixSummary {
+	/**
+	Folds the start of a program. The prefix also stops short of
+	moving the head left of the origin, or reaching a cell there.
+	*/
+	pub fn of(ast: &Ast) -> PrefixSummary {
+		let &Ast(ref ops) = ast;
+		let mut sketch = Sketch::new();
+		let mut effects = Effects::new();
+		let mut len = 0;
+		for op in ops.iter() {
+			match *op {
+				Sub(_) | DoSub(_) | Get | Random | HostCall => break,
+				_ => {}
+			}
+			if !fold_op(op, &mut sketch, &mut effects) { break }
+			len += 1;
+		}
+		let mut cells: ~[(int, u8)] = sketch.cells.iter().map(|(&pos, &value)| (pos, value.unwrap())).collect();
+		cells.sort();
+		PrefixSummary { len: len, head: sketch.head, cycles: sketch.cycles, cells: cells }
+	}
+}
+
+/**
+How many cycles a program takes, if it can be told without running
+it, see `Ast::estimate_trip_counts`.
+*/
+#[deriving(Clone, Eq, Show)]
+pub enum CycleEstimate {
+	/// The cycles the whole program takes, and how many times each of
+	/// its top-level loops runs, in order.
+	Estimated(uint, ~[uint]),
+	/// Some loop could not be followed.
+	Unestimated,
+}
+
+impl CycleEstimate {
+	/// How far along a run is, in percent, when there is an estimate.
+	pub fn percent(&self, cycles: uint) -> Option<uint> {
+		match *self {
+			Estimated(total, _) if cycles < total => Some(cycles * 100 / total),
+			Estimated(..) => Some(100),
+			Unestimated => None,
+		}
+	}
+
+	/// Tells how far along a run is, e.g. `cycle 200 of ~400 (50%)`.
+	pub fn progress(&self, cycles: uint) -> ~str {
+		match (self, self.percent(cycles)) {
+			(&Estimated(total, _), Some(percent)) => format!("cycle {} of ~{} ({}%)", cycles, total, percent),
+			_ => format!("cycle {}", cycles),
+		}
+	}
+}
+
+/**
+Estimates the cycles a program takes, following it from the end of
+its prefix, as run with the common semantics, see `Semantics::common`.
+
+A loop can be followed when the value of the cell it checks is known
+on entry, and every pass through its body goes the same way: the
+body comes back to that cell, only adding to it, and leaves the cells
+checked by the loops inside it as they were. The number of passes is
+then how many times what is taken off the cell on each pass goes into
+its value, wrapping around. Loops which would never end, or whose
+cell comes from input, can not be followed, and then neither can the
+program. The estimate is only advisory: runs never depend on it.
+*/
+pub fn estimate_trip_counts(ast: &Ast, prefix: &PrefixSummary) -> CycleEstimate {
+	let mut sketch = Sketch::new();
+	sketch.head = prefix.head;
+	sketch.cycles = prefix.cycles;
+	for &(pos, value) in prefix.cells.iter() {
+		sketch.cells.insert(pos, Some(value));
+	}
+	let mut effects = Effects::new();
+	let mut trips = Vec::new();
+	let &Ast(ref ops) = ast;
+	for op in ops.slice_from(min(prefix.len, ops.len())).iter() {
+		let followed = match *op {
+			Sub(Ast(ref body)) => fold_loop(body.as_slice(), false, &mut sketch, &mut effects).map(|n| trips.push(n)),
+			DoSub(Ast(ref body)) => fold_loop(body.as_slice(), true, &mut sketch, &mut effects).map(|n| trips.push(n)),
+			_ => if fold_op(op, &mut sketch, &mut effects) { Some(()) } else { None },
+		};
+		if followed.is_none() {
+			return Unestimated;
+		}
+	}
+	Estimated(sketch.cycles, trips.move_iter().collect())
+}
+
+/**
+What is known of a run followed without running it: where the head
+is, the cycles spent, and the cells which are not zero, or whose
+value is not known, by position.
+*/
+#[deriving(Clone)]
+struct Sketch {
+	head: int,
+	cycles: uint,
+	cells: HashMap<int, Option<u8>>,
+}
+
+impl Sketch {
+	/// A tape of zeros.
+	fn new() -> Sketch {
+		Sketch { head: 0, cycles: 0, cells: HashMap::new() }
+	}
+
+	/// The value of a cell, if known.
+	fn value(&self, pos: int) -> Option<u8> {
+		match self.cells.find(&pos) {
+			Some(&value) => value,
+			None => Some(0),
+		}
+	}
+
+	/// Adds to a cell, which stays unknown if it was.
+	fn add(&mut self, pos: int, delta: int) {
+		let value = self.value(pos).map(|value| (value as int + delta) as u8);
+		self.cells.insert(pos, value);
+	}
+}
+
+/// The cells a run of operators checks the value of, and those it sets.
+struct Effects {
+	checked: HashSet<int>,
+	set: HashSet<int>,
+}
+
+impl Effects {
+	fn new() -> Effects {
+		Effects { checked: HashSet::new(), set: HashSet::new() }
+	}
+}
+
+/**
+Follows an operator, or returns false if it moves the head left of
+the origin, or reaches a cell there, where it would be clamped.
+*/
+fn fold_op(op: &Operator, sketch: &mut Sketch, effects: &mut Effects) -> bool {
+	let head = sketch.head;
+	let reached = match *op {
+		Prev => head - 1,
+		Next => head + 1,
+		PrevN(n) => head - n as int,
+		NextN(n) => head + n as int,
+		Move(offset) | AddAt(offset, _) | SetAt(offset, _) => head + offset,
+		OffsetWrite(ref writes) => head + writes.iter().fold(0, |lowest, &(offset, _)| min(lowest, offset)),
+		_ => head,
+	};
+	if reached < 0 { return false }
+	match *op {
+		Incr => sketch.add(head, 1),
+		Decr => sketch.add(head, -1),
+		IncrN(n) => sketch.add(head, n as int),
+		DecrN(n) => sketch.add(head, -(n as int)),
+		AddAt(offset, delta) => sketch.add(head + offset, delta),
+		OffsetWrite(ref writes) => {
+			for &(offset, delta) in writes.iter() {
+				sketch.add(head + offset, delta);
+			}
+		}
+		SetAt(offset, value) => {
+			sketch.cells.insert(head + offset, Some(value));
+			effects.set.insert(head + offset);
+		}
+		SetCell(value) => {
+			sketch.cells.insert(head, Some(value));
+			effects.set.insert(head);
+		}
+		Get | Random => {
+			sketch.cells.insert(head, None);
+			effects.set.insert(head);
+		}
+		// What negating leaves depends on the value negated.
+		Negate => {
+			let negated = sketch.value(head).map(|value| 0 - value);
+			sketch.cells.insert(head, negated);
+			effects.checked.insert(head);
+		}
+		Prev | Next | PrevN(_) | NextN(_) | Move(_) => sketch.head = reached,
+		Sub(Ast(ref body)) => return fold_loop(body.as_slice(), false, sketch, effects).is_some(),
+		DoSub(Ast(ref body)) => return fold_loop(body.as_slice(), true, sketch, effects).is_some(),
+		Shebang(_) => return true,
+		// The host may do anything to the tape.
+		HostCall => return false,
+		Put | Nop(_) | Checkpoint(_) | Skip | Loop | DoSkip | DoLoop => {}
+	}
+	sketch.cycles += 1;
+	true
+}
+
+/**
+Follows a loop through all of its passes, see `estimate_trip_counts`,
+returning how many there are.
+*/
+fn fold_loop(body: &[Operator], do_while: bool, sketch: &mut Sketch, effects: &mut Effects) -> Option<uint> {
+	let guard = sketch.head;
+	effects.checked.insert(guard);
+	let entered = match sketch.value(guard) {
+		Some(value) => value,
+		None => return None,
+	};
+	if entered == 0 && !do_while {
+		sketch.cycles += 1;
+		return Some(0);
+	}
+
+	// Follow the first pass, which every other one has to go like.
+	let start = sketch.clone();
+	let mut pass = Effects::new();
+	for op in body.iter() {
+		if !fold_op(op, sketch, &mut pass) { return None }
+	}
+	if sketch.head != guard || pass.checked.contains(&guard) || pass.set.contains(&guard) {
+		return None;
+	}
+	for &pos in pass.checked.iter() {
+		if sketch.value(pos).is_none() || sketch.value(pos) != start.value(pos) { return None }
+	}
+	let taken = entered - sketch.value(guard).unwrap();
+	let trips = match range(1u, 257).find(|&n| n * taken as uint % 256 == entered as uint) {
+		Some(trips) => trips,
+		None => return None,
+	};
+
+	// Cells added to get as much again on each pass, while cells set
+	// end up as the first pass left them.
+	let positions: ~[int] = sketch.cells.keys().map(|&pos| pos).collect();
+	for &pos in positions.iter() {
+		if pass.set.contains(&pos) { continue }
+		let value = match (start.value(pos), sketch.value(pos)) {
+			(Some(before), Some(after)) => Some((before as uint + trips * (after - before) as uint) as u8),
+			_ => None,
+		};
+		sketch.cells.insert(pos, value);
+	}
+	// Each pass is followed by a check, and loops start with one.
+	let per_pass = sketch.cycles - start.cycles + 1;
+	sketch.cycles = start.cycles + trips * per_pass + if do_while { 0 } else { 1 };
+	for &pos in pass.checked.iter().chain(pass.set.iter()) {
+		effects.checked.insert(pos);
+	}
+	for &pos in pass.set.iter() {
+		effects.set.insert(pos);
+	}
+	effects.set.insert(guard);
+	Some(trips)
+}
+
+#[test]
+/// Should find a fragment repeated five times, but not its near-miss.
+fn test_find_repeats_reports_exact_fragment() {
+	let source = "[->+>+<<]>[->+>+<<]<[->+>+<<]+[->+>+<<]-[->+>+<<].[->+>-<<]";
+	let ast = Ast::parse_str(source).unwrap();
+	let repeats = find_repeats(&ast, 9);
+	assert!(repeats.len() == 1);
+	let repeat = repeats.get(0);
+	let fragment: ~[~str] = repeat.fragment.iter().map(|op| format!("{}", op)).collect();
+	assert!(fragment.concat() == ~"[->+>+<<]");
+	assert!(repeat.count == 5);
+	assert!(repeat.saved == 5 * 9 - 9 - 5);
+	let starts: ~[uint] = repeat.spans.iter().map(|s| s.start).collect();
+	let ends: ~[uint] = repeat.spans.iter().map(|s| s.end).collect();
+	assert!(starts == ~[0, 10, 20, 30, 40]);
+	assert!(ends == ~[9, 19, 29, 39, 49]);
+}
+
+#[test]
+/// Comments should not break a repeat, but spans should account for them.
+fn test_find_repeats_ignores_comments() {
+	let ast = Ast::parse_str("+- and +-").unwrap();
+	let repeats = find_repeats(&ast, 2);
+	assert!(repeats.len() == 1);
+	let repeat = repeats.get(0);
+	assert!(repeat.count == 2);
+	assert!(repeat.spans == ~[Span { start: 0, end: 2 }, Span { start: 7, end: 9 }]);
+}
+
+#[test]
+/// Overlapping occurrences should only be counted once.
+fn test_find_repeats_does_not_count_overlaps() {
+	let ast = Ast::parse_str("++++").unwrap();
+	let repeats = find_repeats(&ast, 2);
+	assert!(repeats.len() == 1);
+	assert!(repeats.get(0).count == 2);
+	assert!(repeats.get(0).spans == ~[Span { start: 0, end: 2 }, Span { start: 2, end: 4 }]);
+
+	let ast = Ast::parse_str("+++").unwrap();
+	assert!(find_repeats(&ast, 2).is_empty());
+}
+
+#[cfg(test)]
+/// The spans of the reads flagged in a program.
+fn flagged_reads(source: &str) -> ~[(uint, uint)] {
+	let report = eof_sensitivity(&Ast::parse_str(source).unwrap());
+	report.reads.iter().map(|span| (span.start, span.end)).collect()
+}
+
+#[test]
+/// Reads checked by a loop should be flagged, wherever the read is.
+fn test_eof_sensitivity_flags_checked_reads() {
+	assert!(flagged_reads(",[.,]") == ~[(0, 1), (3, 4)]);
+	// Adding to the cell read still depends on it.
+	assert!(flagged_reads(">,+<>[.-]") == ~[(1, 2)]);
+	// Once the head is lost track of, any pending read may be checked.
+	assert!(flagged_reads(",>[>]<[.]") == ~[(0, 1)]);
+}
+
+#[test]
+/// Reads never checked, or overwritten first, should not be flagged.
+fn test_eof_sensitivity_ignores_unchecked_reads() {
+	assert!(flagged_reads(",.").is_empty());
+	assert!(flagged_reads(",[-]+[.-]").is_empty());
+	assert!(flagged_reads(",>+[-]").is_empty());
+}
+
+#[cfg(test)]
+/// The estimate for a program, from the end of its prefix.
+fn estimate(source: &str) -> CycleEstimate {
+	let ast = Ast::parse_str(source).unwrap();
+	estimate_trip_counts(&ast, &PrefixSummary::of(&ast))
+}
+
+#[test]
+/// Loops counting their cell down should be followed through, nested or not.
+fn test_estimate_trip_counts() {
+	let ast = Ast::parse_str("++++>+<[>++<-]").unwrap();
+	let prefix = PrefixSummary::of(&ast);
+	assert!(prefix.len == 7 && prefix.head == 0 && prefix.cycles == 7);
+	assert!(prefix.cells == ~[(0, 4), (1, 1)]);
+	// 4 + 1 + 4 * (5 + 1) + 2, as the machine counts them.
+	assert!(estimate("++++[>++<-]>.") == Estimated(31, ~[4]));
+	assert!(estimate("++[>+++[>+<-]<-]") == Estimated(49, ~[2]));
+	assert!(estimate("++++++[--]") == Estimated(16, ~[3]));
+	// Taking 3 off 1 wraps around 2 times before reaching zero.
+	assert!(estimate("+[---]") == Estimated(1 + 1 + 171 * 4, ~[171]));
+	assert!(estimate("[+]>") == Estimated(2, ~[0]));
+	assert!(estimate("[+]>").percent(1) == Some(50));
+	assert!(estimate("[+]>").progress(1) == ~"cycle 1 of ~2 (50%)");
+}
+
+#[test]
+/// Loops depending on input, or never ending, should not be followed.
+fn test_estimate_trip_counts_unestimated() {
+	assert!(estimate(",[-]") == Unestimated);
+	assert!(estimate("++[>,[-]<-]") == Unestimated);
+	assert!(estimate("+[]") == Unestimated);
+	assert!(estimate("+[>+]") == Unestimated);
+	assert!(estimate("++[>[-]+<-]") == Unestimated);
+	assert!(Unestimated.progress(12) == ~"cycle 12");
+}
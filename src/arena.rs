@@ -0,0 +1,238 @@
+/*!
+A flat representation of programs, for huge ones.
+
+An `Ast` owns a vector for the body of each of its loops, which adds
+up to a lot of small allocations for programs of several megabytes.
+A `ProgramArena` instead keeps every operator of a program in a
+single vector, where each loop body is a contiguous range, and loops
+refer to the range of their body by index.
+*/
+
+use std::mem::size_of;
+use operators::{Operator, Sub, DoSub, Skip, Loop, DoSkip, DoLoop, Random, Negate, HostCall, Nop, Shebang};
+use ast::{Ast, split_shebang};
+
+
+/**
+An operator in an arena.
+*/
+#[deriving(Eq, Clone, Show)]
+pub enum ArenaOp {
+	/// Any operator but loops.
+	Plain(Operator),
+	/// A loop, whose body is the range at the given index.
+	SubRef(uint),
+	/// A do-while loop, whose body is the range at the given index.
+	DoSubRef(uint),
+}
+
+/**
+A whole program, laid out flat.
+*/
+#[deriving(Eq, Clone, Show)]
+pub struct ProgramArena {
+	/// Every operator of the program, each loop body being contiguous.
+	pub ops: Vec<ArenaOp>,
+	/// The ranges of `ops` holding each loop body, from start to end.
+	pub ranges: Vec<(uint, uint)>,
+	/// The index of the range holding the top-level operators.
+	pub root: uint,
+}
+
+impl ProgramArena {
+	/**
+	Parses a program straight into an arena, exactly like
+	`Ast::parse_str` would parse it.
+
+	Operators go onto a single stack of pending operators, on top of
+	those of the loops enclosing them, and are moved to the arena as
+	soon as their loop ends, so that there is no allocation per loop.
+	*/
+	pub fn parse(source: &str) -> Result<ProgramArena, ~str> {
+		let mut arena = ProgramArena { ops: Vec::new(), ranges: Vec::new(), root: 0 };
+		let mut pending: Vec<ArenaOp> = Vec::new();
+		// Where the pending operators of each open loop start.
+		let mut starts: Vec<uint> = Vec::new();
+
+		let (shebang, source) = split_shebang(source);
+		match shebang {
+			Some(line) => pending.push(Plain(Shebang(line.to_owned()))),
+			None => {}
+		}
+		for token in source.chars() {
+			match from_str::<Operator>(token.to_str()) {
+				Some(Skip) => starts.push(pending.len()),
+				Some(Loop) => {
+					let start = match starts.pop() {
+						Some(start) => start,
+						None => return Err(~"Unmatched `]`."),
+					};
+					let body = arena.take(&mut pending, start);
+					pending.push(SubRef(body));
+				}
+				// Do-while loops, `?`, `!` and `@` are extensions, see `ParseOptions`.
+				Some(DoSkip) | Some(DoLoop) | Some(Random) | Some(Negate) | Some(HostCall) => pending.push(Plain(Nop(token.to_str()))),
+				Some(op) => pending.push(Plain(op)),
+				None => continue,
+			}
+		}
+
+		if !starts.is_empty() {
+			return Err(~"Unmatched `[`.");
+		}
+		arena.root = arena.take(&mut pending, 0);
+		Ok(arena)
+	}
+
+	/**
+	Lays out an AST in an arena.
+	*/
+	pub fn from_ast(ast: &Ast) -> ProgramArena {
+		let mut arena = ProgramArena { ops: Vec::new(), ranges: Vec::new(), root: 0 };
+		let &Ast(ref ops) = ast;
+		arena.root = arena.lay_out(ops.as_slice());
+		arena
+	}
+
+	/**
+	Converts the program back into an AST.
+	*/
+	pub fn to_ast(&self) -> Ast {
+		self.ast_of(self.root)
+	}
+
+	/**
+	The operators in the range at the given index.
+	*/
+	pub fn body<'a>(&'a self, range: uint) -> &'a [ArenaOp] {
+		let &(start, end) = self.ranges.get(range);
+		self.ops.slice(start, end)
+	}
+
+	/**
+	A rough estimate of the memory taken by the arena, in bytes, to
+	compare with `Ast::size_estimate`. Likewise, the text of comments
+	and the spare capacity of the vectors are not accounted for.
+	*/
+	pub fn size_estimate(&self) -> uint {
+		self.ops.len() * size_of::<ArenaOp>() + self.ranges.len() * size_of::<(uint, uint)>()
+	}
+
+	/**
+	Moves the pending operators from `start` on into a new range,
+	returning its index.
+	*/
+	fn take(&mut self, pending: &mut Vec<ArenaOp>, start: uint) -> uint {
+		let first = self.ops.len();
+		self.ops.push_all(pending.slice_from(start));
+		pending.truncate(start);
+		self.ranges.push((first, self.ops.len()));
+		self.ranges.len() - 1
+	}
+
+	/**
+	Lays out a sequence of operators as a new range, returning its
+	index. The range is reserved first, and the loop bodies laid out
+	after it, so that it stays contiguous.
+	*/
+	fn lay_out(&mut self, ops: &[Operator]) -> uint {
+		let start = self.ops.len();
+		self.ops.grow(ops.len(), &Plain(Nop(~"")));
+		let index = self.ranges.len();
+		self.ranges.push((start, start + ops.len()));
+		for (i, op) in ops.iter().enumerate() {
+			let laid_out = match *op {
+				Sub(Ast(ref body)) => SubRef(self.lay_out(body.as_slice())),
+				DoSub(Ast(ref body)) => DoSubRef(self.lay_out(body.as_slice())),
+				ref other => Plain(other.clone()),
+			};
+			*self.ops.get_mut(start + i) = laid_out;
+		}
+		index
+	}
+
+	/**
+	Converts the range at the given index into an AST.
+	*/
+	fn ast_of(&self, range: uint) -> Ast {
+		Ast(self.body(range).iter().map(|op| match *op {
+			Plain(ref op) => op.clone(),
+			SubRef(body) => Sub(self.ast_of(body)),
+			DoSubRef(body) => DoSub(self.ast_of(body)),
+		}).collect())
+	}
+}
+
+#[cfg(test)]
+use machine::Machine;
+#[cfg(test)]
+use test::BenchHarness;
+
+#[test]
+/// Parsing into an arena should be the same as laying out the AST.
+fn test_arena_parse() {
+	let source = "+[>[-]<,]. x";
+	let arena = ProgramArena::parse(source).unwrap();
+	let ast = Ast::parse_str(source).unwrap();
+	assert!(arena.to_ast() == ast);
+	assert!(ProgramArena::from_ast(&ast).to_ast() == ast);
+	// Bodies are laid out as their loop ends, innermost first.
+	assert!(arena.body(0) == [Plain(::operators::Decr)].as_slice());
+	assert!(ProgramArena::parse("+[").is_err());
+	assert!(ProgramArena::parse("+]").is_err());
+}
+
+#[test]
+/// Corpus programs should convert both ways, and run the same either way.
+fn test_arena_corpus() {
+	for &(name, source, input) in ::corpus::PROGRAMS.iter() {
+		let ast = Ast::parse_str(source).unwrap();
+		for ast in [ast.clone(), ast.optimize(), ast.optimize().optimize_offsets()].iter() {
+			let arena = ProgramArena::from_ast(ast);
+			assert!(arena.to_ast() == *ast);
+			let mut machine = Machine::capturing(input);
+			machine.run_arena(&arena).unwrap();
+			let (output, tape) = ::corpus::run(ast, input);
+			let range = machine.touched_range();
+			if machine.captured_output() != output.as_slice()
+			|| machine.tape_report(range).render_ascii(64) != tape {
+				fail!("{} behaves differently from an arena", name);
+			}
+		}
+		assert!(ProgramArena::parse(source).unwrap().to_ast() == ast);
+	}
+}
+
+#[test]
+/// Deeply nested loops should convert both ways.
+fn test_arena_deep_nesting() {
+	let source = format!("+{}-{}.", "[".repeat(500), "]".repeat(500));
+	let arena = ProgramArena::parse(source).unwrap();
+	assert!(arena.ranges.len() == 501);
+	assert!(arena.to_ast() == Ast::parse_str(source).unwrap());
+	let mut machine = Machine::capturing([]);
+	// `+`, entering each loop, `-`, leaving each loop, then `.`.
+	assert!(machine.run_arena(&arena).unwrap() == 1 + 500 + 1 + 500 + 1);
+}
+
+#[cfg(test)]
+/// A generated program of a few megabytes, nesting loops a bit.
+fn huge_program() -> ~str {
+	"++++[>++[>+<-]<-]>>.<< comments ".repeat(100000)
+}
+
+#[bench]
+/// Parsing a huge program into an AST, to compare with the arena.
+fn bench_parse_huge_ast(b: &mut BenchHarness) {
+	let source = huge_program();
+	b.bytes = source.len() as u64;
+	b.iter(|| { Ast::parse_str(source).unwrap(); });
+}
+
+#[bench]
+/// Parsing a huge program into an arena.
+fn bench_parse_huge_arena(b: &mut BenchHarness) {
+	let source = huge_program();
+	b.bytes = source.len() as u64;
+	b.iter(|| { ProgramArena::parse(source).unwrap(); });
+}
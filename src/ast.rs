@@ -1,88 +1,2201 @@
 use std::fmt;
+use std::str;
+use std::io::{Reader, Writer, MemWriter, IoResult, EndOfFile};
+use std::mem::size_of;
+use std::cmp::{min, max};
 use std::from_str::FromStr;
-use operators::{Operator, Sub, Skip, Loop};
+use std::slice::Items;
+use std::hash::sip::SipState;
+use collections::hashmap::HashMap;
+use operators::{Operator, Sub, Skip, Loop, Incr, Decr, Prev, Next, Put, Get, Nop};
+use operators::{DoSub, DoSkip, DoLoop, Random, Negate, HostCall, Checkpoint, SetCell, Shebang};
+use operators::{IncrN, DecrN, PrevN, NextN, AddAt, SetAt, Move, OffsetWrite};
+use rewrite::{Ruleset, OpPattern, Match, is_clear};
+use sourcemap::SourceMap;
+use parse::ParseTree;
 
 
 /**
 The internal parsed representation of a program source.
 */
+#[deriving(Eq, TotalEq, Hash, Clone)]
 pub struct Ast(~[Operator]);
 
+/**
+A range of characters in a program source, from `start` inclusive
+to `end` exclusive. Offsets are counted in chars, not bytes.
+*/
+#[deriving(Eq, TotalEq, Clone)]
+pub struct Span {
+	pub start: uint,
+	pub end: uint,
+}
+
+/**
+Options for parsing a program, see `Ast::parse_with`.
+*/
+#[deriving(Clone, Eq, Hash, Show)]
+pub struct ParseOptions {
+	/**
+	Whether to recover from unmatched brackets instead of failing.
+	Unmatched `[` are closed at the end of the source, as if enough
+	`]` followed it, and unmatched `]` are dropped. Do-while
+	brackets are never recovered from.
+	*/
+	pub recover_brackets: bool,
+	/**
+	Whether to parse the extended operators: `{...}` do-while loops,
+	whose body runs once before the cell is first checked, `?`
+	setting the cell to a random value, `!` negating the cell,
+	`#name` checkpoints, named by the letters, digits and
+	underscores after the `#`, `=N` setting the cell to the
+	value N, written without leading zeros, and `@` calling the
+	host back.
+	Otherwise these are comments, as in standard programs.
+	*/
+	pub extensions: bool,
+	/**
+	How many operators the program may have at most, loops counting
+	as one each on top of their bodies, and comments included. Bigger
+	programs fail to parse as soon as they go over.
+	*/
+	pub max_ops: Option<uint>,
+	/**
+	Whether a `!` ends the program, what follows it being input for
+	it, as some interpreters written in Brainfuck expect. Otherwise
+	it is a comment, or negates the cell with extensions, which
+	take precedence.
+	*/
+	pub input_section: bool,
+	/**
+	Whether to fold runs of `+` and `-` as they are parsed, into
+	`IncrN` and `DecrN`, as optimizing would. Anything else between
+	two of them breaks the run, comments and whitespace included,
+	and single ones are left as they are. `max_ops` still counts
+	each of them.
+	*/
+	pub compress_runs: bool,
+}
+
+/// How many bytes of a stream are read at once, see `Ast::parse_reader`.
+pub static PARSE_BUFFER_SIZE: uint = 16 * 1024;
+
+/**
+How deep loops may nest in programs parsed or loaded from bytes, and
+run, so that walking them recursively can not run out of stack. See
+`Ast::nesting`.
+*/
+pub static MAX_NESTING: uint = 1000;
+
+impl ParseOptions {
+	/// The default options, failing on anything amiss.
+	pub fn strict() -> ParseOptions {
+		ParseOptions { recover_brackets: false, extensions: false, max_ops: None, input_section: false, compress_runs: false }
+	}
+}
+
+/**
+Options for finding programs in other text, see
+`Ast::extract_from_text`.
+*/
+#[deriving(Clone, Eq, Show)]
+pub struct ExtractOptions {
+	/**
+	How many operators a run of them needs, at least, to be taken
+	for a program rather than incidental punctuation.
+	*/
+	pub min_ops: uint,
+	/**
+	Whether whitespace may go between the operators of a run. Any
+	other character that is not one of the eight operators ends it.
+	*/
+	pub whitespace_inside: bool,
+}
+
+impl ExtractOptions {
+	/// Runs of 16 operators or more, which whitespace does not break.
+	pub fn default() -> ExtractOptions {
+		ExtractOptions { min_ops: 16, whitespace_inside: true }
+	}
+}
+
+/**
+What a recovering parse did about a problem in the source.
+*/
+#[deriving(Clone, Eq, Show)]
+pub enum WarningKind {
+	/// An unmatched `[` was closed at the end of the source.
+	ClosedAtEnd,
+	/// An unmatched `]` was dropped.
+	DroppedClose,
+}
+
+/**
+A problem a recovering parse worked around, and where it was found.
+*/
+#[deriving(Clone, Eq)]
+pub struct ParseWarning {
+	/// The offset of the offending bracket in the source, in chars.
+	pub position: uint,
+	/// What was done about it.
+	pub kind: WarningKind,
+}
+
+impl fmt::Show for ParseWarning {
+	fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+		match self.kind {
+			ClosedAtEnd => write!(f.buf, "Unmatched `[` at {}, closed at the end.", self.position),
+			DroppedClose => write!(f.buf, "Unmatched `]` at {}, dropped.", self.position),
+		}
+	}
+}
+
+/**
+Splits a `#!` line off the start of a source, newline included, so
+that scripts can be run directly. Returns the line, if any, and the
+rest of the source. Lines merely starting with `#` are kept.
+*/
+pub fn split_shebang<'a>(source: &'a str) -> (Option<&'a str>, &'a str) {
+	if !source.starts_with("#!") {
+		return (None, source);
+	}
+	let end = source.find('\n').map_or(source.len(), |newline| newline + 1);
+	(Some(source.slice_to(end)), source.slice_from(end))
+}
+
 impl Ast {
 	/**
 	Produce an AST from a source string.
 	This is the most commod method to generate an Ast.
 	*/
 	pub fn parse_str(source: &str) -> Result<Ast, ~str> {
+		Ast::parse_with(source, ParseOptions::strict()).map(|(ast, _)| ast)
+	}
+
+	/**
+	Produce an AST from a source string, recovering from unmatched
+	brackets rather than failing, see `ParseOptions::recover_brackets`.
+	Returns what was recovered from alongside the AST.
+	*/
+	pub fn parse_str_recover(source: &str) -> (Ast, Vec<ParseWarning>) {
+		let options = ParseOptions { recover_brackets: true, ..ParseOptions::strict() };
+		match Ast::parse_with(source, options) {
+			Ok(parsed) => parsed,
+			Err(msg) => fail!("Recovering parse failed: {}", msg),
+		}
+	}
+
+	/**
+	Produce an AST from a source string, failing as soon as it goes
+	over `max_ops` operators, see `ParseOptions::max_ops`, rather than
+	parsing it whole first. Useful to refuse huge programs cheaply.
+	*/
+	pub fn parse_str_limited(source: &str, max_ops: uint) -> Result<Ast, ~str> {
+		let options = ParseOptions { max_ops: Some(max_ops), ..ParseOptions::strict() };
+		Ast::parse_with(source, options).map(|(ast, _)| ast)
+	}
+
+	/**
+	Produce an AST from a source string, with runs of `+` and `-`
+	folded as they are parsed, see `ParseOptions::compress_runs`.
+	Cheaper than parsing then optimizing, for callers who want no
+	more than that. Programs with runs folded have no `source`.
+	*/
+	pub fn parse_str_compressed(source: &str) -> Result<Ast, ~str> {
+		let options = ParseOptions { compress_runs: true, ..ParseOptions::strict() };
+		Ast::parse_with(source, options).map(|(ast, _)| ast)
+	}
+
+	/**
+	Finds the programs in a text which is not one, such as a post or
+	a comment, along with where each one is, in chars.
+
+	Candidates are maximal runs of the eight standard operators, see
+	`ExtractOptions` for what breaks a run. Runs are trimmed to their
+	first and last operators, and kept if they have enough operators
+	and their brackets balance. The others are skipped.
+	*/
+	pub fn extract_from_text(text: &str, options: ExtractOptions) -> Vec<(Span, Ast)> {
+		let is_op = |c: char| "+-<>.,[]".contains_char(c);
+		let chars: ~[char] = text.chars().collect();
+		let mut found = Vec::new();
+		let mut i = 0;
+		while i < chars.len() {
+			if !is_op(chars[i]) {
+				i += 1;
+				continue;
+			}
+			let (start, mut end, mut ops) = (i, i, 0u);
+			while i < chars.len() && (is_op(chars[i]) || (options.whitespace_inside && chars[i].is_whitespace())) {
+				if is_op(chars[i]) {
+					ops += 1;
+					end = i + 1;
+				}
+				i += 1;
+			}
+			if ops < options.min_ops {
+				continue;
+			}
+			let source: ~str = chars.slice(start, end).iter().map(|&c| c).collect();
+			match Ast::parse_str(source) {
+				Ok(ast) => found.push((Span { start: start, end: end }, ast)),
+				Err(_) => {}
+			}
+		}
+		found
+	}
+
+	/**
+	Produce an AST from a source string, with the given options, by
+	lowering its `ParseTree`. Returns the warnings about what was
+	recovered from, if anything, alongside the AST.
+
+	A `#!` line starting the source is skipped before anything else,
+	and kept as a `Shebang` operator, so that offsets in the source
+	still count it. See `split_shebang`.
+	*/
+	pub fn parse_with(source: &str, options: ParseOptions) -> Result<(Ast, Vec<ParseWarning>), ~str> {
+		// The tree keeps every character, and lowering makes the
+		// operators out of it, see `ParseTree`.
+		ParseTree::parse(source, options)
+			.and_then(|tree| tree.lower_with_warnings(options))
+			.map_err(|err| format!("{}", err))
+	}
+
+	/**
+	Produce an AST from a source read from a stream, as `parse_str`
+	would from the whole of it, without holding the source itself.
+	The stream is read `PARSE_BUFFER_SIZE` bytes at a time, so only
+	the AST grows with the source.
+
+	Errors tell the offset in bytes from the start of the stream
+	where the parse failed: that of the unmatched bracket, of the
+	bracket nesting deeper than `MAX_NESTING`, of the char which is
+	not UTF-8, or of what could not be read.
+	*/
+	pub fn parse_reader(reader: &mut Reader) -> Result<Ast, ~str> {
 		/*
-		We parse loops by making a context to group its operators,
-		pushing on it until the matching loop end. As we create the
-		context, we push the previous one onto a stack. After the
-		nest has been collected, we pop the context and replace it
-		with the subprocess operator.
+		Chars are decoded as the bytes come, keeping the bytes of a
+		char split across reads for the next one, then parsed like
+		`parse_with` does, with a stack of the contexts of the loops
+		being read, along with where they started.
 		*/
-		let mut stack:~[ ~[Operator] ] = ~[];
+		let mut buffer = [0u8, ..PARSE_BUFFER_SIZE];
+		let mut stack: ~[ (~[Operator], uint) ] = ~[];
 		let mut ops: ~[Operator] = ~[];
+		// The bytes of the char being decoded.
+		let mut pending: Vec<u8> = Vec::new();
+		// The `#!` line being read, if the source starts with `#`.
+		let mut shebang: Option<StrBuf> = None;
+		let mut offset = 0u;
+		loop {
+			let read = match reader.read(buffer) {
+				Ok(read) => read,
+				Err(ref e) if e.kind == EndOfFile => break,
+				Err(e) => return Err(format!("Could not read the source at byte {}: {}", offset, e)),
+			};
+			for &byte in buffer.slice_to(read).iter() {
+				offset += 1;
+				pending.push(byte);
+				if pending.len() < str::utf8_char_width(*pending.get(0)) {
+					continue;
+				}
+				let position = offset - pending.len();
+				let token = match str::from_utf8(pending.as_slice()) {
+					Some(chars) => chars.char_at(0),
+					None => return Err(format!("Invalid UTF-8 at byte {}.", position)),
+				};
+				pending.clear();
+				// A `#` starting the source may start a `#!` line,
+				// which goes on until its newline, included.
+				if position == 0 && token == '#' {
+					shebang = Some(StrBuf::from_owned_str(~"#"));
+					continue;
+				}
+				if shebang.is_some() {
+					if shebang.get_ref().len() > 1 || token == '!' {
+						shebang.get_mut_ref().push_char(token);
+						if token == '\n' {
+							ops.push(Shebang(shebang.take_unwrap().into_owned()));
+						}
+						continue;
+					}
+					ops.push(Nop(shebang.take_unwrap().into_owned()));
+				}
+				match from_str::<Operator>(token.to_str()) {
+					Some(Skip) if stack.len() == MAX_NESTING => {
+						return Err(format!("Loops nested too deep at byte {}.", position));
+					}
+					Some(Skip) => {
+						stack.push((ops, position));
+						ops = ~[];
+					}
+					Some(Loop) => match stack.pop() {
+						Some((outer, _)) => {
+							let sub_ast = Sub(Ast( ops ));
+							ops = outer;
+							ops.push(sub_ast);
+						}
+						None => return Err(format!("Unmatched `]` at byte {}.", position)),
+					},
+					// Extensions are comments, as with `parse_str`.
+					Some(DoSkip) | Some(DoLoop) | Some(Random) | Some(Negate) | Some(HostCall) => ops.push(Nop(token.to_str())),
+					Some(op) => ops.push(op),
+					None => {}
+				}
+			}
+		}
+		if !pending.is_empty() {
+			return Err(format!("Invalid UTF-8 at byte {}.", offset - pending.len()));
+		}
+		match shebang.take() {
+			Some(line) => ops.push(if line.len() > 1 { Shebang(line.into_owned()) } else { Nop(line.into_owned()) }),
+			None => {}
+		}
+		match stack.last() {
+			Some(&(_, position)) => Err(format!("Unmatched `[` at byte {}.", position)),
+			None => Ok(Ast(ops)),
+		}
+	}
+
+	/**
+	The number of source characters this AST was parsed from.
+	As the parser keeps every character, this is the length of the
+	original source.
+	*/
+	pub fn source_len(&self) -> uint {
+		let &Ast(ref ops) = self;
+		ops.iter().fold(0, |len, op| len + op.source_len())
+	}
+
+	/**
+	The source this AST was parsed from, if it is still as parsed.
+	The parser keeps every character, comments and `#!` line
+	included, so that the AST is its own source, and holding it
+	apart would cost as much memory again. The source is spelled
+	out anew on each call, so it is owned, there being no string to
+	borrow it from. ASTs holding optimized operators have no source,
+	as those may be spelled out differently than they were written.
+	That goes for counted `+` and `-` too, even when the parser made
+	them, see `ParseOptions::compress_runs`: they can not be told
+	apart from those optimizing made, e.g. of `+ +`, so compressed
+	parses have no source either.
+
+	ASTs recovered from unmatched brackets, see
+	`ParseOptions::recover_brackets`, give back the source as it was
+	recovered, not as it was written: with the `]` closing unmatched
+	`[` at its end, and without the unmatched `]` which were dropped.
+	Whether there were any is told by the warnings of `parse_with`.
+	*/
+	pub fn source(&self) -> Option<~str> {
+		if self.is_as_parsed() { Some(format!("{}", *self)) } else { None }
+	}
+
+	/// Whether the AST holds only operators the parser makes.
+	fn is_as_parsed(&self) -> bool {
+		let &Ast(ref ops) = self;
+		ops.iter().all(|op| match *op {
+			Sub(ref ast) | DoSub(ref ast) => ast.is_as_parsed(),
+			IncrN(..) | DecrN(..) | PrevN(..) | NextN(..) => false,
+			AddAt(..) | SetAt(..) | Move(..) | OffsetWrite(..) => false,
+			_ => true,
+		})
+	}
+
+	/**
+	The number of operators in this AST, counting those in loop
+	bodies. A loop counts as one operator on top of its body, so
+	`[+]` has two.
+	*/
+	pub fn node_count(&self) -> uint {
+		let &Ast(ref ops) = self;
+		ops.iter().fold(0, |count, op| count + match *op {
+			Sub(ref ast) | DoSub(ref ast) => 1 + ast.node_count(),
+			_ => 1,
+		})
+	}
+
+	/**
+	Every loop of the program, with where its opening bracket is in
+	the source, and how far a pass through its body moves the head:
+	zero for balanced loops, which check the same cell every time,
+	and otherwise how far they drift. Loops are listed in source
+	order, those in a body after the loop holding them.
+
+	How far an unbalanced loop goes depends on the tape, so loops in
+	a body are taken as balanced there, being reported on their own.
+	*/
+	pub fn loop_balance_report(&self) -> Vec<(uint, int)> {
+		let mut report = Vec::new();
+		loop_balance(self, 0, &mut report);
+		report
+	}
+
+	/**
+	A rough estimate of the memory taken by this AST, in bytes: the
+	size of an operator for each of its nodes. The text of comments,
+	and the spare capacity of loop bodies, are not accounted for.
+	*/
+	pub fn size_estimate(&self) -> uint {
+		self.node_count() * size_of::<Operator>()
+	}
+
+	/**
+	Whether the program reads any input, that is whether it has a
+	`,` anywhere, even in a loop which would never be entered.
+	*/
+	pub fn uses_input(&self) -> bool {
+		let &Ast(ref ops) = self;
+		ops.iter().any(|op| match *op {
+			Get => true,
+			Sub(ref ast) | DoSub(ref ast) => ast.uses_input(),
+			_ => false,
+		})
+	}
+
+	/**
+	Whether the program does any I/O, that is whether it has a `.`, a
+	`,` or an `@` anywhere, even in a loop which would never be
+	entered. Calling the host back counts, as the host may do anything.
+	Programs without any can only compute on their tape.
+	*/
+	pub fn has_io(&self) -> bool {
+		let &Ast(ref ops) = self;
+		ops.iter().any(|op| match *op {
+			Get | Put | HostCall => true,
+			Sub(ref ast) | DoSub(ref ast) => ast.has_io(),
+			_ => false,
+		})
+	}
+
+	/**
+	Produces an assembler-style listing of the program, in its
+	flattened form: one instruction per line, prefixed with its
+	address. Loops become a `JZ` to the instruction after the loop,
+	and a `JNZ` back to the first instruction of their body, while
+	do-while loops only need the `JNZ`.
+	Comments are not executable, so they are left out.
+
+	For example, `[+]` is listed as:
+
+	```text
+	0000 JZ 0003
+	0001 INC
+	0002 JNZ 0001
+	```
+	*/
+	pub fn disassemble(&self) -> ~str {
+		let &Ast(ref ops) = self;
+		let mut listing = Vec::new();
+		flatten(ops.as_slice(), &mut listing);
+
+		let mut out = StrBuf::new();
+		for (address, instruction) in listing.iter().enumerate() {
+			out.push_str(format!("{:04u} {}\n", address, *instruction));
+		}
+		out.into_owned()
+	}
+
+	/**
+	Produces an optimized copy of the program, by applying the
+	built-in rewrite rules: runs of `+`, `-`, `<` or `>` are folded
+	into single counted operators, which execute in a single cycle,
+	repeated clearing loops are dropped, and so are comments. What
+	the program does is kept under any semantics, see
+	`rewrite::Ruleset::builtin`.
+	*/
+	pub fn optimize(&self) -> Ast {
+		Ruleset::builtin().apply(self)
+	}
+
+	/**
+	Optimizes the program like `optimize` does, along with a map of
+	where each optimized operator came from in the source, so that
+	errors can still be located: `IncrN(5)` spans its five `+`.
+	The program must be as it was parsed.
+	*/
+	pub fn optimize_mapped(&self) -> (Ast, SourceMap) {
+		Ruleset::builtin().apply_mapped(self, &SourceMap::of(self))
+	}
+
+	/**
+	Produces a copy of the program where the head only moves when it
+	has to. Straight-line code, between loops and I/O, is rewritten
+	into operators adding to or setting the cells at some offset from
+	the head, followed by a single `Move` of the head if it ended up
+	anywhere else. Clearing loops, `[-]`, count as straight-line code
+	setting their cell to zero. Comments are dropped.
+
+	For example, `>>+++<-<` becomes `AddAt(2, 3)` and `AddAt(1, -1)`,
+	and needs no `Move` at all, as the head ends where it started.
+
+	Segments never reach further left than the head provably is from
+	the left edge of the tape, counting from the start of the program,
+	so that the head stops at the edge where it would have: `<+>` at
+	the start keeps its `<` as it is. The rewritten program then does
+	the same to the tape, in fewer cycles, as long as it keeps away
+	from the right edge of tapes of a fixed size, and its cells wrap
+	around: cells are only checked for overflow after each whole
+	addition.
+	*/
+	pub fn optimize_offsets(&self) -> Ast {
+		self.offsets_within(0)
+	}
+
+	/**
+	Rewrites the program like `optimize_offsets`, given how many cells
+	the head is at least from the left edge of the tape.
+	*/
+	fn offsets_within(&self, room: int) -> Ast {
+		let &Ast(ref ops) = self;
+		let mut optimized = ~[];
+		let mut segment = Segment { head: 0, room: room, cells: Vec::new() };
+		for op in ops.iter() {
+			if segment.absorb(op) { continue }
+			// Anything else is a barrier, before which the segment's
+			// effects must have happened.
+			segment.flush(&mut optimized);
+			optimized.push(match *op {
+				Sub(ref ast) => Sub(ast.offsets_within(loop_room(ast, segment.room))),
+				DoSub(ref ast) => DoSub(ast.offsets_within(loop_room(ast, segment.room))),
+				_ => op.clone(),
+			});
+			segment.room = match *op {
+				Sub(ref ast) | DoSub(ref ast) => loop_room(ast, segment.room),
+				// Moves going too far left may stop at the edge.
+				_ => max(0, segment.room + write_effect(op).map_or(0, |(moved, _)| moved)),
+			};
+		}
+		segment.flush(&mut optimized);
+		Ast(optimized)
+	}
+
+	/**
+	Produces a copy of the program where balanced runs of moves and
+	additions, bringing the head back where they started, are folded
+	into a single `OffsetWrite` each, adding to every cell they change
+	at once. For example, `>+>++<<` becomes `OffsetWrite(~[(1, 1),
+	(2, 2)])`, which costs one cycle instead of seven. Of a run, the
+	longest stretch bringing the head back is folded first, then the
+	rest of the run is searched likewise. Comments within what is
+	folded are dropped. Unlike with `optimize_offsets`, runs leaving
+	the head anywhere else are left alone.
+
+	Only stretches whose writes are provably the same under any
+	semantics are folded: those adding to each cell in a single
+	direction, so that whatever overflows still does, and never
+	reaching further left than the head provably is from the left
+	edge, as with `optimize_offsets`. `>-+<` and `<+>` at the start of
+	a program are kept as they are.
+	*/
+	pub fn fold_offset_writes(&self) -> Ast {
+		self.writes_within(0)
+	}
 
-		for token in source.chars() {
-			match from_str::<Operator>(token.to_str()) {
-				/*
-				Start of a loop. Produce a new context in which
-				to push operators, and push the old one on the
-				stack.
-				*/
-				Some(Skip) => {
-					stack.push(ops);
-					ops = ~[];
+	/**
+	Folds the program like `fold_offset_writes`, given how many cells
+	the head is at least from the left edge of the tape.
+	*/
+	fn writes_within(&self, room: int) -> Ast {
+		let &Ast(ref ops) = self;
+		let mut folded = ~[];
+		let mut room = room;
+		let mut i = 0;
+		while i < ops.len() {
+			// Where the head is at before each operator of the run,
+			// and after its last.
+			let mut heads = vec!(0);
+			for op in ops.slice_from(i).iter() {
+				match write_effect(op) {
+					Some((moved, _)) => { let head = *heads.last().unwrap(); heads.push(head + moved) }
+					None => break,
 				}
-				/*
-				End of a loop. Make a subprocess operator out of
-				the just-collected context, and push that on the
-				previous context.
-				*/
-				Some(Loop) => {
-					let sub_ast = Sub(Ast( ops ));
-					// Try to pop the previous context from the stack.
-					// If this does not work, it's an unmatched `]`.
-					ops = match stack.pop() {
-						Some(ops) => ops,
-						_ => return Err(~"Unmatched `]`."),
-					};
-					ops.push(sub_ast);
+			}
+			let run = ops.slice(i, i + heads.len() - 1);
+			if run.is_empty() {
+				folded.push(match ops[i] {
+					Sub(ref ast) => Sub(ast.writes_within(loop_room(ast, room))),
+					DoSub(ref ast) => DoSub(ast.writes_within(loop_room(ast, room))),
+					ref other => other.clone(),
+				});
+				match ops[i] {
+					Sub(ref ast) | DoSub(ref ast) => room = loop_room(ast, room),
+					_ => {}
+				}
+				i += 1;
+				continue;
+			}
+
+			let mut last = HashMap::new();
+			for (k, head) in heads.iter().enumerate() {
+				last.insert(*head, k);
+			}
+			let mut k = 0;
+			while k < run.len() {
+				let end = *last.get(heads.get(k));
+				let lowest = heads.slice(k, end + 1).iter().fold(*heads.get(k), |lowest, &head| min(lowest, head));
+				let writes = if end > k && lowest - *heads.get(k) >= -room {
+					balanced_writes(run.slice(k, end))
+				} else {
+					None
+				};
+				match writes {
+					Some(ref writes) if writes.iter().any(|&(offset, _)| offset != 0) => {
+						folded.push(OffsetWrite(writes.clone()));
+						k = end;
+					}
+					// Whatever is left alone may stop at the edge.
+					_ => {
+						folded.push(run[k].clone());
+						room = max(0, room + write_effect(&run[k]).map_or(0, |(moved, _)| moved));
+						k += 1;
+					}
 				}
-				// Push the operator onto the context.
-				Some(op) => ops.push(op),
-				// Unknown. Probably comments. Nop.
-				_ => continue
 			}
+			i += run.len();
 		}
+		Ast(folded)
+	}
 
-		// If we still have things on the stack, then we have one or
-		// more unmatched `[`.
-		if ! stack.is_empty() {
-			return Err(~"Unmatched `[`.");
+	/**
+	Drops the operators at the start of the program which provably do
+	nothing, the tape being all zeros there and the head on the origin:
+	loops, which are skipped, and `!` and `=0`, which leave the cell at
+	zero. Comments, checkpoints and shebang lines are kept, and looked
+	past. Everything from the first other operator on is kept as it
+	is, moves included: what moving left of the origin does depends on
+	the semantics and the tape.
+	*/
+	pub fn minimize(&self) -> Ast {
+		let &Ast(ref ops) = self;
+		let mut kept = Vec::new();
+		for (i, op) in ops.iter().enumerate() {
+			match *op {
+				Sub(_) | Negate | SetCell(0) | SetAt(0, 0) => {}
+				Nop(_) | Checkpoint(_) | Shebang(_) => kept.push(op.clone()),
+				_ => {
+					kept.push_all(ops.slice_from(i));
+					break;
+				}
+			}
 		}
+		Ast(kept.move_iter().collect())
+	}
 
-		// Everything went well.
-		return Ok(Ast(ops));
+	/**
+	Produces the mirror image of the program, where the head moves
+	the other way: every `<` becomes a `>` and vice versa, loops
+	included. Offsets of optimized operators are mirrored as well.
+	*/
+	pub fn mirror(&self) -> Ast {
+		let &Ast(ref ops) = self;
+		Ast(ops.iter().map(|op| match *op {
+			Prev => Next,
+			Next => Prev,
+			PrevN(n) => NextN(n),
+			NextN(n) => PrevN(n),
+			Move(offset) => Move(-offset),
+			AddAt(offset, delta) => AddAt(-offset, delta),
+			SetAt(offset, value) => SetAt(-offset, value),
+			OffsetWrite(ref writes) => OffsetWrite(writes.iter().map(|&(offset, delta)| (-offset, delta)).collect()),
+			Sub(ref ast) => Sub(ast.mirror()),
+			DoSub(ref ast) => DoSub(ast.mirror()),
+			ref other => other.clone(),
+		}).collect())
 	}
-}
 
-impl FromStr for Ast {
-	fn from_str(source: &str) -> Option<Ast> {
-		Ast::parse_str(source).ok()
+	/**
+	Splits the program at its top level into segments: each run of
+	operators between loops, comments included, and each loop on its
+	own. Putting the segments back together gives the program again.
+	*/
+	pub fn top_level_segments(&self) -> Vec<Ast> {
+		let &Ast(ref ops) = self;
+		let mut segments = Vec::new();
+		let mut run = Vec::new();
+		for op in ops.iter() {
+			match *op {
+				Sub(_) | DoSub(_) => {
+					if !run.is_empty() {
+						segments.push(Ast(::std::mem::replace(&mut run, Vec::new()).move_iter().collect()));
+					}
+					segments.push(Ast(~[op.clone()]));
+				}
+				_ => run.push(op.clone()),
+			}
+		}
+		if !run.is_empty() {
+			segments.push(Ast(run.move_iter().collect()));
+		}
+		segments
 	}
-}
 
-impl fmt::Show for Ast {
 	/**
-	Parses a string into the matching operator.
+	Serializes the program into a compact binary form, which
+	`from_bytes` loads back much faster than parsing the source, e.g.
+	to cache optimized programs. Every operator is kept as it is,
+	counted and offset ones included.
+
+	The bytes start with `BFA` and a version byte, followed by the
+	operators. Each operator is a tag byte, then its arguments:
+	numbers as LEB128 varints, zig-zag encoded first when signed,
+	text as its length in bytes then its UTF-8, and loops as the
+	length of their body in bytes, then the body.
 	*/
-	fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+	pub fn to_bytes(&self) -> Vec<u8> {
+		let mut out = Vec::from_slice(BYTES_MAGIC);
 		let &Ast(ref ops) = self;
-		let display = |op: &Operator| -> ~str { format!("{}", op) };
-		let repr: ~[~str] = ops.iter().map(display).collect();
-		f.buf.write(format!("{}", repr.concat()).as_bytes()
-		)
+		encode_ops(ops.as_slice(), &mut out);
+		out
+	}
+
+	/**
+	Loads a program serialized by `to_bytes`, or tells what is wrong
+	with the bytes. Programs nesting loops deeper than `MAX_NESTING`
+	are refused.
+	*/
+	pub fn from_bytes(bytes: &[u8]) -> Result<Ast, ~str> {
+		if !bytes.starts_with(BYTES_MAGIC) {
+			return Err(~"Not a serialized program, or of another version.");
+		}
+		let mut decoder = Decoder { bytes: bytes, pos: BYTES_MAGIC.len(), depth: 0 };
+		decoder.ops(bytes.len())
+	}
+
+	/**
+	A hash of the program which stays the same from run to run, and
+	from machine to machine, e.g. to key a cache of results: the
+	SipHash-2-4 of `to_bytes`, keyed with zeros. Programs differing
+	in anything, comments included, have different signatures, save
+	for collisions.
+	*/
+	pub fn signature(&self) -> u64 {
+		let mut state = SipState::new_with_keys(0, 0);
+		// Writing to the state can not fail.
+		let _ = state.write(self.to_bytes().as_slice());
+		state.result()
+	}
+
+	/**
+	The form shared by every program doing the same operations, however
+	it is written: comments and shebang lines are dropped, the rules of
+	`Ruleset::wrapping()` are applied until nothing changes, e.g. turning
+	`+-+` into `+`, then counted operators are spelled out, `IncrN(3)`
+	as `+++`, and `Move(-2)` as `<<`. Cells are taken to wrap around,
+	as under the default semantics; moves cancelling out, such as `<>`,
+	are still kept, as the head may stop at the edge of the tape.
+	*/
+	pub fn canonical(&self) -> Ast {
+		let Ast(ops) = Ruleset::wrapping().apply(self);
+		Ast(spell_out(ops.as_slice()))
+	}
+
+	/**
+	A hash identifying the program by what it does rather than how it
+	is written, e.g. to key a database of programs: the `signature` of
+	its `canonical` form. Programs differing in comments, whitespace,
+	how runs are counted or additions cancelling out hash the same,
+	while any change to what they do when cells wrap around makes a
+	different hash, save for collisions.
+	*/
+	pub fn canonical_hash(&self) -> u64 {
+		self.canonical().signature()
+	}
+
+	/// The `canonical_hash` of the program, as 16 hex digits.
+	pub fn canonical_hash_hex(&self) -> ~str {
+		format!("{:016x}", self.canonical_hash())
+	}
+
+	/**
+	Describes how `other` differs from this program, operator by
+	operator, in sequence.
+
+	The alignment is merely positional: the operators at the same
+	position are compared, recursing into loops present at the same
+	position in both programs. This is enough to show how the program
+	was changed in place, e.g. by `optimize`, but an operator inserted
+	or removed early on makes every operator after it look changed.
+	*/
+	pub fn diff(&self, other: &Ast) -> Vec<DiffEntry> {
+		let mut entries = Vec::new();
+		diff_ops(self, other, ~[], &mut entries);
+		entries
+	}
+
+	/**
+	Finds where a pattern matches the program, loop bodies included,
+	with the span of source each match covers. Comments are skipped,
+	both between the operators matched and in the loop bodies matched
+	by `LoopOf`. See `OpPattern`.
+
+	Matches never overlap. The program is searched leftmost-longest:
+	from left to right, the longest match starting at each operator is
+	taken, and the search goes on right after it, so that loops which
+	are part of a match are not searched themselves. Patterns matching
+	no operator at all are never reported.
+	*/
+	pub fn find(&self, pattern: &OpPattern) -> Vec<Match> {
+		::rewrite::find(self, &SourceMap::of(self), pattern)
+	}
+}
+
+/**
+A difference between two programs, as found by `Ast::diff`.
+
+Each entry carries the position of the operator it is about, as the
+path of indices leading to it through the nested loops.
+*/
+#[deriving(Eq, Clone, Show)]
+pub enum DiffEntry {
+	/// An operator only present in the other program.
+	Added(~[uint], Operator),
+	/// An operator only present in the original program.
+	Removed(~[uint], Operator),
+	/// An operator which was replaced by another.
+	Changed(~[uint], Operator, Operator),
+}
+
+/**
+A segment of straight-line code being rewritten by `optimize_offsets`.
+*/
+struct Segment {
+	/// Where the head is at, relative to the start of the segment.
+	head: int,
+	/// How many cells the head is at least from the left edge of the
+	/// tape, at the start of the segment.
+	room: int,
+	/**
+	By order of first access, the position of each cell the segment
+	changed, the value it was set to if any, and how much was added
+	to it since.
+	*/
+	cells: Vec<(int, Option<u8>, int)>,
+}
+
+impl Segment {
+	/**
+	Takes in the effects of an operator, or returns false if it can
+	not be part of a segment, reaching too far left included.
+	*/
+	fn absorb(&mut self, op: &Operator) -> bool {
+		let (head, room) = (self.head, self.room);
+		let reaches = |offset: int| head + offset >= -room;
+		let safe = match *op {
+			AddAt(offset, _) | SetAt(offset, _) => reaches(offset),
+			OffsetWrite(ref writes) => writes.iter().all(|&(offset, _)| reaches(offset)),
+			_ => write_effect(op).map_or(true, |(moved, _)| reaches(moved)),
+		};
+		if !safe { return false }
+		match *op {
+			Incr => self.add(head, 1),
+			Decr => self.add(head, -1),
+			IncrN(n) => self.add(head, n as int),
+			DecrN(n) => self.add(head, -(n as int)),
+			AddAt(offset, delta) => self.add(head + offset, delta),
+			SetAt(offset, value) => self.set(head + offset, value),
+			SetCell(value) => self.set(head, value),
+			OffsetWrite(ref writes) => {
+				for &(offset, delta) in writes.iter() {
+					self.add(head + offset, delta);
+				}
+			}
+			Sub(_) if is_clear(op) => self.set(head, 0),
+			Prev => self.head -= 1,
+			Next => self.head += 1,
+			PrevN(n) => self.head -= n as int,
+			NextN(n) => self.head += n as int,
+			Move(offset) => self.head += offset,
+			Nop(_) | Shebang(_) => {}
+			_ => return false,
+		}
+		true
+	}
+
+	/// Adds to the cell at a position.
+	fn add(&mut self, pos: int, delta: int) {
+		match *self.cell(pos) {
+			(_, _, ref mut added) => *added += delta,
+		}
+	}
+
+	/// Sets the cell at a position, forgetting what was added to it.
+	fn set(&mut self, pos: int, value: u8) {
+		*self.cell(pos) = (pos, Some(value), 0);
 	}
+
+	/// The changes to the cell at a position.
+	fn cell<'a>(&'a mut self, pos: int) -> &'a mut (int, Option<u8>, int) {
+		match self.cells.iter().position(|&(p, _, _)| p == pos) {
+			Some(i) => self.cells.get_mut(i),
+			None => {
+				self.cells.push((pos, None, 0));
+				self.cells.mut_last().unwrap()
+			}
+		}
+	}
+
+	/**
+	Emits the offset operators having the effects of the segment so
+	far, and starts over from an empty segment.
+	*/
+	fn flush(&mut self, ops: &mut ~[Operator]) {
+		for &(pos, set, added) in self.cells.iter() {
+			match set {
+				Some(value) => ops.push(SetAt(pos, (value as int + added) as u8)),
+				None if added != 0 => ops.push(AddAt(pos, added)),
+				None => {}
+			}
+		}
+		if self.head != 0 {
+			ops.push(Move(self.head));
+		}
+		self.room += self.head;
+		self.head = 0;
+		self.cells.clear();
+	}
+}
+
+/**
+How far an operator moves the head, and how much it adds to the
+cell it ends on, or `None` if it can not be part of a balanced run
+folded by `fold_offset_writes`.
+*/
+fn write_effect(op: &Operator) -> Option<(int, int)> {
+	match *op {
+		Incr => Some((0, 1)),
+		Decr => Some((0, -1)),
+		IncrN(n) => Some((0, n as int)),
+		DecrN(n) => Some((0, -(n as int))),
+		Prev => Some((-1, 0)),
+		Next => Some((1, 0)),
+		PrevN(n) => Some((-(n as int), 0)),
+		NextN(n) => Some((n as int, 0)),
+		Move(offset) => Some((offset, 0)),
+		Nop(_) | Shebang(_) => Some((0, 0)),
+		_ => None,
+	}
+}
+
+/**
+How far a run of a sequence of operators moves the head, if always
+the same: its loops have to bring the head back where they started.
+*/
+fn displacement(ast: &Ast) -> Option<int> {
+	let &Ast(ref ops) = ast;
+	let mut moved = 0;
+	for op in ops.iter() {
+		match *op {
+			Sub(ref body) | DoSub(ref body) => if displacement(body) != Some(0) { return None },
+			_ => moved += write_effect(op).map_or(0, |(by, _)| by),
+		}
+	}
+	Some(moved)
+}
+
+/**
+How many cells the head is at least from the left edge of the tape
+within a loop, and after it, given as much before it: as much, if
+the loop never brings the head further left, or else none.
+*/
+fn loop_room(body: &Ast, room: int) -> int {
+	match displacement(body) {
+		Some(moved) if moved >= 0 => room,
+		_ => 0,
+	}
+}
+
+/**
+Adds the loops of a program found at some offset of the source to a
+report, see `Ast::loop_balance_report`.
+*/
+fn loop_balance(ast: &Ast, start: uint, report: &mut Vec<(uint, int)>) {
+	let &Ast(ref ops) = ast;
+	let mut pos = start;
+	for op in ops.iter() {
+		match *op {
+			Sub(ref body) | DoSub(ref body) => {
+				let &Ast(ref body_ops) = body;
+				let moved = body_ops.iter().fold(0, |moved, op| moved + write_effect(op).map_or(0, |(by, _)| by));
+				report.push((pos, moved));
+				loop_balance(body, pos + 1, report);
+			}
+			_ => {}
+		}
+		pos += op.source_len();
+	}
+}
+
+/**
+What a balanced run adds to each cell, by offset from where it
+started, in order of first change, or `None` if it both adds to and
+subtracts from some cell, which would hide it overflowing.
+*/
+fn balanced_writes(run: &[Operator]) -> Option<~[(int, int)]> {
+	let mut head = 0;
+	let mut writes: Vec<(int, int)> = Vec::new();
+	for op in run.iter() {
+		let (moved, delta) = write_effect(op).unwrap();
+		head += moved;
+		if delta == 0 { continue }
+		match writes.iter().position(|&(offset, _)| offset == head) {
+			Some(i) => match *writes.get_mut(i) {
+				(_, ref mut added) if (*added > 0) == (delta > 0) => *added += delta,
+				_ => return None,
+			},
+			None => writes.push((head, delta)),
+		}
+	}
+	Some(writes.move_iter().collect())
+}
+
+/**
+Compares two sequences of operators position by position.
+*/
+fn diff_ops(a: &Ast, b: &Ast, path: ~[uint], entries: &mut Vec<DiffEntry>) {
+	let (&Ast(ref a), &Ast(ref b)) = (a, b);
+	for i in range(0, ::std::cmp::max(a.len(), b.len())) {
+		let pos = path + [i];
+		match (a.get(i), b.get(i)) {
+			(Some(x), Some(y)) if x == y => {}
+			(Some(&Sub(ref x)), Some(&Sub(ref y))) => diff_ops(x, y, pos, entries),
+			(Some(&DoSub(ref x)), Some(&DoSub(ref y))) => diff_ops(x, y, pos, entries),
+			(Some(x), Some(y)) => entries.push(Changed(pos, x.clone(), y.clone())),
+			(Some(x), None) => entries.push(Removed(pos, x.clone())),
+			(None, Some(y)) => entries.push(Added(pos, y.clone())),
+			(None, None) => {}
+		}
+	}
+}
+
+/**
+Flattens operators into instructions, resolving the loops' jump
+targets as their bodies get laid out.
+*/
+fn flatten(ops: &[Operator], listing: &mut Vec<~str>) {
+	for op in ops.iter() {
+		match *op {
+			Incr => listing.push(~"INC"),
+			Decr => listing.push(~"DEC"),
+			Prev => listing.push(~"LEFT"),
+			Next => listing.push(~"RIGHT"),
+			Put  => listing.push(~"OUT"),
+			Get  => listing.push(~"IN"),
+			Random => listing.push(~"RAND"),
+			Negate => listing.push(~"NEG"),
+			HostCall => listing.push(~"HOST"),
+			SetCell(value) => listing.push(format!("SET {}", value)),
+			IncrN(n) => listing.push(format!("INC {}", n)),
+			DecrN(n) => listing.push(format!("DEC {}", n)),
+			PrevN(n) => listing.push(format!("LEFT {}", n)),
+			NextN(n) => listing.push(format!("RIGHT {}", n)),
+			Sub(Ast(ref body)) => {
+				// The skip target is only known after the body.
+				let start = listing.len();
+				listing.push(~"JZ");
+				flatten(body.as_slice(), listing);
+				listing.push(format!("JNZ {:04u}", start + 1));
+				let end = listing.len();
+				*listing.get_mut(start) = format!("JZ {:04u}", end);
+			}
+			DoSub(Ast(ref body)) => {
+				let start = listing.len();
+				flatten(body.as_slice(), listing);
+				listing.push(format!("JNZ {:04u}", start));
+			}
+			// Comments, and brackets outside of a loop, do not
+			// execute anything.
+			_ => {}
+		}
+	}
+}
+
+impl FromStr for Ast {
+	fn from_str(source: &str) -> Option<Ast> {
+		Ast::parse_str(source).ok()
+	}
+}
+
+impl fmt::Show for Span {
+	fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+		write!(f.buf, "{}..{}", self.start, self.end)
+	}
+}
+
+impl fmt::Show for Ast {
+	/**
+	Writes the source of the program, straight into the formatter,
+	or the pretty view of `pretty` with the `#` flag. Either is
+	padded to the width asked for, if any, for which it is written
+	out in full first.
+	*/
+	fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+		let alternate = f.flags & (1 << (fmt::parse::FlagAlternate as uint)) != 0;
+		if f.width.is_none() {
+			return if alternate { self.write_pretty(f.buf) } else { self.write_source(f.buf) };
+		}
+		let mut text = MemWriter::new();
+		try!(if alternate { self.write_pretty(&mut text) } else { self.write_source(&mut text) });
+		f.pad(str::from_utf8(text.get_ref()).unwrap())
+	}
+}
+
+impl Ast {
+	/**
+	The program laid out for reading: the brackets of each loop on
+	lines of their own, around its body indented by a tab more, and
+	the operators between them on a line. The layout stands in for
+	whitespace around comments, which is left out, but words within
+	a comment stay apart, by a single space.
+
+	~~~
+	+++
+	[
+		>+<-
+	]
+	.
+	~~~
+	*/
+	pub fn pretty(&self) -> ~str {
+		format!("{:#}", *self)
+	}
+
+	/// Writes the source of the program, see `fmt`.
+	fn write_source(&self, out: &mut Writer) -> IoResult<()> {
+		for piece in self.pieces() {
+			try!(match piece {
+				Opening(bracket) | Closing(bracket) => out.write_str(bracket),
+				Leaf(op) => write!(out, "{}", *op),
+			});
+		}
+		Ok(())
+	}
+
+	/// Writes the program laid out for reading, see `pretty`.
+	fn write_pretty(&self, out: &mut Writer) -> IoResult<()> {
+		let mut depth = 0u;
+		// Whether operators were written on the current line.
+		let mut in_line = false;
+		// Whether a comment was written last, and whitespace followed it.
+		let (mut in_comment, mut spaced) = (false, false);
+		for piece in self.pieces() {
+			let text = match piece {
+				Leaf(&Nop(ref c)) => {
+					let mut text = StrBuf::new();
+					for ch in c.chars() {
+						if ch.is_whitespace() {
+							spaced = in_comment;
+							continue;
+						}
+						if spaced { text.push_char(' ') }
+						text.push_char(ch);
+						in_comment = true;
+						spaced = false;
+					}
+					text.into_owned()
+				}
+				_ => {
+					in_comment = false;
+					spaced = false;
+					match piece {
+						Opening(bracket) | Closing(bracket) => bracket.to_owned(),
+						Leaf(&Shebang(ref line)) => line.trim_right().to_owned(),
+						Leaf(op) => format!("{}", *op),
+					}
+				}
+			};
+			if text.is_empty() { continue }
+			let own_line = match piece { Leaf(&Shebang(_)) | Opening(_) | Closing(_) => true, _ => false };
+			if own_line && in_line {
+				try!(out.write_str("\n"));
+				in_line = false;
+			}
+			match piece { Closing(_) => depth -= 1, _ => {} }
+			if !in_line {
+				try!(out.write_str("\t".repeat(depth).as_slice()));
+			}
+			try!(out.write_str(text.as_slice()));
+			match piece { Opening(_) => depth += 1, _ => {} }
+			if own_line {
+				try!(out.write_str("\n"));
+			} else {
+				in_line = true;
+			}
+		}
+		if in_line {
+			try!(out.write_str("\n"));
+		}
+		Ok(())
+	}
+
+	/**
+	The source of the program, cut after at most `max_len` characters
+	if it is longer, followed by an ellipsis and how many operators
+	were left out, as counted by `node_count`. Operators are never cut
+	halfway, e.g. in error messages and logs:
+
+	```rust
+	use brainfuck::Ast;
+	let ast = Ast::parse_str("+++[->+<]").unwrap();
+	assert!(ast.display_truncated(6) == ~"+++[->... (2 more operators)");
+	```
+	*/
+	pub fn display_truncated(&self, max_len: uint) -> ~str {
+		let mut out = StrBuf::new();
+		let (mut len, mut truncated, mut omitted) = (0u, false, 0u);
+		for piece in self.pieces() {
+			if !truncated {
+				let source = match piece {
+					Opening(bracket) | Closing(bracket) => bracket.to_owned(),
+					Leaf(op) => format!("{}", *op),
+				};
+				let source_len = source.char_len();
+				if len + source_len <= max_len {
+					out.push_str(source);
+					len += source_len;
+					continue;
+				}
+				truncated = true;
+			}
+			match piece {
+				Opening(_) | Leaf(_) => omitted += 1,
+				Closing(_) => {}
+			}
+		}
+		if truncated {
+			let plural = if omitted == 1 { "" } else { "s" };
+			out.push_str(format!("... ({} more operator{})", omitted, plural));
+		}
+		out.into_owned()
+	}
+
+	/**
+	Walks the source of the program, see `Pieces`.
+	*/
+	fn pieces<'a>(&'a self) -> Pieces<'a> {
+		let &Ast(ref ops) = self;
+		Pieces { stack: vec!((ops.iter(), "")) }
+	}
+
+	/**
+	How deep the loops of the program nest, along with the opening
+	bracket of the first loop nested deeper than `allowed`, if any.
+	Unlike most walks of programs, this one does not recurse, so that
+	it can tell about programs nesting too deep for those, e.g. built
+	by hand, see `MAX_NESTING`.
+	*/
+	pub fn nesting(&self, allowed: uint) -> (uint, Option<Span>) {
+		let (mut depth, mut deepest, mut first, mut position) = (0, 0, None, 0);
+		for piece in self.pieces() {
+			match piece {
+				Opening(_) => {
+					depth += 1;
+					deepest = max(deepest, depth);
+					if depth > allowed && first.is_none() {
+						first = Some(Span { start: position, end: position + 1 });
+					}
+					position += 1;
+				}
+				Closing(_) => {
+					depth -= 1;
+					position += 1;
+				}
+				Leaf(op) => position += op.source_len(),
+			}
+		}
+		(deepest, first)
+	}
+}
+
+/// What serialized programs start with: `BFA`, then the version.
+static BYTES_MAGIC: &'static [u8] = bytes!("BFA\x01");
+
+/**
+Serializes operators, see `Ast::to_bytes`. Their tags are, in order
+from 0: `+ - < > . , [ ]`, comments, `{ } ?`, checkpoints, loops,
+do-while loops, the optimized operators in the order they are
+declared, shebang lines, cell values, then negations.
+*/
+fn encode_ops(ops: &[Operator], out: &mut Vec<u8>) {
+	for op in ops.iter() {
+		match *op {
+			Incr => out.push(0),
+			Decr => out.push(1),
+			Prev => out.push(2),
+			Next => out.push(3),
+			Put => out.push(4),
+			Get => out.push(5),
+			Skip => out.push(6),
+			Loop => out.push(7),
+			Nop(ref text) => { out.push(8); encode_text(*text, out) }
+			DoSkip => out.push(9),
+			DoLoop => out.push(10),
+			Random => out.push(11),
+			Checkpoint(ref name) => { out.push(12); encode_text(*name, out) }
+			Sub(Ast(ref body)) => { out.push(13); encode_body(body.as_slice(), out) }
+			DoSub(Ast(ref body)) => { out.push(14); encode_body(body.as_slice(), out) }
+			IncrN(n) => { out.push(15); encode_uint(n, out) }
+			DecrN(n) => { out.push(16); encode_uint(n, out) }
+			PrevN(n) => { out.push(17); encode_uint(n, out) }
+			NextN(n) => { out.push(18); encode_uint(n, out) }
+			AddAt(offset, delta) => { out.push(19); encode_int(offset, out); encode_int(delta, out) }
+			SetAt(offset, value) => { out.push(20); encode_int(offset, out); out.push(value) }
+			Move(offset) => { out.push(21); encode_int(offset, out) }
+			OffsetWrite(ref writes) => {
+				out.push(22);
+				encode_uint(writes.len(), out);
+				for &(offset, delta) in writes.iter() {
+					encode_int(offset, out);
+					encode_int(delta, out);
+				}
+			}
+			Shebang(ref line) => { out.push(23); encode_text(*line, out) }
+			SetCell(value) => { out.push(24); out.push(value) }
+			Negate => out.push(25),
+			HostCall => out.push(26),
+		}
+	}
+}
+
+/**
+Spells out counted operators as that many plain ones, in loop bodies
+too, see `Ast::canonical`.
+*/
+fn spell_out(ops: &[Operator]) -> ~[Operator] {
+	let mut out = Vec::new();
+	for op in ops.iter() {
+		match *op {
+			IncrN(n) => out.grow(n, &Incr),
+			DecrN(n) => out.grow(n, &Decr),
+			PrevN(n) => out.grow(n, &Prev),
+			NextN(n) => out.grow(n, &Next),
+			Move(offset) if offset < 0 => out.grow((-offset) as uint, &Prev),
+			Move(offset) => out.grow(offset as uint, &Next),
+			Sub(Ast(ref body)) => out.push(Sub(Ast(spell_out(body.as_slice())))),
+			DoSub(Ast(ref body)) => out.push(DoSub(Ast(spell_out(body.as_slice())))),
+			ref other => out.push(other.clone()),
+		}
+	}
+	out.move_iter().collect()
+}
+
+/// Serializes the body of a loop, prefixed with its length.
+fn encode_body(ops: &[Operator], out: &mut Vec<u8>) {
+	let mut body = Vec::new();
+	encode_ops(ops, &mut body);
+	encode_uint(body.len(), out);
+	out.push_all(body.as_slice());
+}
+
+/// Serializes text, prefixed with its length.
+fn encode_text(text: &str, out: &mut Vec<u8>) {
+	encode_uint(text.len(), out);
+	out.push_all(text.as_bytes());
+}
+
+/// Serializes an unsigned number as a LEB128 varint.
+fn encode_uint(n: uint, out: &mut Vec<u8>) {
+	let mut n = n;
+	while n >= 0x80 {
+		out.push((n & 0x7f) as u8 | 0x80);
+		n >>= 7;
+	}
+	out.push(n as u8);
+}
+
+/// Serializes a signed number, zig-zag encoded so that small ones stay short.
+fn encode_int(n: int, out: &mut Vec<u8>) {
+	encode_uint(if n < 0 { ((-(n + 1)) as uint << 1) | 1 } else { n as uint << 1 }, out);
+}
+
+/**
+Loads serialized operators, see `Ast::from_bytes`.
+*/
+struct Decoder<'a> {
+	bytes: &'a [u8],
+	/// Where the next byte to read is.
+	pos: uint,
+	/// How many loops deep the next operator is.
+	depth: uint,
+}
+
+impl<'a> Decoder<'a> {
+	/// Loads operators up to the given position.
+	fn ops(&mut self, end: uint) -> Result<Ast, ~str> {
+		let mut ops = Vec::new();
+		while self.pos < end {
+			ops.push(try!(self.op()));
+		}
+		if self.pos > end {
+			return Err(~"A loop body goes past its length.");
+		}
+		Ok(Ast(ops.move_iter().collect()))
+	}
+
+	/// Loads an operator, see `encode_ops` for the tags.
+	fn op(&mut self) -> Result<Operator, ~str> {
+		let tag = try!(self.byte());
+		Ok(match tag {
+			0 => Incr,
+			1 => Decr,
+			2 => Prev,
+			3 => Next,
+			4 => Put,
+			5 => Get,
+			6 => Skip,
+			7 => Loop,
+			8 => Nop(try!(self.text())),
+			9 => DoSkip,
+			10 => DoLoop,
+			11 => Random,
+			12 => Checkpoint(try!(self.text())),
+			13 | 14 => {
+				if self.depth == MAX_NESTING {
+					return Err(~"Loops are nested too deep.");
+				}
+				let end = try!(self.end_of(try!(self.uint())));
+				self.depth += 1;
+				let body = try!(self.ops(end));
+				self.depth -= 1;
+				if tag == 13 { Sub(body) } else { DoSub(body) }
+			}
+			15 => IncrN(try!(self.uint())),
+			16 => DecrN(try!(self.uint())),
+			17 => PrevN(try!(self.uint())),
+			18 => NextN(try!(self.uint())),
+			19 => {
+				let offset = try!(self.int());
+				AddAt(offset, try!(self.int()))
+			}
+			20 => {
+				let offset = try!(self.int());
+				SetAt(offset, try!(self.byte()))
+			}
+			21 => Move(try!(self.int())),
+			22 => {
+				let count = try!(self.uint());
+				let mut writes = Vec::new();
+				for _ in range(0, count) {
+					let offset = try!(self.int());
+					writes.push((offset, try!(self.int())));
+				}
+				OffsetWrite(writes.move_iter().collect())
+			}
+			23 => Shebang(try!(self.text())),
+			24 => SetCell(try!(self.byte())),
+			25 => Negate,
+			26 => HostCall,
+			_ => return Err(format!("Unknown operator tag {}.", tag)),
+		})
+	}
+
+	/// Where something `len` bytes long, starting at the next byte, ends.
+	fn end_of(&self, len: uint) -> Result<uint, ~str> {
+		if len > self.bytes.len() - self.pos {
+			return Err(~"The serialized program ends too early.");
+		}
+		Ok(self.pos + len)
+	}
+
+	fn byte(&mut self) -> Result<u8, ~str> {
+		let end = try!(self.end_of(1));
+		self.pos = end;
+		Ok(self.bytes[end - 1])
+	}
+
+	fn uint(&mut self) -> Result<uint, ~str> {
+		let (mut n, mut shift) = (0u, 0u);
+		loop {
+			let byte = try!(self.byte());
+			if shift >= ::std::uint::BITS {
+				return Err(~"A number is too large.");
+			}
+			n |= (byte & 0x7f) as uint << shift;
+			if byte & 0x80 == 0 {
+				return Ok(n);
+			}
+			shift += 7;
+		}
+	}
+
+	fn int(&mut self) -> Result<int, ~str> {
+		let n = try!(self.uint());
+		Ok(if n & 1 == 1 { -((n >> 1) as int) - 1 } else { (n >> 1) as int })
+	}
+
+	fn text(&mut self) -> Result<~str, ~str> {
+		let end = try!(self.end_of(try!(self.uint())));
+		let text = match ::std::str::from_utf8(self.bytes.slice(self.pos, end)) {
+			Some(text) => text.to_owned(),
+			None => return Err(~"A comment is not valid UTF-8."),
+		};
+		self.pos = end;
+		Ok(text)
+	}
+}
+
+/**
+A piece of the source of a program.
+*/
+enum Piece<'a> {
+	/// The opening bracket of a loop, standing for the loop itself.
+	Opening(&'static str),
+	/// The closing bracket of a loop.
+	Closing(&'static str),
+	/// Any operator but loops.
+	Leaf(&'a Operator),
+}
+
+/**
+Walks the source of a program in order, piece by piece. Loops are
+entered on an explicit stack rather than by recursion, so that deeply
+nested programs can not run out of stack.
+*/
+struct Pieces<'a> {
+	/// The operators left in each loop entered, and its closing bracket.
+	stack: Vec<(Items<'a, Operator>, &'static str)>,
+}
+
+impl<'a> Iterator<Piece<'a>> for Pieces<'a> {
+	fn next(&mut self) -> Option<Piece<'a>> {
+		let next = match self.stack.mut_last() {
+			Some(&(ref mut ops, _)) => ops.next(),
+			None => return None,
+		};
+		match next {
+			Some(op) => Some(match *op {
+				Sub(Ast(ref body)) => {
+					self.stack.push((body.iter(), "]"));
+					Opening("[")
+				}
+				DoSub(Ast(ref body)) => {
+					self.stack.push((body.iter(), "}"));
+					Opening("{")
+				}
+				_ => Leaf(op),
+			}),
+			None => match self.stack.pop() {
+				// The top level has no closing bracket, and ends the walk.
+				Some((_, "")) | None => None,
+				Some((_, bracket)) => Some(Closing(bracket)),
+			},
+		}
+	}
+}
+
+#[cfg(test)]
+use time::precise_time_ns;
+
+#[test]
+/// Formatting a huge program should be quick.
+fn test_ast_show_huge() {
+	let ast = Ast(Vec::from_fn(1000000, |i| if i % 2 == 0 { Incr } else { Next }).move_iter().collect());
+	let started = precise_time_ns();
+	let source = format!("{}", ast);
+	assert!(precise_time_ns() - started < 2000000000);
+	assert!(source.len() == 1000000);
+	assert!(source.starts_with("+>+>"));
+}
+
+#[test]
+/// Formatting deeply nested loops should not run out of stack.
+fn test_ast_show_deep_nesting() {
+	let source = format!("+{}-{}", "[".repeat(50000), "]".repeat(50000));
+	let ast = Ast::parse_str(source).unwrap();
+	assert!(format!("{}", ast) == source);
+	assert!(ast.display_truncated(4) == ~"+[[[... (49998 more operators)");
+	// Dropping the AST recurses as deep as it nests, which is not
+	// what is tested here.
+	unsafe { ::std::cast::forget(ast) }
+}
+
+#[test]
+/// Truncated programs should tell how many operators were left out.
+fn test_ast_display_truncated() {
+	let ast = Ast::parse_str("+++[->+<]>.").unwrap().optimize();
+	// `IncrN(3)`, the loop, `Decr`, `Next`, `Incr`, `Prev`, `Next`, `Put`.
+	assert!(ast.display_truncated(7) == ~"+++[->+... (3 more operators)");
+	// `IncrN(3)` is not cut halfway.
+	assert!(ast.display_truncated(2) == ~"... (8 more operators)");
+	assert!(ast.display_truncated(11) == ~"+++[->+<]>.");
+	assert!(ast.display_truncated(10) == ~"+++[->+<]>... (1 more operator)");
+	// The closing bracket is cut, but is not an operator itself.
+	assert!(ast.display_truncated(8) == ~"+++[->+<... (2 more operators)");
+}
+
+#[test]
+/// Programs with too many operators should fail to parse, loops counting as one.
+fn test_ast_parse_str_limited() {
+	assert!(Ast::parse_str_limited("+[->+<]", 6) == Ok(Ast::parse_str("+[->+<]").unwrap()));
+	assert!(Ast::parse_str_limited("+[->+<]", 5) == Err(~"program too large"));
+	assert!(Ast::parse_str_limited("+++ +", 4) == Err(~"program too large"));
+	// Parsing stops early, before even seeing the unmatched bracket.
+	assert!(Ast::parse_str_limited("++++]", 2) == Err(~"program too large"));
+	assert!(Ast::parse_str_limited("", 0) == Ok(Ast(~[])));
+}
+
+#[test]
+/// Runs of `+` and `-` should fold as parsed, up to anything else.
+fn test_ast_parse_str_compressed() {
+	assert!(Ast::parse_str_compressed("++a++").unwrap() == Ast(~[IncrN(2), Nop(~"a"), IncrN(2)]));
+	assert!(Ast::parse_str_compressed("+>---[-+]").unwrap() == Ast(~[Incr, Next, DecrN(3), Sub(Ast(~[Decr, Incr]))]));
+	assert!(Ast::parse_str_compressed("+++ +").unwrap() == Ast(~[IncrN(3), Nop(~" "), Incr]));
+	// Not knowing whether runs were folded by the parser, there is no source.
+	assert!(Ast::parse_str_compressed("++a++").unwrap().source() == None);
+	assert!(Ast::parse_str_compressed("+>-").unwrap().source() == Some(~"+>-"));
+	for &(name, source, input) in ::corpus::PROGRAMS.iter() {
+		let compressed = Ast::parse_str_compressed(source).unwrap();
+		if ::corpus::run(&compressed, input) != ::corpus::run(&Ast::parse_str(source).unwrap(), input) {
+			fail!("{} runs differently once compressed", name);
+		}
+		assert!(format!("{}", compressed) == source.to_owned());
+	}
+}
+
+#[test]
+/// A missing `]` should be closed at the end, as if it was there.
+fn test_ast_parse_recover_missing_close() {
+	let source = "++++++[>+++++++++++<-]>-.>+++[<+.>-";
+	assert!(Ast::parse_str(source).is_err());
+	let (ast, warnings) = Ast::parse_str_recover(source);
+	assert!(warnings == vec!(ParseWarning { position: 29, kind: ClosedAtEnd }));
+	let balanced = Ast::parse_str(format!("{}]", source)).unwrap();
+	assert!(ast == balanced);
+	let mut machine = ::machine::Machine::capturing([]);
+	machine.run_program(&ast).unwrap();
+	assert!(machine.captured_output() == bytes!("ABCD"));
+}
+
+#[test]
+/// Stray `]` should be dropped, and nested `[` closed innermost first.
+fn test_ast_parse_recover_stray_and_nested() {
+	let (ast, warnings) = Ast::parse_str_recover("+.]+.");
+	assert!(ast == Ast::parse_str("+.+.").unwrap());
+	assert!(warnings == vec!(ParseWarning { position: 2, kind: DroppedClose }));
+
+	let (ast, warnings) = Ast::parse_str_recover("]+[>+[>+");
+	assert!(ast == Ast::parse_str("+[>+[>+]]").unwrap());
+	assert!(warnings == vec!(
+		ParseWarning { position: 0, kind: DroppedClose },
+		ParseWarning { position: 2, kind: ClosedAtEnd },
+		ParseWarning { position: 5, kind: ClosedAtEnd },
+	));
+}
+
+#[test]
+/// Loops should count once, on top of their body.
+fn test_ast_node_count() {
+	assert!(Ast::parse_str("[+]").unwrap().node_count() == 2);
+	let ast = Ast::parse_str("+[>[-]<]").unwrap();
+	assert!(ast.node_count() == 6);
+	assert!(ast.size_estimate() == 6 * size_of::<Operator>());
+}
+
+#[test]
+/// Output should count as I/O, as well as input.
+fn test_ast_has_io() {
+	assert!(Ast::parse_str("+.").unwrap().has_io());
+	assert!(!Ast::parse_str("+[-]").unwrap().has_io());
+	assert!(Ast::parse_str("+[>[,]<-]").unwrap().has_io());
+	assert!(!Ast::parse_str("+[->+<] comment").unwrap().optimize().has_io());
+	// Calling the host back is I/O too, with extensions.
+	let extended = ParseOptions { extensions: true, ..ParseOptions::strict() };
+	let (program, _) = Ast::parse_with("+[@-]", extended).unwrap();
+	assert!(program.has_io());
+	assert!(!Ast::parse_str("+[@-]").unwrap().has_io());
+}
+
+#[test]
+/// Input should be found anywhere, even where it is never reached.
+fn test_ast_uses_input() {
+	assert!(!Ast::parse_str("+[>.<-]").unwrap().uses_input());
+	assert!(Ast::parse_str("+[>.<-],").unwrap().uses_input());
+	assert!(Ast::parse_str("[-][[,]]").unwrap().uses_input());
+}
+
+#[test]
+/// Braces should only make do-while loops when extensions are on.
+fn test_ast_parse_do_while() {
+	let extended = ParseOptions { extensions: true, ..ParseOptions::strict() };
+	let (ast, _) = Ast::parse_with("+{-[.]}", extended).unwrap();
+	assert!(ast == Ast(~[Incr, DoSub(Ast(~[Decr, Sub(Ast(~[Put]))]))]));
+	assert!(format!("{}", ast) == ~"+{-[.]}");
+	assert!(Ast::parse_str("{-}").unwrap() == Ast(~[Nop(~"{"), Decr, Nop(~"}")]));
+	assert!(Ast::parse_with("{-]", extended) == Err(~"Unmatched `{`."));
+	assert!(Ast::parse_with("[-}", extended) == Err(~"Unmatched `[`."));
+	assert!(Ast::parse_with("{-", extended) == Err(~"Unmatched `{`."));
+}
+
+#[test]
+/// `#name` should be a checkpoint with extensions, and a comment otherwise.
+fn test_ast_parse_checkpoint() {
+	let extended = ParseOptions { extensions: true, ..ParseOptions::strict() };
+	let source = "+#start_1[-#loop]# #end";
+	let (ast, _) = Ast::parse_with(source, extended).unwrap();
+	assert!(ast == Ast(~[
+		Incr, Checkpoint(~"start_1"),
+		Sub(Ast(~[Decr, Checkpoint(~"loop")])),
+		Nop(~"#"), Nop(~" "), Checkpoint(~"end"),
+	]));
+	assert!(format!("{}", ast) == source.to_owned());
+	assert!(ast.source_len() == source.char_len());
+	assert!(Ast::parse_str("#a").unwrap() == Ast(~[Nop(~"#"), Nop(~"a")]));
+}
+
+#[test]
+/// `=N` should set the cell with extensions, and be comments otherwise.
+fn test_ast_parse_cell_literal() {
+	let extended = ParseOptions { extensions: true, ..ParseOptions::strict() };
+	let (ast, _) = Ast::parse_with("=72.", extended).unwrap();
+	assert!(ast == Ast(~[SetCell(72), Put]));
+	assert!(format!("{}", ast) == ~"=72.");
+	assert!(ast.source_len() == 4);
+	let mut machine = ::machine::Machine::capturing([]);
+	machine.set_extensions(::features::ExtensionSet::none().with(::features::CellValues));
+	assert!(machine.run_program(&ast) == Ok(2));
+	assert!(machine.captured_output() == bytes!("H"));
+
+	let (ast, _) = Ast::parse_with("+= =0=07", extended).unwrap();
+	assert!(ast == Ast(~[Incr, Nop(~"="), Nop(~" "), SetCell(0), SetCell(0), Nop(~"7")]));
+	assert!(Ast::parse_with("=256", extended) == Err(~"Cell value `=256` is out of range."));
+	assert!(Ast::parse_str("=7").unwrap() == Ast(~[Nop(~"="), Nop(~"7")]));
+}
+
+#[test]
+/// Loops should be reported where they are, with how far they drift.
+fn test_ast_loop_balance_report() {
+	let ast = Ast::parse_str("+[->+<]>[>[<<]]").unwrap();
+	assert!(ast.loop_balance_report() == vec!((1, 0), (8, 1), (10, -2)));
+	let ast = Ast::parse_str("no loops, only +.").unwrap();
+	assert!(ast.loop_balance_report().is_empty());
+}
+
+#[test]
+/// `!` should negate the cell with extensions, and be a comment otherwise.
+fn test_ast_parse_negate() {
+	let extended = ParseOptions { extensions: true, ..ParseOptions::strict() };
+	let (ast, _) = Ast::parse_with("+!.>!.", extended).unwrap();
+	assert!(ast == Ast(~[Incr, Negate, Put, Next, Negate, Put]));
+	assert!(format!("{}", ast) == ~"+!.>!.");
+	assert!(Ast::from_bytes(ast.to_bytes().as_slice()) == Ok(ast.clone()));
+	let mut machine = ::machine::Machine::capturing([]);
+	machine.set_extensions(::features::ExtensionSet::none().with(::features::Negation));
+	assert!(machine.run_program(&ast) == Ok(6));
+	assert!(machine.captured_output() == bytes!(255, 0));
+	assert!(Ast::parse_str("!").unwrap() == Ast(~[Nop(~"!")]));
+}
+
+#[test]
+/// A `#!` line should be skipped whole, but still count in positions.
+fn test_ast_parse_shebang() {
+	let shebang = "#!/usr/bin/env bf [./bin]\n";
+	let program = "++++++++[>++++++++<-]>+.";
+	let script = format!("{}{}", shebang, program);
+	let ast = Ast::parse_str(script).unwrap();
+	assert!(ast.source_len() == script.char_len());
+	assert!(format!("{}", ast) == script);
+	let run = |ast: &Ast| {
+		let mut machine = ::machine::Machine::capturing([]);
+		let cycles = machine.run_program(ast).unwrap();
+		(cycles, machine.captured_output().to_owned())
+	};
+	assert!(run(&ast) == run(&Ast::parse_str(program).unwrap()));
+	assert!(run(&ast).val1() == ~[65]);
+
+	let (_, warnings) = Ast::parse_str_recover(format!("{}+.\n]", shebang));
+	assert!(warnings == vec!(ParseWarning { position: shebang.char_len() + 3, kind: DroppedClose }));
+
+	// Merely a comment, whose brackets count.
+	assert!(Ast::parse_str("# [./bin]\n+").unwrap().source_len() == 11);
+	assert!(Ast::parse_str("# [./bin\n+").is_err());
+}
+
+#[test]
+/// Parsed programs should give back their exact source.
+fn test_ast_source() {
+	let source = "#!/usr/bin/env bf\nA comment, then +[->+<] and .\n";
+	assert!(Ast::parse_str(source).unwrap().source() == Some(source.to_owned()));
+	let extended = ParseOptions { extensions: true, ..ParseOptions::strict() };
+	let source = "{-}?=42 #end";
+	let (ast, _) = Ast::parse_with(source, extended).unwrap();
+	assert!(ast.source() == Some(source.to_owned()));
+	assert!(Ast::parse_str("+++[-]").unwrap().optimize().source() == None);
+	// Recovered programs give back their source as recovered.
+	let recover = ParseOptions { recover_brackets: true, ..ParseOptions::strict() };
+	let (ast, warnings) = Ast::parse_with("]+[>+", recover).unwrap();
+	assert!(warnings.len() == 2);
+	assert!(ast.source() == Some(~"+[>+]"));
+}
+
+#[cfg(test)]
+use std::io::MemReader;
+
+#[test]
+/// Parsing from a stream should give what parsing the whole source does.
+fn test_ast_parse_reader() {
+	let parse = |source: &str| Ast::parse_reader(&mut MemReader::new(source.as_bytes().to_owned()));
+	// Long enough to take many reads, with chars split across them.
+	let source = format!("\\#!/usr/bin/env bf\n{}", "+[->+<]>. é ".repeat(100000));
+	assert!(source.len() > 10 * PARSE_BUFFER_SIZE);
+	assert!(parse(source) == Ast::parse_str(source));
+	assert!(parse("#+").unwrap() == Ast::parse_str("#+").unwrap());
+	assert!(parse("#!bf").unwrap() == Ast::parse_str("#!bf").unwrap());
+	assert!(parse("").unwrap() == Ast(~[]));
+
+	let unmatched = format!("{}]", "+".repeat(PARSE_BUFFER_SIZE * 2));
+	assert!(parse(unmatched) == Err(format!("Unmatched `]` at byte {}.", PARSE_BUFFER_SIZE * 2)));
+	assert!(parse("é[[-]") == Err(~"Unmatched `[` at byte 2."));
+	let deep = "[".repeat(MAX_NESTING + 1);
+	assert!(parse(deep) == Err(format!("Loops nested too deep at byte {}.", MAX_NESTING)));
+	let invalid = Ast::parse_reader(&mut MemReader::new(~[43, 43, 0xff, 43]));
+	assert!(invalid == Err(~"Invalid UTF-8 at byte 2."));
+	let truncated = Ast::parse_reader(&mut MemReader::new(~[43, 0xc3]));
+	assert!(truncated == Err(~"Invalid UTF-8 at byte 1."));
+}
+
+#[test]
+/// Loops should be listed as jumps around their body.
+fn test_ast_disassemble_loop() {
+	let ast = Ast::parse_str("[+]").unwrap();
+	assert!(ast.disassemble() == ~"0000 JZ 0003\n0001 INC\n0002 JNZ 0001\n");
+}
+
+#[test]
+/// Addresses should skip comments, and resolve nested loop targets.
+fn test_ast_disassemble_nested() {
+	let ast = Ast::parse_str("loop: >[-[.]<]").unwrap();
+	assert!(ast.disassemble() == ~"\
+		0000 RIGHT\n\
+		0001 JZ 0008\n\
+		0002 DEC\n\
+		0003 JZ 0006\n\
+		0004 OUT\n\
+		0005 JNZ 0004\n\
+		0006 LEFT\n\
+		0007 JNZ 0002\n");
+}
+
+#[test]
+/// Optimizing should fold runs and drop comments, recursing into loops.
+fn test_ast_optimize_folds_runs() {
+	let ast = Ast::parse_str("+++ >> [-- x <] ..").unwrap().optimize();
+	assert!(ast == Ast(~[
+		IncrN(3), NextN(2),
+		Sub(Ast(~[DecrN(2), Prev])),
+		Put, Put,
+	]));
+}
+
+#[test]
+/// Mirroring should swap directions, and be its own inverse.
+fn test_ast_mirror() {
+	let ast = Ast::parse_str(">>+<<[<-]").unwrap();
+	assert!(ast.mirror() == Ast::parse_str("<<+>>[>-]").unwrap());
+	assert!(ast.mirror().mirror() == ast);
+}
+
+#[test]
+/// Operators doing nothing on the pristine tape should be dropped from the start.
+fn test_ast_minimize() {
+	let minimize = |source: &str| Ast::parse_str(source).unwrap().minimize();
+	assert!(minimize("[-]+.") == Ast::parse_str("+.").unwrap());
+	assert!(minimize("[-] clear [>+<-]>.") == Ast::parse_str(" clear >.").unwrap());
+	// Only the start goes, and moves stay, even left of the origin.
+	assert!(minimize("+[-][-]") == Ast::parse_str("+[-][-]").unwrap());
+	assert!(minimize("<[-]") == Ast::parse_str("<[-]").unwrap());
+	assert!(Ast(~[SetCell(0), Negate, SetCell(1), Put]).minimize() == Ast(~[SetCell(1), Put]));
+}
+
+#[test]
+/// Runs of operators and loops should each make a segment.
+fn test_ast_top_level_segments() {
+	let segments = Ast::parse_str("++[--]>>").unwrap().top_level_segments();
+	assert!(segments == vec!(
+		Ast(~[Incr, Incr]),
+		Ast(~[Sub(Ast(~[Decr, Decr]))]),
+		Ast(~[Next, Next]),
+	));
+	assert!(Ast::parse_str("[-][-]").unwrap().top_level_segments().len() == 2);
+	assert!(Ast::parse_str("").unwrap().top_level_segments().is_empty());
+}
+
+#[test]
+/// Serializing a nested program, optimized or not, should load it back as it was.
+fn test_ast_bytes_round_trip() {
+	let extended = ParseOptions { extensions: true, ..ParseOptions::strict() };
+	let (ast, _) = Ast::parse_with("+++[>++[-<+>]<{?#loop}] ≠ x\n--[<<->>-]", extended).unwrap();
+	for ast in [ast.clone(), ast.optimize(), ast.optimize().optimize_offsets(), ast.optimize().fold_offset_writes()].iter() {
+		assert!(Ast::from_bytes(ast.to_bytes().as_slice()) == Ok(ast.clone()));
+	}
+	let bytes = Ast(~[Sub(Ast(~[Decr]))]).to_bytes();
+	assert!(bytes.as_slice() == bytes!("BFA\x01", 13, 1, 1));
+	assert!(Ast::from_bytes(bytes.as_slice().slice_to(bytes.len() - 1)).is_err());
+	assert!(Ast::from_bytes(bytes!("+[-]")).is_err());
+	assert!(Ast::from_bytes(bytes!("BFA\x01", 99)) == Err(~"Unknown operator tag 99."));
+
+	let mut deep = Ast(~[]);
+	for _ in range(0, MAX_NESTING + 1) {
+		deep = Ast(~[Sub(deep)]);
+	}
+	assert!(Ast::from_bytes(deep.to_bytes().as_slice()) == Err(~"Loops are nested too deep."));
+	assert!(deep.nesting(MAX_NESTING) == (MAX_NESTING + 1, Some(Span { start: MAX_NESTING, end: MAX_NESTING + 1 })));
+	let Ast(mut outer) = deep;
+	let shallower = match outer.pop() { Some(Sub(body)) => body, _ => fail!() };
+	assert!(Ast::from_bytes(shallower.to_bytes().as_slice()) == Ok(shallower.clone()));
+	assert!(shallower.nesting(MAX_NESTING) == (MAX_NESTING, None));
+}
+
+#[test]
+/// Signatures should only depend on the program, and be pinned down.
+fn test_ast_signature() {
+	let source = "++[>+<-] comment";
+	let (a, b) = (Ast::parse_str(source).unwrap(), Ast::parse_str(source.to_owned()).unwrap());
+	assert!(a.signature() == b.signature());
+	assert!(a.signature() != Ast::parse_str("++[>+<-]").unwrap().signature());
+	assert!(a.signature() != a.optimize().signature());
+	assert!(Ast::parse_str("+[-]").unwrap().signature() == 0x6cd6f38b07eb821d);
+}
+
+#[test]
+/// Canonical hashes should only depend on what programs do, and be pinned down.
+fn test_ast_canonical_hash() {
+	let hash = |source: &str| Ast::parse_str(source).unwrap().canonical_hash();
+	let hello = "++++++++++[>+++++++>++++++++++>+++>+<<<<-]>++.>+.+++++++..+++.>++.<<+++++++++++++++.>.+++.------.--------.>+.>.";
+	let commented = "Set up the letters ++++++++++[>+++++++>++++++++++>+++>+<<<<-]\n\
+		H >++.   e >+.   llo +++++++..+++.\n\
+		space and W >++.<<+++++++++++++++.   orld >.+++.------.--------.   ! and newline >+.>.";
+	assert!(hash(hello) == hash(commented));
+	assert!(hash(hello) != hash(hello.slice_from(1)));
+	assert!(hash("[-] [-]") == hash("[-]"));
+	assert!(hash("+-+") == hash("+"));
+	assert!(hash("+[-]") == hash("[-]"));
+	// These differ where the head stops at the origin.
+	assert!(hash(">+<<>") != hash(">+<"));
+	assert!(hash("+++") == Ast(~[IncrN(3)]).canonical_hash());
+	assert!(hash("[-]") != hash("[+]"));
+	assert!(hash(hello) == 0x72f9f17aa15eb6f7);
+	assert!(Ast::parse_str(hello).unwrap().canonical_hash_hex() == ~"72f9f17aa15eb6f7");
+}
+
+#[test]
+/// Diffing a run against its folded form should show the collapse.
+fn test_ast_diff_folded_run() {
+	let ast = Ast::parse_str("+++").unwrap();
+	let diff = ast.diff(&ast.optimize());
+	assert!(diff == vec!(
+		Changed(~[0], Incr, IncrN(3)),
+		Removed(~[1], Incr),
+		Removed(~[2], Incr),
+	));
+}
+
+#[test]
+/// Diffing should recurse into loops, and report additions.
+fn test_ast_diff_nested() {
+	let a = Ast::parse_str("+[->+<]").unwrap();
+	let b = Ast::parse_str("+[->-<].").unwrap();
+	assert!(a.diff(&b) == vec!(
+		Changed(~[1, 2], Incr, Decr),
+		Added(~[2], Put),
+	));
+	assert!(a.diff(&a).is_empty());
+}
+
+#[test]
+/// Straight-line code should become offset operators and a single move.
+fn test_ast_optimize_offsets_rewrites_segments() {
+	let ast = Ast::parse_str(">>+++<-<").unwrap().optimize_offsets();
+	assert!(ast == Ast(~[AddAt(2, 3), AddAt(1, -1)]));
+
+	// Output and loops are barriers, clearing loops are not.
+	let ast = Ast::parse_str(">>+++<<->>.<[-]++ [>+<-]").unwrap().optimize_offsets();
+	assert!(ast == Ast(~[
+		AddAt(2, 3), AddAt(0, -1), Move(2),
+		Put,
+		SetAt(-1, 2), Move(-1),
+		Sub(Ast(~[AddAt(1, 1), AddAt(0, -1)])),
+	]));
+}
+
+#[test]
+/// Segments should never reach left of where the head may be at the edge.
+fn test_ast_optimize_offsets_left_edge() {
+	let ast = Ast::parse_str("<+>+.<.").unwrap();
+	let optimized = ast.optimize_offsets();
+	assert!(optimized == Ast(~[Prev, AddAt(0, 1), AddAt(1, 1), Move(1), Put, Move(-1), Put]));
+	assert!(::corpus::run(&optimized, []) == ::corpus::run(&ast, []));
+	// Loops bringing the head left could start anywhere.
+	let ast = Ast::parse_str("+[<+>-]>>[<]").unwrap().optimize_offsets();
+	assert!(ast == Ast(~[
+		AddAt(0, 1),
+		Sub(Ast(~[Prev, AddAt(0, 1), AddAt(1, -1), Move(1)])),
+		Move(2),
+		Sub(Ast(~[Prev])),
+	]));
+}
+
+#[cfg(test)]
+use machine::Machine;
+#[cfg(test)]
+use semantics::Semantics;
+#[cfg(test)]
+use test::BenchHarness;
+
+#[test]
+/// Offset operators should not change what the corpus programs do.
+fn test_ast_optimize_offsets_corpus() {
+	for &(name, source, input) in ::corpus::PROGRAMS.iter() {
+		let ast = Ast::parse_str(source).unwrap();
+		let expected = ::corpus::run(&ast, input);
+		let optimized = ::corpus::run(&ast.optimize().optimize_offsets(), input);
+		if optimized != expected {
+			fail!("{} behaves differently once optimized", name);
+		}
+	}
+}
+
+#[test]
+/// Balanced runs should fold into a single write, leaving the tape as it was.
+fn test_ast_fold_offset_writes() {
+	let naive = Ast::parse_str(">+>++<<").unwrap();
+	let folded = naive.fold_offset_writes();
+	assert!(folded == Ast(~[OffsetWrite(~[(1, 1), (2, 2)])]));
+	assert!(::corpus::run(&folded, []) == ::corpus::run(&naive, []));
+	let mut machine = Machine::capturing([]);
+	assert!(machine.run_program(&folded) == Ok(1));
+
+	// Only the stretch bringing the head back is folded, not the last `>`.
+	let ast = Ast::parse_str("+>+<>.").unwrap().fold_offset_writes();
+	assert!(ast == Ast(~[OffsetWrite(~[(0, 1), (1, 1)]), Next, Put]));
+}
+
+#[test]
+/// Writes which may overflow, or stop at the left edge, should be kept.
+fn test_ast_fold_offset_writes_unsound() {
+	// Subtracting then adding may overflow under strict semantics.
+	let ast = Ast::parse_str(">-+<").unwrap();
+	assert!(ast.fold_offset_writes() == ast);
+	let mut machine = Machine::capturing([]);
+	machine.set_semantics(Semantics::strict());
+	assert!(machine.run_program(&ast.fold_offset_writes()).is_err());
+
+	// Moving left of the origin stops at the edge, so `+` adds to the origin.
+	let ast = Ast::parse_str("<+>.").unwrap();
+	assert!(ast.fold_offset_writes() == ast);
+	assert!(::corpus::run(&ast.fold_offset_writes(), []) == ::corpus::run(&ast, []));
+
+	// Once the head is known to be right of the edge, it is all folded.
+	let ast = Ast::parse_str(">>.<+>").unwrap().fold_offset_writes();
+	assert!(ast == Ast(~[Next, Next, Put, OffsetWrite(~[(-1, 1)])]));
+}
+
+#[test]
+/// Folding balanced writes should not change what the corpus programs do.
+fn test_ast_fold_offset_writes_corpus() {
+	for &(name, source, input) in ::corpus::PROGRAMS.iter() {
+		let ast = Ast::parse_str(source).unwrap();
+		let expected = ::corpus::run(&ast, input);
+		for folded in [ast.fold_offset_writes(), ast.optimize().fold_offset_writes()].iter() {
+			if ::corpus::run(folded, input) != expected {
+				fail!("{} behaves differently once writes are folded", name);
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+/// A post with two programs in it, among decoy punctuation.
+static POST: &'static str = "# Hello, Brainfuck!\n\
+	\n\
+	Here's the classic, more or less:\n\
+	\n\
+	\t++++++++[>++++++++<-]>+.\n\
+	\n\
+	A decoy - clearing a cell is just `[-]`, and --> arrows, or \"...\", are not code.\n\
+	Nor is this, for its brackets: ]++++++++++++++++[\n\
+	Another, printing \"Hi\":\n\
+	\n\
+	\t+++++++++[>++++++++<-]>.\n\
+	\t+++++++++++++++++++++++++++++++++.\n";
+
+#[test]
+/// Programs should be found in other text, whitespace and all.
+fn test_ast_extract_from_text() {
+	let found = Ast::extract_from_text(POST, ExtractOptions::default());
+	assert!(found.len() == 2);
+	let &(ref span, ref first) = found.get(0);
+	assert!(*span == Span { start: 57, end: 81 });
+	assert!(::corpus::run(first, []).val0() == bytes!("A").to_owned());
+	let &(ref span, ref second) = found.get(1);
+	assert!(*span == Span { start: 240, end: 300 });
+	assert!(::corpus::run(second, []).val0() == bytes!("Hi").to_owned());
+	let text: ~str = POST.chars().skip(240).take(60).collect();
+	assert!(text.starts_with("+++++++++[") && text.ends_with("++."));
+}
+
+#[test]
+/// Short runs should be dropped, and runs broken where asked to.
+fn test_ast_extract_filters() {
+	let found = Ast::extract_from_text(POST, ExtractOptions { min_ops: 3, ..ExtractOptions::default() });
+	let sources: ~[~str] = found.iter().map(|&(_, ref ast)| format!("{}", ast)).collect();
+	assert!(sources.len() == 5);
+	assert!(sources.contains(&~"[-]") && sources.contains(&~"-->") && sources.contains(&~"..."));
+	// The unbalanced run is long enough, but skipped all the same.
+	assert!(!found.iter().any(|&(ref span, _)| span.start == 195));
+
+	let unbroken = Ast::extract_from_text(POST, ExtractOptions { whitespace_inside: false, ..ExtractOptions::default() });
+	let spans: ~[Span] = unbroken.iter().map(|&(ref span, _)| span.clone()).collect();
+	assert!(spans == ~[Span { start: 57, end: 81 }, Span { start: 240, end: 264 }, Span { start: 266, end: 300 }]);
+}
+
+#[cfg(test)]
+/// Shuffles values across a few cells, back and forth.
+static POINTER_HEAVY: &'static str = "++++++++[>++++++++[>+>++>+++>++++<<<<-]<-]>>[<+>>>+<<-]";
+
+#[bench]
+/// Running a pointer-heavy program with runs folded.
+fn bench_pointer_heavy_folded(b: &mut BenchHarness) {
+	let program = Ast::parse_str(POINTER_HEAVY).unwrap().optimize();
+	b.iter(|| { Machine::capturing([]).run_program(&program).unwrap(); });
+}
+
+#[bench]
+/// Running a pointer-heavy program with offset operators.
+fn bench_pointer_heavy_offsets(b: &mut BenchHarness) {
+	let program = Ast::parse_str(POINTER_HEAVY).unwrap().optimize().optimize_offsets();
+	b.iter(|| { Machine::capturing([]).run_program(&program).unwrap(); });
+}
+
+#[test]
+/// Operators and programs should be padded, and spelled out with `#`.
+fn test_ast_format_flags() {
+	let symbols = [(Incr, "+"), (Decr, "-"), (Prev, "<"), (Next, ">"), (Put, "."), (Get, ","),
+		(Skip, "["), (Loop, "]"), (DoSkip, "{"), (DoLoop, "}"), (Random, "?")];
+	for &(ref op, symbol) in symbols.iter() {
+		assert!(format!("{:>4}", *op) == format!("   {}", symbol));
+		assert!(format!("{:<3}|", *op) == format!("{}  |", symbol));
+		assert!(format!("{:*^5}", *op) == format!("**{}**", symbol));
+	}
+	assert!(format!("{:>6}", IncrN(3)) == ~"   +++");
+	assert!(format!("{:*<4}", Nop(~"x")) == ~"x***");
+
+	assert!(format!("{:#}", Incr) == ~"Incr");
+	assert!(format!("{:#8}|", Decr) == ~"Decr    |");
+	assert!(format!("{:#}", AddAt(2, -1)) == ~"AddAt(2, -1)");
+	let program = Ast::parse_str("+[->+<]").unwrap();
+	{
+		let Ast(ref ops) = program;
+		assert!(format!("{:#}", ops[1]) == ~"Sub(4 ops)");
+		assert!(format!("{:>12}|", ops[1]) == ~"      [->+<]|");
+	}
+	assert!(format!("{:>10}", program) == ~"   +[->+<]");
+	assert!(format!("{}", program) == ~"+[->+<]");
+
+	let reference = Ast::parse_str("++ set up\n[>++[>+<-]<-]\n>>.").unwrap();
+	let expected = "++set up\n[\n\t>++\n\t[\n\t\t>+<-\n\t]\n\t<-\n]\n>>.\n";
+	assert!(reference.pretty().as_slice() == expected);
+	assert!(format!("{:#}", reference) == reference.pretty());
+	assert!(format!("{:#}", Ast::parse_str("[]").unwrap()) == ~"[\n]\n");
+	// Words of comments stay apart, whatever whitespace parts them.
+	assert!(Ast::parse_str("+ a  b\n\tc +").unwrap().pretty() == ~"+a b c+\n");
 }
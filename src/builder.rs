@@ -0,0 +1,477 @@
+/*!
+A builder for configuring machines in one go.
+*/
+
+use std::fmt;
+use std::mem::size_of;
+use std::io::stdio::{stdin_raw, stdout_raw};
+use storage::{Unit, Tape, tape_from_name};
+use machine::{Machine, Limits, CancelToken, InputMode, Blocking, OutputMode, Raw, DEFAULT_SEED};
+use machine::{NewlineMode, RawNewlines};
+use machine::DEFAULT_MAX_EXEC_DEPTH;
+use semantics::{Semantics, Eof, Overflow, Bounds, FailAtEdges};
+use pacing::{Pacing, Unpaced};
+use filters::Filter;
+use features::ExtensionSet;
+
+
+/**
+Why a machine could not be built.
+*/
+#[deriving(Clone, Eq)]
+pub enum BuildError {
+	/// Cells of the given width, in bits, are not supported.
+	UnsupportedCellWidth(uint),
+	/// A tape wrapping around has no edges for moving past them to fail.
+	WrappingTapeFailsAtEdges,
+	/// No tape has the given name, see `storage::tape_from_name`.
+	UnknownTape(~str),
+	/// A ring tape was named without a size, or with a size of zero.
+	RingWithoutSize,
+}
+
+impl fmt::Show for BuildError {
+	fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			UnsupportedCellWidth(bits) => {
+				write!(f.buf, "Cells of {} bits are not supported, only of {}.", bits, size_of::<Unit>() * 8)
+			}
+			WrappingTapeFailsAtEdges => {
+				write!(f.buf, "A tape wrapping around can not fail at its edges.")
+			}
+			UnknownTape(ref name) => write!(f.buf, "Unknown tape `{}`.", *name),
+			RingWithoutSize => write!(f.buf, "A ring tape needs a size of at least one cell, e.g. `ring:256`."),
+		}
+	}
+}
+
+/**
+Configures a machine, then builds it. Anything left alone is as for
+`Machine::new()`.
+
+```rust
+use brainfuck::{MachineBuilder, Ast, Tape, RingTape};
+use brainfuck::semantics::{Semantics, EofMinusOne, FailOnOverflow};
+use std::io::MemReader;
+use std::io::util::NullWriter;
+
+let mut machine = MachineBuilder::new()
+	.semantics(Semantics::classic())
+	.cell_width(8)
+	.eof(EofMinusOne)
+	.overflow(FailOnOverflow)
+	.cycle_limit(10000)
+	.output_limit(64)
+	.tape(~RingTape::new(256) as ~Tape<u8>)
+	.input(~MemReader::new(~[]) as ~Reader)
+	.output(~NullWriter as ~Writer)
+	.numeric_io(true)
+	.build()
+	.unwrap();
+machine.run_program(&Ast::parse_str("++.").unwrap()).unwrap();
+```
+*/
+pub struct MachineBuilder {
+	cell_bits: uint,
+	semantics: Semantics,
+	limits: Limits,
+	memory_limit: Option<uint>,
+	max_exec_depth: uint,
+	tape: Option<~Tape<Unit>>,
+	tape_name: Option<~str>,
+	input: Option<~Reader>,
+	output: Option<~Writer>,
+	numeric_io: bool,
+	input_mode: InputMode,
+	output_mode: OutputMode,
+	newline_mode: NewlineMode,
+	output_console: Option<bool>,
+	seed: u64,
+	debug: bool,
+	extensions: ExtensionSet,
+	cancel: Option<CancelToken>,
+	pacing: Pacing,
+	track_clamps: bool,
+	forbid_clamp: bool,
+	input_filters: Vec<Filter>,
+	output_filters: Vec<Filter>,
+}
+
+impl MachineBuilder {
+	/// Starts from the defaults of `Machine::new()`.
+	pub fn new() -> MachineBuilder {
+		MachineBuilder {
+			cell_bits: size_of::<Unit>() * 8,
+			semantics: Semantics::common(),
+			limits: Limits::none(),
+			memory_limit: None,
+			max_exec_depth: DEFAULT_MAX_EXEC_DEPTH,
+			tape: None,
+			tape_name: None,
+			input: None,
+			output: None,
+			numeric_io: false,
+			input_mode: Blocking,
+			output_mode: Raw,
+			newline_mode: RawNewlines,
+			output_console: None,
+			seed: DEFAULT_SEED,
+			debug: false,
+			extensions: ExtensionSet::none(),
+			cancel: None,
+			pacing: Unpaced,
+			track_clamps: false,
+			forbid_clamp: false,
+			input_filters: Vec::new(),
+			output_filters: Vec::new(),
+		}
+	}
+
+	/// The width of cells, in bits. Only 8 is supported for now.
+	pub fn cell_width(mut self, bits: uint) -> MachineBuilder {
+		self.cell_bits = bits;
+		self
+	}
+
+	/// Follows the given semantics. Later settings override them.
+	pub fn semantics(mut self, semantics: Semantics) -> MachineBuilder {
+		self.semantics = semantics;
+		self
+	}
+
+	/// What `Get` does at the end of input.
+	pub fn eof(mut self, eof: Eof) -> MachineBuilder {
+		self.semantics.eof = eof;
+		self
+	}
+
+	/// What happens when a cell goes out of its range.
+	pub fn overflow(mut self, overflow: Overflow) -> MachineBuilder {
+		self.semantics.overflow = overflow;
+		self
+	}
+
+	/// What happens when the head moves past the edges of the tape.
+	pub fn bounds(mut self, bounds: Bounds) -> MachineBuilder {
+		self.semantics.bounds = bounds;
+		self
+	}
+
+	/// The maximum number of cycles a run can execute.
+	pub fn cycle_limit(mut self, cycles: uint) -> MachineBuilder {
+		self.limits.cycles = Some(cycles);
+		self
+	}
+
+	/// The maximum number of bytes a run can output.
+	pub fn output_limit(mut self, bytes: uint) -> MachineBuilder {
+		self.limits.output = Some(bytes);
+		self
+	}
+
+	/// The maximum time a run can take, in milliseconds, see `Limits::time`.
+	pub fn time_limit(mut self, ms: u64) -> MachineBuilder {
+		self.limits.time = Some(ms);
+		self
+	}
+
+	/// The maximum number of bytes the tape can take, see `Machine::set_memory_limit`.
+	pub fn memory_limit(mut self, bytes: uint) -> MachineBuilder {
+		self.memory_limit = Some(bytes);
+		self
+	}
+
+	/// How many loops deep runs may go, see `Machine::set_max_exec_depth`.
+	pub fn max_exec_depth(mut self, depth: uint) -> MachineBuilder {
+		self.max_exec_depth = depth;
+		self
+	}
+
+	/// Uses the given tape instead of a `VectorTape`.
+	pub fn tape(mut self, tape: ~Tape<Unit>) -> MachineBuilder {
+		self.tape = Some(tape);
+		self.tape_name = None;
+		self
+	}
+
+	/**
+	Uses the tape of the given name, see `storage::tape_from_name`.
+	Names which are not those of a tape are only rejected by `build`.
+	*/
+	pub fn tape_named(mut self, name: &str) -> MachineBuilder {
+		self.tape_name = Some(name.to_owned());
+		self.tape = None;
+		self
+	}
+
+	/// Reads from the given input instead of `stdin`.
+	pub fn input(mut self, input: ~Reader) -> MachineBuilder {
+		self.input = Some(input);
+		self
+	}
+
+	/// Writes to the given output instead of `stdout`.
+	pub fn output(mut self, output: ~Writer) -> MachineBuilder {
+		self.output = Some(output);
+		self
+	}
+
+	/// Switches numeric I/O on or off, see `Machine::set_numeric_io`.
+	pub fn numeric_io(mut self, numeric: bool) -> MachineBuilder {
+		self.numeric_io = numeric;
+		self
+	}
+
+	/// What reading does when input is not there yet, see `InputMode`.
+	pub fn input_mode(mut self, mode: InputMode) -> MachineBuilder {
+		self.input_mode = mode;
+		self
+	}
+
+	/// What writing a cell outputs, see `OutputMode`.
+	pub fn output_mode(mut self, mode: OutputMode) -> MachineBuilder {
+		self.output_mode = mode;
+		self
+	}
+
+	/**
+	How line endings are read, and written to consoles, see
+	`NewlineMode`.
+	*/
+	pub fn newline_mode(mut self, mode: NewlineMode) -> MachineBuilder {
+		self.newline_mode = mode;
+		self
+	}
+
+	/**
+	Whether the output is a console, see `Machine::set_output_console`.
+	Unless told, only `stdout` may be one, if it is a terminal.
+	*/
+	pub fn output_console(mut self, console: bool) -> MachineBuilder {
+		self.output_console = Some(console);
+		self
+	}
+
+	/// How fast output is written, see `Machine::set_output_pacing`.
+	pub fn output_pacing(mut self, pacing: Pacing) -> MachineBuilder {
+		self.pacing = pacing;
+		self
+	}
+
+	/// Notes the head stopping at the origin, see `Machine::set_track_clamps`.
+	pub fn track_clamps(mut self, track: bool) -> MachineBuilder {
+		self.track_clamps = track;
+		self
+	}
+
+	/// Fails runs whose head stops at the origin, see `Machine::set_forbid_clamp`.
+	pub fn forbid_clamp(mut self, forbid: bool) -> MachineBuilder {
+		self.forbid_clamp = forbid;
+		self
+	}
+
+	/// Passes input through a filter, after any added before, see `Machine::add_input_filter`.
+	pub fn input_filter(mut self, filter: Filter) -> MachineBuilder {
+		self.input_filters.push(filter);
+		self
+	}
+
+	/// Passes output through a filter, after any added before, see `Machine::add_output_filter`.
+	pub fn output_filter(mut self, filter: Filter) -> MachineBuilder {
+		self.output_filters.push(filter);
+		self
+	}
+
+	/// Seeds the random values of `?`, see `Machine::seed_rng`.
+	pub fn seed(mut self, seed: u64) -> MachineBuilder {
+		self.seed = seed;
+		self
+	}
+
+	/// Switches debug mode on or off, see `Machine::set_debug`.
+	pub fn debug(mut self, debug: bool) -> MachineBuilder {
+		self.debug = debug;
+		self
+	}
+
+	/// The extensions programs may use, see `Machine::set_extensions`.
+	pub fn extensions(mut self, extensions: ExtensionSet) -> MachineBuilder {
+		self.extensions = extensions;
+		self
+	}
+
+	/// Lets runs be cancelled with the given token, see `Machine::set_cancel_token`.
+	pub fn cancel_token(mut self, token: CancelToken) -> MachineBuilder {
+		self.cancel = Some(token);
+		self
+	}
+
+	/**
+	Builds the machine, or tells why the settings do not make sense
+	together.
+	*/
+	pub fn build<'a>(self) -> Result<Machine<'a>, BuildError> {
+		let MachineBuilder { cell_bits, semantics, limits, memory_limit, max_exec_depth, tape, tape_name, input, output,
+		                     numeric_io, input_mode, output_mode, newline_mode, output_console, seed, debug, extensions, cancel, pacing,
+		                     track_clamps, forbid_clamp, input_filters, output_filters } = self;
+		if cell_bits != size_of::<Unit>() * 8 {
+			return Err(UnsupportedCellWidth(cell_bits));
+		}
+		let tape = match tape_name {
+			Some(name) => match tape_from_name(name) {
+				Some(tape) => Some(tape),
+				None if name == ~"ring" || name == ~"ring:" || name == ~"ring:0" => return Err(RingWithoutSize),
+				None => return Err(UnknownTape(name)),
+			},
+			None => tape,
+		};
+		let wraps = tape.as_ref().map_or(false, |tape| tape.wraps());
+		if wraps && semantics.bounds == FailAtEdges {
+			return Err(WrappingTapeFailsAtEdges);
+		}
+
+		let input = input.unwrap_or_else(|| ~stdin_raw() as ~Reader);
+		let output_console = output_console.unwrap_or_else(|| output.is_none() && stdout_raw().isatty());
+		let output = output.unwrap_or_else(|| ~stdout_raw() as ~Writer);
+		let mut machine = Machine::with_io(input, output);
+		machine.set_semantics(semantics);
+		machine.set_limits(limits);
+		machine.set_memory_limit(memory_limit);
+		machine.set_max_exec_depth(max_exec_depth);
+		machine.set_numeric_io(numeric_io);
+		machine.set_input_mode(input_mode);
+		machine.set_output_mode(output_mode);
+		machine.set_newline_mode(newline_mode.clone());
+		machine.set_output_newline_mode(newline_mode);
+		machine.set_output_console(output_console);
+		machine.set_output_pacing(pacing);
+		machine.set_track_clamps(track_clamps);
+		machine.set_forbid_clamp(forbid_clamp);
+		for filter in input_filters.move_iter() {
+			machine.add_input_filter(filter);
+		}
+		for filter in output_filters.move_iter() {
+			machine.add_output_filter(filter);
+		}
+		machine.seed_rng(seed);
+		machine.set_debug(debug);
+		machine.set_extensions(extensions);
+		match cancel {
+			Some(token) => machine.set_cancel_token(token),
+			None => {}
+		}
+		match tape {
+			Some(tape) => machine.set_tape(tape),
+			None => {}
+		}
+		Ok(machine)
+	}
+}
+
+#[cfg(test)]
+use std::io::MemReader;
+#[cfg(test)]
+use std::io::util::NullWriter;
+#[cfg(test)]
+use ast::Ast;
+#[cfg(test)]
+use machine::RuntimeError;
+#[cfg(test)]
+use storage::RingTape;
+#[cfg(test)]
+use semantics::EofMinusOne;
+
+#[test]
+/// Every setting should end up on the machine.
+fn test_machine_builder() {
+	let mut machine = MachineBuilder::new()
+		.eof(EofMinusOne)
+		.cycle_limit(10)
+		.output(~NullWriter as ~Writer)
+		.input(~::std::io::MemReader::new(~[]) as ~Reader)
+		.build()
+		.unwrap();
+	let program = ::ast::Ast::parse_str(",+[+]").unwrap();
+	// EOF reads as 255, which `+` wraps to 0, skipping the loop.
+	// Clearing 255 instead goes over the cycle limit.
+	assert!(machine.run_program(&program) == Ok(3));
+	let program = ::ast::Ast::parse_str(",[-]").unwrap();
+	assert!(machine.run_program(&program).is_err());
+}
+
+#[test]
+/// Settings which do not make sense together should be rejected.
+fn test_machine_builder_rejects() {
+	let built = MachineBuilder::new().cell_width(16).build();
+	assert!(built.err() == Some(UnsupportedCellWidth(16)));
+	let built = MachineBuilder::new().tape(~RingTape::new(10) as ~Tape<Unit>).bounds(FailAtEdges).build();
+	assert!(built.err() == Some(WrappingTapeFailsAtEdges));
+	assert!(format!("{}", WrappingTapeFailsAtEdges) == ~"A tape wrapping around can not fail at its edges.");
+	let built = MachineBuilder::new().tape_named("tapeworm").build();
+	assert!(built.err() == Some(UnknownTape(~"tapeworm")));
+	assert!(format!("{}", UnknownTape(~"tapeworm")) == ~"Unknown tape `tapeworm`.");
+	for name in ["ring", "ring:", "ring:0"].iter() {
+		assert!(MachineBuilder::new().tape_named(*name).build().err() == Some(RingWithoutSize));
+	}
+	let built = MachineBuilder::new().tape_named("ring:8").bounds(FailAtEdges).build();
+	assert!(built.err() == Some(WrappingTapeFailsAtEdges));
+}
+
+#[test]
+/// A machine built with every setting should run programs accordingly.
+fn test_machine_builder_full() {
+	let mut output = Vec::new();
+	let result = {
+		let mut machine = MachineBuilder::new()
+			.semantics(Semantics::common())
+			.cell_width(8)
+			.eof(EofMinusOne)
+			.cycle_limit(1000)
+			.output_limit(8)
+			.memory_limit(1 << 20)
+			.tape_named("ring:4")
+			.input(~MemReader::new(~[]) as ~Reader)
+			.output(~NullWriter as ~Writer)
+			.numeric_io(true)
+			.seed(7)
+			.debug(true)
+			.build()
+			.unwrap();
+		machine.on_output(|byte| output.push(byte));
+		assert!(machine.rng_seed() == 7);
+		machine.run_program(&Ast::parse_str(",.").unwrap())
+	};
+	assert!(result == Ok(2));
+	// EOF reads as 255, written as a number.
+	assert!(output.as_slice() == bytes!("255 "));
+}
+
+#[cfg(test)]
+/// Runs a program on a machine with the default settings, built or
+/// not, returning how it went, its output and a picture of its tape.
+fn run_default(program: &Ast, input: &[u8], built: bool) -> (Result<uint, RuntimeError>, Vec<u8>, ~str) {
+	let mut output = Vec::new();
+	let (result, tape) = {
+		let reader = ~MemReader::new(input.to_owned()) as ~Reader;
+		let mut machine = if built {
+			MachineBuilder::new().input(reader).output(~NullWriter as ~Writer).build().unwrap()
+		} else {
+			Machine::with_io(reader, ~NullWriter as ~Writer)
+		};
+		machine.on_output(|byte| output.push(byte));
+		let result = machine.run_program(program);
+		(result, machine.tape_report(machine.touched_range()).render_ascii(64))
+	};
+	(result, output, tape)
+}
+
+#[test]
+/// Machines built with the defaults should run like `Machine::with_io`.
+fn test_machine_builder_defaults() {
+	for &(name, source, input) in ::corpus::PROGRAMS.iter() {
+		let program = Ast::parse_str(source).unwrap();
+		if run_default(&program, input, true) != run_default(&program, input, false) {
+			fail!("{} runs differently on a built machine", name);
+		}
+	}
+}
@@ -0,0 +1,370 @@
+use operators::{Operator, Sub, Skip, Loop, Incr, Decr, Prev, Next, Put, Get, Nop};
+use operators::{IncrN, DecrN, PrevN, NextN, AddAt, SetAt, Move, OffsetWrite};
+use operators::{DoSub, DoSkip, DoLoop, Random, Negate, HostCall, Checkpoint, SetCell, Shebang};
+use ast::Ast;
+use arena::{ProgramArena, Plain, SubRef, DoSubRef};
+
+
+/**
+The kinds of instruction a machine executes.
+
+Opcodes are a dense range of small integers, so that dispatching on
+them compiles down to an indexed jump. They are listed by how often
+they tend to be executed, most first.
+*/
+#[repr(u8)]
+#[deriving(Eq, Clone, Show)]
+pub enum Opcode {
+	/// Moves the head by `a` cells.
+	OpMove = 0,
+	/// Adds `a` to the current cell.
+	OpAdd = 1,
+	/// Jumps back to `a` if the current cell is not zero.
+	OpJnz = 2,
+	/// Jumps forward to `a` if the current cell is zero.
+	OpJz = 3,
+	/// Adds `b` to the cell `a` cells away from the head.
+	OpAddAt = 4,
+	/// Sets the cell `a` cells away from the head to `b`.
+	OpSetAt = 5,
+	/// Adds to cells away from the head, as listed by write `a`.
+	OpOffsetWrite = 6,
+	/// Outputs the current cell.
+	OpPut = 7,
+	/// Inputs into the current cell.
+	OpGet = 8,
+	/// Sets the current cell to a pseudo-random value.
+	OpRandom = 9,
+	/// Negates the current cell.
+	OpNegate = 10,
+	/// Reports reaching checkpoint `a` in debug mode, and is otherwise
+	/// like a comment.
+	OpCheckpoint = 11,
+	/// Does nothing, and may not even cost a cycle.
+	OpComment = 12,
+	/// Does nothing.
+	OpNop = 13,
+	/// Calls the host back.
+	OpHostCall = 14,
+}
+
+/// How many opcodes there are.
+pub static OPCODE_COUNT: uint = 15;
+
+/// Every opcode, in order.
+pub static OPCODES: [Opcode, ..OPCODE_COUNT] = [
+	OpMove, OpAdd, OpJnz, OpJz, OpAddAt, OpSetAt, OpOffsetWrite,
+	OpPut, OpGet, OpRandom, OpNegate, OpCheckpoint, OpComment, OpNop,
+	OpHostCall,
+];
+
+impl Opcode {
+	/// A short lowercase name for the opcode.
+	pub fn name(&self) -> &'static str {
+		match *self {
+			OpMove => "move",
+			OpAdd => "add",
+			OpJnz => "jnz",
+			OpJz => "jz",
+			OpAddAt => "add_at",
+			OpSetAt => "set_at",
+			OpOffsetWrite => "offset_write",
+			OpPut => "put",
+			OpGet => "get",
+			OpRandom => "random",
+			OpNegate => "negate",
+			OpCheckpoint => "checkpoint",
+			OpComment => "comment",
+			OpNop => "nop",
+			OpHostCall => "host_call",
+		}
+	}
+}
+
+/**
+A single instruction, made of an opcode and up to two arguments,
+whose meaning depends on the opcode.
+*/
+#[deriving(Eq, Clone, Show)]
+pub struct Instr {
+	pub opcode: Opcode,
+	pub a: int,
+	pub b: int,
+}
+
+/**
+A program flattened into a sequence of instructions, where loops
+become jumps around their body, ready to be executed by a machine.
+*/
+#[deriving(Eq, Clone, Show)]
+pub struct Bytecode {
+	pub code: ~[Instr],
+	/// What each `OpOffsetWrite` adds, by offset from the head.
+	pub writes: ~[~[(int, int)]],
+	/// The name of each `OpCheckpoint`.
+	pub checkpoints: ~[~str],
+	/// How many loops each instruction is in, the loop's own jumps aside.
+	pub depths: ~[uint],
+	/**
+	Where the tail of the program starts, after which instructions
+	only add to the current cell, set it, move, output, or are
+	comments, and never jump: the end of the code if there is no such
+	tail.
+	*/
+	pub tail: uint,
+}
+
+impl Bytecode {
+	/**
+	Flattens a program into instructions. Each operator becomes
+	exactly one instruction, and each loop a `OpJz` before its body
+	and a `OpJnz` after it, or only the latter for do-while loops,
+	so that running the bytecode costs as many cycles as running the
+	program would. Shebang lines become no instruction at all.
+	*/
+	pub fn compile(ast: &Ast) -> Bytecode {
+		let (mut code, mut tables) = (Vec::new(), Tables::new());
+		let &Ast(ref ops) = ast;
+		emit(ops.as_slice(), &mut code, &mut tables);
+		tables.bytecode(code)
+	}
+
+	/**
+	Flattens a program held in an arena, exactly like `compile`
+	would flatten the same program as an AST.
+	*/
+	pub fn compile_arena(arena: &ProgramArena) -> Bytecode {
+		let (mut code, mut tables) = (Vec::new(), Tables::new());
+		emit_arena(arena, arena.root, &mut code, &mut tables);
+		tables.bytecode(code)
+	}
+
+	/**
+	Turns operators into instructions run one after the other, e.g.
+	a recorded trace, see `instruction_ops`. Brackets become no-ops
+	costing a cycle each, like the jumps they stand for. Shebang
+	lines become no instruction, and loops can not be compiled so:
+	fails with where the first of them is, if any.
+	*/
+	pub fn compile_linear(ops: &[Operator]) -> Result<Bytecode, uint> {
+		match ops.iter().position(|op| match *op { Sub(_) | DoSub(_) => true, _ => false }) {
+			Some(i) => return Err(i),
+			None => {}
+		}
+		let mut tables = Tables::new();
+		let code = ops.iter().filter(|op| match **op { Shebang(_) => false, _ => true })
+			.map(|op| plain(op, &mut tables)).collect();
+		Ok(tables.bytecode(code))
+	}
+}
+
+/**
+The operator behind each instruction `Bytecode::compile` makes of a
+program, by address: the `OpJz` entering a loop is its `[`, and the
+`OpJnz` repeating it its `]`, or `}` for do-while loops.
+*/
+pub fn instruction_ops(ast: &Ast) -> ~[Operator] {
+	let mut ops = Vec::new();
+	let &Ast(ref program) = ast;
+	list_ops(program.as_slice(), &mut ops);
+	ops.move_iter().collect()
+}
+
+/// Lists the operator of each instruction, see `instruction_ops`.
+fn list_ops(program: &[Operator], ops: &mut Vec<Operator>) {
+	for op in program.iter() {
+		match *op {
+			Sub(Ast(ref body)) => {
+				ops.push(Skip);
+				list_ops(body.as_slice(), ops);
+				ops.push(Loop);
+			}
+			DoSub(Ast(ref body)) => {
+				list_ops(body.as_slice(), ops);
+				ops.push(DoLoop);
+			}
+			Shebang(_) => {}
+			ref other => ops.push(other.clone()),
+		}
+	}
+}
+
+/**
+What instructions refer to by index, gathered as they are emitted.
+*/
+struct Tables {
+	writes: Vec<~[(int, int)]>,
+	checkpoints: Vec<~str>,
+}
+
+impl Tables {
+	fn new() -> Tables {
+		Tables { writes: Vec::new(), checkpoints: Vec::new() }
+	}
+
+	/// Makes the bytecode of the emitted instructions.
+	fn bytecode(self, code: Vec<Instr>) -> Bytecode {
+		let depths = depths(code.as_slice());
+		let tail = tail(code.as_slice());
+		Bytecode {
+			depths: depths,
+			tail: tail,
+			code: code.move_iter().collect(),
+			writes: self.writes.move_iter().collect(),
+			checkpoints: self.checkpoints.move_iter().collect(),
+		}
+	}
+}
+
+/**
+How many loops each instruction is in. Every `OpJnz` closes a loop
+whose body goes from its target to itself, whether the loop starts
+with a `OpJz` or not.
+*/
+fn depths(code: &[Instr]) -> ~[uint] {
+	let mut changes = Vec::from_elem(code.len() + 1, 0i);
+	for (pc, instr) in code.iter().enumerate() {
+		if instr.opcode == OpJnz {
+			*changes.get_mut(instr.a as uint) += 1;
+			*changes.get_mut(pc) -= 1;
+		}
+	}
+	let mut depth = 0i;
+	code.iter().enumerate().map(|(pc, _)| {
+		depth += *changes.get(pc);
+		depth as uint
+	}).collect()
+}
+
+/// Where the tail of straight-line output starts, see `Bytecode::tail`.
+fn tail(code: &[Instr]) -> uint {
+	let mut start = code.len();
+	for instr in code.iter().rev() {
+		match instr.opcode {
+			OpAdd | OpMove | OpPut | OpComment => start -= 1,
+			OpSetAt if instr.a == 0 => start -= 1,
+			_ => break,
+		}
+	}
+	start
+}
+
+/// Shorthand for making an instruction.
+fn instr(opcode: Opcode, a: int, b: int) -> Instr {
+	Instr { opcode: opcode, a: a, b: b }
+}
+
+/**
+Emits the instructions for a sequence of operators, resolving the
+loops' jump targets as their bodies get laid out.
+*/
+fn emit(ops: &[Operator], code: &mut Vec<Instr>, tables: &mut Tables) {
+	for op in ops.iter() {
+		match *op {
+			Sub(Ast(ref body)) => {
+				// The skip target is only known after the body.
+				let start = code.len();
+				code.push(instr(OpJz, 0, 0));
+				emit(body.as_slice(), code, tables);
+				code.push(instr(OpJnz, start as int + 1, 0));
+				let end = code.len();
+				code.get_mut(start).a = end as int;
+			}
+			DoSub(Ast(ref body)) => {
+				// The body always runs once, so there is nothing to skip.
+				let start = code.len();
+				emit(body.as_slice(), code, tables);
+				code.push(instr(OpJnz, start as int, 0));
+			}
+			// Shebang lines are skipped altogether.
+			Shebang(_) => {}
+			ref other => code.push(plain(other, tables)),
+		}
+	}
+}
+
+/**
+Emits the instructions for a range of an arena, like `emit` does.
+*/
+fn emit_arena(arena: &ProgramArena, range: uint, code: &mut Vec<Instr>, tables: &mut Tables) {
+	for op in arena.body(range).iter() {
+		match *op {
+			SubRef(body) => {
+				let start = code.len();
+				code.push(instr(OpJz, 0, 0));
+				emit_arena(arena, body, code, tables);
+				code.push(instr(OpJnz, start as int + 1, 0));
+				let end = code.len();
+				code.get_mut(start).a = end as int;
+			}
+			DoSubRef(body) => {
+				let start = code.len();
+				emit_arena(arena, body, code, tables);
+				code.push(instr(OpJnz, start as int, 0));
+			}
+			Plain(Shebang(_)) => {}
+			Plain(ref op) => code.push(plain(op, tables)),
+		}
+	}
+}
+
+/**
+The instruction for any operator but loops, adding to `tables` what
+an `OffsetWrite` adds, or the name of a checkpoint.
+*/
+fn plain(op: &Operator, tables: &mut Tables) -> Instr {
+	match *op {
+		Incr => instr(OpAdd, 1, 0),
+		Decr => instr(OpAdd, -1, 0),
+		IncrN(n) => instr(OpAdd, n as int, 0),
+		DecrN(n) => instr(OpAdd, -(n as int), 0),
+		Prev => instr(OpMove, -1, 0),
+		Next => instr(OpMove, 1, 0),
+		PrevN(n) => instr(OpMove, -(n as int), 0),
+		NextN(n) => instr(OpMove, n as int, 0),
+		Move(offset) => instr(OpMove, offset, 0),
+		AddAt(offset, delta) => instr(OpAddAt, offset, delta),
+		SetAt(offset, value) => instr(OpSetAt, offset, value as int),
+		SetCell(value) => instr(OpSetAt, 0, value as int),
+		OffsetWrite(ref added) => {
+			tables.writes.push(added.clone());
+			instr(OpOffsetWrite, tables.writes.len() as int - 1, 0)
+		}
+		Put => instr(OpPut, 0, 0),
+		Get => instr(OpGet, 0, 0),
+		Random => instr(OpRandom, 0, 0),
+		Negate => instr(OpNegate, 0, 0),
+		HostCall => instr(OpHostCall, 0, 0),
+		Checkpoint(ref name) => {
+			tables.checkpoints.push(name.clone());
+			instr(OpCheckpoint, tables.checkpoints.len() as int - 1, 0)
+		}
+		Nop(_) => instr(OpComment, 0, 0),
+		Skip | Loop | DoSkip | DoLoop => instr(OpNop, 0, 0),
+		Sub(_) | DoSub(_) => fail!("Loops are not plain instructions."),
+		Shebang(_) => fail!("Shebang lines are not instructions."),
+	}
+}
+
+#[test]
+/// Loops should jump past their end, and back to the start of their body.
+fn test_bytecode_compiles_loops() {
+	let ast = Ast::parse_str("[>+<-]").unwrap();
+	let opcodes: ~[(Opcode, int)] = Bytecode::compile(&ast).code.iter().map(|i| (i.opcode, i.a)).collect();
+	assert!(opcodes == ~[
+		(OpJz, 6),
+		(OpMove, 1), (OpAdd, 1), (OpMove, -1), (OpAdd, -1),
+		(OpJnz, 1),
+	]);
+}
+
+#[test]
+/// The tail should start after the last jump, at the first of the plain instructions after it.
+fn test_bytecode_tail() {
+	let tail = |source: &str| Bytecode::compile(&Ast::parse_str(source).unwrap()).tail;
+	assert!(tail("+[-]>++. ") == 4);
+	assert!(tail("+[-],.") == 5);
+	assert!(tail("+[-]") == 4);
+	assert!(tail("++.") == 0);
+	assert!(Bytecode::compile(&Ast(~[Incr, SetAt(1, 0), SetCell(0), Put])).tail == 2);
+}
@@ -0,0 +1,231 @@
+/*!
+An on-disk cache of compiled programs, so that large programs run
+again and again are only optimized once.
+
+Entries are named after a hash of the source and of the options it is
+compiled with. They hold a header, a checksum, and the program, see
+`Ast::to_bytes`. Entries which can not be read back, for being
+truncated, corrupt or of another version, are compiled again and
+replaced.
+*/
+
+use std::str;
+use std::hash::hash;
+use std::rand::random;
+use std::io::{File, BufReader, IoResult, UserRWX};
+use std::io::fs;
+use ast::{Ast, ParseOptions};
+
+
+/// Starts every entry. The last byte is the version of the format.
+static HEADER: &'static [u8] = bytes!("BFC\x02");
+
+/// The size of the checksum following the header.
+static CHECKSUM_LEN: uint = 8;
+
+/**
+How to compile a program: everything changing what it compiles to.
+*/
+#[deriving(Clone, Eq, Hash, Show)]
+pub struct CompileOptions {
+	/// How to parse the source.
+	pub parse: ParseOptions,
+	/// How much to optimize the program, see `optimize`.
+	pub opt_level: uint,
+}
+
+/**
+A compiled program, and where it came from.
+*/
+#[deriving(Clone, Eq, Show)]
+pub struct Compiled {
+	/// The program, parsed and optimized.
+	pub program: Ast,
+	/// Whether the program was loaded from the cache, or compiled.
+	pub loaded: bool,
+}
+
+/**
+Optimizes a program: 0 leaves it as it is, 1 folds runs, 2 also uses
+offsets, and 3 folds balanced writes instead.
+*/
+pub fn optimize(program: &Ast, opt_level: uint) -> Ast {
+	match opt_level {
+		0 => program.clone(),
+		1 => program.optimize(),
+		2 => program.optimize().optimize_offsets(),
+		_ => program.optimize().fold_offset_writes(),
+	}
+}
+
+/**
+Parses and optimizes a program, without going through the cache.
+*/
+pub fn compile(source: &[u8], options: &CompileOptions) -> Result<Ast, ~str> {
+	let source = match str::from_utf8(source) {
+		Some(source) => source,
+		None => return Err(~"The source is not valid UTF-8."),
+	};
+	let (program, _) = try!(Ast::parse_with(source, options.parse.clone()));
+	Ok(optimize(&program, options.opt_level))
+}
+
+/**
+Loads a program from the cache in `cache_dir`, or compiles it and
+stores it there, making the directory if need be.
+
+Entries are written aside, then renamed into place, so that runs
+sharing a cache never see half an entry. Failing to store an entry
+is not an error, as the cache only saves time. Only parse errors are.
+*/
+pub fn get_or_compile(source: &[u8], options: &CompileOptions, cache_dir: &Path) -> Result<Compiled, ~str> {
+	match lookup(source, options, cache_dir) {
+		Some(program) => return Ok(Compiled { program: program, loaded: true }),
+		None => {}
+	}
+	let program = try!(compile(source, options));
+	save(source, options, cache_dir, &program);
+	Ok(Compiled { program: program, loaded: false })
+}
+
+/**
+Loads a program from the cache in `cache_dir`, without parsing nor
+compiling it, unless it is not there or can not be read back.
+*/
+pub fn lookup(source: &[u8], options: &CompileOptions, cache_dir: &Path) -> Option<Ast> {
+	load(&entry(source, options, cache_dir))
+}
+
+/**
+Stores a program compiled from `source` with the given options in
+the cache in `cache_dir`, like `get_or_compile` does, for when it
+was compiled some other way.
+*/
+pub fn save(source: &[u8], options: &CompileOptions, cache_dir: &Path, program: &Ast) {
+	let _ = store(program, &entry(source, options, cache_dir));
+}
+
+/// Where the entry of a program compiled with the given options goes.
+fn entry(source: &[u8], options: &CompileOptions, cache_dir: &Path) -> Path {
+	cache_dir.join(format!("{}.bfc", key(source, options)))
+}
+
+/**
+Names the entry of a program compiled with the given options, every
+one of them, so that none added later can be left out.
+*/
+fn key(source: &[u8], options: &CompileOptions) -> ~str {
+	format!("{:016x}", hash(&(source, options, HEADER)))
+}
+
+/// Reads an entry back, unless it is missing or can not be read.
+fn load(path: &Path) -> Option<Ast> {
+	let bytes = match File::open(path).read_to_end() {
+		Ok(bytes) => bytes,
+		Err(_) => return None,
+	};
+	let start = HEADER.len() + CHECKSUM_LEN;
+	if bytes.len() < start || !bytes.starts_with(HEADER) {
+		return None;
+	}
+	// Programs cut between two operators would still load.
+	let payload = bytes.slice_from(start);
+	let checksum = BufReader::new(bytes.slice(HEADER.len(), start)).read_le_u64();
+	if checksum.ok() != Some(hash(&payload)) {
+		return None;
+	}
+	Ast::from_bytes(payload).ok()
+}
+
+/// Writes an entry, replacing any there was.
+fn store(program: &Ast, path: &Path) -> IoResult<()> {
+	let dir = path.dir_path();
+	if !dir.exists() {
+		try!(fs::mkdir_recursive(&dir, UserRWX));
+	}
+	let bytes = program.to_bytes();
+	let payload = bytes.as_slice();
+	let temp = path.with_extension(format!("{:016x}.tmp", random::<u64>()));
+	{
+		let mut file = try!(File::create(&temp));
+		try!(file.write(HEADER));
+		try!(file.write_le_u64(hash(&payload)));
+		try!(file.write(payload));
+	}
+	match fs::rename(&temp, path) {
+		Ok(()) => Ok(()),
+		Err(err) => {
+			let _ = fs::unlink(&temp);
+			Err(err)
+		}
+	}
+}
+
+#[cfg(test)]
+use std::io::TempDir;
+
+#[cfg(test)]
+/// A program worth optimizing.
+static SOURCE: &'static [u8] = bytes!("++++++++[>++++++++<-]>+.[-]");
+
+#[cfg(test)]
+/// Strict parsing, then optimizing to the given level.
+fn level(opt_level: uint) -> CompileOptions {
+	CompileOptions { parse: ParseOptions::strict(), opt_level: opt_level }
+}
+
+#[test]
+/// A program compiled once should be loaded the next time.
+fn test_cache_loads_entries() {
+	let dir = TempDir::new("bf-cache").unwrap();
+	let first = get_or_compile(SOURCE, &level(2), dir.path()).unwrap();
+	assert!(!first.loaded);
+	let second = get_or_compile(SOURCE, &level(2), dir.path()).unwrap();
+	assert!(second.loaded);
+	assert!(second.program == first.program);
+	assert!(first.program == compile(SOURCE, &level(2)).unwrap());
+}
+
+#[test]
+/// Other options should make other entries.
+fn test_cache_keys_options() {
+	assert!(key(SOURCE, &level(2)) != key(SOURCE, &level(3)));
+	let extended = CompileOptions { parse: ParseOptions { extensions: true, ..ParseOptions::strict() }, ..level(2) };
+	assert!(key(SOURCE, &level(2)) != key(SOURCE, &extended));
+	let limited = CompileOptions { parse: ParseOptions { max_ops: Some(10), ..ParseOptions::strict() }, ..level(2) };
+	assert!(key(SOURCE, &level(2)) != key(SOURCE, &limited));
+	assert!(key(SOURCE, &level(2)) == key(SOURCE, &level(2)));
+
+	let dir = TempDir::new("bf-cache").unwrap();
+	get_or_compile(SOURCE, &level(2), dir.path()).unwrap();
+	assert!(!get_or_compile(SOURCE, &level(3), dir.path()).unwrap().loaded);
+	assert!(fs::readdir(dir.path()).unwrap().len() == 2);
+}
+
+#[test]
+/// Programs saved aside should be looked up as they were, without compiling them.
+fn test_cache_lookup() {
+	let dir = TempDir::new("bf-cache").unwrap();
+	assert!(lookup(SOURCE, &level(2), dir.path()).is_none());
+	// Not what SOURCE compiles to, so that it can only come from the entry.
+	let program = Ast::parse_str("+.").unwrap();
+	save(SOURCE, &level(2), dir.path(), &program);
+	assert!(lookup(SOURCE, &level(2), dir.path()) == Some(program.clone()));
+	assert!(get_or_compile(SOURCE, &level(2), dir.path()).unwrap() == Compiled { program: program, loaded: true });
+	assert!(lookup(SOURCE, &level(3), dir.path()).is_none());
+}
+
+#[test]
+/// A truncated entry should be compiled again, and replaced.
+fn test_cache_recompiles_truncated() {
+	let dir = TempDir::new("bf-cache").unwrap();
+	get_or_compile(SOURCE, &level(2), dir.path()).unwrap();
+	let path = entry(SOURCE, &level(2), dir.path());
+	let bytes = File::open(&path).read_to_end().unwrap();
+	File::create(&path).write(bytes.slice_to(bytes.len() - 3)).unwrap();
+
+	let again = get_or_compile(SOURCE, &level(2), dir.path()).unwrap();
+	assert!(!again.loaded);
+	assert!(again.program == compile(SOURCE, &level(2)).unwrap());
+	assert!(get_or_compile(SOURCE, &level(2), dir.path()).unwrap().loaded);
+}
@@ -0,0 +1,174 @@
+/*!
+Checking that every way of running a program, see `exec`, does the
+same: the same output, the same end, and the same cycles where they
+count them alike.
+*/
+
+use std::fmt;
+use ast::Ast;
+use machine::Limits;
+use semantics::Semantics;
+use exec::{Backend, RunOutcome, backends};
+
+
+/**
+How backends ran a program, and where they first disagreed.
+*/
+#[deriving(Clone, Eq)]
+pub struct ConformanceReport {
+	/// The outcome of each backend, by name, the reference one first.
+	pub outcomes: ~[(&'static str, RunOutcome)],
+	/**
+	The first backend to disagree with the reference one, and how,
+	if any did.
+	*/
+	pub divergence: Option<(&'static str, ~str)>,
+}
+
+impl ConformanceReport {
+	/// Whether every backend did the same.
+	pub fn is_ok(&self) -> bool {
+		self.divergence.is_none()
+	}
+}
+
+impl fmt::Show for ConformanceReport {
+	/**
+	Tells how each backend went, then how the first diverging one
+	differed from the reference one.
+	*/
+	fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+		for &(name, ref outcome) in self.outcomes.iter() {
+			let end = match outcome.result {
+				Ok(()) => ~"ended",
+				Err(ref err) => format!("failed: {}", *err),
+			};
+			let bytes = outcome.output.len();
+			try!(writeln!(f.buf, "{}: {} after {} cycles, with {} byte{} of output",
+				name, end, outcome.cycles, bytes, if bytes == 1 { "" } else { "s" }));
+		}
+		match self.divergence {
+			Some((name, ref what)) => write!(f.buf, "`{}` diverges from `{}`: {}", name, self.outcomes[0].val0(), *what),
+			None => write!(f.buf, "All backends agree."),
+		}
+	}
+}
+
+/**
+Runs a program on every backend, with the same input, limits and
+semantics, and checks that they all do the same.
+*/
+pub fn check_all(program: &Ast, input: &[u8], limits: Limits, semantics: Semantics) -> ConformanceReport {
+	check(backends(), program, input, limits, semantics)
+}
+
+/**
+Runs a program on the given backends, checking that they all do the
+same as the first one.
+*/
+pub fn check(mut backends: ~[~Backend], program: &Ast, input: &[u8], limits: Limits, semantics: Semantics) -> ConformanceReport {
+	let mut outcomes = ~[];
+	let mut divergence = None;
+	let mut reference_counts = true;
+	for (i, backend) in backends.mut_iter().enumerate() {
+		let outcome = backend.run(program, input, limits.clone(), semantics.clone());
+		if i == 0 {
+			reference_counts = backend.shares_cost_model();
+		} else if divergence.is_none() {
+			let counts = reference_counts && backend.shares_cost_model();
+			let &(_, ref expected) = &outcomes[0];
+			divergence = difference(expected, &outcome, counts).map(|what| (backend.name(), what));
+		}
+		outcomes.push((backend.name(), outcome));
+	}
+	ConformanceReport { outcomes: outcomes, divergence: divergence }
+}
+
+/**
+Tells how an outcome differs from the expected one, if it does,
+comparing cycles only when asked to.
+*/
+fn difference(expected: &RunOutcome, actual: &RunOutcome, cycles: bool) -> Option<~str> {
+	if actual.output != expected.output {
+		let same = expected.output.iter().zip(actual.output.iter()).take_while(|&(a, b)| a == b).count();
+		let show = |output: &[u8]| if same < output.len() { output[same].to_str() } else { ~"nothing" };
+		return Some(format!("output differs from byte {}: expected {}, got {}",
+			same, show(expected.output), show(actual.output)));
+	}
+	if actual.result != expected.result {
+		let show = |result: &Result<(), ::machine::RuntimeError>| match *result {
+			Ok(()) => ~"ending",
+			Err(ref err) => format!("failing with `{}`", *err),
+		};
+		return Some(format!("expected {}, got {}", show(&expected.result), show(&actual.result)));
+	}
+	if cycles && actual.cycles != expected.cycles {
+		return Some(format!("expected {} cycles, got {}", expected.cycles, actual.cycles));
+	}
+	None
+}
+
+#[cfg(test)]
+use exec::BytecodeBackend;
+
+#[cfg(test)]
+/// Runs programs like the bytecode machine, but counts a cycle too many.
+struct OffByOne;
+
+#[cfg(test)]
+impl Backend for OffByOne {
+	fn name(&self) -> &'static str { "off-by-one" }
+
+	fn run(&mut self, program: &Ast, input: &[u8], limits: Limits, semantics: Semantics) -> RunOutcome {
+		let mut reference = BytecodeBackend;
+		let mut outcome = reference.run(program, input, limits, semantics);
+		outcome.cycles += 1;
+		outcome
+	}
+}
+
+#[test]
+/// Every corpus program should run the same on every backend.
+fn test_conformance_corpus() {
+	for &(name, source, input) in ::corpus::PROGRAMS.iter() {
+		let program = Ast::parse_str(source).unwrap();
+		for program in [program.clone(), program.optimize(), program.optimize().optimize_offsets()].iter() {
+			let report = check_all(program, input, Limits::none(), Semantics::common());
+			if !report.is_ok() {
+				fail!("{} runs differently:\n{}", name, report);
+			}
+		}
+	}
+}
+
+#[test]
+/// Limits should stop every backend alike.
+fn test_conformance_limits() {
+	let program = Ast::parse_str("+[.]").unwrap();
+	let report = check_all(&program, [], Limits { cycles: Some(100), ..Limits::none() }, Semantics::common());
+	assert!(report.is_ok());
+	let report = check_all(&program, [], Limits { output: Some(10), ..Limits::none() }, Semantics::common());
+	assert!(report.is_ok());
+	assert!(report.outcomes.iter().all(|&(_, ref outcome)| outcome.output.len() == 10));
+}
+
+#[test]
+/// Every backend should run with the semantics it is given.
+fn test_conformance_semantics() {
+	let program = Ast::parse_str("<+.").unwrap();
+	let report = check_all(&program, [], Limits::none(), Semantics::strict());
+	assert!(report.is_ok());
+	assert!(report.outcomes.iter().all(|&(_, ref outcome)| outcome.result == Err(::machine::TapeOutOfBounds)));
+}
+
+#[test]
+/// A backend off by a cycle should be caught, and told apart.
+fn test_conformance_off_by_one() {
+	let program = Ast::parse_str("++[-].").unwrap();
+	let backends = ~[~BytecodeBackend as ~Backend, ~OffByOne as ~Backend];
+	let report = check(backends, &program, [], Limits::none(), Semantics::common());
+	assert!(!report.is_ok());
+	assert!(format!("{}", report) == ~"bytecode: ended after 8 cycles, with 1 byte of output\n\
+		off-by-one: ended after 9 cycles, with 1 byte of output\n\
+		`off-by-one` diverges from `bytecode`: expected 8 cycles, got 9");
+}
@@ -0,0 +1,309 @@
+/*!
+The core of running programs, for embedding where there is little
+to spare: a tape over memory given by the caller, and a loop running
+operators straight from the AST.
+
+Nothing here allocates once the program is parsed, nor does any I/O:
+input comes from an iterator, and output goes to a closure. Runs
+follow the common semantics, see `Semantics::common`, but on a tape
+of fixed size, and without `?`, for lack of a source of randomness.
+Nor does anything recurse: the loops being run are kept in an array
+of fixed size, so that the stack a run takes is known in advance.
+*/
+
+use std::mem::size_of;
+use std::num::Zero;
+use ast::Ast;
+use operators::{Operator, Incr, Decr, Prev, Next, Put, Get, Skip, Loop, Nop, Shebang};
+use operators::{DoSkip, DoLoop, Random, Negate, HostCall, Checkpoint, SetCell, Sub, DoSub};
+use operators::{IncrN, DecrN, PrevN, NextN, AddAt, SetAt, Move, OffsetWrite};
+use semantics::{Bounds, FailAtEdges};
+use storage::Tape;
+
+
+/// How many loops deep core runs may go, see `run_core`.
+pub static CORE_MAX_DEPTH: uint = 256;
+
+
+/**
+Why a core run stopped before the end of the program.
+*/
+#[deriving(Clone, Eq, Show)]
+pub enum CoreError {
+	/// The head was moved past the edges of a tape failing there.
+	EdgeReached,
+	/// The output closure asked for the run to stop.
+	OutputStopped,
+	/// The program uses `?`, which the core can not run.
+	RandomUnsupported,
+	/// The program uses `@`, having no host to call back.
+	HostCallUnsupported,
+	/// The run went deeper in loops than `CORE_MAX_DEPTH`.
+	NestedTooDeep,
+}
+
+/**
+A tape over cells given by the caller, e.g. an array on the stack,
+which never grows. Its edges are those of the cells, past which the
+head stops, or the run fails, depending on its bounds.
+*/
+pub struct FixedTape<'a, T> {
+	/// The cells, owned by the caller.
+	cells: &'a mut [T],
+	/// Keeps track of the reading head.
+	cur: int,
+	/// What moving past the edges does.
+	bounds: Bounds,
+}
+
+impl<'a, T:Int> FixedTape<'a, T> {
+	/**
+	Makes a tape over the given cells, which can not be empty, with
+	the head on the first one. The cells are used as they are.
+	*/
+	pub fn new(cells: &'a mut [T], bounds: Bounds) -> FixedTape<'a, T> {
+		if cells.is_empty() { fail!("A fixed tape needs at least one cell.") }
+		FixedTape { cells: cells, cur: 0, bounds: bounds }
+	}
+
+	/// The index of the cell at a position, stopping at the edges.
+	fn index(&self, pos: int) -> uint {
+		let last = self.cells.len() as int - 1;
+		if pos < 0 { 0 } else if pos > last { last as uint } else { pos as uint }
+	}
+}
+
+impl<'a, T:Int> Tape<T> for FixedTape<'a, T> {
+	fn wind(&mut self, offset: int) {
+		self.cur = self.index(self.cur + offset) as int;
+	}
+	fn cell<'b>(&'b mut self) -> &'b mut T {
+		&mut self.cells[self.cur as uint]
+	}
+	fn at_offset<'b>(&'b mut self, offset: int) -> &'b mut T {
+		let index = self.index(self.cur + offset);
+		&mut self.cells[index]
+	}
+	fn position(&self) -> int {
+		self.cur
+	}
+	fn peek(&self, pos: int) -> T {
+		if pos < 0 || pos >= self.cells.len() as int { Zero::zero() } else { self.cells[pos as uint] }
+	}
+	fn cells(&self) -> Vec<(int, T)> {
+		self.cells.iter().enumerate().map(|(pos, &v)| (pos as int, v)).collect()
+	}
+	fn clear(&mut self) {
+		for cell in self.cells.mut_iter() {
+			*cell = Zero::zero();
+		}
+		self.cur = 0;
+	}
+	fn fails_at_edges(&self) -> bool {
+		self.bounds == FailAtEdges
+	}
+	/// The cells given, which the tape did not allocate itself.
+	fn bytes_allocated(&self) -> uint {
+		self.cells.len() * size_of::<T>()
+	}
+}
+
+/**
+Runs operators on a tape, reading from `input` and handing every
+byte written to `output`, which returns false to stop the run.
+Returns the cycles executed, counted like `Machine::run_program`
+does with the common semantics.
+
+At the end of input, `,` sets the cell to zero. Cells wrap around.
+Moving the head past the edges of the tape stops it there, unless
+the tape fails at its edges, see `Tape::fails_at_edges`. Offset
+operators reach cells past the edges like `Tape::at_offset` does.
+Entering a loop deeper than `CORE_MAX_DEPTH` fails the run.
+*/
+pub fn run_core(ops: &[Operator], tape: &mut Tape<u8>, input: &mut Iterator<u8>,
+                output: |u8| -> bool) -> Result<uint, CoreError> {
+	let mut output = output;
+	let mut cycles = 0;
+	// The operators of the program, then of each loop entered, along
+	// with where the run is in them.
+	let mut frames = [(&[], 0u), ..CORE_MAX_DEPTH + 1];
+	frames[0] = (ops, 0);
+	let mut depth = 0;
+	loop {
+		let (ops, at) = frames[depth];
+		if at == ops.len() {
+			if depth == 0 { return Ok(cycles) }
+			// The body of a loop ran, so its condition is checked
+			// again, costing a cycle, to either repeat it or leave.
+			cycles += 1;
+			if *tape.cell() != 0 { frames[depth] = (ops, 0) } else { depth -= 1 }
+			continue;
+		}
+		frames[depth] = (ops, at + 1);
+		let body = match ops[at] {
+			// Entering a loop is checked once, costing a cycle, unless
+			// it is a do-while loop.
+			Sub(Ast(ref body)) => {
+				cycles += 1;
+				if *tape.cell() == 0 { continue }
+				body
+			}
+			DoSub(Ast(ref body)) => body,
+			Shebang(_) => continue,
+			ref other => {
+				cycles += 1;
+				try!(run_op(other, tape, input, &mut output));
+				continue;
+			}
+		};
+		if depth == CORE_MAX_DEPTH {
+			return Err(NestedTooDeep);
+		}
+		depth += 1;
+		frames[depth] = (body.as_slice(), 0);
+	}
+}
+
+/// Runs any operator but loops.
+fn run_op(op: &Operator, tape: &mut Tape<u8>, input: &mut Iterator<u8>,
+          output: &mut |u8| -> bool) -> Result<(), CoreError> {
+	match *op {
+		Incr => add_at(tape, 0, 1),
+		Decr => add_at(tape, 0, -1),
+		IncrN(n) => add_at(tape, 0, n as int),
+		DecrN(n) => add_at(tape, 0, -(n as int)),
+		Prev => return seek(tape, -1),
+		Next => return seek(tape, 1),
+		PrevN(n) => return seek(tape, -(n as int)),
+		NextN(n) => return seek(tape, n as int),
+		Move(offset) => return seek(tape, offset),
+		AddAt(offset, delta) => add_at(tape, offset, delta),
+		SetAt(offset, value) => *tape.at_offset(offset) = value,
+		SetCell(value) => *tape.cell() = value,
+		Negate => { let cell = tape.cell(); *cell = 0 - *cell }
+		OffsetWrite(ref added) => {
+			for &(offset, delta) in added.iter() {
+				add_at(tape, offset, delta);
+			}
+		}
+		Put => if !(*output)(*tape.cell()) { return Err(OutputStopped) },
+		Get => *tape.cell() = input.next().unwrap_or(0),
+		Random => return Err(RandomUnsupported),
+		HostCall => return Err(HostCallUnsupported),
+		Nop(_) | Checkpoint(_) | Skip | Loop | DoSkip | DoLoop => {}
+		Sub(_) | DoSub(_) | Shebang(_) => fail!("Not a plain operator."),
+	}
+	Ok(())
+}
+
+/// Adds to the cell `offset` cells away from the head, wrapping around.
+fn add_at(tape: &mut Tape<u8>, offset: int, delta: int) {
+	let cell = tape.at_offset(offset);
+	*cell = (*cell as int + delta) as u8;
+}
+
+/// Moves the head, failing if it stopped short at an edge it fails at.
+fn seek(tape: &mut Tape<u8>, offset: int) -> Result<(), CoreError> {
+	let target = tape.position() + offset;
+	tape.wind(offset);
+	if tape.position() != target && !tape.wraps() && tape.fails_at_edges() {
+		return Err(EdgeReached);
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+use machine::Machine;
+#[cfg(test)]
+use semantics::ClampAtEdges;
+
+#[cfg(test)]
+/// Runs a program on a tape of the given cells, returning what it wrote.
+fn run_on(program: &Ast, cells: &mut [u8], bounds: Bounds, input: &[u8]) -> (Result<uint, CoreError>, ~[u8]) {
+	let &Ast(ref ops) = program;
+	let mut tape = FixedTape::new(cells, bounds);
+	let mut written = Vec::new();
+	let result = run_core(ops.as_slice(), &mut tape, &mut input.iter().map(|&byte| byte),
+		|byte| { written.push(byte); true });
+	(result, written.move_iter().collect())
+}
+
+#[cfg(test)]
+/// Parses a standard program.
+fn parse(source: &str) -> Ast {
+	Ast::parse_str(source).unwrap()
+}
+
+#[test]
+/// The corpus should run on the core like it does on a machine.
+fn test_core_corpus() {
+	for &(name, source, input) in ::corpus::PROGRAMS.iter() {
+		let program = parse(source);
+		let mut machine = Machine::capturing(input);
+		let cycles = machine.run_program(&program).unwrap();
+		let programs = [program.clone(), program.optimize(), program.optimize().optimize_offsets()];
+		for (i, program) in programs.iter().enumerate() {
+			let mut cells = [0u8, ..30000];
+			let (result, written) = run_on(program, &mut cells, ClampAtEdges, input);
+			if result.is_err() || written.as_slice() != machine.captured_output() {
+				fail!("{} runs differently on the core", name);
+			}
+			// Optimized programs take fewer cycles.
+			if i == 0 && result != Ok(cycles) {
+				fail!("{} takes {} cycles on the core, instead of {}", name, result, cycles);
+			}
+		}
+	}
+}
+
+#[test]
+/// A small tape should stop the head, or fail the run, at its edges.
+fn test_core_fixed_tape_edges() {
+	let sweep = parse(format!("{}+<<.", ">".repeat(100)));
+	let mut cells = [0u8, ..64];
+	assert!(run_on(&sweep, &mut cells, ClampAtEdges, []) == (Ok(104), ~[0]));
+	assert!(cells[63] == 1 && cells.iter().fold(0, |sum, &cell| sum + cell) == 1);
+
+	let mut cells = [0u8, ..64];
+	assert!(run_on(&sweep, &mut cells, FailAtEdges, []).val0() == Err(EdgeReached));
+	assert!(cells.iter().all(|&cell| cell == 0));
+	let mut cells = [0u8, ..64];
+	assert!(run_on(&parse("+[>+]"), &mut cells, FailAtEdges, []).val0() == Err(EdgeReached));
+	assert!(cells.iter().all(|&cell| cell == 1));
+}
+
+#[test]
+/// The output closure should be able to stop the run, and input end.
+fn test_core_output_stops() {
+	let mut cells = [0u8, ..8];
+	let mut count = 0;
+	let nothing: &[u8] = [];
+	let result = {
+		let mut tape = FixedTape::new(&mut cells, ClampAtEdges);
+		let Ast(ops) = parse("+[.]");
+		run_core(ops.as_slice(), &mut tape, &mut nothing.iter().map(|&byte| byte), |_| { count += 1; count < 3 })
+	};
+	assert!(result == Err(OutputStopped));
+	assert!(count == 3);
+	assert!(run_on(&parse(",.,."), &mut cells, ClampAtEdges, [7]) == (Ok(4), ~[7, 0]));
+	assert!(run_on(&Ast(~[Random]), &mut cells, ClampAtEdges, []).val0() == Err(RandomUnsupported));
+}
+
+#[test]
+/// Loops should run however deep, up to the bound.
+fn test_core_depth() {
+	let nested = |depth: uint| {
+		let mut program = Ast(Vec::from_elem(depth, Decr).move_iter().collect());
+		for _ in range(0, depth) {
+			program = Ast(~[Incr, Sub(program)]);
+		}
+		program
+	};
+	let mut cells = [0u8, ..8];
+	assert!(run_on(&nested(CORE_MAX_DEPTH), &mut cells, ClampAtEdges, []).val0() == Ok(4 * CORE_MAX_DEPTH));
+	assert!(run_on(&nested(CORE_MAX_DEPTH + 1), &mut cells, ClampAtEdges, []).val0() == Err(NestedTooDeep));
+	// Do-while loops are entered without a check.
+	let mut cells = [0u8, ..8];
+	let program = Ast(~[Incr, DoSub(Ast(~[Decr, DoSub(Ast(~[Put]))]))]);
+	assert!(run_on(&program, &mut cells, ClampAtEdges, []) == (Ok(5), ~[0]));
+}
@@ -0,0 +1,32 @@
+/*!
+The programs from `tests/` and `examples/`, for tests which need to
+run real programs, along with some input to give them.
+
+Programs which do not parse, or which run for too long to be part
+of the test suite, are left out.
+*/
+
+use ast::Ast;
+use machine::Machine;
+
+/// The name, source and input of each program.
+pub static PROGRAMS: &'static [(&'static str, &'static str, &'static [u8])] = &[
+	("helloworld", include_str!("../examples/helloworld.bf"), &[]),
+	("rot13", include_str!("../examples/rot13.bf"), bytes!("Hello, World!\n")),
+	("bf", include_str!("../examples/bf.bf"), bytes!("++++++++[>++++++++<-]>+.!")),
+	("io", include_str!("../tests/io.bf"), bytes!("\n")),
+	("misc", include_str!("../tests/misc.bf"), &[]),
+	("tape-bound", include_str!("../tests/tape-bound.bf"), &[]),
+	("tape-size", include_str!("../tests/tape-size.bf"), &[]),
+];
+
+/**
+Runs a program in memory, returning its output and a picture of its
+tape, so that runs can be compared.
+*/
+pub fn run(program: &Ast, input: &[u8]) -> (~[u8], ~str) {
+	let mut machine = Machine::capturing(input);
+	machine.run_program(program).unwrap();
+	let range = machine.touched_range();
+	(machine.captured_output().to_owned(), machine.tape_report(range).render_ascii(64))
+}
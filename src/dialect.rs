@@ -0,0 +1,200 @@
+/*!
+Brainfuck derivatives, which only differ from it by how they spell
+its eight operators, and translation between them.
+*/
+
+use operators::{Nop, Sub, DoSub, Random, Negate, HostCall, Checkpoint, SetCell, Shebang, Decr, IncrN};
+use ast::split_shebang;
+use ast::Ast;
+
+
+/// The operators of Brainfuck, in the order dialects spell them.
+static OPERATORS: &'static str = "+-<>.,[]";
+
+/**
+A token table: how a dialect spells each operator.
+*/
+pub struct Dialect {
+	/// The name of the dialect, as given on the command line.
+	pub name: &'static str,
+	/// The spelling of `+ - < > . , [ ]`, in this order.
+	pub tokens: [&'static str, ..8],
+	/**
+	What goes between tokens. Dialects with a separator are made of
+	words, which may be separated by any whitespace when parsing.
+	*/
+	pub separator: &'static str,
+}
+
+/// Brainfuck itself.
+pub static BRAINFUCK: Dialect = Dialect {
+	name: "bf",
+	tokens: ["+", "-", "<", ">", ".", ",", "[", "]"],
+	separator: "",
+};
+
+/// Ook!, for orangutans.
+pub static OOK: Dialect = Dialect {
+	name: "ook",
+	tokens: [
+		"Ook. Ook.", "Ook! Ook!", "Ook? Ook.", "Ook. Ook?",
+		"Ook! Ook.", "Ook. Ook!", "Ook! Ook?", "Ook? Ook!",
+	],
+	separator: " ",
+};
+
+impl Dialect {
+	/**
+	Looks up a dialect by name: `bf` or `ook`.
+	*/
+	pub fn from_name(name: &str) -> Option<&'static Dialect> {
+		match name {
+			"bf" => Some(&BRAINFUCK),
+			"ook" => Some(&OOK),
+			_ => None,
+		}
+	}
+
+	/**
+	Parses a program written in this dialect. Anything which is not
+	a token is a comment, and comments are dropped, as is a shebang
+	line.
+	*/
+	pub fn parse(&self, source: &str) -> Result<Ast, ~str> {
+		let (_, source) = split_shebang(source);
+		Ast::parse_str(self.to_brainfuck(source))
+	}
+
+	/**
+	Spells a program in this dialect. Comments and checkpoints are
+	dropped, and optimized operators spelled out.
+
+	Do-while loops can not be spelled with the standard operators,
+	so `{B}` is spelled as `B[B]`, which does the same, and `=N` as
+	`[-]` followed by N `+`. Nothing does what `?` does, nor what `!`
+	does without a spare cell, so they are dropped like comments are.
+	When asked to, each loop is annotated with a comment line before
+	and after it, to make the output easier to follow.
+	*/
+	pub fn render(&self, ast: &Ast, annotate_loops: bool) -> ~str {
+		let mut out = StrBuf::new();
+		self.render_ops(ast, annotate_loops, &mut out);
+		out.into_owned()
+	}
+
+	/**
+	Translates a program written in this dialect into Brainfuck,
+	dropping anything which is not a token.
+	*/
+	fn to_brainfuck(&self, source: &str) -> ~str {
+		let tokens: ~[~[~str]] = self.tokens.iter().map(|token| self.units(*token)).collect();
+		let units = self.units(source);
+		let mut out = StrBuf::new();
+		let mut i = 0;
+		while i < units.len() {
+			let rest = units.slice_from(i);
+			match tokens.iter().position(|token| rest.starts_with(token.as_slice())) {
+				Some(op) => {
+					out.push_char(OPERATORS.char_at(op));
+					i += tokens[op].len();
+				}
+				None => i += 1,
+			}
+		}
+		out.into_owned()
+	}
+
+	/**
+	Splits a source into what tokens are made of: words for dialects
+	with a separator, characters for the others.
+	*/
+	fn units(&self, source: &str) -> ~[~str] {
+		if self.separator.is_empty() {
+			source.chars().map(|c| c.to_str()).collect()
+		} else {
+			source.words().map(|word| word.to_owned()).collect()
+		}
+	}
+
+	/// Spells a sequence of operators, see `render`.
+	fn render_ops(&self, ast: &Ast, annotate_loops: bool, out: &mut StrBuf) {
+		let &Ast(ref ops) = ast;
+		for op in ops.iter() {
+			match *op {
+				Nop(_) | Shebang(_) | Random | Negate | HostCall | Checkpoint(_) => {}
+				Sub(ref body) => {
+					if annotate_loops { out.push_str("\n(loop)\n") }
+					self.push_token(out, '[');
+					self.render_ops(body, annotate_loops, out);
+					self.push_token(out, ']');
+					if annotate_loops { out.push_str("\n(end loop)\n") }
+				}
+				DoSub(ref body) => {
+					self.render_ops(body, annotate_loops, out);
+					self.render_ops(&Ast(~[Sub(body.clone())]), annotate_loops, out);
+				}
+				SetCell(value) => {
+					let spelled = Ast(~[Sub(Ast(~[Decr])), IncrN(value as uint)]);
+					self.render_ops(&spelled, annotate_loops, out);
+				}
+				ref other => {
+					for c in format!("{}", other).chars() {
+						self.push_token(out, c);
+					}
+				}
+			}
+		}
+	}
+
+	/// Spells an operator, separating it from the previous token.
+	fn push_token(&self, out: &mut StrBuf, op: char) {
+		let index = OPERATORS.find(op).expect("Not an operator.");
+		let at_line_start = out.len() == 0 || out.as_slice().ends_with("\n");
+		if !at_line_start { out.push_str(self.separator) }
+		out.push_str(self.tokens[index]);
+	}
+}
+
+/**
+Translates a program between two dialects, named as by
+`Dialect::from_name`. See `Dialect::render` for how the result is
+spelled.
+*/
+pub fn translate(source: &str, from: &str, to: &str, annotate_loops: bool) -> Result<~str, ~str> {
+	let lookup = |name: &str| match Dialect::from_name(name) {
+		Some(dialect) => Ok(dialect),
+		None => Err(format!("Unknown dialect `{}`, expected `bf` or `ook`.", name)),
+	};
+	let (from, to) = (try!(lookup(from)), try!(lookup(to)));
+	let ast = try!(from.parse(source));
+	Ok(to.render(&ast, annotate_loops))
+}
+
+#[test]
+/// Ook! tokens should be emitted in pairs, separated by spaces.
+fn test_dialect_ook_tokens() {
+	let ook = translate("+[-]. comment", "bf", "ook", false).unwrap();
+	assert!(ook == ~"Ook. Ook. Ook! Ook? Ook! Ook! Ook? Ook! Ook! Ook.");
+	let annotated = translate("+[-].", "bf", "bf", true).unwrap();
+	assert!(annotated == ~"+\n(loop)\n[-]\n(end loop)\n.");
+}
+
+#[test]
+/// Going to Ook! and back should keep every operator, but no comment.
+fn test_dialect_round_trip() {
+	for &(name, source, _) in ::corpus::PROGRAMS.iter() {
+		let ook = translate(source, "bf", "ook", true).unwrap();
+		let back = translate(ook, "ook", "bf", false).unwrap();
+		// Parsing as a dialect drops comments, on both sides.
+		if BRAINFUCK.parse(back).unwrap() != BRAINFUCK.parse(source).unwrap() {
+			fail!("{} changed going through Ook!", name);
+		}
+	}
+}
+
+#[test]
+/// Unknown dialects should be named in the error.
+fn test_dialect_unknown() {
+	assert!(translate("+", "bf", "klingon", false) == Err(~"Unknown dialect `klingon`, expected `bf` or `ook`."));
+	assert!(translate("+", "ook!", "bf", false).is_err());
+}
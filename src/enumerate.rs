@@ -0,0 +1,236 @@
+/*!
+Enumerating every small program, e.g. to search for the shortest one
+doing something, see `superopt::search`.
+
+Programs are enumerated as sources, shortest first, then in the
+order of their tokens, the order of the alphabet they are made of.
+*/
+
+
+/**
+A kind of operator programs can be made of, see `programs`. Loops
+bring both of their brackets.
+*/
+#[deriving(Clone, Eq, Show)]
+pub enum OpKind {
+	KindIncr,
+	KindDecr,
+	KindPrev,
+	KindNext,
+	KindPut,
+	KindGet,
+	KindLoop,
+}
+
+/// Every kind of operator, in their usual order.
+pub static ALL_KINDS: [OpKind, ..7] = [KindIncr, KindDecr, KindPrev, KindNext, KindPut, KindGet, KindLoop];
+
+/// The tokens of an alphabet, in order, each one once.
+fn tokens(alphabet: &[OpKind]) -> ~[char] {
+	let mut tokens = Vec::new();
+	for kind in alphabet.iter() {
+		let chars: &[char] = match *kind {
+			KindIncr => ['+'],
+			KindDecr => ['-'],
+			KindPrev => ['<'],
+			KindNext => ['>'],
+			KindPut => ['.'],
+			KindGet => [','],
+			KindLoop => ['[', ']'],
+		};
+		for &c in chars.iter() {
+			if !tokens.contains(&c) { tokens.push(c) }
+		}
+	}
+	tokens.move_iter().collect()
+}
+
+/**
+Every program of at most `max_len` tokens made of the given kinds of
+operators, with balanced brackets, the empty one first, see
+`ProgramIter`.
+
+When pruning, programs with a loop which can never run are left out:
+those starting with a loop, where the cell is still zero, or with a
+loop right after another, which leaves the cell zero. Each of them
+does what a shorter program does.
+*/
+pub fn programs(max_len: uint, alphabet: &[OpKind], prune_trivial: bool) -> ProgramIter {
+	ProgramIter {
+		tokens: tokens(alphabet),
+		max_len: max_len,
+		prune_trivial: prune_trivial,
+		digits: Vec::new(),
+		done: false,
+	}
+}
+
+/**
+How many programs `programs` would yield, without making any of
+them. Without pruning, and with `k` kinds of operators besides loops,
+there are `k * P(n-1) + sum P(i) * P(n-2-i)` programs `P(n)` of each
+length `n`, which for loops alone are the Catalan numbers.
+*/
+pub fn count(max_len: uint, alphabet: &[OpKind], prune_trivial: bool) -> u64 {
+	let tokens = tokens(alphabet);
+	let loops = tokens.contains(&'[');
+	let plain = tokens.iter().filter(|&&c| c != '[' && c != ']').len() as u64;
+	// The ways to write programs of the length so far, by depth, split
+	// by whether their last token closes a loop.
+	let mut open = Vec::from_elem(max_len + 2, 0u64);
+	let mut closed = Vec::from_elem(max_len + 2, 0u64);
+	*open.get_mut(0) = 1;
+	let mut total = 1;
+	for len in range(1, max_len + 1) {
+		let mut next_open = Vec::from_elem(max_len + 2, 0u64);
+		let mut next_closed = Vec::from_elem(max_len + 2, 0u64);
+		for depth in range(0, len) {
+			let ways = *open.get(depth) + *closed.get(depth);
+			*next_open.get_mut(depth) += ways * plain;
+			if !loops { continue }
+			*next_open.get_mut(depth + 1) += match prune_trivial {
+				false => ways,
+				true if len == 1 => 0,
+				true => *open.get(depth),
+			};
+			if depth > 0 {
+				*next_closed.get_mut(depth - 1) += ways;
+			}
+		}
+		open = next_open;
+		closed = next_closed;
+		total += *open.get(0) + *closed.get(0);
+	}
+	total
+}
+
+/**
+An iterator over the sources of every program of a given alphabet,
+up to a given length, shortest first. Programs of the same length
+come in the order of their tokens: with `+` before `[` before `]`,
+`+[]` comes before `[+]`.
+
+Tokens are counted through like digits, skipping at once every
+program starting with a prefix which can not be completed.
+*/
+pub struct ProgramIter {
+	tokens: ~[char],
+	max_len: uint,
+	prune_trivial: bool,
+	/// The tokens of the next program, as indices.
+	digits: Vec<uint>,
+	/// Whether every program was yielded.
+	done: bool,
+}
+
+impl ProgramIter {
+	/**
+	Where the current tokens stop making a program, however they go
+	on: a `]` closing nothing, more loops open than there are tokens
+	left to close them, or a loop pruned as never running.
+	*/
+	fn first_bad(&self) -> Option<uint> {
+		let len = self.digits.len();
+		let mut depth = 0u;
+		let mut after_close = false;
+		for (i, &digit) in self.digits.iter().enumerate() {
+			match self.tokens[digit] {
+				'[' if self.prune_trivial && (i == 0 || after_close) => return Some(i),
+				'[' => depth += 1,
+				']' if depth == 0 => return Some(i),
+				']' => depth -= 1,
+				_ => {}
+			}
+			if depth > len - i - 1 {
+				return Some(i);
+			}
+			after_close = self.tokens[digit] == ']';
+		}
+		None
+	}
+
+	/**
+	Moves on to the next program, going on to longer ones once every
+	program of this length was seen.
+	*/
+	fn advance(&mut self) {
+		let radix = self.tokens.len();
+		if radix == 0 {
+			self.done = true;
+			return;
+		}
+		// Counts up from the last token, or from the first bad one.
+		let mut at = self.digits.len();
+		loop {
+			for digit in self.digits.mut_slice_from(at).mut_iter() {
+				*digit = 0;
+			}
+			let (mut i, mut carried) = (at, true);
+			while carried && i > 0 {
+				i -= 1;
+				let digit = self.digits.get_mut(i);
+				*digit = (*digit + 1) % radix;
+				carried = *digit == 0;
+			}
+			if carried {
+				let len = self.digits.len() + 1;
+				if len > self.max_len {
+					self.done = true;
+					return;
+				}
+				self.digits = Vec::from_elem(len, 0u);
+			}
+			match self.first_bad() {
+				Some(i) => at = i + 1,
+				None => return,
+			}
+		}
+	}
+}
+
+impl Iterator<~str> for ProgramIter {
+	fn next(&mut self) -> Option<~str> {
+		if self.done {
+			return None;
+		}
+		let source = self.digits.iter().map(|&digit| self.tokens[digit]).collect();
+		self.advance();
+		Some(source)
+	}
+}
+
+#[cfg(test)]
+use ast::Ast;
+
+#[test]
+/// Programs should be counted as they are enumerated, all of them parsing.
+fn test_enumerate_count() {
+	// 1 + 6 + (36 + 1) + (216 + 6 + 6 + 6), then 6 * 234 + 37 + 36 + 37.
+	assert!(count(3, ALL_KINDS, false) == 278);
+	assert!(count(4, ALL_KINDS, false) == 1792);
+	// `[]` goes, then `[]x` and `[x]`, then `[]xy`, `[x]y`, `[xy]`, `[][]` and `[[]]`.
+	assert!(count(3, ALL_KINDS, true) == 265);
+	assert!(count(4, ALL_KINDS, true) == 1669);
+	for &prune in [false, true].iter() {
+		let sources: ~[~str] = programs(4, ALL_KINDS, prune).collect();
+		assert!(sources.len() as u64 == count(4, ALL_KINDS, prune));
+		assert!(sources.iter().all(|source| Ast::parse_str(source.as_slice()).is_ok()));
+	}
+	assert!(count(6, [KindLoop], false) == 1 + 1 + 2 + 5);
+	assert!(programs(6, [KindLoop], false).len() == 9);
+}
+
+#[test]
+/// Programs should come shortest first, then in the order of the alphabet.
+fn test_enumerate_order() {
+	let sources: ~[~str] = programs(3, [KindIncr, KindLoop], false).collect();
+	assert!(sources == ~[~"", ~"+", ~"++", ~"[]", ~"+++", ~"+[]", ~"[+]", ~"[]+"]);
+	let sources: ~[~str] = programs(3, [KindIncr, KindLoop], true).collect();
+	assert!(sources == ~[~"", ~"+", ~"++", ~"+++", ~"+[]"]);
+	let pruned: ~[~str] = programs(4, ALL_KINDS, true).collect();
+	for source in programs(4, ALL_KINDS, false) {
+		let trivial = source.starts_with("[") || source.contains("][");
+		assert!(pruned.contains(&source) != trivial);
+	}
+	assert!(programs(3, [], false).collect::<~[~str]>() == ~[~""]);
+}
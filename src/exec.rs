@@ -0,0 +1,156 @@
+/*!
+The ways of running a program, behind a common interface, so that
+they can be checked against each other, see `conformance`.
+
+Every backend runs on a fresh machine with the given semantics,
+reading from memory and capturing its output.
+*/
+
+use std::io::MemReader;
+use std::io::util::NullWriter;
+use ast::Ast;
+use arena::ProgramArena;
+use features::ExtensionSet;
+use semantics::Semantics;
+use machine::{Machine, Limits, RuntimeError, StreamOptions, Halted, Paused, Blocked, ConditionHit};
+
+
+/**
+How a run went, as far as anyone can tell from outside.
+*/
+#[deriving(Clone, Eq, Show)]
+pub struct RunOutcome {
+	/// Everything the program wrote to its output.
+	pub output: ~[u8],
+	/// Whether the program ended, or why it failed.
+	pub result: Result<(), RuntimeError>,
+	/// The cycles executed, up to the failure if any.
+	pub cycles: uint,
+}
+
+/**
+A way of running programs.
+*/
+pub trait Backend {
+	/// A short name for the backend, for reports.
+	fn name(&self) -> &'static str;
+
+	/**
+	Whether the backend counts cycles like `Machine::run_program`
+	does, so that its cycles can be compared with the others'.
+	*/
+	fn shares_cost_model(&self) -> bool {
+		true
+	}
+
+	/// Runs a program on the given input, within the given limits and semantics.
+	fn run(&mut self, program: &Ast, input: &[u8], limits: Limits, semantics: Semantics) -> RunOutcome;
+}
+
+/// Runs programs with `Machine::run_program`, going through bytecode.
+pub struct BytecodeBackend;
+
+/// Runs programs one cycle at a time, with `Machine::resume_for`.
+pub struct StepperBackend;
+
+/// Runs programs laid out flat, with `Machine::run_arena`.
+pub struct ArenaBackend;
+
+/// Runs programs with `Machine::run_streaming`, in chunks of a few bytes.
+pub struct StreamingBackend;
+
+/**
+Every backend there is, the reference one first, which is the
+bytecode machine.
+*/
+pub fn backends() -> ~[~Backend] {
+	~[
+		~BytecodeBackend as ~Backend,
+		~StepperBackend as ~Backend,
+		~ArenaBackend as ~Backend,
+		~StreamingBackend as ~Backend,
+	]
+}
+
+/**
+A fresh capturing machine, reading from `input`, within `limits`
+and with `semantics`. Every extension is enabled, as backends compare runs, not what
+programs may use.
+*/
+fn machine<'a>(input: &[u8], limits: Limits, semantics: Semantics) -> Machine<'a> {
+	let mut machine = Machine::capturing(input);
+	machine.set_limits(limits);
+	machine.set_semantics(semantics);
+	machine.set_extensions(ExtensionSet::all());
+	machine
+}
+
+/// The outcome of a run made by a capturing machine.
+fn outcome<T>(machine: &Machine, result: Result<T, RuntimeError>) -> RunOutcome {
+	RunOutcome {
+		output: machine.captured_output().to_owned(),
+		result: result.map(|_| ()),
+		cycles: machine.cycles(),
+	}
+}
+
+impl Backend for BytecodeBackend {
+	fn name(&self) -> &'static str { "bytecode" }
+
+	fn run(&mut self, program: &Ast, input: &[u8], limits: Limits, semantics: Semantics) -> RunOutcome {
+		let mut machine = machine(input, limits, semantics);
+		let result = machine.run_program(program);
+		outcome(&machine, result)
+	}
+}
+
+impl Backend for StepperBackend {
+	fn name(&self) -> &'static str { "stepper" }
+
+	fn run(&mut self, program: &Ast, input: &[u8], limits: Limits, semantics: Semantics) -> RunOutcome {
+		let mut machine = machine(input, limits, semantics);
+		let result = StepperBackend::step_through(&mut machine, program);
+		outcome(&machine, result)
+	}
+}
+
+impl StepperBackend {
+	/// Runs a program to its end, a cycle at a time.
+	fn step_through(machine: &mut Machine, program: &Ast) -> Result<(), RuntimeError> {
+		let mut execution = machine.start(program);
+		loop {
+			match try!(machine.resume_for(&mut execution, 1)) {
+				Halted => return Ok(()),
+				Paused | Blocked | ConditionHit(..) => continue,
+			}
+		}
+	}
+}
+
+impl Backend for ArenaBackend {
+	fn name(&self) -> &'static str { "arena" }
+
+	fn run(&mut self, program: &Ast, input: &[u8], limits: Limits, semantics: Semantics) -> RunOutcome {
+		let mut machine = machine(input, limits, semantics);
+		let result = machine.run_arena(&ProgramArena::from_ast(program));
+		outcome(&machine, result)
+	}
+}
+
+impl Backend for StreamingBackend {
+	fn name(&self) -> &'static str { "streaming" }
+
+	fn run(&mut self, program: &Ast, input: &[u8], limits: Limits, semantics: Semantics) -> RunOutcome {
+		let mut machine = Machine::with_io(~MemReader::new(input.to_owned()), ~NullWriter);
+		machine.set_limits(limits);
+		machine.set_semantics(semantics);
+		let mut output = Vec::new();
+		let result = machine.run_streaming(program, |chunk| { output.push_all(chunk); true },
+			StreamOptions { chunk_size: 7 });
+		RunOutcome {
+			output: output.move_iter().collect(),
+			result: result.map(|_| ()),
+			cycles: machine.cycles(),
+		}
+	}
+}
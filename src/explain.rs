@@ -0,0 +1,286 @@
+/*!
+Narration of runs, telling in plain words what each instruction did,
+for following a program as it runs.
+*/
+
+use ast::Span;
+use bytecode::{OpMove, OpAdd, OpJnz, OpJz, OpAddAt, OpSetAt, OpOffsetWrite,
+               OpPut, OpGet, OpRandom, OpNegate, OpCheckpoint, OpComment, OpNop, OpHostCall};
+use machine::Step;
+
+
+/**
+What an instruction did, as far as narration goes.
+*/
+#[deriving(Clone, Eq, Show)]
+pub enum Action {
+	/// A cell was added to, by a delta: the cell, before, after and delta.
+	CellChanged(int, u8, u8, int),
+	/// The head moved, from a cell to another.
+	HeadMoved(int, int),
+	/// A loop was entered, the cell holding the given value.
+	LoopEntered(int, u8),
+	/// A loop was skipped, the cell being zero: the ops of its body.
+	LoopSkipped(int, uint),
+	/// A loop goes round again, the cell holding the given value.
+	LoopRepeated(int, u8),
+	/// A loop ended, the cell being zero.
+	LoopExited(int),
+	/// A byte was read into a cell.
+	ByteRead(int, u8),
+	/// Input was over, leaving the cell with the given value.
+	InputEnded(int, u8),
+	/// A byte was written out from a cell.
+	ByteWritten(int, u8),
+	/// A cell was set to a pseudo-random value.
+	RandomSet(int, u8),
+	/// A cell was negated: the cell, before and after.
+	CellNegated(int, u8, u8),
+	/// The host was called back on a cell: the cell, before and after.
+	HostCalled(int, u8, u8),
+	/// Cells at offsets from the head, which stays on the given cell,
+	/// were changed.
+	CellsChanged(int),
+}
+
+/**
+Something which happened during a run, and where in the source.
+*/
+#[deriving(Clone, Eq, Show)]
+pub struct Event {
+	/// The cycles spent so far, this instruction included.
+	pub cycle: uint,
+	/// The source of the instruction, e.g. `+` or `[`.
+	pub source: ~str,
+	/// The line of the source the instruction starts on, from 1.
+	pub line: uint,
+	/// The column of the source the instruction starts at, from 1.
+	pub col: uint,
+	/// What the instruction did.
+	pub action: Action,
+}
+
+/**
+Tells in a sentence what happened, e.g.
+`cycle 12: '+' at line 3 col 7 — cell #4 goes 8 -> 9`.
+*/
+pub fn describe(event: &Event) -> ~str {
+	let what = match event.action {
+		CellChanged(cell, before, after, delta) => {
+			if (delta > 0 && after < before) || (delta < 0 && after > before) {
+				format!("cell \\#{} wraps to {}", cell, after)
+			} else {
+				format!("cell \\#{} goes {} -> {}", cell, before, after)
+			}
+		}
+		HeadMoved(_, to) => format!("head moves to cell \\#{}", to),
+		LoopEntered(cell, value) => format!("cell \\#{} is {}, entering loop", cell, value),
+		LoopSkipped(cell, ops) => format!("cell \\#{} is 0, skipping loop body ({} {})",
+			cell, ops, if ops == 1 { "op" } else { "ops" }),
+		LoopRepeated(cell, value) => format!("cell \\#{} is {}, repeating loop", cell, value),
+		LoopExited(cell) => format!("cell \\#{} is 0, leaving loop", cell),
+		ByteRead(cell, byte) => format!("reads {} into cell \\#{}", show_byte(byte), cell),
+		InputEnded(cell, value) => format!("end of input, cell \\#{} is now {}", cell, value),
+		ByteWritten(cell, byte) => format!("writes {} from cell \\#{}", show_byte(byte), cell),
+		RandomSet(cell, value) => format!("cell \\#{} is set to {} at random", cell, value),
+		CellNegated(cell, before, after) => format!("cell \\#{} is negated, {} -> {}", cell, before, after),
+		HostCalled(cell, before, after) => format!("the host is called on cell \\#{}, {} -> {}", cell, before, after),
+		CellsChanged(cell) => format!("changes cells around cell \\#{}", cell),
+	};
+	format!("cycle {}: '{}' at line {} col {} — {}", event.cycle, event.source, event.line, event.col, what)
+}
+
+/// Shows a byte, along with its character when printable.
+fn show_byte(byte: u8) -> ~str {
+	if byte >= 32 && byte < 127 {
+		format!("'{}' ({})", byte as char, byte)
+	} else {
+		byte.to_str()
+	}
+}
+
+/**
+Narrates a run, step by step, see `Machine::on_step`, telling each
+line as soon as its step is made, so that runs can be followed as
+they go, and nothing is kept but the count.
+
+Comments and checkpoints are not narrated. Past a given number of
+lines, a notice that the narration was truncated is told, and the
+rest of the run goes untold.
+*/
+pub struct ExplainTracer {
+	/// The source, line and column of each instruction.
+	places: ~[(~str, uint, uint)],
+	/// How many lines to narrate at most.
+	limit: uint,
+	/// How many lines were told so far.
+	told: uint,
+	/// Whether the notice of truncation was told.
+	truncated: bool,
+}
+
+impl ExplainTracer {
+	/**
+	Makes a tracer for a program, given its source and the span of
+	each of its instructions, see `SourceMap::instruction_spans`.
+	*/
+	pub fn new(source: &str, spans: &[Span], limit: uint) -> ExplainTracer {
+		let chars: ~[char] = source.chars().collect();
+		// Where each character of the source is, by line and column.
+		let mut positions = Vec::with_capacity(chars.len() + 1);
+		let (mut line, mut col) = (1u, 1u);
+		for &c in chars.iter() {
+			positions.push((line, col));
+			if c == '\n' { line += 1; col = 1 } else { col += 1 }
+		}
+		positions.push((line, col));
+
+		let places = spans.iter().map(|span| {
+			let text: ~str = chars.slice(span.start, span.end).iter().map(|&c| c).collect();
+			let &(line, col) = positions.get(span.start);
+			(text, line, col)
+		}).collect();
+		ExplainTracer { places: places, limit: limit, told: 0, truncated: false }
+	}
+
+	/**
+	Narrates a step, telling its line right away, if there is anything
+	to tell about it and the limit was not reached.
+	*/
+	pub fn step(&mut self, step: &Step, tell: |&str|) {
+		let action = match ExplainTracer::action(step) {
+			Some(action) => action,
+			None => return,
+		};
+		if self.told >= self.limit {
+			if !self.truncated {
+				tell("…narration truncated…");
+				self.truncated = true;
+			}
+			return;
+		}
+		let (ref source, line, col) = self.places[step.pc];
+		let event = Event { cycle: step.cycle, source: source.clone(), line: line, col: col, action: action };
+		tell(describe(&event));
+		self.told += 1;
+	}
+
+	/// How many lines were told so far, the notice of truncation aside.
+	pub fn told(&self) -> uint {
+		self.told
+	}
+
+	/// What a step did, unless it did nothing worth telling.
+	fn action(step: &Step) -> Option<Action> {
+		let jumped = step.next != step.pc + 1;
+		Some(match step.instr.opcode {
+			OpAdd => CellChanged(step.to, step.before, step.after, step.instr.a),
+			OpMove => HeadMoved(step.from, step.to),
+			OpJz if jumped => LoopSkipped(step.to, step.instr.a as uint - step.pc - 2),
+			OpJz => LoopEntered(step.to, step.after),
+			OpJnz if jumped => LoopRepeated(step.to, step.after),
+			OpJnz => LoopExited(step.to),
+			OpGet if step.at_eof => InputEnded(step.to, step.after),
+			OpGet => ByteRead(step.to, step.after),
+			OpPut => ByteWritten(step.to, step.after),
+			OpRandom => RandomSet(step.to, step.after),
+			OpNegate => CellNegated(step.to, step.before, step.after),
+			OpHostCall => HostCalled(step.to, step.before, step.after),
+			OpAddAt | OpSetAt | OpOffsetWrite => CellsChanged(step.to),
+			OpCheckpoint | OpComment | OpNop => return None,
+		})
+	}
+}
+
+#[cfg(test)]
+use ast::Ast;
+#[cfg(test)]
+use sourcemap::SourceMap;
+#[cfg(test)]
+use machine::Machine;
+
+#[cfg(test)]
+/// Runs a program while narrating it, returning the narration, the
+/// cycles spent and the output.
+fn narrate(source: &str, input: &[u8], limit: uint) -> (~[~str], uint, ~[u8]) {
+	let ast = Ast::parse_str(source).unwrap();
+	let spans = SourceMap::of(&ast).instruction_spans(&ast);
+	let mut tracer = ExplainTracer::new(source, spans, limit);
+	let mut lines = ~[];
+	let (cycles, output) = {
+		let mut machine = Machine::capturing(input);
+		machine.on_step(|step| tracer.step(step, |line| lines.push(line.to_owned())));
+		let cycles = machine.run_program(&ast).unwrap();
+		(cycles, machine.captured_output().to_owned())
+	};
+	(lines, cycles, output)
+}
+
+#[test]
+/// Every kind of sentence, each telling where its instruction is.
+fn test_explain_describe() {
+	let event = |action| Event { cycle: 12, source: ~"+", line: 3, col: 7, action: action };
+	assert!(describe(&event(CellChanged(4, 8, 9, 1))) == ~"cycle 12: '+' at line 3 col 7 — cell #4 goes 8 -> 9");
+	assert!(describe(&event(CellChanged(0, 0, 255, -1))).ends_with("— cell #0 wraps to 255"));
+	assert!(describe(&event(LoopSkipped(2, 14))).ends_with("— cell #2 is 0, skipping loop body (14 ops)"));
+	assert!(describe(&event(ByteWritten(1, 10))).ends_with("— writes 10 from cell #1"));
+}
+
+#[test]
+/// A short program should be narrated exactly, comments aside.
+fn test_explain_narration() {
+	let (lines, _, _) = narrate("-[+]>+.\n,<[-]++[-],", [65], 200);
+	assert!(lines == ~[
+		~"cycle 1: '-' at line 1 col 1 — cell #0 wraps to 255",
+		~"cycle 2: '[' at line 1 col 2 — cell #0 is 255, entering loop",
+		~"cycle 3: '+' at line 1 col 3 — cell #0 wraps to 0",
+		~"cycle 4: ']' at line 1 col 4 — cell #0 is 0, leaving loop",
+		~"cycle 5: '>' at line 1 col 5 — head moves to cell #1",
+		~"cycle 6: '+' at line 1 col 6 — cell #1 goes 0 -> 1",
+		~"cycle 7: '.' at line 1 col 7 — writes 1 from cell #1",
+		~"cycle 9: ',' at line 2 col 1 — reads 'A' (65) into cell #1",
+		~"cycle 10: '<' at line 2 col 2 — head moves to cell #0",
+		~"cycle 11: '[' at line 2 col 3 — cell #0 is 0, skipping loop body (1 op)",
+		~"cycle 12: '+' at line 2 col 6 — cell #0 goes 0 -> 1",
+		~"cycle 13: '+' at line 2 col 7 — cell #0 goes 1 -> 2",
+		~"cycle 14: '[' at line 2 col 8 — cell #0 is 2, entering loop",
+		~"cycle 15: '-' at line 2 col 9 — cell #0 goes 2 -> 1",
+		~"cycle 16: ']' at line 2 col 10 — cell #0 is 1, repeating loop",
+		~"cycle 17: '-' at line 2 col 9 — cell #0 goes 1 -> 0",
+		~"cycle 18: ']' at line 2 col 10 — cell #0 is 0, leaving loop",
+		~"cycle 19: ',' at line 2 col 11 — end of input, cell #0 is now 0",
+	]);
+}
+
+#[test]
+/// Each line should be told as its step is made, not when the run ends.
+fn test_explain_streaming() {
+	let ast = Ast::parse_str("+.+.").unwrap();
+	let spans = SourceMap::of(&ast).instruction_spans(&ast);
+	let mut tracer = ExplainTracer::new("+.+.", spans, 200);
+	// Along with each line, how much output there was when it was told.
+	let mut told = ~[];
+	{
+		let written = ::std::cell::Cell::new(0u);
+		let mut machine = Machine::capturing([]);
+		machine.on_output(|_| written.set(written.get() + 1));
+		machine.on_step(|step| tracer.step(step, |line| told.push((line.to_owned(), written.get()))));
+		machine.run_program(&ast).unwrap();
+	}
+	assert!(told.iter().map(|&(_, written)| written).collect::<~[uint]>() == ~[0, 1, 1, 2]);
+	let (ref last, _) = told[3];
+	assert!(last.ends_with("— writes 2 from cell #0"));
+	assert!(tracer.told() == 4);
+}
+
+#[test]
+/// Past the limit, narration should stop, but not the run.
+fn test_explain_truncation() {
+	let source = "++++++++[>++++++++<-]>+.";
+	let (lines, cycles, output) = narrate(source, [], 3);
+	assert!(lines.len() == 4);
+	assert!(lines[3] == ~"…narration truncated…");
+	let (_, all_cycles, all_output) = narrate(source, [], 1000);
+	assert!(cycles == all_cycles && output == all_output);
+	assert!(output == ~[65]);
+}
@@ -0,0 +1,270 @@
+/*!
+What this build can do, and which extensions to the language a machine
+runs, for embedders to check before handing programs over.
+
+Features and extensions have stable names, along with versions bumped
+whenever what they do changes. Their string forms, e.g. `random/1
+checkpoints/1`, list them in a fixed order, so that they can be
+compared across builds, e.g. by scripts reading run reports.
+*/
+
+use std::fmt;
+use ast::Ast;
+use operators::{Operator, Sub, DoSub, DoSkip, DoLoop, Random, Checkpoint, HostCall, Negate, SetCell};
+
+
+/// The version of this build of the library.
+pub static VERSION: &'static str = "0.1.0";
+
+/**
+An extension to the language, which machines only run once enabled,
+see `Machine::set_extensions`.
+
+Every operator which only the parser, given `extensions`, or
+`Ast::from_bytes` makes needs enabling. Those which optimizing
+standard programs makes, e.g. `SetAt` and `OffsetWrite`, do not.
+*/
+#[deriving(Clone, Eq, Show)]
+pub enum Extension {
+	/// `{...}` do-while loops.
+	DoWhileLoops,
+	/// `?` setting the cell to a random value.
+	RandomValues,
+	/// `#name` checkpoints.
+	Checkpoints,
+	/// `@` calling the host back, see `Machine::on_host_call`.
+	HostCalls,
+	/// `!` negating the cell.
+	Negation,
+	/// `=N` setting the cell to a value.
+	CellValues,
+}
+
+/// Every extension, in the order of their string forms.
+pub static EXTENSIONS: [Extension, ..6] = [DoWhileLoops, RandomValues, Checkpoints, HostCalls, Negation, CellValues];
+
+impl Extension {
+	/// The stable name of the extension.
+	pub fn name(&self) -> &'static str {
+		match *self {
+			DoWhileLoops => "do-while",
+			RandomValues => "random",
+			Checkpoints => "checkpoints",
+			HostCalls => "host-call",
+			Negation => "negation",
+			CellValues => "cell-values",
+		}
+	}
+
+	/// The version of the extension, bumped whenever what it does changes.
+	pub fn version(&self) -> uint {
+		match *self {
+			DoWhileLoops | RandomValues | Checkpoints | HostCalls | Negation | CellValues => 1,
+		}
+	}
+
+	/// The extension an operator needs, if any.
+	pub fn required_by(op: &Operator) -> Option<Extension> {
+		match *op {
+			DoSub(_) | DoSkip | DoLoop => Some(DoWhileLoops),
+			Random => Some(RandomValues),
+			Checkpoint(_) => Some(Checkpoints),
+			HostCall => Some(HostCalls),
+			Negate => Some(Negation),
+			SetCell(_) => Some(CellValues),
+			_ => None,
+		}
+	}
+
+	/// The bit of the extension in a set.
+	fn bit(&self) -> uint {
+		1 << (*self as uint)
+	}
+}
+
+/**
+A set of extensions, e.g. those enabled on a machine.
+*/
+#[deriving(Clone, Eq)]
+pub struct ExtensionSet {
+	bits: uint,
+}
+
+impl ExtensionSet {
+	/// No extension at all, as with standard programs.
+	pub fn none() -> ExtensionSet {
+		ExtensionSet { bits: 0 }
+	}
+
+	/// Every extension, as parsed by `ParseOptions { extensions: true, .. }`.
+	pub fn all() -> ExtensionSet {
+		EXTENSIONS.iter().fold(ExtensionSet::none(), |set, &extension| set.with(extension))
+	}
+
+	/// The same set, along with another extension.
+	pub fn with(&self, extension: Extension) -> ExtensionSet {
+		ExtensionSet { bits: self.bits | extension.bit() }
+	}
+
+	/// Whether an extension is in the set.
+	pub fn contains(&self, extension: Extension) -> bool {
+		self.bits & extension.bit() != 0
+	}
+
+	/// The extensions in the set, in the order of its string form.
+	pub fn extensions(&self) -> Vec<Extension> {
+		EXTENSIONS.iter().map(|&extension| extension).filter(|&extension| self.contains(extension)).collect()
+	}
+
+	/**
+	Checks that a program only uses the extensions in the set, or
+	tells the first operator, in the order of the source, needing one
+	which is not.
+	*/
+	pub fn check(&self, program: &Ast) -> Result<(), ValidationError> {
+		let &Ast(ref ops) = program;
+		self.check_ops(ops.as_slice())
+	}
+
+	/**
+	Checks operators like `check` does a program, e.g. those of a
+	recorded trace.
+	*/
+	pub fn check_ops(&self, ops: &[Operator]) -> Result<(), ValidationError> {
+		for op in ops.iter() {
+			match Extension::required_by(op) {
+				Some(extension) if !self.contains(extension) => {
+					// Do-while loops are told by their opening bracket.
+					let operator = match *op { DoSub(_) => DoSkip, ref other => other.clone() };
+					return Err(ValidationError { operator: operator, extension: extension });
+				}
+				_ => {}
+			}
+			match *op {
+				Sub(ref body) | DoSub(ref body) => try!(self.check(body)),
+				_ => {}
+			}
+		}
+		Ok(())
+	}
+}
+
+impl fmt::Show for ExtensionSet {
+	/**
+	Writes the stable string form of the set, e.g. `do-while/1
+	random/1`, or `none` if empty.
+	*/
+	fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+		let extensions = self.extensions();
+		if extensions.is_empty() {
+			return write!(f.buf, "none");
+		}
+		for (i, extension) in extensions.iter().enumerate() {
+			if i > 0 { try!(write!(f.buf, " ")); }
+			try!(write!(f.buf, "{}/{}", extension.name(), extension.version()));
+		}
+		Ok(())
+	}
+}
+
+/**
+An operator which the machine meant to run a program does not have
+the extension of enabled, see `Machine::validate`.
+*/
+#[deriving(Clone, Eq)]
+pub struct ValidationError {
+	/// The operator, or the opening bracket of a do-while loop.
+	pub operator: Operator,
+	/// The extension it needs.
+	pub extension: Extension,
+}
+
+impl fmt::Show for ValidationError {
+	fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+		write!(f.buf, "`{}` needs the {} extension, which is not enabled.", self.operator, self.extension.name())
+	}
+}
+
+/**
+The capabilities compiled into this build, by name and version, see
+`features`.
+*/
+#[deriving(Clone, Eq)]
+pub struct FeatureSet {
+	/// The name and version of each feature, in a fixed order.
+	pub features: ~[(&'static str, uint)],
+}
+
+impl FeatureSet {
+	/// The version of a feature, if compiled in.
+	pub fn version(&self, name: &str) -> Option<uint> {
+		self.features.iter().find(|&&(feature, _)| feature == name).map(|&(_, version)| version)
+	}
+}
+
+impl fmt::Show for FeatureSet {
+	/// Writes the stable string form of the set, e.g. `do-while/1 random/1`.
+	fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+		for (i, &(name, version)) in self.features.iter().enumerate() {
+			if i > 0 { try!(write!(f.buf, " ")); }
+			try!(write!(f.buf, "{}/{}", name, version));
+		}
+		Ok(())
+	}
+}
+
+/**
+What this build can do: every extension to the language, then the
+other capabilities of the library, each with its version.
+*/
+pub fn features() -> FeatureSet {
+	let mut features: Vec<(&'static str, uint)> = EXTENSIONS.iter()
+		.map(|extension| (extension.name(), extension.version())).collect();
+	features.push_all([
+		("shebang", 1),
+		("dialects", 1),
+		("tapes", 1),
+		("ffi", 1),
+	]);
+	FeatureSet { features: features.move_iter().collect() }
+}
+
+#[cfg(test)]
+use ast::ParseOptions;
+#[cfg(test)]
+use machine::Machine;
+
+#[test]
+/// The string forms should stay the same from build to build.
+fn test_features_string() {
+	assert!(format!("{}", features()) == ~"do-while/1 random/1 checkpoints/1 host-call/1 negation/1 cell-values/1 shebang/1 dialects/1 tapes/1 ffi/1");
+	assert!(features().version("random") == Some(1) && features().version("multi-tape") == None);
+	assert!(format!("{}", ExtensionSet::none()) == ~"none");
+	assert!(format!("{}", ExtensionSet::none().with(Checkpoints).with(DoWhileLoops)) == ~"do-while/1 checkpoints/1");
+	assert!(format!("{}", ExtensionSet::all()) == ~"do-while/1 random/1 checkpoints/1 host-call/1 negation/1 cell-values/1");
+}
+
+#[test]
+/// Default machines should refuse every extension operator, until enabled.
+fn test_features_validation() {
+	let extended = ParseOptions { extensions: true, ..ParseOptions::strict() };
+	let cases = [("+{-}", DoSkip, DoWhileLoops), ("[-?]", Random, RandomValues), ("#here+", Checkpoint(~"here"), Checkpoints),
+		("+@", HostCall, HostCalls), ("+!", Negate, Negation), ("+=7", SetCell(7), CellValues)];
+	for &(source, ref operator, extension) in cases.iter() {
+		let (program, _) = Ast::parse_with(source, extended).unwrap();
+		let mut machine = Machine::capturing([]);
+		let expected = ValidationError { operator: operator.clone(), extension: extension };
+		assert!(machine.validate(&program) == Err(expected.clone()));
+		assert!(machine.run_program(&program) == Err(::machine::ExtensionDisabled(expected)));
+		assert!(machine.cycles() == 0);
+
+		machine.set_extensions(ExtensionSet::none().with(extension));
+		assert!(machine.validate(&program) == Ok(()));
+		assert!(machine.run_program(&program).is_ok());
+	}
+	// Optimized standard programs need no extension.
+	let program = Ast::parse_str("+[-]>>+++<<[->+<]").unwrap().optimize().optimize_offsets().fold_offset_writes();
+	assert!(Machine::capturing([]).validate(&program) == Ok(()));
+	assert!(format!("{}", ValidationError { operator: Random, extension: RandomValues }) ==
+		~"`?` needs the random extension, which is not enabled.");
+}
@@ -0,0 +1,439 @@
+/*!
+A C-compatible interface, for embedding the interpreter in programs
+written in other languages. It is compiled into the crate's dylib.
+
+The ABI, as a C header:
+
+```c
+typedef struct BfProgram BfProgram;
+
+// The fields are laid out as in C, in this order, which never
+// changes: any new field only ever goes last.
+typedef struct {
+    uint64_t max_cycles;  // 0 for unlimited
+    uint64_t max_output;  // 0 for unlimited
+    uint64_t max_memory;  // bytes of tape, 0 for unlimited
+} BfLimits;
+
+typedef enum {
+    BF_OK = 0,
+    BF_PARSE_ERROR = 1,
+    BF_RUNTIME_ERROR = 2,
+    BF_CYCLE_LIMIT = 3,
+    BF_OUTPUT_LIMIT = 4,
+    BF_BUFFER_TOO_SMALL = 5,
+    BF_INVALID_ARGUMENT = 6,
+    BF_INTERNAL_ERROR = 7,
+    BF_MEMORY_LIMIT = 8,
+} BfStatus;
+
+// Parses `len` bytes of UTF-8 source. Returns NULL on failure, and
+// stores the reason in `*err_out` unless it is NULL.
+BfProgram *bf_parse(const uint8_t *source, size_t len, BfStatus *err_out);
+
+// Runs a program on `input_len` bytes of input, writing its output
+// to `output`. The output length is stored in `*output_len`; when
+// larger than `output_cap`, nothing is written, BF_BUFFER_TOO_SMALL
+// is returned, and the run can be retried with a large enough buffer.
+// `limits` may be NULL for no limits.
+BfStatus bf_run(const BfProgram *program,
+                const uint8_t *input, size_t input_len,
+                uint8_t *output, size_t output_cap, size_t *output_len,
+                const BfLimits *limits);
+
+// Frees a program. Does nothing on NULL.
+void bf_program_free(BfProgram *program);
+
+// Describes the last error occurred on the calling thread, or
+// returns NULL. Valid until the next call on the same thread.
+const char *bf_last_error_message(void);
+```
+
+Every pointer is checked for NULL before use, and failures inside
+the interpreter are caught before they reach the caller, becoming
+`BF_INTERNAL_ERROR`.
+
+Any thread may call in, whether Rust started it or not, e.g. one of
+a Python program calling through ctypes. Catching failures takes a
+Rust task, which such threads have none of, so each call makes one
+for itself on them, running natively on the thread. For the same
+reason, last errors are kept by thread rather than in tasks.
+*/
+
+use std::cast;
+use std::ptr;
+use std::str;
+use std::task;
+use std::slice::raw::buf_as_slice;
+use std::libc::{size_t, c_char};
+use std::rt::local::Local;
+use std::rt::task::Task;
+use std::unstable::mutex::{StaticNativeMutex, NATIVE_MUTEX_INIT};
+use sync::Arc;
+use native;
+use ast::Ast;
+use machine::{Machine, Limits, LimitExceeded, CycleLimit, OutputLimit, MemoryLimitExceeded};
+
+
+/// A parsed program, opaque to C.
+pub struct BfProgram {
+	ast: Arc<Ast>,
+}
+
+/// Limits for a run. Zero means unlimited. Laid out as in the header.
+#[repr(C)]
+pub struct BfLimits {
+	pub max_cycles: u64,
+	pub max_output: u64,
+	pub max_memory: u64,
+}
+
+/// The outcome of a call.
+#[repr(C)]
+#[deriving(Eq, Show)]
+pub enum BfStatus {
+	BfOk = 0,
+	BfParseError = 1,
+	BfRuntimeError = 2,
+	BfCycleLimit = 3,
+	BfOutputLimit = 4,
+	BfBufferTooSmall = 5,
+	BfInvalidArgument = 6,
+	BfInternalError = 7,
+	BfMemoryLimit = 8,
+}
+
+// The last error message of each thread, NUL-terminated, along with
+// the thread it occurred on. Threads not started by Rust may run a
+// task for no longer than a call, so this can not be task-local.
+static mut LAST_ERRORS_LOCK: StaticNativeMutex = NATIVE_MUTEX_INIT;
+static mut LAST_ERRORS: *mut Vec<(uint, ~[u8])> = 0 as *mut Vec<(uint, ~[u8])>;
+
+/// How much stack the tasks made for calls assume their thread has.
+static STACK_ESTIMATE: uint = 1 << 20;
+
+#[cfg(unix)]
+extern {
+	fn pthread_self() -> uint;
+}
+
+#[cfg(windows)]
+extern "system" {
+	fn GetCurrentThreadId() -> u32;
+}
+
+/// Identifies the calling thread, whether it runs a task or not.
+#[cfg(unix)]
+fn thread_id() -> uint {
+	unsafe { pthread_self() }
+}
+
+/// Identifies the calling thread, whether it runs a task or not.
+#[cfg(windows)]
+fn thread_id() -> uint {
+	unsafe { GetCurrentThreadId() as uint }
+}
+
+/// Runs `f` on the last error messages of every thread, holding their lock.
+fn with_last_errors<T>(f: |&mut Vec<(uint, ~[u8])>| -> T) -> T {
+	unsafe {
+		let _guard = LAST_ERRORS_LOCK.lock();
+		if LAST_ERRORS.is_null() {
+			LAST_ERRORS = cast::transmute(~Vec::<(uint, ~[u8])>::new());
+		}
+		f(&mut *LAST_ERRORS)
+	}
+}
+
+/**
+Records the last error message of the calling thread. The one it
+replaces is freed, which only this thread could still be reading.
+*/
+fn set_last_error(message: &str) {
+	let mut bytes = message.as_bytes().to_owned();
+	bytes.push(0);
+	let (thread, mut bytes) = (thread_id(), Some(bytes));
+	with_last_errors(|errors| {
+		match errors.iter().position(|&(id, _)| id == thread) {
+			Some(i) => match *errors.get_mut(i) { (_, ref mut last) => *last = bytes.take_unwrap() },
+			None => errors.push((thread, bytes.take_unwrap())),
+		}
+	});
+}
+
+/**
+Runs `f` like `task::try` does, catching its failure. On threads
+running no task, e.g. those of C programs, that would abort the
+process instead, so it is run from a native task made for the call.
+*/
+fn try_in_task<T: Send>(f: proc() -> T) -> Result<T, ()> {
+	if Local::exists(None::<Task>) {
+		return task::try(f).map_err(|_| ());
+	}
+	let mut f = Some(f);
+	let mut result = Err(());
+	// Like `native::start`, guess where the stack of the thread ends.
+	let top = &result as *Result<T, ()> as uint;
+	let call = native::task::new((top - STACK_ESTIMATE, top));
+	drop(call.run(|| { result = task::try(f.take_unwrap()).map_err(|_| ()); }));
+	result
+}
+
+/// Stores a status through a pointer, unless it is NULL.
+unsafe fn report(out: *mut BfStatus, status: BfStatus) {
+	if out.is_not_null() { *out = status; }
+}
+
+
+/**
+Parses a program from `len` bytes of UTF-8 source.
+*/
+#[no_mangle]
+pub extern "C" fn bf_parse(source: *u8, len: size_t, err_out: *mut BfStatus) -> *mut BfProgram {
+	unsafe {
+		if source.is_null() {
+			set_last_error("Source is NULL.");
+			report(err_out, BfInvalidArgument);
+			return ptr::mut_null();
+		}
+		let source = buf_as_slice(source, len as uint, |bytes| {
+			str::from_utf8(bytes).map(|s| s.to_owned())
+		});
+		let source = match source {
+			Some(source) => source,
+			None => {
+				set_last_error("Source is not valid UTF-8.");
+				report(err_out, BfInvalidArgument);
+				return ptr::mut_null();
+			}
+		};
+
+		match try_in_task(proc() { Ast::parse_str(source) }) {
+			Ok(Ok(ast)) => {
+				report(err_out, BfOk);
+				cast::transmute(~BfProgram { ast: Arc::new(ast) })
+			}
+			Ok(Err(message)) => {
+				set_last_error(message);
+				report(err_out, BfParseError);
+				ptr::mut_null()
+			}
+			Err(_) => {
+				set_last_error("The parser failed unexpectedly.");
+				report(err_out, BfInternalError);
+				ptr::mut_null()
+			}
+		}
+	}
+}
+
+/**
+Runs a program on the given input, writing its output to a buffer.
+*/
+#[no_mangle]
+pub extern "C" fn bf_run(program: *BfProgram,
+                         input: *u8, input_len: size_t,
+                         output: *mut u8, output_cap: size_t, output_len: *mut size_t,
+                         limits: *BfLimits) -> BfStatus {
+	unsafe {
+		if program.is_null() || output_len.is_null()
+		|| (input.is_null() && input_len > 0)
+		|| (output.is_null() && output_cap > 0) {
+			set_last_error("Invalid NULL argument.");
+			return BfInvalidArgument;
+		}
+
+		// Copy everything the run needs, as it happens in another task.
+		let ast = (*program).ast.clone();
+		let input = if input_len > 0 {
+			buf_as_slice(input, input_len as uint, |bytes| bytes.to_owned())
+		} else {
+			~[]
+		};
+		let to_limit = |max: u64| if max == 0 { None } else { Some(max as uint) };
+		let (limits, memory) = if limits.is_null() {
+			(Limits::none(), None)
+		} else {
+			let limits = &*limits;
+			(Limits { cycles: to_limit(limits.max_cycles), output: to_limit(limits.max_output), time: None }, to_limit(limits.max_memory))
+		};
+
+		let outcome = try_in_task(proc() {
+			let mut machine = Machine::capturing(input);
+			machine.set_limits(limits);
+			machine.set_memory_limit(memory);
+			let result = machine.run_program(&*ast);
+			(result, machine.take_captured_output())
+		});
+		let (result, bytes) = match outcome {
+			Ok(outcome) => outcome,
+			Err(_) => {
+				set_last_error("The machine failed unexpectedly.");
+				return BfInternalError;
+			}
+		};
+
+		// Hand over the output, or tell how much room it needs.
+		*output_len = bytes.len() as size_t;
+		if bytes.len() > output_cap as uint {
+			set_last_error(format!("Output needs {} bytes.", bytes.len()));
+			return BfBufferTooSmall;
+		}
+		ptr::copy_nonoverlapping_memory(output, bytes.as_ptr(), bytes.len());
+
+		match result {
+			Ok(_) => BfOk,
+			Err(err) => {
+				set_last_error(format!("{}", err));
+				match err {
+					LimitExceeded(CycleLimit) => BfCycleLimit,
+					LimitExceeded(OutputLimit) => BfOutputLimit,
+					MemoryLimitExceeded(..) => BfMemoryLimit,
+					_ => BfRuntimeError,
+				}
+			}
+		}
+	}
+}
+
+/**
+Frees a program made by `bf_parse`.
+*/
+#[no_mangle]
+pub extern "C" fn bf_program_free(program: *mut BfProgram) {
+	if program.is_not_null() {
+		let _: ~BfProgram = unsafe { cast::transmute(program) };
+	}
+}
+
+/**
+Describes the last error occurred on the calling thread.
+*/
+#[no_mangle]
+pub extern "C" fn bf_last_error_message() -> *c_char {
+	let thread = thread_id();
+	with_last_errors(|errors| {
+		match errors.iter().find(|&&(id, _)| id == thread) {
+			Some(&(_, ref message)) => message.as_ptr() as *c_char,
+			None => ptr::null(),
+		}
+	})
+}
+
+#[cfg(test)]
+use std::c_str::CString;
+#[cfg(test)]
+use std::rt::thread::Thread;
+
+#[cfg(test)]
+/// Reads the last error message back as a Rust string.
+fn last_error_message() -> ~str {
+	unsafe { CString::new(bf_last_error_message(), false).as_str().unwrap().to_owned() }
+}
+
+#[cfg(test)]
+/// Parses a program, expecting it to succeed.
+fn parse(source: &str) -> *mut BfProgram {
+	let mut status = BfInternalError;
+	let program = bf_parse(source.as_ptr(), source.len() as size_t, &mut status);
+	assert!(status == BfOk);
+	assert!(program.is_not_null());
+	program
+}
+
+#[test]
+/// Parse errors should return NULL, and explain themselves.
+fn test_ffi_parse_error() {
+	let source = "+[";
+	let mut status = BfOk;
+	let program = bf_parse(source.as_ptr(), source.len() as size_t, &mut status);
+	assert!(program.is_null());
+	assert!(status == BfParseError);
+	assert!(last_error_message() == ~"Unmatched `[`.");
+
+	let program = bf_parse(ptr::null(), 0, &mut status);
+	assert!(program.is_null());
+	assert!(status == BfInvalidArgument);
+}
+
+#[test]
+/// Runs over the cycle limit should report so.
+fn test_ffi_cycle_limit() {
+	let program = parse("+[]");
+	let limits = BfLimits { max_cycles: 100, max_output: 0, max_memory: 0 };
+	let mut len = 0;
+	let status = bf_run(program as *BfProgram, ptr::null(), 0, ptr::mut_null(), 0, &mut len, &limits);
+	assert!(status == BfCycleLimit);
+	bf_program_free(program);
+}
+
+#[test]
+/// Runs taking too much memory should report so.
+fn test_ffi_memory_limit() {
+	let program = parse("+[>+]");
+	let limits = BfLimits { max_cycles: 0, max_output: 0, max_memory: 100000 };
+	let mut len = 0;
+	let status = bf_run(program as *BfProgram, ptr::null(), 0, ptr::mut_null(), 0, &mut len, &limits);
+	assert!(status == BfMemoryLimit);
+	bf_program_free(program);
+}
+
+#[test]
+/// A buffer too small should tell how large it has to be, and a retry work.
+fn test_ffi_buffer_too_small() {
+	let program = parse(",[.,]");
+	let input = bytes!("hello");
+	let mut output = [0u8, ..5];
+	let mut len = 0;
+	let status = bf_run(program as *BfProgram, input.as_ptr(), input.len() as size_t,
+		output.as_mut_ptr(), 2, &mut len, ptr::null());
+	assert!(status == BfBufferTooSmall);
+	assert!(len == 5);
+
+	let status = bf_run(program as *BfProgram, input.as_ptr(), input.len() as size_t,
+		output.as_mut_ptr(), len, &mut len, ptr::null());
+	assert!(status == BfOk);
+	assert!(output.as_slice() == input);
+	bf_program_free(program);
+}
+
+#[test]
+/// Threads running no task, like those of C programs, should get statuses, not aborts.
+fn test_ffi_thread_without_task() {
+	let (parsed, status, message, limited) = Thread::start(proc() {
+		let source = "+[";
+		let mut status = BfOk;
+		let program = bf_parse(source.as_ptr(), source.len() as size_t, &mut status);
+		let message = last_error_message();
+
+		let program_ok = parse("+[]");
+		let limits = BfLimits { max_cycles: 100, max_output: 0, max_memory: 0 };
+		let mut len = 0;
+		let limited = bf_run(program_ok as *BfProgram, ptr::null(), 0, ptr::mut_null(), 0, &mut len, &limits);
+		bf_program_free(program_ok);
+		(program.is_null(), status, message, limited)
+	}).join();
+	assert!(parsed && status == BfParseError);
+	assert!(message == ~"Unmatched `[`.");
+	assert!(limited == BfCycleLimit);
+}
+
+#[test]
+/// NULL programs and buffers should be refused.
+fn test_ffi_invalid_arguments() {
+	let mut len = 0;
+	let status = bf_run(ptr::null(), ptr::null(), 0, ptr::mut_null(), 0, &mut len, ptr::null());
+	assert!(status == BfInvalidArgument);
+	bf_program_free(ptr::mut_null());
+}
+
+#[test]
+/// Limits should be laid out as the header has them.
+fn test_ffi_limits_layout() {
+	let limits = BfLimits { max_cycles: 0, max_output: 0, max_memory: 0 };
+	let base = &limits as *BfLimits as uint;
+	assert!(::std::mem::size_of::<BfLimits>() == 24);
+	assert!(&limits.max_cycles as *u64 as uint - base == 0);
+	assert!(&limits.max_output as *u64 as uint - base == 8);
+	assert!(&limits.max_memory as *u64 as uint - base == 16);
+}
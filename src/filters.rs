@@ -0,0 +1,158 @@
+/*!
+Filters transforming the bytes programs read or write on the fly,
+e.g. to feed programs expecting uppercase ASCII from mixed-case files
+with CRLF line endings, without preprocessing the files.
+
+Any filter works on either side, see `Machine::add_input_filter` and
+`Machine::add_output_filter`, and filters are chained in the order
+they were added.
+*/
+
+use std::ascii::StrAsciiExt;
+use std::from_str::from_str;
+
+
+/**
+A transformation of a stream of bytes.
+*/
+#[deriving(Clone, Eq, Show)]
+pub enum Filter {
+	/// Turns ASCII lowercase letters into uppercase ones.
+	Uppercase,
+	/// Drops carriage returns, `0x0D`.
+	StripCr,
+	/// Writes a carriage return before each line feed, `0x0A`.
+	CrLf,
+	/// Turns every byte `from` into a byte `to`.
+	Translate(u8, u8),
+	/// Ends the stream at the given byte, which is dropped, as is
+	/// everything after it: programs reading get EOF from there on.
+	TakeWhile(u8),
+}
+
+impl Filter {
+	/**
+	Passes a byte through the filter, pushing whatever comes out onto
+	`out`. Returns false when the stream ends there instead.
+	*/
+	pub fn apply(&self, byte: u8, out: &mut Vec<u8>) -> bool {
+		match *self {
+			Uppercase if byte >= 'a' as u8 && byte <= 'z' as u8 => out.push(byte - 32),
+			StripCr if byte == 0x0D => {}
+			CrLf if byte == 0x0A => { out.push(0x0D); out.push(0x0A) }
+			Translate(from, to) if byte == from => out.push(to),
+			TakeWhile(end) if byte == end => return false,
+			_ => out.push(byte),
+		}
+		true
+	}
+}
+
+/**
+Makes a filter from its name, and its arguments after colons, if
+any: `uppercase`, `strip-cr`, `crlf`, `translate:FROM:TO` and
+`take-while:BYTE`, where bytes are decimal numbers, or else single
+ASCII characters.
+*/
+pub fn filter_from_name(spec: &str) -> Option<Filter> {
+	let parts: ~[&str] = spec.split(':').collect();
+	let bytes: ~[Option<u8>] = parts.slice_from(1).iter().map(|&arg| byte_from_arg(arg)).collect();
+	match (parts[0].to_ascii_lower().as_slice(), bytes.as_slice()) {
+		("uppercase", []) => Some(Uppercase),
+		("strip-cr", []) => Some(StripCr),
+		("crlf", []) => Some(CrLf),
+		("translate", [Some(from), Some(to)]) => Some(Translate(from, to)),
+		("take-while", [Some(end)]) => Some(TakeWhile(end)),
+		_ => None,
+	}
+}
+
+/// A byte given as a decimal number, or as a single ASCII character.
+fn byte_from_arg(arg: &str) -> Option<u8> {
+	match from_str::<u8>(arg) {
+		Some(byte) => Some(byte),
+		None if arg.len() == 1 && arg[0] < 128 => Some(arg[0]),
+		None => None,
+	}
+}
+
+/**
+Filters chained in order, each one passing what comes out of it on
+to the next, until one of them ends the stream.
+*/
+#[deriving(Clone)]
+pub struct FilterChain {
+	filters: Vec<Filter>,
+	/// Whether a filter ended the stream.
+	ended: bool,
+}
+
+impl FilterChain {
+	/// A chain passing every byte as it is.
+	pub fn new() -> FilterChain {
+		FilterChain { filters: Vec::new(), ended: false }
+	}
+
+	/// Adds a filter at the end of the chain.
+	pub fn push(&mut self, filter: Filter) {
+		self.filters.push(filter);
+	}
+
+	/// Whether there are no filters at all.
+	pub fn is_empty(&self) -> bool {
+		self.filters.is_empty()
+	}
+
+	/// Whether a filter ended the stream, after which nothing comes out.
+	pub fn ended(&self) -> bool {
+		self.ended
+	}
+
+	/// Starts the stream over, e.g. for a new run.
+	pub fn restart(&mut self) {
+		self.ended = false;
+	}
+
+	/**
+	Passes a byte through every filter, pushing whatever comes out of
+	the last one onto `out`. Once the stream ended, nothing does;
+	what came out before the end in the same pass does.
+	*/
+	pub fn apply(&mut self, byte: u8, out: &mut Vec<u8>) {
+		if self.ended { return }
+		let mut bytes = vec!(byte);
+		for filter in self.filters.iter() {
+			let mut passed = Vec::with_capacity(bytes.len());
+			for &byte in bytes.iter() {
+				if !filter.apply(byte, &mut passed) {
+					self.ended = true;
+					break;
+				}
+			}
+			bytes = passed;
+			if self.ended { break }
+		}
+		out.push_all(bytes.as_slice());
+	}
+}
+
+#[test]
+/// Filters should be made from their names, and chain in order.
+fn test_filters_chain() {
+	assert!(filter_from_name("strip-cr") == Some(StripCr));
+	assert!(filter_from_name("translate:97:B") == Some(Translate(97, 66)));
+	assert!(filter_from_name("take-while:10") == Some(TakeWhile(10)));
+	assert!(filter_from_name("translate:1") == None);
+	assert!(filter_from_name("lowercase") == None);
+
+	let mut chain = FilterChain::new();
+	chain.push(Translate('a' as u8, '\n' as u8));
+	chain.push(CrLf);
+	chain.push(TakeWhile('!' as u8));
+	let mut out = Vec::new();
+	for &byte in bytes!("bar!baz").iter() {
+		chain.apply(byte, &mut out);
+	}
+	assert!(out.as_slice() == bytes!("b\r\nr"));
+	assert!(chain.ended());
+}
@@ -0,0 +1,404 @@
+/*!
+Random changes to programs, for evolutionary searches: mutating a
+program, see `mutate`, and crossing two of them over, see `crossover`.
+Runs can then be scored with `Machine::run_batch`.
+
+Programs are changed as trees, so that brackets always stay balanced.
+New operators are standard ones, and new loops plain `[...]` ones.
+*/
+
+use std::rand::Rng;
+use ast::Ast;
+use operators::{Operator, Incr, Decr, Prev, Next, Put, Get, Sub, DoSub};
+
+
+/**
+A kind of mutation, see `mutate`.
+*/
+#[deriving(Clone, Eq, Show)]
+pub enum Mutation {
+	/// Replaces an operator, not a loop, with another one.
+	PointMutation,
+	/// Inserts an operator anywhere.
+	Insertion,
+	/// Deletes an operator, not a loop.
+	Deletion,
+	/// Copies an operator or a loop right after itself.
+	Duplication,
+	/// Wraps a run of operators, without loops, in a loop.
+	Wrapping,
+	/// Replaces a loop with its body.
+	Unwrapping,
+}
+
+/// Every kind of mutation, in the order of their weights.
+pub static MUTATIONS: [Mutation, ..6] = [PointMutation, Insertion, Deletion, Duplication, Wrapping, Unwrapping];
+
+/// How many times a mutation going over the limits is tried again.
+pub static MUTATION_TRIES: uint = 16;
+
+/**
+How to mutate programs, see `mutate`.
+*/
+#[deriving(Clone, Eq, Show)]
+pub struct MutationConfig {
+	/**
+	How likely each kind of mutation is, relative to the others, in
+	the order of `MUTATIONS`. Kinds weighing zero never happen.
+	*/
+	pub weights: [uint, ..6],
+	/// How many operators mutated programs may have, loops counting as one.
+	pub max_len: uint,
+	/// How deep loops may be nested in mutated programs.
+	pub max_depth: uint,
+}
+
+impl MutationConfig {
+	/// Point mutations twice as likely as the other kinds, on programs of up to 256 operators.
+	pub fn default() -> MutationConfig {
+		MutationConfig { weights: [2, 1, 1, 1, 1, 1], max_len: 256, max_depth: 8 }
+	}
+
+	/// The weight of a kind of mutation.
+	pub fn weight(&self, mutation: Mutation) -> uint {
+		self.weights[mutation as uint]
+	}
+}
+
+/**
+Mutates a program at random, see `mutate_kind`.
+*/
+pub fn mutate<R: Rng>(ast: &Ast, rng: &mut R, config: &MutationConfig) -> Ast {
+	mutate_kind(ast, rng, config).val0()
+}
+
+/**
+Mutates a program at random, telling which kind of mutation was
+drawn, by their weights, along with the result.
+
+Mutations which can not be made, e.g. deletions in empty programs,
+leave the program as it was. Results going over the limits are
+thrown away, and the same kind of mutation tried again, up to
+`MUTATION_TRIES` times; after that, the program is kept, cut down to
+the limits if need be: loops nested too deep are dropped, and the
+operators past the length, in the order of the source.
+*/
+pub fn mutate_kind<R: Rng>(ast: &Ast, rng: &mut R, config: &MutationConfig) -> (Ast, Mutation) {
+	let mutation = draw(rng, config);
+	let &Ast(ref ops) = ast;
+	for _ in range(0, MUTATION_TRIES) {
+		let mut mutated = ops.clone();
+		apply(&mut mutated, mutation, rng);
+		if fits(mutated.as_slice(), config) {
+			return (Ast(mutated), mutation);
+		}
+	}
+	let mut budget = config.max_len;
+	(Ast(truncate(ops.as_slice(), &mut budget, config.max_depth)), mutation)
+}
+
+/**
+Crosses two programs over: a random operator or loop of `a` is
+replaced with a random one of `b`, so that the result is balanced.
+Either program being empty, the result is the other one's part, or
+`a` as it is. Limits are not minded; see `mutate` for those.
+*/
+pub fn crossover<R: Rng>(a: &Ast, b: &Ast, rng: &mut R) -> Ast {
+	let (&Ast(ref a), &Ast(ref b)) = (a, b);
+	let donors = nodes(b.as_slice()).val0();
+	if donors.is_empty() {
+		return Ast(a.clone());
+	}
+	let (seq, index, _) = pick(rng, donors.as_slice());
+	let part = sequence_of(b, seq)[index].clone();
+
+	let mut crossed = a.clone();
+	let sites = nodes(crossed.as_slice()).val0();
+	if sites.is_empty() {
+		crossed.push(part);
+	} else {
+		let (seq, index, _) = pick(rng, sites.as_slice());
+		sequence(&mut crossed, &mut seq.clone()).unwrap()[index] = part;
+	}
+	Ast(crossed)
+}
+
+/// Draws a kind of mutation by their weights.
+fn draw<R: Rng>(rng: &mut R, config: &MutationConfig) -> Mutation {
+	let total = config.weights.iter().fold(0, |total, &weight| total + weight);
+	if total == 0 { fail!("Every kind of mutation weighs zero.") }
+	let mut drawn = rng.gen_range(0, total);
+	for &mutation in MUTATIONS.iter() {
+		let weight = config.weight(mutation);
+		if drawn < weight { return mutation }
+		drawn -= weight;
+	}
+	fail!("No kind of mutation drawn.")
+}
+
+/// One of the items, at random.
+fn pick<R: Rng, T: Clone>(rng: &mut R, items: &[T]) -> T {
+	items[rng.gen_range(0, items.len())].clone()
+}
+
+/// A random standard operator, but for loops.
+fn random_op<R: Rng>(rng: &mut R) -> Operator {
+	match rng.gen_range(0u, 6) {
+		0 => Incr,
+		1 => Decr,
+		2 => Prev,
+		3 => Next,
+		4 => Put,
+		_ => Get,
+	}
+}
+
+/// Makes a mutation of a program, if it can be made.
+fn apply<R: Rng>(ops: &mut ~[Operator], mutation: Mutation, rng: &mut R) {
+	let (all, lens) = nodes(ops.as_slice());
+	let plain: Vec<(uint, uint, bool)> = all.iter().filter(|&&(_, _, is_loop)| !is_loop).map(|&node| node).collect();
+	let loops: Vec<(uint, uint, bool)> = all.iter().filter(|&&(_, _, is_loop)| is_loop).map(|&node| node).collect();
+	match mutation {
+		PointMutation if !plain.is_empty() => {
+			let (seq, index, _) = pick(rng, plain.as_slice());
+			let target = sequence(ops, &mut seq.clone()).unwrap();
+			let mut op = random_op(rng);
+			while op == target[index] { op = random_op(rng) }
+			target[index] = op;
+		}
+		Insertion => {
+			// Every sequence has a place more than it has operators.
+			let places = lens.iter().fold(0, |total, &len| total + len + 1);
+			let mut place = rng.gen_range(0, places);
+			let mut seq = 0;
+			while place > *lens.get(seq) {
+				place -= *lens.get(seq) + 1;
+				seq += 1;
+			}
+			let op = random_op(rng);
+			sequence(ops, &mut seq).unwrap().insert(place, op);
+		}
+		Deletion if !plain.is_empty() => {
+			let (seq, index, _) = pick(rng, plain.as_slice());
+			sequence(ops, &mut seq.clone()).unwrap().remove(index);
+		}
+		Duplication if !all.is_empty() => {
+			let (seq, index, _) = pick(rng, all.as_slice());
+			let target = sequence(ops, &mut seq.clone()).unwrap();
+			let copy = target[index].clone();
+			target.insert(index + 1, copy);
+		}
+		Wrapping if !plain.is_empty() => {
+			let (seq, start, _) = pick(rng, plain.as_slice());
+			let target = sequence(ops, &mut seq.clone()).unwrap();
+			let mut end = start + 1;
+			while end < target.len() && !is_loop(&target[end]) && rng.gen::<bool>() {
+				end += 1;
+			}
+			let body: ~[Operator] = range(start, end).map(|_| target.remove(start).unwrap()).collect();
+			target.insert(start, Sub(Ast(body)));
+		}
+		Unwrapping if !loops.is_empty() => {
+			let (seq, index, _) = pick(rng, loops.as_slice());
+			let target = sequence(ops, &mut seq.clone()).unwrap();
+			let body = match target.remove(index) {
+				Some(Sub(Ast(body))) | Some(DoSub(Ast(body))) => body,
+				_ => fail!("Not a loop."),
+			};
+			for (offset, op) in body.move_iter().enumerate() {
+				target.insert(index + offset, op);
+			}
+		}
+		// Nothing to mutate.
+		_ => {}
+	}
+}
+
+/// Whether an operator is a loop.
+fn is_loop(op: &Operator) -> bool {
+	match *op { Sub(_) | DoSub(_) => true, _ => false }
+}
+
+/**
+Every operator and loop of a program, in the order of the source: the
+number of the sequence it is in, its index there, and whether it is a
+loop. Sequences are the program, then loop bodies in the order of the
+source, numbered from 0; their lengths come along.
+*/
+fn nodes(ops: &[Operator]) -> (Vec<(uint, uint, bool)>, Vec<uint>) {
+	let (mut nodes, mut lens) = (Vec::new(), Vec::new());
+	walk(ops, &mut nodes, &mut lens);
+	(nodes, lens)
+}
+
+/// Lists the nodes of a sequence and of those in it, see `nodes`.
+fn walk(ops: &[Operator], nodes: &mut Vec<(uint, uint, bool)>, lens: &mut Vec<uint>) {
+	let seq = lens.len();
+	lens.push(ops.len());
+	for (index, op) in ops.iter().enumerate() {
+		match *op {
+			Sub(Ast(ref body)) | DoSub(Ast(ref body)) => {
+				nodes.push((seq, index, true));
+				walk(body.as_slice(), nodes, lens);
+			}
+			_ => nodes.push((seq, index, false)),
+		}
+	}
+}
+
+/// The sequence numbered `n`, counting down to it, see `nodes`.
+fn sequence<'a>(ops: &'a mut ~[Operator], n: &mut uint) -> Option<&'a mut ~[Operator]> {
+	if *n == 0 {
+		return Some(ops);
+	}
+	*n -= 1;
+	for op in ops.mut_iter() {
+		match *op {
+			Sub(Ast(ref mut body)) | DoSub(Ast(ref mut body)) => match sequence(body, n) {
+				Some(found) => return Some(found),
+				None => {}
+			},
+			_ => {}
+		}
+	}
+	None
+}
+
+/// The sequence numbered `n`, to read, see `nodes`.
+fn sequence_of<'a>(ops: &'a ~[Operator], n: uint) -> &'a ~[Operator] {
+	fn find<'a>(ops: &'a ~[Operator], n: &mut uint) -> Option<&'a ~[Operator]> {
+		if *n == 0 { return Some(ops) }
+		*n -= 1;
+		for op in ops.iter() {
+			match *op {
+				Sub(Ast(ref body)) | DoSub(Ast(ref body)) => match find(body, n) {
+					Some(found) => return Some(found),
+					None => {}
+				},
+				_ => {}
+			}
+		}
+		None
+	}
+	find(ops, &mut n.clone()).unwrap()
+}
+
+/// How deep loops are nested in a program.
+fn depth(ops: &[Operator]) -> uint {
+	ops.iter().map(|op| match *op {
+		Sub(Ast(ref body)) | DoSub(Ast(ref body)) => 1 + depth(body.as_slice()),
+		_ => 0,
+	}).max().unwrap_or(0)
+}
+
+/// Whether a program is within the limits of a configuration.
+fn fits(ops: &[Operator], config: &MutationConfig) -> bool {
+	nodes(ops).val0().len() <= config.max_len && depth(ops) <= config.max_depth
+}
+
+/**
+Cuts a program down to `budget` operators, loops counting as one, in
+the order of the source, dropping loops nested deeper than `depth`.
+*/
+fn truncate(ops: &[Operator], budget: &mut uint, depth: uint) -> ~[Operator] {
+	let mut kept = ~[];
+	for op in ops.iter() {
+		if *budget == 0 { break }
+		match *op {
+			Sub(_) | DoSub(_) if depth == 0 => {}
+			Sub(Ast(ref body)) => { *budget -= 1; kept.push(Sub(Ast(truncate(body.as_slice(), budget, depth - 1)))) }
+			DoSub(Ast(ref body)) => { *budget -= 1; kept.push(DoSub(Ast(truncate(body.as_slice(), budget, depth - 1)))) }
+			ref other => { *budget -= 1; kept.push(other.clone()) }
+		}
+	}
+	kept
+}
+
+#[cfg(test)]
+use std::rand::{SeedableRng, XorShiftRng};
+
+#[cfg(test)]
+/// A generator always drawing the same numbers.
+fn seeded(seed: u32) -> XorShiftRng {
+	SeedableRng::from_seed([seed, 0x9e3779b9, 0x243f6a88, 0xb7e15162])
+}
+
+#[test]
+/// Mutated programs should always parse back, within the limits.
+fn test_fuzz_mutations_stay_valid() {
+	let mut rng = seeded(1);
+	for &(name, source, _) in ::corpus::PROGRAMS.iter() {
+		let original = Ast::parse_str(source).unwrap();
+		let config = MutationConfig { max_len: original.node_count() + 32, max_depth: 6, ..MutationConfig::default() };
+		let mut program = original.clone();
+		for _ in range(0, 10000 / ::corpus::PROGRAMS.len()) {
+			program = mutate(&program, &mut rng, &config);
+			let &Ast(ref ops) = &program;
+			if !fits(ops.as_slice(), &config) || Ast::parse_str(format!("{}", program)).is_err() {
+				fail!("Mutating {} went wrong: {}", name, program);
+			}
+		}
+		let crossed = crossover(&original, &program, &mut rng);
+		assert!(Ast::parse_str(format!("{}", crossed)) == Ok(crossed));
+	}
+}
+
+#[test]
+/// Each kind of mutation should be drawn about as often as it weighs.
+fn test_fuzz_mutation_weights() {
+	let config = MutationConfig { weights: [5, 4, 3, 2, 1, 0], ..MutationConfig::default() };
+	let program = Ast::parse_str("+[->+<]>.").unwrap();
+	let mut rng = seeded(2);
+	let mut counts = [0u, ..6];
+	for _ in range(0, 15000) {
+		counts[mutate_kind(&program, &mut rng, &config).val1() as uint] += 1;
+	}
+	for (&count, &weight) in counts.iter().zip(config.weights.iter()) {
+		let expected = weight * 1000;
+		assert!(count * 10 >= expected * 9 && count * 10 <= expected * 11);
+	}
+}
+
+#[test]
+/// The same seed should make the same programs.
+fn test_fuzz_deterministic() {
+	let program = Ast::parse_str(::corpus::PROGRAMS[0].val1()).unwrap();
+	let evolve = |seed: u32| {
+		let mut rng = seeded(seed);
+		let mut current = program.clone();
+		for _ in range(0, 200) {
+			let mutated = mutate(&current, &mut rng, &MutationConfig::default());
+			current = crossover(&mutated, &program, &mut rng);
+		}
+		current
+	};
+	assert!(evolve(3) == evolve(3));
+	assert!(evolve(3) != evolve(4));
+}
+
+#[test]
+/// Each kind of mutation should do what it says, where it can.
+fn test_fuzz_mutation_kinds() {
+	let only = |mutation: Mutation| {
+		let mut weights = [0u, ..6];
+		weights[mutation as uint] = 1;
+		MutationConfig { weights: weights, ..MutationConfig::default() }
+	};
+	let mut rng = seeded(5);
+	let program = Ast::parse_str("+[->+<]").unwrap();
+	let mutated = mutate(&program, &mut rng, &only(Unwrapping));
+	assert!(mutated == Ast::parse_str("+->+<").unwrap());
+	let mutated = mutate(&program, &mut rng, &only(Insertion));
+	assert!(mutated.node_count() == program.node_count() + 1);
+	let mutated = mutate(&program, &mut rng, &only(Deletion));
+	assert!(mutated.node_count() == program.node_count() - 1);
+	let mutated = mutate(&program, &mut rng, &only(PointMutation));
+	assert!(mutated.node_count() == program.node_count() && mutated != program);
+	// Nothing to unwrap, nor to delete.
+	let empty = Ast(~[]);
+	assert!(mutate(&empty, &mut rng, &only(Unwrapping)) == empty);
+	assert!(mutate(&empty, &mut rng, &only(Deletion)) == empty);
+	// Programs going over the limits are cut down to them.
+	let config = MutationConfig { max_len: 3, max_depth: 0, ..only(Duplication) };
+	assert!(mutate(&program, &mut rng, &config) == Ast(~[Incr]));
+}
@@ -0,0 +1,181 @@
+/*!
+Heatmaps of programs, telling how many times each character of their
+source was executed.
+*/
+
+use ast::Ast;
+use operators::{Sub, DoSub, Nop, Shebang};
+use sourcemap::SourceMap;
+
+
+/// Characters used to draw counts, from zero up to the largest one.
+static INTENSITY: &'static [char] = &[' ', '.', ':', '-', '=', '+', '*', '#', '%', '@'];
+
+/**
+How to render a heatmap, see `Heatmap::render`.
+*/
+#[deriving(Clone, Eq, Show)]
+pub enum HeatmapStyle {
+	/// Each line of source is followed by the counts of its characters.
+	HeatCounts,
+	/// Each line of source is followed by a character per count,
+	/// telling how high it is compared to the largest one.
+	HeatIntensity,
+}
+
+/**
+How many times each character of a program's source was executed.
+
+Operators folded by the optimizer count for every character they
+were folded from, so `+++` folded into `IncrN(3)` counts each `+`
+once per execution. Both brackets of a loop count every check of its
+condition, and comments are never executed.
+*/
+#[deriving(Clone, Eq, Show)]
+pub struct Heatmap {
+	/// The counts, by offset in characters into the source.
+	pub counts: ~[uint],
+}
+
+impl Heatmap {
+	/**
+	Attributes to the source how many times each instruction of a
+	program was executed, given in the order of `Bytecode::compile`.
+	The map must be one of the program, and `source_len` the length
+	of its source in characters.
+	*/
+	pub fn of(program: &Ast, map: &SourceMap, executed: &[uint], source_len: uint) -> Heatmap {
+		let mut counts = Vec::from_elem(source_len, 0u);
+		let mut pc = 0;
+		attribute(program, map, executed, &mut pc, &mut counts);
+		Heatmap { counts: counts.move_iter().collect() }
+	}
+
+	/// The count of the character at an offset, zero past the end.
+	pub fn count(&self, offset: uint) -> uint {
+		if offset < self.counts.len() { self.counts[offset] } else { 0 }
+	}
+
+	/**
+	Renders the source, following each of its lines with a line of
+	counts, aligned with the characters they are about. Counts are
+	written in full, in columns as wide as the widest on the line,
+	or as intensity characters. Tabs are not expanded.
+	*/
+	pub fn render(&self, source: &str, style: HeatmapStyle) -> ~str {
+		let max = self.counts.iter().fold(0, |max, &count| if count > max { count } else { max });
+		let mut out = StrBuf::new();
+		let mut offset = 0;
+		let lines: ~[&str] = source.split('\n').collect();
+		for (i, line) in lines.iter().enumerate() {
+			// A final newline does not start a line.
+			if i == lines.len() - 1 && line.is_empty() && i > 0 { break }
+			let chars: ~[char] = line.chars().collect();
+			let counts: ~[uint] = range(offset, offset + chars.len()).map(|at| self.count(at)).collect();
+			offset += chars.len() + 1;
+
+			match style {
+				HeatCounts => {
+					let width = counts.iter().fold(1, |width, count| {
+						let digits = count.to_str().len();
+						if digits > width { digits } else { width }
+					});
+					let separator = if width > 1 { " " } else { "" };
+					let source_cols: ~[~str] = chars.iter().map(|c| pad(c.to_str(), width)).collect();
+					let count_cols: ~[~str] = counts.iter().map(|count| pad(count.to_str(), width)).collect();
+					out.push_str(source_cols.connect(separator).trim_right());
+					out.push_char('\n');
+					out.push_str(count_cols.connect(separator).trim_right());
+					out.push_char('\n');
+				}
+				HeatIntensity => {
+					out.push_str(*line);
+					out.push_char('\n');
+					let levels = INTENSITY.len() - 1;
+					let strip: ~str = counts.iter().map(|&count| {
+						// Any count at all shows, however small.
+						INTENSITY[if max == 0 { 0 } else { (count * levels + max - 1) / max }]
+					}).collect();
+					out.push_str(strip.trim_right());
+					out.push_char('\n');
+				}
+			}
+		}
+		out.into_owned()
+	}
+}
+
+/// Pads some text with spaces up to a width.
+fn pad(text: ~str, width: uint) -> ~str {
+	let padding = " ".repeat(width - text.char_len());
+	text + padding
+}
+
+/**
+Adds the counts of the instructions of a sequence of operators to
+the characters they come from, starting at instruction `pc`.
+*/
+fn attribute(ast: &Ast, map: &SourceMap, executed: &[uint], pc: &mut uint, counts: &mut Vec<uint>) {
+	let (&Ast(ref ops), &SourceMap(ref nodes)) = (ast, map);
+	assert!(nodes.len() == ops.len(), "Source map does not match the program.");
+	for (op, node) in ops.iter().zip(nodes.iter()) {
+		let span = node.span;
+		let checks = match *op {
+			Sub(ref body) => {
+				let entered = executed[*pc];
+				*pc += 1;
+				attribute(body, &node.body, executed, pc, counts);
+				entered
+			}
+			DoSub(ref body) => {
+				attribute(body, &node.body, executed, pc, counts);
+				0
+			}
+			Nop(_) => {
+				*pc += 1;
+				continue;
+			}
+			Shebang(_) => continue,
+			_ => {
+				for at in range(span.start, span.end) {
+					*counts.get_mut(at) += executed[*pc];
+				}
+				*pc += 1;
+				continue;
+			}
+		};
+		// Loops end with checking their condition again.
+		let checks = checks + executed[*pc];
+		*pc += 1;
+		*counts.get_mut(span.start) += checks;
+		*counts.get_mut(span.end - 1) += checks;
+	}
+}
+
+#[cfg(test)]
+use machine::Machine;
+
+#[test]
+/// Every character should count how many times it was executed.
+fn test_heatmap_counts() {
+	let source = "++[>+<-] x";
+	let ast = Ast::parse_str(source).unwrap();
+	let (heatmap, result) = Machine::capturing([]).run_heatmap(&ast, &SourceMap::of(&ast), source);
+	assert!(result.is_ok());
+	// The loop runs twice, checking its condition three times.
+	assert!(heatmap.counts == ~[1, 1, 3, 2, 2, 2, 2, 3, 0, 0]);
+
+	let (optimized, map) = ast.optimize_mapped();
+	let (folded, _) = Machine::capturing([]).run_heatmap(&optimized, &map, source);
+	assert!(folded == heatmap);
+}
+
+#[test]
+/// Counts should line up with the source, either way.
+fn test_heatmap_render() {
+	let source = "+++++ +++++\n[-]\n";
+	let ast = Ast::parse_str(source).unwrap();
+	let (heatmap, _) = Machine::capturing([]).run_heatmap(&ast, &SourceMap::of(&ast), source);
+	assert!(heatmap.render(source, HeatCounts) == ~"+++++ +++++\n11111011111\n[  -  ]\n11 10 11\n");
+	assert!(heatmap.render(source, HeatIntensity) == ~"+++++ +++++\n..... .....\n[-]\n@@@\n");
+}
@@ -0,0 +1,197 @@
+use storage::{Tape, Unit};
+
+
+/// Characters used to draw cells, from zero up to the largest values.
+static HEAT: &'static [char] = &[' ', '.', ':', '*', '#', '@'];
+
+
+/**
+A summary of the contents of a tape over a range of cells.
+
+Besides some statistics, the report can be rendered as a compact
+heat-strip, see `TapeReport::render_ascii`.
+*/
+pub struct TapeReport {
+	/// The first cell of the range.
+	pub start: int,
+	/// The cell right past the end of the range.
+	pub end: int,
+	/// The position of the tape head.
+	pub head: int,
+	/// How many cells in the range hold each value.
+	pub histogram: Vec<uint>,
+	/// How many cells in the range are not zero.
+	pub nonzero: uint,
+	/// The cells which are not zero, sorted by position.
+	cells: Vec<(int, Unit)>,
+}
+
+impl TapeReport {
+	/**
+	Produces a report of the cells of `tape` from `start` inclusive
+	to `end` exclusive.
+	*/
+	pub fn new(tape: &Tape<Unit>, (start, end): (int, int)) -> TapeReport {
+		let cells: Vec<(int, Unit)> = tape.cells().move_iter()
+			.filter(|&(pos, v)| v != 0 && pos >= start && pos < end)
+			.collect();
+		let mut histogram = Vec::from_elem(256, 0u);
+		for &(_, v) in cells.iter() {
+			*histogram.get_mut(v as uint) += 1;
+		}
+		// Every other cell in the range is zero.
+		let size = if end > start { (end - start) as uint } else { 0 };
+		*histogram.get_mut(0) = size - cells.len();
+
+		TapeReport {
+			start: start,
+			end: end,
+			head: tape.position(),
+			histogram: histogram,
+			nonzero: cells.len(),
+			cells: cells,
+		}
+	}
+
+	/**
+	Returns the smallest range containing the origin, the head, and
+	every cell which is not zero.
+	*/
+	pub fn touched_range(tape: &Tape<Unit>) -> (int, int) {
+		let head = tape.position();
+		let (mut start, mut end) = if head < 0 { (head, 1) } else { (0, head + 1) };
+		for &(pos, v) in tape.cells().iter() {
+			if v == 0 { continue }
+			if pos < start { start = pos }
+			if pos >= end { end = pos + 1 }
+		}
+		(start, end)
+	}
+
+	/**
+	Renders the range as an ASCII heat-strip, `width` cells per row.
+
+	Each cell is drawn with one of ` .:*#@` by magnitude, and each
+	row starts with the position of its first cell. Rows containing
+	the origin or the head are followed by a row marking them with
+	`o` and `^` respectively. Runs of zero cells which would take
+	more than a full row, or which lie at the edges of the range, are
+	elided as `…(N zero cells)…`, so that far-apart cells on sparse
+	tapes do not produce millions of characters.
+	*/
+	pub fn render_ascii(&self, width: uint) -> ~str {
+		let width = if width == 0 { 1 } else { width };
+		let mut out = StrBuf::new();
+		out.push_str(format!("cells {}..{}, head at {}\n", self.start, self.end, self.head));
+
+		// The cells worth drawing, around which zeros are elided.
+		let mut points: Vec<int> = self.cells.iter().map(|&(pos, _)| pos).collect();
+		for &pos in [0, self.head].iter() {
+			if pos >= self.start && pos < self.end { points.push(pos) }
+		}
+		points.as_mut_slice().sort();
+		points.dedup();
+
+		let mut pos = self.start;
+		let mut i = 0;
+		while i < points.len() {
+			// Gather a cluster of points not too far apart.
+			let first = *points.get(i);
+			let mut last = first;
+			while i < points.len() && *points.get(i) - last <= width as int {
+				last = *points.get(i);
+				i += 1;
+			}
+			self.render_gap(&mut out, first - pos);
+			self.render_cells(&mut out, first, last + 1, width);
+			pos = last + 1;
+		}
+		self.render_gap(&mut out, self.end - pos);
+		out.into_owned()
+	}
+
+	/// Renders an elided run of zero cells.
+	fn render_gap(&self, out: &mut StrBuf, size: int) {
+		if size > 0 {
+			out.push_str(format!("         …({} zero cells)…\n", size));
+		}
+	}
+
+	/// Renders the cells from `start` to `end` in rows, along with markers.
+	fn render_cells(&self, out: &mut StrBuf, start: int, end: int, width: uint) {
+		let mut row = start;
+		while row < end {
+			let row_end = ::std::cmp::min(row + width as int, end);
+			let strip: ~str = range(row, row_end).map(|pos| HEAT[self.bucket(pos)]).collect();
+			out.push_str(format!("{:>8} {}\n", row, strip));
+
+			let has_origin = row <= 0 && 0 < row_end;
+			let has_head = row <= self.head && self.head < row_end;
+			if has_origin || has_head {
+				let markers: ~str = range(row, row_end).map(|pos| {
+					if pos == self.head { '^' } else if pos == 0 { 'o' } else { ' ' }
+				}).collect();
+				out.push_str(format!("         {}\n", markers.trim_right()));
+			}
+			row = row_end;
+		}
+	}
+
+	/// The heat bucket of the cell at a position.
+	fn bucket(&self, pos: int) -> uint {
+		let value = match self.cells.as_slice().bsearch(|&(p, _)| p.cmp(&pos)) {
+			Some(i) => { let (_, v) = *self.cells.get(i); v as uint }
+			None => 0,
+		};
+		if value == 0 { 0 } else { 1 + (value - 1) * (HEAT.len() - 1) / 255 }
+	}
+}
+
+#[cfg(test)]
+use storage::SparseTape;
+#[cfg(test)]
+use machine::Machine;
+#[cfg(test)]
+use ast::Ast;
+
+#[test]
+/// The report should draw cells and mark the origin and head.
+fn test_tape_report_renders_cells_and_markers() {
+	let program = Ast::parse_str("->+>").unwrap();
+	let mut machine = Machine::capturing([]);
+	machine.run_program(&program).unwrap();
+	let report = machine.tape_report((0, 3));
+	assert!(report.nonzero == 2);
+	assert!(*report.histogram.get(0) == 1);
+	assert!(*report.histogram.get(1) == 1);
+	assert!(*report.histogram.get(255) == 1);
+	let expected = [
+		"cells 0..3, head at 2",
+		"       0 @. ",
+		"         o ^",
+		""].connect("\n");
+	assert!(report.render_ascii(8) == expected);
+}
+
+#[test]
+/// Far-apart cells should not render the zeros in between.
+fn test_tape_report_elides_zero_gaps() {
+	let mut tape : SparseTape<Unit> = SparseTape::new();
+	tape.mutate( |v|{ *v = 1; } );
+	tape.wind(1000000);
+	tape.mutate( |v|{ *v = 255; } );
+	tape.wind(-999998);
+	let range = TapeReport::touched_range(&tape as &Tape<Unit>);
+	assert!(range == (0, 1000001));
+	let report = TapeReport::new(&tape as &Tape<Unit>, range);
+	assert!(report.nonzero == 2);
+	assert!(*report.histogram.get(0) == 999999);
+	let expected = [
+		"cells 0..1000001, head at 2",
+		"       0 .  ",
+		"         o ^",
+		"         …(999997 zero cells)…",
+		" 1000000 @",
+		""].connect("\n");
+	assert!(report.render_ascii(4) == expected);
+}
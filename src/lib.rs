@@ -8,14 +8,63 @@
 #[phase(syntax, link)] extern crate log;
 #[cfg(test)] extern crate test;
 extern crate collections;
+extern crate sync;
+extern crate native;
+extern crate time;
 
 // Re-export
-pub use storage::{Unit, Tape, VectorTape, SparseTape};
+pub use storage::{Unit, Tape, VectorTape, SparseTape, TwoWayTape, ChunkedTape, RingTape, TrackingTape};
 pub use operators::Operator;
-pub use ast::Ast;
-pub use machine::Machine;
+pub use core::{FixedTape, run_core};
+pub use ast::{Ast, Span, DiffEntry, ParseOptions, ParseWarning, ExtractOptions};
+pub use machine::{Machine, Execution, RunState, Breakpoint, Limits, RuntimeError, CancelToken, StreamOptions, ClosedInput, InputMode, OutputMode, NewlineMode};
+pub use machine::{LocatedError, BatchResult, Condition, CondId, LoopId, ClampEvent, Snapshot};
+pub use builder::MachineBuilder;
+pub use parse::{ParseTree, ParseError};
+pub use sourcemap::SourceMap;
+pub use heatmap::{Heatmap, HeatmapStyle};
+pub use explain::ExplainTracer;
+pub use trace::{TraceLog, Divergence};
+pub use arena::ProgramArena;
+pub use pool::{MachinePool, Job, JobResult};
+pub use scheduler::{Scheduler, Pid, ProcIo, SchedulerResult};
+pub use inspect::TapeReport;
+pub use semantics::Semantics;
+pub use report::RunReport;
+pub use filters::Filter;
+pub use features::{features, FeatureSet, Extension, ExtensionSet, ValidationError, VERSION};
 
 pub mod storage;
+pub mod core;
+pub mod pacing;
+pub mod filters;
 pub mod operators;
 pub mod ast;
+pub mod parse;
+pub mod bytecode;
+pub mod rewrite;
+pub mod optimizer;
+pub mod sourcemap;
+pub mod heatmap;
+pub mod explain;
+pub mod trace;
+pub mod arena;
+pub mod dialect;
 pub mod machine;
+pub mod exec;
+pub mod cache;
+pub mod conformance;
+pub mod builder;
+pub mod analysis;
+pub mod enumerate;
+pub mod fuzz;
+pub mod superopt;
+pub mod pool;
+pub mod scheduler;
+pub mod inspect;
+pub mod semantics;
+pub mod features;
+pub mod report;
+pub mod runner;
+pub mod ffi;
+#[cfg(test)] mod corpus;
@@ -1,89 +1,4061 @@
+use std::fmt;
+use std::char;
+use std::cmp::{min, max};
+use std::num::Bounded;
+use std::io::{Reader, Writer, MemReader, IoResult, EndOfFile, ResourceUnavailable, standard_error};
+use std::io::util::{NullWriter, ZeroReader};
 use std::io::stdio::{stdin_raw, stdout_raw};
-use storage::{Tape, VectorTape};
-use operators::{Sub, Incr, Decr, Prev, Next, Put, Get};
-use ast::Ast;
+use std::io::timer::sleep;
+use std::hash::hash;
+use std::sync::atomics::{AtomicBool, AtomicUint, SeqCst};
+use std::rand::{Rng, SeedableRng, XorShiftRng};
+use sync::Arc;
+use collections::{RingBuf, Deque};
+use storage::{Unit, Tape, VectorTape, dump};
+use ast::{Ast, Span, MAX_NESTING};
+use operators::{Operator, Sub};
+use sourcemap::SourceMap;
+use heatmap::Heatmap;
+use arena::ProgramArena;
+use bytecode::{Bytecode, Instr, OpMove, OpAdd, OpJnz, OpJz, OpAddAt, OpSetAt, OpOffsetWrite};
+use bytecode::{Opcode, OpPut, OpGet, OpRandom, OpNegate, OpCheckpoint, OpComment, OpNop, OpHostCall, OPCODE_COUNT};
+use inspect::TapeReport;
+use semantics::{Semantics, EofZero, EofMinusOne, EofUnchanged};
+use semantics::{FailOnOverflow, FailAtEdges};
+use pacing::{Pacing, Unpaced, PerByte, BytesPerSecond, Clock, SystemClock};
+use filters::{Filter, FilterChain};
+use features::{ExtensionSet, ValidationError};
+
+
+/// The seed of the random values of `?`, unless seeded otherwise.
+pub static DEFAULT_SEED: u64 = 0;
+
+/// How many loops deep runs may go, unless set otherwise.
+pub static DEFAULT_MAX_EXEC_DEPTH: uint = 1000;
+
+/// How many clamps of the head a run notes at most, see `Machine::set_track_clamps`.
+pub static CLAMP_EVENT_LIMIT: uint = 1000;
+
+/// How many instructions a collected trace holds at most, a byte each.
+pub static TRACE_LIMIT: uint = 1 << 20;
+
+/// How many snapshots a run keeps at most, see `Machine::run_with_snapshots`.
+pub static MAX_SNAPSHOTS: uint = 256;
+
+/// How many of its snapshots a run keeps the whole state of at most.
+pub static MAX_RESUMABLE_SNAPSHOTS: uint = 16;
+
+/// How many of the cells written last a snapshot tells.
+pub static SNAPSHOT_RECENT_CELLS: uint = 8;
+
+/**
+Resource limits for running a program.
+
+A limit of `None` means that the resource is unlimited, which is
+the default for new machines.
+*/
+#[deriving(Clone, Eq)]
+pub struct Limits {
+	/// The maximum number of cycles a run can execute.
+	pub cycles: Option<uint>,
+	/// The maximum number of bytes a run can output.
+	pub output: Option<uint>,
+	/**
+	The maximum time a run can take, in milliseconds, as told by the
+	machine's clock. The time spent pacing output is not counted, so
+	that pacing can not make runs go over. It is checked as often as
+	the cancel token, see `Machine::set_cancel_granularity`.
+	*/
+	pub time: Option<u64>,
+}
+
+impl Limits {
+	/// No limits at all.
+	pub fn none() -> Limits {
+		Limits { cycles: None, output: None, time: None }
+	}
+}
+
+/// The kinds of limit a run can hit.
+#[deriving(Clone, Eq, Show)]
+pub enum Limit {
+	CycleLimit,
+	OutputLimit,
+	TimeLimit,
+}
+
+/**
+The ways in which running a program can fail.
+*/
+#[deriving(Clone, Eq)]
+pub enum RuntimeError {
+	/// The output could not be written to.
+	OutputError,
+	/// The run went over one of the machine's limits.
+	LimitExceeded(Limit),
+	/// A cell was incremented past its largest value.
+	CellOverflow,
+	/// A cell was decremented past zero.
+	CellUnderflow,
+	/// The head was moved past the edges of the tape.
+	TapeOutOfBounds,
+	/// The run was cancelled through a `CancelToken`, after some cycles.
+	Cancelled(uint),
+	/// A streaming run was stopped by its consumer, see `run_streaming`.
+	Aborted,
+	/**
+	The tape took more memory than allowed, see
+	`Machine::set_memory_limit`: the bytes it took, and the cycles
+	executed by then.
+	*/
+	MemoryLimitExceeded(uint, uint),
+	/**
+	The program nests loops deeper than allowed, see
+	`Machine::set_max_exec_depth`: how deep it nests, and the opening
+	bracket of the first loop too deep. Nothing was run.
+	*/
+	NestingTooDeep(uint, Span),
+	/**
+	The head was moved left of the origin, and stopped there, while
+	clamps were forbidden, see `Machine::set_forbid_clamp`: where it
+	would have gone.
+	*/
+	ClampedAtLeftEdge(int),
+	/**
+	The program uses an extension not enabled on the machine, see
+	`Machine::validate`. Nothing was run.
+	*/
+	ExtensionDisabled(ValidationError),
+	/**
+	The snapshot to resume from was thinned out, see
+	`Snapshot::is_resumable`: after how many loop exits it was taken.
+	Nothing was run.
+	*/
+	SnapshotNotResumable(uint),
+	/**
+	The snapshot to resume from was taken from another program, see
+	`Machine::resume_from`: after how many loop exits it was taken.
+	Nothing was run.
+	*/
+	SnapshotOfAnotherProgram(uint),
+	/**
+	The trace to replay holds a whole loop, see `Machine::replay`:
+	where in the trace. Nothing was run.
+	*/
+	LoopInTrace(uint),
+}
+
+/**
+The head stopped at the origin, moving left of it, see
+`Machine::set_track_clamps`.
+*/
+#[deriving(Clone, Eq, Show)]
+pub struct ClampEvent {
+	/// The cycles spent when it happened, the move included.
+	pub cycle: uint,
+	/// Where the head would have gone.
+	pub attempted: int,
+	/// The instruction moving the head.
+	pub pc: uint,
+	/// The source of the operator moving the head, if the run was mapped.
+	pub span: Option<Span>,
+}
+
+/**
+A runtime error, along with where in the source it happened.
+*/
+#[deriving(Clone, Eq)]
+pub struct LocatedError {
+	pub error: RuntimeError,
+	/// The source of the operator which failed.
+	pub span: Span,
+}
+
+impl fmt::Show for LocatedError {
+	fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+		write!(f.buf, "At {}: {}", self.span, self.error)
+	}
+}
+
+/**
+The result of running a program against one of the inputs of a
+batch, see `Machine::run_batch`.
+*/
+#[deriving(Clone, Eq, Show)]
+pub struct BatchResult {
+	/// Everything the program wrote to its output, even if it failed.
+	pub output: Vec<u8>,
+	/// The cycles executed, even if the run failed.
+	pub cycles: uint,
+	/// Why the run stopped, if it did not end.
+	pub error: Option<RuntimeError>,
+}
+
+impl fmt::Show for RuntimeError {
+	fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+		f.buf.write(match *self {
+			OutputError => "Cannot write to output.",
+			LimitExceeded(CycleLimit) => "Cycle limit exceeded.",
+			LimitExceeded(OutputLimit) => "Output limit exceeded.",
+			LimitExceeded(TimeLimit) => "Time limit exceeded.",
+			CellOverflow => "Cell incremented past its largest value.",
+			CellUnderflow => "Cell decremented past zero.",
+			TapeOutOfBounds => "Head moved past the edges of the tape.",
+			Aborted => "Aborted by the output consumer.",
+			Cancelled(cycles) => return write!(f.buf, "Cancelled after {} cycles.", cycles),
+			MemoryLimitExceeded(bytes, cycles) => {
+				return write!(f.buf, "Memory limit exceeded: the tape took {} bytes after {} cycles.", bytes, cycles)
+			}
+			NestingTooDeep(depth, span) => {
+				return write!(f.buf, "Loops nested too deep: {} levels, from char {}.", depth, span.start)
+			}
+			ClampedAtLeftEdge(pos) => return write!(f.buf, "Head stopped at the origin, moving to {}.", pos),
+			ExtensionDisabled(ref err) => return write!(f.buf, "{}", *err),
+			SnapshotNotResumable(exit) => {
+				return write!(f.buf, "The snapshot after {} loop exits was thinned out, and can not be resumed.", exit)
+			}
+			SnapshotOfAnotherProgram(exit) => {
+				return write!(f.buf, "The snapshot after {} loop exits was taken from another program.", exit)
+			}
+			LoopInTrace(at) => return write!(f.buf, "The trace holds a loop at operator {}, and can not be replayed.", at),
+		}.as_bytes())
+	}
+}
+
+
+/**
+A handle for cancelling the runs of a machine from another task, and
+following their progress.
+
+Tokens are cheap to clone, and every clone controls the same machine.
+Cancelling is cooperative: the machine only notices every so many
+cycles, see `Machine::set_cancel_granularity`, and then fails the
+run with `Cancelled`. A cancellation is consumed by the run it
+stops, so the machine can run again afterwards. The tape, and the
+output so far, are left as they were when the run stopped, so that
+they can still be inspected.
+
+Both the cancellation and the cycle count are shared atomically,
+with sequential consistency. A cancellation is noticed at the next
+check, within the granularity's worth of cycles. The cycle count is
+published at each check too: while running it lags behind by less
+than the granularity, and it is exact once the run halted or was
+cancelled. It is reset when a run starts. Nothing else about the
+machine is published, so its state is only to be looked at from the
+task running it, once the run is over.
+*/
+#[deriving(Clone)]
+pub struct CancelToken {
+	flag: Arc<AtomicBool>,
+	cycles: Arc<AtomicUint>,
+}
+
+impl CancelToken {
+	/// Produces a new token, not cancelled.
+	pub fn new() -> CancelToken {
+		CancelToken { flag: Arc::new(AtomicBool::new(false)), cycles: Arc::new(AtomicUint::new(0)) }
+	}
+
+	/// The cycles executed by the machine's current run, or its last one.
+	pub fn cycles(&self) -> uint {
+		self.cycles.load(SeqCst)
+	}
+
+	/// Asks the current run, or the next one, to stop.
+	pub fn cancel(&self) {
+		self.flag.store(true, SeqCst);
+	}
+
+	/// Whether a cancellation is pending.
+	pub fn is_cancelled(&self) -> bool {
+		self.flag.load(SeqCst)
+	}
+
+	/// Consumes a pending cancellation, telling whether there was one.
+	fn take(&self) -> bool {
+		self.flag.swap(false, SeqCst)
+	}
+
+	/// Publishes the cycles executed so far.
+	fn publish(&self, cycles: uint) {
+		self.cycles.store(cycles, SeqCst);
+	}
+}
+
+
+/**
+A condition on which a resumable run pauses.
+*/
+#[deriving(Clone, Eq, Show)]
+pub enum Breakpoint {
+	/**
+	Pause when the current cell holds the given value. This is only
+	checked after operators which change the cell, so that it stays
+	cheap, and so that a cell holding the value does not keep pausing.
+	*/
+	WhenCellEquals(u8),
+}
+
+/**
+Identifies a condition of a machine, see `Machine::add_condition`.
+*/
+#[deriving(Clone, Eq, Show)]
+pub struct CondId(pub uint);
+
+/**
+Identifies a top-level loop of a program by its position among the
+program's top-level operators, see `Machine::run_profiled`.
+*/
+#[deriving(Clone, Eq, Show)]
+pub struct LoopId(pub uint);
+
+/**
+A condition on which a resumable run pauses, telling which one was
+met, see `Machine::add_condition`. Conditions are checked after
+every instruction which changes something. Those on the head only
+count when they come to hold, so that resuming goes on even if they
+still do.
+*/
+#[deriving(Clone, Eq, Show)]
+pub enum Condition {
+	/// The cell under the head comes to hold the given value.
+	CellUnderHeadEquals(Unit),
+	/// The cell under the head comes to hold more than the given value.
+	CellUnderHeadAbove(Unit),
+	/// Any cell is written the given value, wherever the head is.
+	AnyCellEquals(Unit),
+	/// The head comes to the given cell.
+	HeadAt(int),
+	/// The output ends with the given bytes, however many runs or
+	/// chunks they were written over.
+	OutputContains(~[u8]),
+}
+
+/// A condition added to a machine.
+struct Watch {
+	id: CondId,
+	condition: Condition,
+	/// Whether the condition held after the last instruction checked.
+	held: bool,
+}
+
+/**
+Options for streaming runs, see `Machine::run_streaming`.
+*/
+#[deriving(Clone, Eq, Show)]
+pub struct StreamOptions {
+	/// How many bytes of output to gather before handing them over.
+	pub chunk_size: uint,
+}
+
+impl StreamOptions {
+	/// Chunks of 4KB.
+	pub fn default() -> StreamOptions {
+		StreamOptions { chunk_size: 4096 }
+	}
+}
+
+/**
+An input which is closed: every read immediately hits EOF, so that
+programs reading from it get the EOF value of their semantics
+instead of waiting for input which will never come.
+*/
+pub struct ClosedInput;
+
+impl Reader for ClosedInput {
+	fn read(&mut self, _: &mut [u8]) -> IoResult<uint> {
+		Err(standard_error(EndOfFile))
+	}
+}
+
+/**
+What reading input does when the input has nothing to give yet, but
+is not over either: when it fails with `ResourceUnavailable`, as a
+non-blocking terminal or pipe does.
+
+Either way, input which is over applies the EOF policy of the
+semantics, see `Eof`.
+*/
+#[deriving(Clone, Eq, Show)]
+pub enum InputMode {
+	/**
+	Waits for a byte. Resumable runs stop as `Blocked`, and try the
+	read again once resumed; the other runs keep trying until a byte
+	comes, or the input is over.
+	*/
+	Blocking,
+	/**
+	Takes having nothing to read as EOF, applying the EOF policy of
+	the semantics right away. Input coming later is still read by
+	later reads.
+	*/
+	EofOnEmpty,
+}
+
+/**
+What writing a cell outputs, unless numeric I/O is on, see
+`Machine::set_numeric_io`.
+*/
+#[deriving(Clone, Eq, Show)]
+pub enum OutputMode {
+	/// The cell's value, as a single byte.
+	Raw,
+	/**
+	The UTF-8 encoding of the character whose codepoint is the cell's
+	value. Values which are not codepoints, as surrogates are, write
+	the replacement character `U+FFFD` instead. With 8-bit cells,
+	this writes Latin-1 text as UTF-8.
+	*/
+	Codepoint,
+}
+
+/**
+How line endings are read by `,`, see `Machine::set_newline_mode`,
+and written by `.` to consoles, see `Machine::set_output_newline_mode`.
+*/
+#[deriving(Clone, Eq, Show)]
+pub enum NewlineMode {
+	/// Bytes are read and written as they are.
+	RawNewlines,
+	/**
+	Reading, CRLF and lone CR are read as a single LF, even a CR
+	right before EOF, and without waiting for what follows it.
+	Writing to a console, LF is written as the platform spells it,
+	see `PLATFORM_NEWLINE`.
+	*/
+	TranslateNewlines,
+}
+
+/// How the platform spells newlines on consoles.
+#[cfg(windows)]
+pub static PLATFORM_NEWLINE: &'static [u8] = &[13, 10];
+/// How the platform spells newlines on consoles.
+#[cfg(not(windows))]
+pub static PLATFORM_NEWLINE: &'static [u8] = &[10];
+
+/**
+Whether a resumable run is over, or paused.
+*/
+#[deriving(Clone, Eq, Show)]
+pub enum RunState {
+	/// The program has ended.
+	Halted,
+	/// The run hit a breakpoint, or ran for as long as it was asked
+	/// to, and can be resumed.
+	Paused,
+	/**
+	The program is reading input which is not there yet: its input
+	failed with `ResourceUnavailable`. Resuming tries the read again.
+	*/
+	Blocked,
+	/**
+	The run met one of its conditions, see `Machine::add_condition`:
+	which one, and after how many cycles. It can be resumed.
+	*/
+	ConditionHit(CondId, uint),
+}
+
+/**
+What an instruction did, as told to the step hook, see
+`Machine::on_step`.
+*/
+#[deriving(Clone, Eq, Show)]
+pub struct Step {
+	/// The cycles spent so far, this instruction included.
+	pub cycle: uint,
+	/// Where the instruction is in the bytecode.
+	pub pc: uint,
+	/// Where the run goes on from, which tells whether a jump was taken.
+	pub next: uint,
+	/// The instruction itself.
+	pub instr: Instr,
+	/// Where the tape head was before the instruction.
+	pub from: int,
+	/// Where the tape head is after it.
+	pub to: int,
+	/// The contents of the cell under the head before the instruction.
+	pub before: u8,
+	/// The contents of the cell under the head after it.
+	pub after: u8,
+	/// Whether the instruction read input and hit EOF.
+	pub at_eof: bool,
+}
+
+/**
+The state of a resumable run of a program, made by `Machine::start`.
+
+It holds the program, compiled to bytecode, and the program counter
+telling where the run is at.
+*/
+pub struct Execution {
+	/// The program being run.
+	bytecode: Bytecode,
+	/// The next instruction to execute.
+	pc: uint,
+	/// How many times each instruction was executed, if counting.
+	executed: Option<Vec<uint>>,
+	/// The time spent in each top-level loop, if timing them.
+	timed: Option<LoopTimes>,
+	/// The snapshots taken as top-level loops are left, if taking them.
+	snapshots: Option<Snapshots>,
+	/// Why the program was not compiled, to fail the run with.
+	rejected: Option<RuntimeError>,
+}
+
+/**
+The time spent in top-level loops by a run, in nanoseconds, by the pc
+of the `OpJz` entering them, see `Machine::run_profiled`.
+*/
+struct LoopTimes {
+	spent: Vec<u64>,
+	/// The top-level loop being run, if any, and when it was entered.
+	entered: Option<(uint, u64)>,
+}
+
+impl LoopTimes {
+	/// Notes that the loop entered at the given pc started running, at `now`.
+	fn enter(&mut self, pc: uint, now: u64) {
+		self.entered = Some((pc, now));
+	}
+
+	/// Adds the time spent in the loop running, if any, which is over at `now`.
+	fn leave(&mut self, now: u64) {
+		match self.entered.take() {
+			Some((pc, started)) => *self.spent.get_mut(pc) += now - started,
+			None => {}
+		}
+	}
+}
+
+
+/**
+The state of a run as a top-level loop was left, see
+`Machine::run_with_snapshots`.
+
+Every snapshot tells enough to compare runs: where the head was, how
+far the run went, and a checksum of the tape. Those which still hold
+the whole state of the run can also be resumed, see `resume_from`.
+*/
+#[deriving(Clone, Eq, Show)]
+pub struct Snapshot {
+	/// How many top-level loops the run had left, this one included.
+	pub exit: uint,
+	/// The cycles spent so far.
+	pub cycle: uint,
+	/// Where the tape head was.
+	pub head: int,
+	/// The bytes output so far.
+	pub output_len: uint,
+	/// A hash of the cells which were not zero, and of their positions.
+	pub checksum: u64,
+	/// The cells written last, up to `SNAPSHOT_RECENT_CELLS`, with
+	/// their contents, last written first.
+	pub recent: Vec<(int, Unit)>,
+	/// What resuming needs, unless thinned out.
+	state: Option<SavedState>,
+}
+
+impl Snapshot {
+	/// Whether the run can be resumed from here, see `Machine::resume_from`.
+	pub fn is_resumable(&self) -> bool {
+		self.state.is_some()
+	}
+}
+
+/// The whole state of a run kept by a snapshot.
+#[deriving(Clone, Eq, Show)]
+struct SavedState {
+	/// The signature of the program, which resuming must be given.
+	program: u64,
+	/// Where the run goes on from.
+	pc: uint,
+	/// The cells which were not zero, with their positions.
+	cells: Vec<(int, Unit)>,
+	/// The bytes input so far.
+	read: uint,
+	/// The leftmost and rightmost cells reached so far.
+	reached: (int, int),
+}
+
+/**
+The snapshots taken by a run, and how often they are taken, which
+halves as they are thinned out.
+*/
+struct Snapshots {
+	taken: Vec<Snapshot>,
+	/// The signature of the program being run.
+	program: u64,
+	/// How many top-level loop exits go between two snapshots.
+	every: uint,
+	/// How many top-level loops the run left so far.
+	exits: uint,
+}
+
+impl Snapshots {
+	/// Notes that a top-level loop was left, taking a snapshot if due.
+	fn left_loop(&mut self, machine: &Machine, pc: uint) {
+		self.exits += 1;
+		if self.exits % self.every != 0 { return }
+		// Keeping every other snapshot, those which would have been
+		// taken at twice the interval, frees half the room.
+		if self.taken.len() >= MAX_SNAPSHOTS {
+			let every = self.every * 2;
+			let taken = ::std::mem::replace(&mut self.taken, Vec::new());
+			self.taken = taken.move_iter().filter(|snapshot| snapshot.exit % every == 0).collect();
+			self.every = every;
+			if self.exits % every != 0 { return }
+		}
+		// Likewise, every other snapshot holding the whole state
+		// keeps only what tells it, leaving the last one resumable.
+		if self.taken.iter().filter(|snapshot| snapshot.is_resumable()).count() >= MAX_RESUMABLE_SNAPSHOTS {
+			let mut thinned = true;
+			for snapshot in self.taken.mut_iter().filter(|snapshot| snapshot.is_resumable()) {
+				if thinned { snapshot.state = None }
+				thinned = !thinned;
+			}
+		}
+		self.taken.push(machine.snapshot(self.exits, self.program, pc));
+	}
+}
 
 
 /**
 A brainfuck interpreter machine.
 
 Models the internal state of a Brainfuck machine. It is a simple
-tape machine with a program counter representing the current
-operator being executed in an AST.
+tape machine, executing programs given in the form of an AST, which
+are compiled to bytecode first. The state of a run, that is the
+bytecode and where in it the machine is at, is kept apart in an
+`Execution`, so that runs can be paused and resumed.
+
+How the machine behaves where the language is unspecified, e.g.
+at the end of input, is decided by its `Semantics`.
+
+The machine reads its input from a `Reader` and writes its output
+to a `Writer`, which by default are `stdin` and `stdout`. Output
+can also be captured in memory, see `Machine::capturing`, or
+observed byte by byte, see `Machine::on_output`.
 */
-pub struct Machine {
+pub struct Machine<'a> {
 	/// A tape to be used as the main storage.
-	tape: VectorTape<u8>,
-	/// Program counter pointing at the current operator.
-	pc: uint,
+	tape: ~Tape<Unit>,
+	/// Cycles executed by the current run.
+	cycles: uint,
+	/// Bytes output by the current run.
+	written: uint,
+	/// Bytes input by the current run.
+	read: uint,
+	/// Instructions executed by the current run, by opcode.
+	op_counts: [uint, ..OPCODE_COUNT],
+	/// Instructions executed by the current run, in order, if collected.
+	collected: Option<Vec<Opcode>>,
+	/// Whether the current run executed more than `TRACE_LIMIT` instructions.
+	trace_truncated: bool,
+	/// Where `Get` reads from.
+	input: ~Reader,
+	/// Where `Put` writes to, unless capturing.
+	output: ~Writer,
+	/// Output captured in memory, if capturing.
+	captured: Option<Vec<u8>>,
+	/// The last bytes of output, and how many are kept, if keeping a tail.
+	tail: Option<(RingBuf<u8>, uint)>,
+	/// Reads input instead of the input reader, if set.
+	input_hook: Option<||:'a -> Option<u8>>,
+	/// Called with every output byte.
+	output_hook: Option<|u8|:'a>,
+	/// Called after every instruction executed.
+	step_hook: Option<|&Step|:'a>,
+	/// Called with every cell written, and its new value.
+	write_hook: Option<|int, Unit|:'a>,
+	/// Called with the cycles so far, every so many cycles.
+	progress_hook: Option<(uint, |uint|:'a)>,
+	/// Called with the machine by every `@`.
+	host_hook: Option<|&mut Machine<'a>|:'a>,
+	/// Limits applied to every run.
+	limits: Limits,
+	/// How many bytes the tape can take, if limited.
+	memory_limit: Option<uint>,
+	/// How many loops deep runs may go.
+	max_exec_depth: uint,
+	/// How many loops deep the last run went.
+	max_depth_reached: uint,
+	/// The leftmost and rightmost cells reached by the current run.
+	reached: (int, int),
+	/// The times the current run's head stopped at the origin, if noted.
+	clamps: Option<Vec<ClampEvent>>,
+	/// How many times the current run's head stopped at the origin.
+	clamp_count: uint,
+	/// Whether the head stopping at the origin fails the run.
+	forbid_clamp: bool,
+	/// How many cells from the origin hold arguments, see `set_args`.
+	args_cells: uint,
+	/// The dialect decisions honored by every run.
+	semantics: Semantics,
+	/// Where resumable runs should pause.
+	breakpoints: Vec<Breakpoint>,
+	/// The conditions on which resumable runs pause.
+	conditions: Vec<Watch>,
+	/// The id of the next condition added.
+	next_condition: uint,
+	/// The conditions met by the writes and output of the current instruction.
+	met: Vec<CondId>,
+	/// The last bytes of output, as many as conditions look for at most.
+	recent_output: Vec<u8>,
+	/// Whether `Get` and `Put` deal in decimal numbers instead of bytes.
+	numeric_io: bool,
+	/// What `Put` writes, unless in numeric mode.
+	output_mode: OutputMode,
+	/// Whether `Put` does nothing at all.
+	discard_output: bool,
+	/// Whether `Put` does nothing on a zero cell.
+	skip_null_output: bool,
+	/// What input goes through before `Get` reads it.
+	input_filters: FilterChain,
+	/// Input which came out of the filters, but was not read yet.
+	filtered_input: RingBuf<u8>,
+	/// What output goes through before it is written.
+	output_filters: FilterChain,
+	/// How fast output is written.
+	pacing: Pacing,
+	/// Tells the time for pacing and the time limit, and sleeps for pacing.
+	clock: ~Clock:Send,
+	/// When the current run started, as told by the clock.
+	started_at: u64,
+	/// Bytes paced by the current run.
+	paced: uint,
+	/// The time the current run slept pacing output.
+	paced_ms: u64,
+	/// While streaming, how much captured output makes runs pause.
+	flush_at: Option<uint>,
+	/// Tripped from other tasks to stop the current run.
+	cancel: CancelToken,
+	/// How many cycles go by between checks of the cancel token.
+	cancel_granularity: uint,
+	/// How many cycles are left until the next check of the cancel token.
+	until_check: uint,
+	/// Where the random values of every run start from.
+	seed: u64,
+	/// Draws the random values of the current run.
+	rng: XorShiftRng,
+	/// Whether checkpoints are reported when reached.
+	debug: bool,
+	/// The extensions which programs may use, see `validate`.
+	extensions: ExtensionSet,
+	/// The cycle count at which resumed runs pause, see `resume_for`.
+	pause_at: Option<uint>,
+	/// What reading does when the input is not there yet.
+	input_mode: InputMode,
+	/// How `Get` reads line endings.
+	newline_mode: NewlineMode,
+	/// Whether the last byte read was a CR translated into a LF.
+	after_cr: bool,
+	/// How `Put` writes LF to consoles.
+	output_newline_mode: NewlineMode,
+	/// Whether the output is a console, where newlines are translated.
+	output_console: bool,
+	/// Whether the last read failed for the input not being there yet.
+	input_blocked: bool,
+	/// The digits of a number read in numeric mode before the input blocked.
+	pending_number: Option<uint>,
+	/// Whether the current run went into its tail sprint, see `sprint`.
+	sprinted: bool,
+	/// The positions of the cells written last, last written last, if noted.
+	recent_writes: Option<Vec<int>>,
+	/// The checkpoints reached by the current run, with the cycles
+	/// executed before each.
+	checkpoints: Vec<(~str, uint)>,
 }
 
-impl Machine {
+impl<'a> Machine<'a> {
+
+	// Produce a new pristine machine, wired to `stdin` and `stdout`.
+	pub fn new() -> Machine<'a> {
+		let stdout = stdout_raw();
+		let console = stdout.isatty();
+		let mut machine = Machine::with_io(~stdin_raw(), ~stdout);
+		machine.output_console = console;
+		machine
+	}
 
-	// Produce a new pristine machine.
-	pub fn new() -> Machine {
+	/**
+	Produce a new pristine machine reading from `input` and writing
+	to `output`.
+	*/
+	pub fn with_io(input: ~Reader, output: ~Writer) -> Machine<'a> {
 		Machine {
-			tape: VectorTape::new(),
-			pc: 0,
+			tape: ~VectorTape::new() as ~Tape<Unit>,
+			cycles: 0,
+			written: 0,
+			read: 0,
+			op_counts: [0, ..OPCODE_COUNT],
+			collected: None,
+			trace_truncated: false,
+			input: input,
+			output: output,
+			captured: None,
+			tail: None,
+			input_hook: None,
+			output_hook: None,
+			step_hook: None,
+			write_hook: None,
+			progress_hook: None,
+			host_hook: None,
+			limits: Limits::none(),
+			memory_limit: None,
+			max_exec_depth: DEFAULT_MAX_EXEC_DEPTH,
+			max_depth_reached: 0,
+			reached: (0, 0),
+			clamps: None,
+			clamp_count: 0,
+			forbid_clamp: false,
+			args_cells: 0,
+			semantics: Semantics::common(),
+			breakpoints: Vec::new(),
+			conditions: Vec::new(),
+			next_condition: 0,
+			met: Vec::new(),
+			recent_output: Vec::new(),
+			numeric_io: false,
+			output_mode: Raw,
+			discard_output: false,
+			skip_null_output: false,
+			input_filters: FilterChain::new(),
+			filtered_input: RingBuf::new(),
+			output_filters: FilterChain::new(),
+			pacing: Unpaced,
+			clock: ~SystemClock as ~Clock:Send,
+			started_at: 0,
+			paced: 0,
+			paced_ms: 0,
+			flush_at: None,
+			cancel: CancelToken::new(),
+			cancel_granularity: 4096,
+			until_check: 4096,
+			seed: DEFAULT_SEED,
+			rng: rng_from_seed(DEFAULT_SEED),
+			debug: false,
+			extensions: ExtensionSet::none(),
+			checkpoints: Vec::new(),
+			pause_at: None,
+			input_mode: Blocking,
+			newline_mode: RawNewlines,
+			after_cr: false,
+			output_newline_mode: RawNewlines,
+			output_console: false,
+			input_blocked: false,
+			pending_number: None,
+			sprinted: false,
+			recent_writes: None,
 		}
 	}
 
 	/**
-	Run a program, given in the form of a parsed AST, on this
-	machine's tape. Will return the cycles that have been executed.
+	Produce a new pristine machine doing I/O through closures alone,
+	e.g. where there is no `std::io` to speak of: input is read from
+	`input`, until it returns `None`, and output goes to `output`.
+	See `on_input` and `on_output`.
 	*/
-	pub fn run_program<'a>(&mut self, program: &Ast) -> Result<uint, ~str> {
-		self.pc = 0; // Begin interpreting at the start of the AST.
-		let mut cycles: uint = 0; // Keep track of the executed cycles.
-		let Ast(ref ops) = *program; // Extract the actual ops from the AST.
+	pub fn with_callbacks(input: ||:'a -> Option<u8>, output: |u8|:'a) -> Machine<'a> {
+		let mut machine = Machine::with_io(~ClosedInput, ~NullWriter);
+		machine.on_input(input);
+		machine.on_output(output);
+		machine
+	}
 
-		loop {
-			match ops.get(self.pc) {
-				// Operations on tape. Match tape methods perfectly.
-				Some(&Decr) => { self.tape.mutate( |v|{ *v -= 1; } ); }
-				Some(&Incr) => { self.tape.mutate( |v|{ *v += 1; } ); }
-				Some(&Prev) => { self.tape.wind(-1); }
-				Some(&Next) => { self.tape.wind( 1); }
-				// Reads a single char from `stdin` and replaces the
-				// current cell's contents with it.
-				Some(&Get)  => {
-					let byte_in = stdin_raw().read_u8().ok()
-						.unwrap_or(0); // This machine respects EOF -> 0
-					self.tape.mutate( |v|{ *v = byte_in; } );
-				}
-				// Prints the cell's contents to `stdout` as char.
-				Some(&Put)  => {
-					let byte_out = self.tape.cell().clone();
-					match stdout_raw().write_u8(byte_out) {
-						Ok(_) => { /* nop */ },
-						_ => return Err(~"Cannot not write to stdout."),
-					}
-				}
-				// Executes a sub-AST. If the current cell's value
-				// is zero, the ops in the sub-AST will be executed,
-				// else skipping them entirely.
-				Some(&Sub(ref ast)) => {
-					let pc = self.pc; // Save PC and reset
-					while *self.tape.cell() != 0 {
-						match self.run_program(ast) {
-							Ok(cls) => cycles += cls,
-							Err(msg) => return Err(msg),
-						}
-					}
-					self.pc = pc; // Restore PC
-				}
-				// Unknown. Nop.
-				Some(_) => { /* nop */ },
-				// End of program. Stop execution.
-				_ => break
+	/**
+	Produce a new pristine machine without side effects, for
+	reproducible runs, e.g. when fuzzing: `Get` always reads zero,
+	and `Put` does nothing at all, see `set_discard_output`.
+	*/
+	pub fn pure() -> Machine<'a> {
+		let mut machine = Machine::with_io(~ZeroReader, ~NullWriter);
+		machine.set_discard_output(true);
+		machine
+	}
+
+	/**
+	Produce a new pristine machine wired to `stdin` and `stdout`,
+	following the given semantics instead of the common ones.
+	*/
+	pub fn with_semantics(semantics: Semantics) -> Machine<'a> {
+		let mut machine = Machine::new();
+		machine.set_semantics(semantics);
+		machine
+	}
+
+	/**
+	Produce a new pristine machine reading from the given bytes, and
+	capturing its output in memory instead of writing it anywhere.
+	The output can be retrieved with `captured_output`.
+	*/
+	pub fn capturing(input: &[u8]) -> Machine<'a> {
+		let mut machine = Machine::with_io(~MemReader::new(input.to_owned()), ~NullWriter);
+		machine.captured = Some(Vec::new());
+		machine
+	}
+
+	/**
+	Produce a new pristine machine wired to `stdin` and `stdout`,
+	whose tape is seeded with the given cells, see `preload_tape`.
+	*/
+	pub fn with_initial_tape(cells: &[u8]) -> Machine<'a> {
+		let mut machine = Machine::new();
+		machine.preload_tape(cells);
+		machine
+	}
+
+	/**
+	Produce a new pristine machine wired to `stdin` and `stdout`,
+	using the given tape instead of a `VectorTape`.
+	*/
+	pub fn with_tape(tape: ~Tape<Unit>) -> Machine<'a> {
+		let mut machine = Machine::new();
+		machine.set_tape(tape);
+		machine
+	}
+
+	/**
+	Replaces the tape of this machine, e.g. with a `SparseTape` for
+	programs seeking far and wide. The tape is used as it is, so it
+	should usually be cleared.
+	*/
+	pub fn set_tape(&mut self, tape: ~Tape<Unit>) {
+		self.tape = tape;
+	}
+
+	/**
+	Seeds the tape with the given cells, starting from the origin,
+	e.g. to provide programs with a lookup table. The rest of the
+	tape is zeroed, and the head is left at the origin.
+	*/
+	pub fn preload_tape(&mut self, cells: &[u8]) {
+		self.tape.clear();
+		self.args_cells = 0;
+		for &value in cells.iter() {
+			self.tape.mutate( |v|{ *v = value; } );
+			self.tape.wind(1);
+		}
+		self.tape.wind(-(cells.len() as int));
+	}
+
+	/**
+	Preloads the tape with arguments for the program, as a shell would
+	pass them, see `preload_tape`. The number of arguments goes in the
+	cell at the origin, then the bytes of each argument, followed by a
+	zero, in the cells from 1 on. So `foo` and `ab` make the tape
+	`2 f o o 0 a b 0`, and no arguments at all a zeroed tape. The head
+	is left at the origin.
+
+	Arguments can not hold zeros, which would end them early, and
+	there can not be more of them than a cell holds; these are refused
+	rather than cut short, leaving the tape as it was.
+	*/
+	pub fn set_args(&mut self, args: &[&[u8]]) -> Result<(), ~str> {
+		let max: Unit = Bounded::max_value();
+		if args.len() > max as uint {
+			return Err(format!("Too many arguments for a cell: {}, over {}.", args.len(), max));
+		}
+		let mut cells = vec!(args.len() as Unit);
+		for (i, arg) in args.iter().enumerate() {
+			if arg.contains(&0) {
+				return Err(format!("Argument {} holds a zero byte, which would end it early.", i + 1));
 			}
-			// Track this last cycle and advance to the next operator.
-			cycles += 1;
-			self.pc += 1;
+			cells.push_all(*arg);
+			cells.push(0);
 		}
+		self.preload_tape(cells.as_slice());
+		self.args_cells = cells.len();
+		Ok(())
+	}
 
-		// Everything went well. Just return the stats back.
-		Ok(cycles)
+	/**
+	How many cells from the origin were preloaded with arguments, see
+	`set_args`; the cells past them are the program's own.
+	*/
+	pub fn args_cells(&self) -> uint {
+		self.args_cells
 	}
-}
 
+	/**
+	Replaces the input of this machine. Useful for running another
+	program on the same machine.
+	*/
+	pub fn set_input(&mut self, input: ~Reader) {
+		self.input = input;
+	}
+
+	/**
+	Sets the semantics which will be followed by the next runs.
+	*/
+	pub fn set_semantics(&mut self, semantics: Semantics) {
+		self.semantics = semantics;
+	}
+
+	/**
+	Sets the limits which will be applied to the next runs.
+	*/
+	pub fn set_limits(&mut self, limits: Limits) {
+		self.limits = limits;
+	}
+
+	/**
+	Limits the memory the tape can take, as estimated by
+	`Tape::bytes_allocated`, or lifts the limit with `None`. Runs
+	taking more fail with `MemoryLimitExceeded`.
+
+	The tape is only checked when the head reaches a cell further
+	than any reached before, which is when it may grow, and before
+	the cell is accessed, so the tape may go over the limit by what
+	it allocates at once: a chunk for chunked tapes, or as much as it
+	already took for vector tapes.
+	*/
+	pub fn set_memory_limit(&mut self, bytes: Option<uint>) {
+		self.memory_limit = bytes;
+	}
+
+	/**
+	Sets how many loops deep programs may nest, `DEFAULT_MAX_EXEC_DEPTH`
+	by default, and never more than `ast::MAX_NESTING`, deeper than
+	which compiling them could run out of stack. Runs of programs
+	nesting deeper fail with `NestingTooDeep` instead, before anything
+	is compiled or run. Parsed programs, and those loaded from bytes,
+	never nest deeper than `ast::MAX_NESTING`, but programs built by
+	hand may nest arbitrarily.
+	*/
+	pub fn set_max_exec_depth(&mut self, depth: uint) {
+		self.max_exec_depth = depth;
+	}
+
+	/**
+	Switches numeric I/O on or off. When on, `Get` reads a decimal
+	number into the cell, modulo 256, and `Put` writes the cell as a
+	decimal number followed by a space.
+
+	Blanks before a number are skipped, and the character right after
+	it is consumed as a separator. A newline or anything else found
+	before a digit reads as zero, so that an empty line is a zero.
+	*/
+	pub fn set_numeric_io(&mut self, numeric: bool) {
+		self.numeric_io = numeric;
+	}
+
+	/**
+	Tells what writing a cell outputs, see `OutputMode`. Machines
+	start out `Raw`.
+	*/
+	pub fn set_output_mode(&mut self, mode: OutputMode) {
+		self.output_mode = mode;
+	}
+
+	/**
+	Tells what reading does when the input has nothing to give yet,
+	see `InputMode`. Machines start out `Blocking`.
+	*/
+	pub fn set_input_mode(&mut self, mode: InputMode) {
+		self.input_mode = mode;
+	}
+
+	/**
+	Tells how `,` reads line endings, see `NewlineMode`, before the
+	input filters see them. Machines start out `RawNewlines`.
+	*/
+	pub fn set_newline_mode(&mut self, mode: NewlineMode) {
+		self.newline_mode = mode;
+		self.after_cr = false;
+	}
+
+	/**
+	Tells how `.` writes newlines, see `NewlineMode`, after the output
+	filters, but only when the output is a console, see
+	`set_output_console`. Machines start out `RawNewlines`.
+	*/
+	pub fn set_output_newline_mode(&mut self, mode: NewlineMode) {
+		self.output_newline_mode = mode;
+	}
+
+	/**
+	Tells whether the output is a console, where newlines may be
+	translated. Only `Machine::new()` finds out on its own, from
+	`stdout`: other machines never translate newlines, unless told
+	that their output is a console, which forces it.
+	*/
+	pub fn set_output_console(&mut self, console: bool) {
+		self.output_console = console;
+	}
+
+	/**
+	Seeds the random values set by `?`. Every run starts over from
+	the seed, so that runs are reproducible; the default seed is
+	`DEFAULT_SEED`.
+	*/
+	pub fn seed_rng(&mut self, seed: u64) {
+		self.seed = seed;
+		self.rng = rng_from_seed(seed);
+	}
+
+	/**
+	The seed of the random values set by `?`, to reproduce runs.
+	*/
+	pub fn rng_seed(&self) -> u64 {
+		self.seed
+	}
+
+	/**
+	Makes `Put` do nothing at all, or not. Discarded output is not
+	written, captured, nor counted, and the output hook and limit are
+	not minded. It still costs a cycle.
+	*/
+	pub fn set_discard_output(&mut self, discard: bool) {
+		self.discard_output = discard;
+	}
+
+	/**
+	Notes every time the head stops at the origin, moving left of it,
+	or not. A head clamped there changes what programs do, as tapes
+	with room on the left, or wrapping around, would have let it go
+	on, so this tells which programs are not portable even when they
+	run fine. The first `CLAMP_EVENT_LIMIT` times are noted, see
+	`clamp_events`, and all of them counted, see `clamp_count`.
+
+	Only moves are noted, not offset operators reaching left of the
+	origin without moving there. Tapes wrapping around never clamp,
+	nor do tapes with room on the left, see `Tape::left_edge`, and
+	centered ones stop the head at their edge rather than the origin.
+	*/
+	pub fn set_track_clamps(&mut self, track: bool) {
+		self.clamps = if track { Some(Vec::new()) } else { None };
+	}
+
+	/**
+	Makes the head stopping at the origin, moving left of it, fail
+	the run with `ClampedAtLeftEdge`, or not. Unlike `FailAtEdges`
+	semantics, moving past the right edge of a tape of fixed size
+	still stops there.
+	*/
+	pub fn set_forbid_clamp(&mut self, forbid: bool) {
+		self.forbid_clamp = forbid;
+	}
+
+	/**
+	The times the last run's head stopped at the origin, moving left
+	of it, in order, if noted, see `set_track_clamps`. Runs mapped to
+	their source tell where in it each move was, see `run_mapped`.
+	*/
+	pub fn clamp_events<'b>(&'b self) -> &'b [ClampEvent] {
+		match self.clamps {
+			Some(ref events) => events.as_slice(),
+			None => &[],
+		}
+	}
+
+	/**
+	How many times the last run's head stopped at the origin, if
+	noted, including those past `CLAMP_EVENT_LIMIT`.
+	*/
+	pub fn clamp_count(&self) -> uint {
+		self.clamp_count
+	}
+
+	/**
+	Makes `Put` do nothing on a zero cell, or not, e.g. for sinks
+	which take NUL as the end of the output. Skipped output is
+	discarded like with `set_discard_output`, whatever the output mode,
+	so numeric output skips zeros too.
+	*/
+	pub fn set_skip_null_output(&mut self, skip: bool) {
+		self.skip_null_output = skip;
+	}
+
+	/**
+	Passes input through a filter before `Get` reads it, after any
+	filters added before. Filters dropping bytes make reads go on to
+	the next byte, so dropping the last one makes EOF come a read
+	earlier, and filters ending the stream make it come at once.
+	*/
+	pub fn add_input_filter(&mut self, filter: Filter) {
+		self.input_filters.push(filter);
+	}
+
+	/**
+	Passes output through a filter before it is written, after any
+	filters added before. The output limit counts bytes coming out of
+	the filters, e.g. both bytes `CrLf` makes of a line feed.
+	*/
+	pub fn add_output_filter(&mut self, filter: Filter) {
+		self.output_filters.push(filter);
+	}
+
+	/**
+	Paces output like a slow device would, sleeping before the bytes
+	which come too soon, e.g. for animations. Bytes refused by the
+	output limit are not waited for. Paced output is flushed after
+	every byte, so that it is seen at the pace it was written.
+
+	The time slept is not counted by the time limit, see
+	`Limits::time`, nor by anything else but `paced_ms`.
+	*/
+	pub fn set_output_pacing(&mut self, pacing: Pacing) {
+		self.pacing = pacing;
+	}
+
+	/**
+	Replaces the clock output is paced on, and runs are timed by,
+	e.g. with a `MockClock` not to actually sleep.
+	*/
+	pub fn set_clock(&mut self, clock: ~Clock:Send) {
+		self.clock = clock;
+	}
+
+	/**
+	How long the last run slept pacing output, in milliseconds.
+	*/
+	pub fn paced_ms(&self) -> u64 {
+		self.paced_ms
+	}
+
+	/**
+	Sets the extensions which programs run by `run_program` may use,
+	see `validate`. None are enabled by default, as in standard
+	programs.
+	*/
+	pub fn set_extensions(&mut self, extensions: ExtensionSet) {
+		self.extensions = extensions;
+	}
+
+	/// The extensions enabled on this machine, see `set_extensions`.
+	pub fn active_extensions(&self) -> ExtensionSet {
+		self.extensions.clone()
+	}
+
+	/**
+	Checks that a program only uses the extensions enabled on this
+	machine, or tells the first operator needing one which is not.
+	Every run does so before running anything, and fails with
+	`ExtensionDisabled` if not.
+	*/
+	pub fn validate(&self, program: &Ast) -> Result<(), ValidationError> {
+		self.extensions.check(program)
+	}
+
+	/**
+	Switches debug mode on or off. In debug mode, every `#name`
+	checkpoint reached is collected, see `checkpoints`;
+	otherwise checkpoints are ignored like comments.
+	*/
+	pub fn set_debug(&mut self, debug: bool) {
+		self.debug = debug;
+	}
+
+	/**
+	Switches collecting traces on or off. When on, each run collects
+	the opcode of every instruction it executes, in order, see
+	`collected_trace`. Unlike `op_counts`, this keeps the order, but
+	takes memory: a byte per instruction, up to `TRACE_LIMIT`.
+	*/
+	pub fn set_trace_collect(&mut self, collect: bool) {
+		self.collected = if collect { Some(Vec::new()) } else { None };
+	}
+
+	/**
+	Adds a breakpoint, at which resumable runs will pause.
+	*/
+	pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) {
+		self.breakpoints.push(breakpoint);
+	}
+
+	/**
+	Removes every breakpoint.
+	*/
+	pub fn clear_breakpoints(&mut self) {
+		self.breakpoints.clear();
+	}
+
+	/**
+	Adds a condition, on which resumable runs will pause with
+	`ConditionHit`. When several are met at once, the first added
+	is told. Without conditions, runs pay next to nothing for them.
+	*/
+	pub fn add_condition(&mut self, condition: Condition) -> CondId {
+		let id = CondId(self.next_condition);
+		self.next_condition += 1;
+		self.conditions.push(Watch { id: id, condition: condition, held: false });
+		id
+	}
+
+	/**
+	Removes a condition, telling whether there was one by this id.
+	*/
+	pub fn remove_condition(&mut self, id: CondId) -> bool {
+		let before = self.conditions.len();
+		self.conditions.retain(|watch| watch.id != id);
+		self.conditions.len() < before
+	}
+
+	/**
+	Removes every condition.
+	*/
+	pub fn clear_conditions(&mut self) {
+		self.conditions.clear();
+	}
+
+	/**
+	A token with which other tasks can cancel this machine's runs.
+	*/
+	pub fn cancel_token(&self) -> CancelToken {
+		self.cancel.clone()
+	}
+
+	/**
+	Replaces the token cancelling this machine's runs, e.g. to share
+	a single one between several machines.
+	*/
+	pub fn set_cancel_token(&mut self, token: CancelToken) {
+		self.cancel = token;
+	}
+
+	/**
+	Sets how many cycles go by between checks of the cancel token.
+	The default of 4096 is fast to respond, taking well under a
+	millisecond, but costs next to nothing.
+	*/
+	pub fn set_cancel_granularity(&mut self, cycles: uint) {
+		self.cancel_granularity = if cycles == 0 { 1 } else { cycles };
+		self.until_check = self.cancel_granularity;
+	}
+
+	/**
+	Registers a hook to read input from instead of the input reader,
+	replacing the previous one if any. The hook returns `None` at EOF,
+	and is read through the input filters like any input.
+	*/
+	pub fn on_input(&mut self, hook: ||:'a -> Option<u8>) {
+		self.input_hook = Some(hook);
+	}
+
+	/**
+	Registers a hook to be called with every byte the program
+	outputs, replacing the previous one if any. The hook is called
+	first, then the byte is written or captured as usual. Bytes
+	refused because of the output limit are never seen by the hook.
+	*/
+	pub fn on_output(&mut self, hook: |u8|:'a) {
+		self.output_hook = Some(hook);
+	}
+
+	/**
+	Registers a hook to be called with the position and new value of
+	every cell written, replacing the previous one if any. Cells are
+	written by `+` and `-`, `,` unless EOF leaves the cell unchanged,
+	and by `?`, `=N` and the optimized operators, once per cell.
+
+	The hook is called as soon as the cell is written, before the step
+	hook of the instruction. No instruction both writes a cell and
+	outputs, so this hook and the output hook are called in the order
+	of the instructions.
+	*/
+	pub fn on_cell_write(&mut self, hook: |int, Unit|:'a) {
+		self.write_hook = Some(hook);
+	}
+
+	/**
+	Registers a hook to be called after every instruction executed,
+	with what it did, replacing the previous one if any. Jumps count
+	as instructions, and so do comments even when they cost nothing.
+	*/
+	pub fn on_step(&mut self, hook: |&Step|:'a) {
+		self.step_hook = Some(hook);
+	}
+
+	/**
+	Registers a hook to be called with the cycles run so far, every
+	`every` cycles, replacing the previous one if any, e.g. to report
+	progress against `analysis::estimate_trip_counts`.
+	*/
+	pub fn on_progress(&mut self, every: uint, hook: |uint|:'a) {
+		self.progress_hook = Some((if every == 0 { 1 } else { every }, hook));
+	}
+
+	/**
+	Registers a hook to be called with the machine by every `@`,
+	replacing the previous one if any, so that the host can inspect
+	and change the tape, e.g. with `current_cell` and
+	`set_current_cell`. Without a hook, `@` does nothing but cost a
+	cycle. `@` is an extension, see `features::HostCalls`.
+	*/
+	pub fn on_host_call(&mut self, hook: |&mut Machine<'a>|:'a) {
+		self.host_hook = Some(hook);
+	}
+
+	/**
+	The output captured so far. Always empty when not capturing.
+	*/
+	pub fn captured_output<'b>(&'b self) -> &'b [u8] {
+		match self.captured {
+			Some(ref bytes) => bytes.as_slice(),
+			None => &[],
+		}
+	}
+
+	/**
+	Keeps only the last `size` bytes of output, if any, in place of
+	writing or capturing it, dropping what came before. This is for
+	programs writing more than is worth keeping, but for how they
+	ended. Setting it again empties the tail; `None` goes back to
+	writing or capturing output.
+	*/
+	pub fn set_output_tail(&mut self, size: Option<uint>) {
+		self.tail = size.map(|size| (RingBuf::with_capacity(size), size));
+	}
+
+	/**
+	The last bytes of output kept, oldest first, see `set_output_tail`.
+	Always empty when not keeping a tail.
+	*/
+	pub fn output_tail(&self) -> Vec<u8> {
+		match self.tail {
+			Some((ref bytes, _)) => bytes.iter().map(|&byte| byte).collect(),
+			None => Vec::new(),
+		}
+	}
+
+	/**
+	Takes the output captured so far, leaving the capture empty.
+	*/
+	pub fn take_captured_output(&mut self) -> Vec<u8> {
+		match self.captured {
+			Some(ref mut bytes) => ::std::mem::replace(bytes, Vec::new()),
+			None => Vec::new(),
+		}
+	}
+
+	/**
+	The contents of the cell under the head.
+	*/
+	pub fn current_cell(&self) -> u8 {
+		self.tape.peek(self.tape.position())
+	}
+
+	/**
+	Sets the contents of the cell under the head, as if the program
+	wrote it, e.g. from a host call, see `on_host_call`.
+	*/
+	pub fn set_current_cell(&mut self, value: u8) {
+		self.tape.mutate( |v|{ *v = value; } );
+		self.wrote(0);
+	}
+
+	/**
+	The cycles executed by the last run, even if it failed.
+	*/
+	pub fn cycles(&self) -> uint {
+		self.cycles
+	}
+
+	/**
+	How many more cycles the last run could have executed under the
+	cycle limit, or `None` without one. While paused, this is what is
+	left to the rest of the run. A run going over the limit leaves
+	none.
+	*/
+	pub fn remaining_cycles(&self) -> Option<uint> {
+		self.limits.cycles.map(|max| if self.cycles < max { max - self.cycles } else { 0 })
+	}
+
+	/**
+	The bytes read and written by the last run, even if it failed.
+	*/
+	pub fn bytes_io(&self) -> (uint, uint) {
+		(self.read, self.written)
+	}
+
+	/**
+	The instructions executed by the last run, even if it failed,
+	indexed by opcode. See `bytecode::OPCODES`.
+	*/
+	pub fn op_counts<'b>(&'b self) -> &'b [uint] {
+		self.op_counts.as_slice()
+	}
+
+	/**
+	The opcodes of the instructions executed by the last run, even if
+	it failed, in order, when collecting traces. Runs going past
+	`TRACE_LIMIT` instructions only have their first ones collected,
+	see `trace_truncated`.
+	*/
+	pub fn collected_trace<'b>(&'b self) -> &'b [Opcode] {
+		match self.collected {
+			Some(ref trace) => trace.as_slice(),
+			None => &[],
+		}
+	}
+
+	/// Whether the last run executed more instructions than were collected.
+	pub fn trace_truncated(&self) -> bool {
+		self.trace_truncated
+	}
+
+	/**
+	How many loops deep the last run went, even if it failed.
+	*/
+	pub fn max_depth_reached(&self) -> uint {
+		self.max_depth_reached
+	}
+
+	/**
+	How many cells the tape has allocated.
+	*/
+	pub fn allocated_cells(&self) -> uint {
+		self.tape.cells().len()
+	}
+
+	/**
+	The tape, for inspecting its cells, e.g. after a run.
+	*/
+	pub fn tape<'b>(&'b self) -> &'b Tape<Unit> {
+		&*self.tape
+	}
+
+	/**
+	Produces a report of the tape's contents from `start` inclusive
+	to `end` exclusive.
+	*/
+	pub fn tape_report(&self, range: (int, int)) -> TapeReport {
+		TapeReport::new(&*self.tape, range)
+	}
+
+	/**
+	The smallest range of cells containing the origin, the head, and
+	every cell which is not zero.
+	*/
+	pub fn touched_range(&self) -> (int, int) {
+		TapeReport::touched_range(&*self.tape)
+	}
+
+	/**
+	The values of the cells from `start` inclusive to `end` exclusive,
+	by position, the origin being where the head started. Either end
+	can be left open, to go from, or up to, the edge of the touched
+	range, see `touched_range`: `(None, None)` dumps all of it.
+	*/
+	pub fn dump_tape(&self, (start, end): (Option<int>, Option<int>)) -> Vec<Unit> {
+		let (low, high) = self.touched_range();
+		dump(&*self.tape, (start.unwrap_or(low), end.unwrap_or(high)))
+	}
+
+	/**
+	How many cells of the touched range are not zero, which for
+	programs meant to clean up after themselves should be none.
+	*/
+	pub fn nonzero_cell_count(&self) -> uint {
+		self.tape_report(self.touched_range()).nonzero
+	}
+
+	/**
+	The checkpoints reached by the last run in debug mode, in order,
+	with how many cycles were executed before reaching each.
+	*/
+	pub fn checkpoints<'b>(&'b self) -> &'b [(~str, uint)] {
+		self.checkpoints.as_slice()
+	}
+
+	/**
+	Brings the machine back to a pristine state, with a zeroed tape
+	and the head at the origin. I/O and limits are kept, but any
+	captured output is discarded.
+	*/
+	pub fn reset(&mut self) {
+		self.tape.clear();
+		self.args_cells = 0;
+		self.cycles = 0;
+		self.written = 0;
+		self.read = 0;
+		self.op_counts = [0, ..OPCODE_COUNT];
+		self.input_filters.restart();
+		self.filtered_input.clear();
+		self.after_cr = false;
+		self.output_filters.restart();
+		self.take_captured_output();
+	}
+
+	/**
+	Run a program, given in the form of a parsed AST, on this
+	machine's tape. Will return the cycles that have been executed.
+
+	Every operator costs a cycle, and so does every check of a loop's
+	condition: a loop whose body runs N times costs N+1 cycles on top
+	of its body's. This way, even empty loops count towards limits.
+
+	Breakpoints do not stop this kind of run; use `start` and
+	`resume` to be able to pause at them. Programs using extensions
+	not enabled on this machine fail before running, see `validate`.
+	*/
+	pub fn run_program(&mut self, program: &Ast) -> Result<uint, RuntimeError> {
+		let mut execution = self.start(program);
+		self.finish(&mut execution)
+	}
+
+	/**
+	Runs a program held in an arena, like `run_program` does, without
+	converting it to an AST first.
+	*/
+	pub fn run_arena(&mut self, program: &ProgramArena) -> Result<uint, RuntimeError> {
+		let mut execution = self.start_bytecode(Bytecode::compile_arena(program));
+		self.finish(&mut execution)
+	}
+
+	/**
+	Replays a recorded trace, e.g. one from `trace::record_ops`, on
+	this machine's tape: the operators are run one after the other,
+	brackets costing a cycle each but never jumping, so that it takes
+	as many cycles as the run recorded did.
+
+	Each `,` in the trace reads the next byte of `input`, then the end
+	of input once there are none left, like the semantics have it.
+	Input hooks still come first. Afterwards, the machine gets back its
+	own input. Traces holding whole loops can not be replayed, and
+	fail with `LoopInTrace` before running anything.
+	*/
+	pub fn replay(&mut self, trace: &[Operator], input: &[u8]) -> Result<uint, RuntimeError> {
+		let bytecode = try!(self.compile_linear(trace));
+		let reader = ~MemReader::new(input.to_owned()) as ~Reader;
+		let saved = ::std::mem::replace(&mut self.input, reader);
+		let mut execution = self.start_bytecode(bytecode);
+		let result = self.finish(&mut execution);
+		self.input = saved;
+		result
+	}
+
+	/**
+	Runs a program against each of many inputs, e.g. a test suite,
+	under the given limits. The program is compiled only once, and
+	this machine's tape cleared, rather than a new machine built,
+	before each run. Output is captured, and one input failing does
+	not stop the others from running. The results are in the order of
+	the inputs.
+
+	Afterwards, the machine gets back its own limits, input and output.
+	See `pool::run_batch_parallel` to spread a batch over several tasks.
+	*/
+	pub fn run_batch(&mut self, program: &Ast, inputs: &[&[u8]], limits: Limits) -> Vec<BatchResult> {
+		let bytecode = match self.compile(program) {
+			Ok(bytecode) => bytecode,
+			Err(err) => return inputs.iter().map(|_| BatchResult { output: Vec::new(), cycles: 0, error: Some(err.clone()) }).collect(),
+		};
+		let limits = ::std::mem::replace(&mut self.limits, limits);
+		let input = ::std::mem::replace(&mut self.input, ~ClosedInput as ~Reader);
+		let captured = ::std::mem::replace(&mut self.captured, Some(Vec::new()));
+
+		let mut results = Vec::with_capacity(inputs.len());
+		for bytes in inputs.iter() {
+			self.reset();
+			self.input = ~MemReader::new(bytes.to_owned());
+			let mut execution = self.start_bytecode(bytecode.clone());
+			let error = self.finish(&mut execution).err();
+			results.push(BatchResult { output: self.take_captured_output(), cycles: self.cycles, error: error });
+		}
+
+		self.limits = limits;
+		self.input = input;
+		self.captured = captured;
+		results
+	}
+
+	/**
+	Runs each of many programs against its own input, e.g. a corpus
+	of tests, like `run_batch` does with many inputs: on a cleared
+	tape each, with output captured, one failing not stopping the
+	others. The results are the output of each program, or why it
+	failed, in the order of the programs. Runs are held to this
+	machine's limits.
+
+	Afterwards, the machine gets back its own input and output.
+	*/
+	pub fn run_corpus(&mut self, programs: &[(Ast, ~str)]) -> Vec<Result<Vec<u8>, RuntimeError>> {
+		let input = ::std::mem::replace(&mut self.input, ~ClosedInput as ~Reader);
+		let captured = ::std::mem::replace(&mut self.captured, Some(Vec::new()));
+
+		let mut results = Vec::with_capacity(programs.len());
+		for &(ref program, ref bytes) in programs.iter() {
+			self.reset();
+			self.input = ~MemReader::new(bytes.as_bytes().to_owned());
+			let result = self.run_program(program);
+			let output = self.take_captured_output();
+			results.push(result.map(|_| output));
+		}
+
+		self.input = input;
+		self.captured = captured;
+		results
+	}
+
+	/**
+	Resumes a run until the program ends, through any breakpoint.
+	*/
+	fn finish(&mut self, execution: &mut Execution) -> Result<uint, RuntimeError> {
+		loop {
+			match try!(self.resume(execution)) {
+				Halted => break,
+				Paused | ConditionHit(..) => continue,
+				// Give the input some time to come.
+				Blocked => sleep(1),
+			}
+		}
+
+		// Everything went well. Just return the stats back.
+		Ok(self.cycles)
+	}
+
+	/**
+	Runs a program like `run_program` does, locating errors in the
+	source with a map of the program, e.g. from `Ast::optimize_mapped`.
+	*/
+	pub fn run_mapped(&mut self, program: &Ast, map: &SourceMap) -> Result<uint, LocatedError> {
+		let spans = map.instruction_spans(program);
+		let mut execution = self.start(program);
+		let result;
+		loop {
+			match self.resume(&mut execution) {
+				Ok(Halted) => { result = Ok(self.cycles); break }
+				Ok(Paused) | Ok(ConditionHit(..)) => continue,
+				Ok(Blocked) => sleep(1),
+				// Failing instructions are never stepped over.
+				Err(error) => { result = Err(LocatedError { error: error, span: spans[execution.pc] }); break }
+			}
+		}
+		// Tell where the head was moved from when it stopped at the origin.
+		for events in self.clamps.mut_iter() {
+			for event in events.mut_iter() {
+				event.span = Some(spans[event.pc]);
+			}
+		}
+		result
+	}
+
+	/**
+	Runs a program like `run_program` does, counting how many times
+	each character of its source was executed, given a map of the
+	program, e.g. from `Ast::optimize_mapped`. The counts go as far as
+	the run went, even if it failed.
+	*/
+	pub fn run_heatmap(&mut self, program: &Ast, map: &SourceMap, source: &str) -> (Heatmap, Result<uint, RuntimeError>) {
+		let mut execution = self.start(program);
+		execution.executed = Some(Vec::from_elem(execution.bytecode.code.len(), 0u));
+		let result = self.finish(&mut execution);
+		let executed = execution.executed.take_unwrap();
+		(Heatmap::of(program, map, executed.as_slice(), source.char_len()), result)
+	}
+
+	/**
+	Runs a program like `run_program` does, timing each of its
+	top-level loops, with the time spent in every run of it added up,
+	in nanoseconds. Returns the time of every top-level `[...]` loop,
+	in the order of the program, along with how the run went; if it
+	failed in a loop, the time until then counts.
+
+	Each top-level loop run costs two clock readings, and each jump a
+	check of whether timing is on, so this stays cheap unless the
+	program runs short top-level loops over and over. The time is told
+	by the machine's clock, see `set_clock`, which is wall-clock time
+	by default, including that spent waiting for input.
+	*/
+	pub fn run_profiled(&mut self, program: &Ast) -> (Vec<(LoopId, u64)>, Result<uint, RuntimeError>) {
+		let mut execution = self.start(program);
+		execution.timed = Some(LoopTimes {
+			spent: Vec::from_elem(execution.bytecode.code.len(), 0u64),
+			entered: None,
+		});
+		let result = self.finish(&mut execution);
+		let mut times = execution.timed.take_unwrap();
+		times.leave(self.clock.now_ns());
+
+		// Top-level loops are entered by the top-level `OpJz`, in order.
+		let &Ast(ref ops) = program;
+		let ids = ops.iter().enumerate().filter_map(|(i, op)| match *op {
+			Sub(_) => Some(LoopId(i)),
+			_ => None,
+		});
+		let code = execution.bytecode.code.as_slice();
+		let depths = execution.bytecode.depths.as_slice();
+		let pcs = range(0, code.len()).filter(|&pc| code[pc].opcode == OpJz && depths[pc] == 0);
+		(ids.zip(pcs).map(|(id, pc)| (id, *times.spent.get(pc))).collect(), result)
+	}
+
+	/**
+	Runs a program like `run_program` does, taking a snapshot of the
+	run every `every` times it leaves a top-level loop, whether its
+	body ran or not, e.g. to bisect where a long run goes wrong.
+	Returns the snapshots, in order, along with how the run went.
+
+	Runs of two versions of a program can be told apart by comparing
+	the checksums of their snapshots: the first which differ tell the
+	top-level loop exit after which the runs went different ways.
+
+	So that long runs stay within bounds, a run keeps at most
+	`MAX_SNAPSHOTS` snapshots: once there are that many, every other
+	one is dropped, and snapshots are taken half as often from then
+	on. Only `MAX_RESUMABLE_SNAPSHOTS` of them keep the whole state
+	of the run, the others being thinned out the same way, so that
+	the last ones taken are the most likely to be resumable; thinned
+	out snapshots only keep what tells them, to compare them, and can
+	not be resumed.
+	*/
+	pub fn run_with_snapshots(&mut self, program: &Ast, every: uint) -> (Vec<Snapshot>, Result<uint, RuntimeError>) {
+		let mut execution = self.start(program);
+		execution.snapshots = Some(Snapshots {
+			taken: Vec::new(),
+			program: program.signature(),
+			every: max(every, 1),
+			exits: 0,
+		});
+		self.recent_writes = Some(Vec::new());
+		let result = self.finish(&mut execution);
+		self.recent_writes = None;
+		(execution.snapshots.take_unwrap().taken, result)
+	}
+
+	/**
+	Runs a program from a snapshot of an earlier run of it, as if
+	that run went on, see `run_with_snapshots`. Returns the cycles
+	executed, counting those before the snapshot, like `run_program`.
+
+	The tape, and how many cycles and bytes of I/O the run counted,
+	are restored, so limits go on applying as they would have. Input
+	is read on from the machine's own, and random values start over
+	from its seed, so runs of programs using either should be given
+	what they would have had at this point.
+
+	Fails with `SnapshotNotResumable` if the snapshot was thinned out,
+	see `Snapshot::is_resumable`, or with `SnapshotOfAnotherProgram`
+	if it was taken from another program, running nothing either way.
+	*/
+	pub fn resume_from(&mut self, snapshot: &Snapshot, program: &Ast) -> Result<uint, RuntimeError> {
+		let state = match snapshot.state {
+			Some(ref state) => state,
+			None => return Err(SnapshotNotResumable(snapshot.exit)),
+		};
+		if state.program != program.signature() {
+			return Err(SnapshotOfAnotherProgram(snapshot.exit));
+		}
+		let mut execution = self.start(program);
+		match execution.rejected {
+			Some(ref err) => return Err(err.clone()),
+			None => {}
+		}
+		self.tape.clear();
+		for &(position, value) in state.cells.iter() {
+			let offset = position - self.tape.position();
+			self.tape.wind(offset);
+			self.tape.mutate( |v|{ *v = value; } );
+		}
+		let offset = snapshot.head - self.tape.position();
+		self.tape.wind(offset);
+		self.cycles = snapshot.cycle;
+		self.written = snapshot.output_len;
+		self.read = state.read;
+		self.reached = state.reached;
+		self.cancel.publish(self.cycles);
+		execution.pc = state.pc;
+		self.finish(&mut execution)
+	}
+
+	/**
+	Runs a program like `run_program` does, but hands its output
+	over in chunks while it runs, instead of writing it.
+
+	The `chunk` callback is called whenever at least
+	`options.chunk_size` bytes of output were gathered, with all of
+	them, and whenever the run pauses at a breakpoint, with whatever
+	was gathered by then. It can return false to stop the run, which
+	then fails with `Aborted`. Once the run is over, whether it ended
+	or failed, the callback is called one last time with any output
+	left, so that no output is ever lost; its result is ignored then.
+
+	Output limits apply as usual, so the callback never gets more
+	bytes than allowed. The output hook is still called, but nothing
+	is captured, nor written to the output.
+	*/
+	pub fn run_streaming(&mut self, program: &Ast, chunk: |&[u8]| -> bool,
+	                     options: StreamOptions) -> Result<uint, RuntimeError> {
+		let saved = ::std::mem::replace(&mut self.captured, Some(Vec::new()));
+		self.flush_at = Some(options.chunk_size);
+
+		let mut execution = self.start(program);
+		let mut result;
+		loop {
+			result = self.resume(&mut execution);
+			let bytes = self.take_captured_output();
+			let go_on = bytes.is_empty() || chunk(bytes.as_slice());
+			match result {
+				Ok(Paused) | Ok(ConditionHit(..)) if go_on => continue,
+				// Give the input some time to come.
+				Ok(Blocked) if go_on => sleep(1),
+				Ok(Paused) | Ok(Blocked) | Ok(ConditionHit(..)) => { result = Err(Aborted); break }
+				_ => break,
+			}
+		}
+
+		self.captured = saved;
+		self.flush_at = None;
+		result.map(|_| self.cycles)
+	}
+
+	/**
+	Starts a resumable run of a program, which will not execute
+	anything until resumed. Runs of programs nesting too deep, see
+	`set_max_exec_depth`, or using extensions not enabled on this
+	machine, see `validate`, fail as soon as resumed.
+	*/
+	pub fn start(&mut self, program: &Ast) -> Execution {
+		match self.compile(program) {
+			Ok(bytecode) => self.start_bytecode(bytecode),
+			Err(err) => {
+				let mut execution = self.start_bytecode(Bytecode::compile(&Ast(~[])));
+				execution.rejected = Some(err);
+				execution
+			}
+		}
+	}
+
+	/**
+	Compiles a program, unless it nests loops deeper than allowed,
+	see `set_max_exec_depth`, or uses extensions not enabled, see
+	`validate`. Checking once, here, keeps compiling and every other
+	walk of the program from going too deep, and every run from
+	running operators it should not.
+	*/
+	fn compile(&self, program: &Ast) -> Result<Bytecode, RuntimeError> {
+		match program.nesting(min(self.max_exec_depth, MAX_NESTING)) {
+			(depth, Some(span)) => return Err(NestingTooDeep(depth, span)),
+			(_, None) => {}
+		}
+		try!(self.validate(program).map_err(|err| ExtensionDisabled(err)));
+		Ok(Bytecode::compile(program))
+	}
+
+	/// Compiles a trace to replay, checking it like `compile` does programs.
+	fn compile_linear(&self, trace: &[Operator]) -> Result<Bytecode, RuntimeError> {
+		let bytecode = try!(Bytecode::compile_linear(trace).map_err(|at| LoopInTrace(at)));
+		try!(self.extensions.check_ops(trace).map_err(|err| ExtensionDisabled(err)));
+		Ok(bytecode)
+	}
+
+	/// Starts a resumable run of a compiled program.
+	fn start_bytecode(&mut self, bytecode: Bytecode) -> Execution {
+		self.cycles = 0; // Keep track of the executed cycles.
+		self.written = 0; // And of the output bytes.
+		self.read = 0; // And of the input bytes.
+		self.op_counts = [0, ..OPCODE_COUNT];
+		self.sprinted = false;
+		self.pending_number = None;
+		for trace in self.collected.mut_iter() {
+			trace.clear();
+		}
+		self.trace_truncated = false;
+		self.max_depth_reached = 0;
+		for events in self.clamps.mut_iter() {
+			events.clear();
+		}
+		self.clamp_count = 0;
+		for watch in self.conditions.mut_iter() {
+			watch.held = false;
+		}
+		self.met.clear();
+		self.recent_output.clear();
+		self.rng = rng_from_seed(self.seed);
+		self.checkpoints.clear();
+		let position = self.tape.position();
+		self.reached = (position, position);
+		self.cancel.publish(0);
+		self.until_check = self.cancel_granularity;
+		self.started_at = if self.limits.time.is_some() || self.pacing != Unpaced { self.clock.now() } else { 0 };
+		self.paced = 0;
+		self.paced_ms = 0;
+		Execution { bytecode: bytecode, pc: 0, executed: None, timed: None, snapshots: None, rejected: None }
+	}
+
+	/**
+	Resumes a run, until the program ends or a breakpoint is hit.
+	The machine's state is left untouched while paused, so it can be
+	inspected before resuming again.
+	*/
+	pub fn resume(&mut self, execution: &mut Execution) -> Result<RunState, RuntimeError> {
+		let code = execution.bytecode.code.as_slice();
+		let writes = execution.bytecode.writes.as_slice();
+		let names = execution.bytecode.checkpoints.as_slice();
+		let depths = execution.bytecode.depths.as_slice();
+		match execution.rejected {
+			Some(ref err) => return Err(err.clone()),
+			None => {}
+		}
+		loop {
+			let instr = match code.get(execution.pc) {
+				Some(instr) => instr,
+				// End of program. Stop execution.
+				None => {
+					self.cancel.publish(self.cycles);
+					return Ok(Halted);
+				}
+			};
+			// Past its last jump, a program can run without the
+			// bookkeeping below, when nothing is there to see it.
+			if execution.pc >= execution.bytecode.tail && self.can_sprint(execution) {
+				return self.sprint(code, &mut execution.pc);
+			}
+			// Or pause, if the time given to this run is up.
+			match self.pause_at {
+				Some(at) if self.cycles >= at => return Ok(Paused),
+				_ => {}
+			}
+			self.op_counts[instr.opcode as uint] += 1;
+			match self.collected {
+				Some(ref mut trace) if trace.len() < TRACE_LIMIT => trace.push(instr.opcode),
+				Some(_) => self.trace_truncated = true,
+				None => {}
+			}
+			match execution.executed {
+				Some(ref mut executed) => *executed.get_mut(execution.pc) += 1,
+				None => {}
+			}
+			// Where the instruction starts from, for the step hook.
+			let (pc, from) = (execution.pc, self.tape.position());
+			let before = if self.step_hook.is_some() { self.tape.peek(from) } else { 0 };
+			let mut at_eof = false;
+
+			// The opcodes are dense, so this is an indexed jump.
+			match instr.opcode {
+				// Operations on tape. Match tape methods, minding the
+				// edges of cells and tape.
+				OpMove => { try!(self.seek(pc, instr.a)); }
+				OpAdd => { try!(self.add(instr.a)); }
+				// Checks the loop's condition again at the end of its
+				// body, and either repeats it or leaves it.
+				OpJnz => {
+					self.max_depth_reached = max(self.max_depth_reached, depths[pc] + 1);
+					try!(self.tick());
+					execution.pc = if *self.tape.cell() != 0 { instr.a as uint } else { execution.pc + 1 };
+					match execution.timed {
+						Some(ref mut times) if depths[pc] == 0 && execution.pc > pc => times.leave(self.clock.now_ns()),
+						_ => {}
+					}
+					self.trace(pc, instr, from, before, false, execution.pc);
+					match execution.snapshots {
+						Some(ref mut snapshots) if depths[pc] == 0 && execution.pc > pc => snapshots.left_loop(self, execution.pc),
+						_ => {}
+					}
+					continue;
+				}
+				// Enters a loop. If the current cell's value is not
+				// zero, its body will be executed, else skipping it
+				// entirely.
+				OpJz => {
+					match execution.timed {
+						Some(ref mut times) if depths[pc] == 0 => times.enter(pc, self.clock.now_ns()),
+						_ => {}
+					}
+					try!(self.tick());
+					execution.pc = if *self.tape.cell() == 0 { instr.a as uint } else { execution.pc + 1 };
+					if execution.pc == pc + 1 {
+						self.max_depth_reached = max(self.max_depth_reached, depths[pc] + 1);
+					}
+					match execution.timed {
+						Some(ref mut times) if depths[pc] == 0 && execution.pc != pc + 1 => times.leave(self.clock.now_ns()),
+						_ => {}
+					}
+					self.trace(pc, instr, from, before, false, execution.pc);
+					match execution.snapshots {
+						Some(ref mut snapshots) if depths[pc] == 0 && execution.pc != pc + 1 => snapshots.left_loop(self, execution.pc),
+						_ => {}
+					}
+					continue;
+				}
+				// Offset operations reach a cell without moving there.
+				OpAddAt => { try!(self.add_at(instr.a, instr.b)); }
+				OpSetAt => {
+					let offset = try!(self.reach(instr.a));
+					*self.tape.at_offset(offset) = instr.b as u8;
+					self.wrote(offset);
+				}
+				// Balanced writes all happen in a single cycle.
+				OpOffsetWrite => {
+					for &(offset, delta) in writes[instr.a as uint].iter() {
+						try!(self.add_at(offset, delta));
+					}
+				}
+				OpPut => { try!(self.put_cell()); }
+				// Reads a single char from the input and replaces the
+				// current cell's contents with it, or a decimal number
+				// in numeric mode. What happens at EOF depends on the
+				// semantics.
+				OpGet => {
+					let byte_in = if self.numeric_io { self.read_number() } else { self.read_byte() };
+					match byte_in {
+						Some(byte_in) => {
+							self.tape.mutate( |v|{ *v = byte_in; } );
+							self.wrote(0);
+						}
+						// The read is tried again, and counted, once resumed.
+						None if self.input_blocked => {
+							self.op_counts[instr.opcode as uint] -= 1;
+							match self.collected {
+								Some(ref mut trace) if !self.trace_truncated => { trace.pop(); }
+								_ => {}
+							}
+							match execution.executed {
+								Some(ref mut executed) => *executed.get_mut(execution.pc) -= 1,
+								None => {}
+							}
+							return Ok(Blocked);
+						}
+						None => {
+							at_eof = true;
+							match self.semantics.eof {
+								EofZero => self.tape.mutate( |v|{ *v = 0; } ),
+								EofMinusOne => self.tape.mutate( |v|{ *v = 255; } ),
+								EofUnchanged => { /* nop */ },
+							}
+							if self.semantics.eof != EofUnchanged { self.wrote(0) }
+						}
+					}
+				}
+				// Draws a value over the whole range of cells.
+				OpRandom => {
+					let value = self.rng.gen::<Unit>();
+					self.tape.mutate( |v|{ *v = value; } );
+					self.wrote(0);
+				}
+				// Negating wraps around, unless cells fail going out of
+				// range, which negating any value but zero does.
+				OpNegate => {
+					let value = *self.tape.cell();
+					if value != 0 && self.semantics.overflow == FailOnOverflow {
+						return Err(CellUnderflow);
+					}
+					self.tape.mutate( |v|{ *v = 0 - value; } );
+					self.wrote(0);
+				}
+				// The hook is taken out while it runs, as it is given
+				// the machine, unless it registered another one.
+				OpHostCall => {
+					match self.host_hook.take() {
+						Some(mut hook) => {
+							hook(self);
+							if self.host_hook.is_none() { self.host_hook = Some(hook) }
+						}
+						None => {}
+					}
+				}
+				// Checkpoints are only minded in debug mode, and
+				// otherwise are comments.
+				OpCheckpoint if self.debug => {
+					let name = names[instr.a as uint].clone();
+					self.checkpoints.push((name, self.cycles));
+					if !self.semantics.comments_cost_cycles {
+						execution.pc += 1;
+						self.trace(pc, instr, from, before, false, execution.pc);
+						continue;
+					}
+				}
+				// Comments may not even cost a cycle.
+				OpCheckpoint | OpComment if !self.semantics.comments_cost_cycles => {
+					execution.pc += 1;
+					self.trace(pc, instr, from, before, false, execution.pc);
+					continue;
+				}
+				// Unknown. Nop.
+				OpCheckpoint | OpComment | OpNop => { /* nop */ },
+			}
+			// Track this last cycle and advance to the next instruction.
+			try!(self.tick());
+			execution.pc += 1;
+			self.trace(pc, instr, from, before, at_eof, execution.pc);
+
+			// Pause if the instruction met a condition.
+			if !self.conditions.is_empty() {
+				match self.condition_met() {
+					Some(id) => return Ok(ConditionHit(id, self.cycles)),
+					None => {}
+				}
+			}
+
+			// Pause if the instruction hit a breakpoint.
+			let changed_cell = match instr.opcode {
+				OpAdd | OpGet | OpRandom | OpNegate | OpHostCall => true,
+				OpAddAt | OpSetAt => instr.a == 0,
+				OpOffsetWrite => writes[instr.a as uint].iter().any(|&(offset, _)| offset == 0),
+				_ => false,
+			};
+			if changed_cell && self.cell_breakpoint_hit() {
+				return Ok(Paused);
+			}
+			// Or if enough output was gathered while streaming.
+			if instr.opcode == OpPut && self.chunk_ready() {
+				return Ok(Paused);
+			}
+		}
+	}
+
+	/**
+	Resumes a run like `resume` does, pausing after at most the given
+	number of cycles, e.g. to share time between several runs.
+	*/
+	pub fn resume_for(&mut self, execution: &mut Execution, cycles: uint) -> Result<RunState, RuntimeError> {
+		self.pause_at = Some(self.cycles + cycles);
+		let state = self.resume(execution);
+		self.pause_at = None;
+		state
+	}
+
+	/**
+	Resumes a run like `resume` does, pausing right after the program
+	writes the given byte, e.g. a prompt, as if at a breakpoint. The
+	byte is looked for in the output as conditions see it.
+	*/
+	pub fn resume_until_output(&mut self, execution: &mut Execution, byte: u8) -> Result<RunState, RuntimeError> {
+		let id = self.add_condition(OutputContains(~[byte]));
+		let state = self.resume(execution);
+		self.remove_condition(id.clone());
+		match state {
+			Ok(ConditionHit(hit, _)) if hit == id => Ok(Paused),
+			state => state,
+		}
+	}
+
+	/**
+	Starts a run of a program, pausing right after it writes the given
+	byte, see `resume_until_output`. The run goes on with the execution
+	returned alongside how it went, e.g. until the next such byte.
+	*/
+	pub fn run_until_output(&mut self, program: &Ast, byte: u8) -> (Execution, Result<RunState, RuntimeError>) {
+		let mut execution = self.start(program);
+		let state = self.resume_until_output(&mut execution, byte);
+		(execution, state)
+	}
+
+	/**
+	Whether the rest of a run can go through `sprint`: nothing traces
+	it, watches it, counts or times its instructions, nor pauses it.
+	*/
+	fn can_sprint(&self, execution: &Execution) -> bool {
+		self.step_hook.is_none() && self.collected.is_none()
+			&& execution.executed.is_none() && execution.timed.is_none()
+			&& self.conditions.is_empty() && self.breakpoints.is_empty()
+			&& self.pause_at.is_none() && self.flush_at.is_none()
+	}
+
+	/**
+	Runs the tail of a program to its end, see `Bytecode::tail`, in a
+	tight loop doing only what `resume` would do that can be seen
+	without tracing or watching the run: cycles, operator counts,
+	limits, cell writes and output end up the same.
+	*/
+	fn sprint(&mut self, code: &[Instr], pc: &mut uint) -> Result<RunState, RuntimeError> {
+		self.sprinted = true;
+		let comments_cost_cycles = self.semantics.comments_cost_cycles;
+		while *pc < code.len() {
+			let instr = &code[*pc];
+			self.op_counts[instr.opcode as uint] += 1;
+			match instr.opcode {
+				OpMove => { try!(self.seek(*pc, instr.a)); }
+				OpAdd => { try!(self.add(instr.a)); }
+				OpSetAt => {
+					let offset = try!(self.reach(instr.a));
+					*self.tape.at_offset(offset) = instr.b as u8;
+					self.wrote(offset);
+				}
+				OpPut => { try!(self.put_cell()); }
+				OpComment if !comments_cost_cycles => {
+					*pc += 1;
+					continue;
+				}
+				OpComment => {}
+				_ => fail!("Only the tail of a program can be sprinted through."),
+			}
+			try!(self.tick());
+			*pc += 1;
+		}
+		self.cancel.publish(self.cycles);
+		Ok(Halted)
+	}
+
+	/**
+	Whether the current cell satisfies any of the breakpoints which
+	are about cell values.
+	*/
+	fn cell_breakpoint_hit(&mut self) -> bool {
+		let value = *self.tape.cell();
+		self.breakpoints.iter().any(|breakpoint| {
+			match *breakpoint {
+				WhenCellEquals(expected) => value == expected,
+			}
+		})
+	}
+
+	/**
+	The first condition met by the last instruction, if any. Every
+	condition on the head is checked, to know which ones hold now.
+	*/
+	fn condition_met(&mut self) -> Option<CondId> {
+		let position = self.tape.position();
+		let value = self.tape.peek(position);
+		let mut first = None;
+		for watch in self.conditions.mut_iter() {
+			let holds = match watch.condition {
+				CellUnderHeadEquals(expected) => value == expected,
+				CellUnderHeadAbove(bound) => value > bound,
+				HeadAt(cell) => position == cell,
+				AnyCellEquals(_) | OutputContains(_) => {
+					if first.is_none() && self.met.contains(&watch.id) { first = Some(watch.id) }
+					continue;
+				}
+			};
+			if holds && !watch.held && first.is_none() { first = Some(watch.id) }
+			watch.held = holds;
+		}
+		self.met.clear();
+		first
+	}
+
+	/**
+	Keeps track of the last bytes of output, noting the conditions
+	met by the output ending with what they look for.
+	*/
+	fn watch_output(&mut self, byte: u8) {
+		let longest = self.conditions.iter().fold(0, |longest, watch| match watch.condition {
+			OutputContains(ref bytes) => max(longest, bytes.len()),
+			_ => longest,
+		});
+		self.recent_output.push(byte);
+		// Dropped from time to time rather than at every byte.
+		if self.recent_output.len() > 2 * longest + 16 {
+			let start = self.recent_output.len() - longest;
+			self.recent_output = Vec::from_slice(self.recent_output.slice_from(start));
+		}
+		for watch in self.conditions.iter() {
+			match watch.condition {
+				OutputContains(ref bytes) if self.recent_output.as_slice().ends_with(bytes.as_slice()) => {
+					self.met.push(watch.id);
+				}
+				_ => {}
+			}
+		}
+	}
+
+	/**
+	Tells the step hook, if any, what the instruction at `pc` did,
+	given where the head was and what the current cell held before.
+	*/
+	fn trace(&mut self, pc: uint, instr: &Instr, from: int, before: u8, at_eof: bool, next: uint) {
+		if self.step_hook.is_none() { return }
+		let to = self.tape.position();
+		let step = Step {
+			cycle: self.cycles,
+			pc: pc,
+			next: next,
+			instr: instr.clone(),
+			from: from,
+			to: to,
+			before: before,
+			after: self.tape.peek(to),
+			at_eof: at_eof,
+		};
+		match self.step_hook {
+			Some(ref mut hook) => (*hook)(&step),
+			None => {}
+		}
+	}
+
+	/**
+	Tells the write hook, if any, what the cell `offset` cells away
+	from the head now holds.
+	*/
+	fn wrote(&mut self, offset: int) {
+		if self.write_hook.is_none() && self.conditions.is_empty() && self.recent_writes.is_none() { return }
+		let position = self.tape.position() + offset;
+		let value = self.tape.peek(position);
+		match self.recent_writes {
+			Some(ref mut recent) => {
+				match recent.iter().position(|&written| written == position) {
+					Some(i) => { recent.remove(i); }
+					None if recent.len() >= SNAPSHOT_RECENT_CELLS => { recent.remove(0); }
+					None => {}
+				}
+				recent.push(position);
+			}
+			None => {}
+		}
+		for watch in self.conditions.iter() {
+			match watch.condition {
+				AnyCellEquals(expected) if value == expected => self.met.push(watch.id),
+				_ => {}
+			}
+		}
+		match self.write_hook {
+			Some(ref mut hook) => (*hook)(position, value),
+			None => {}
+		}
+	}
+
+	/**
+	Whether a streaming run has gathered enough output to hand over.
+	*/
+	fn chunk_ready(&self) -> bool {
+		match (self.flush_at, &self.captured) {
+			(Some(size), &Some(ref bytes)) => bytes.len() >= size,
+			_ => false,
+		}
+	}
+
+	/**
+	Takes a snapshot of the current run, of the given program, after
+	leaving as many top-level loops as told, going on from `pc`.
+	*/
+	fn snapshot(&self, exit: uint, program: u64, pc: uint) -> Snapshot {
+		let cells: Vec<(int, Unit)> = self.tape.cells().move_iter().filter(|&(_, value)| value != 0).collect();
+		let recent = match self.recent_writes {
+			Some(ref positions) => positions.iter().rev().map(|&position| (position, self.tape.peek(position))).collect(),
+			None => Vec::new(),
+		};
+		Snapshot {
+			exit: exit,
+			cycle: self.cycles,
+			head: self.tape.position(),
+			output_len: self.written,
+			checksum: hash(&cells.as_slice()),
+			recent: recent,
+			state: Some(SavedState { program: program, pc: pc, cells: cells, read: self.read, reached: self.reached }),
+		}
+	}
+
+	/**
+	Adds `delta` to the current cell, wrapping around or failing
+	when going out of the cell's range.
+	*/
+	fn add(&mut self, delta: int) -> Result<(), RuntimeError> {
+		self.add_at(0, delta)
+	}
+
+	/**
+	Adds `delta` to the cell `offset` cells away from the head, like
+	`add` does to the current cell.
+	*/
+	fn add_at(&mut self, offset: int, delta: int) -> Result<(), RuntimeError> {
+		let offset = try!(self.reach(offset));
+		{
+			let cell = self.tape.at_offset(offset);
+			let value = *cell as int + delta;
+			if self.semantics.overflow == FailOnOverflow {
+				if value < 0 { return Err(CellUnderflow) }
+				if value > 255 { return Err(CellOverflow) }
+			}
+			*cell = value as u8;
+		}
+		self.wrote(offset);
+		Ok(())
+	}
+
+	/**
+	Moves the head by `offset` cells, clamping or failing at the
+	edges of the tape, and minding the head stopping at the origin,
+	see `set_track_clamps`.
+	*/
+	fn seek(&mut self, pc: uint, offset: int) -> Result<(), RuntimeError> {
+		let moved = try!(self.reach(offset));
+		let attempted = self.tape.position() + offset;
+		if moved != offset && offset < 0 && !self.tape.wraps() {
+			try!(self.clamped(pc, attempted));
+		}
+		self.tape.wind(moved);
+		Ok(())
+	}
+
+	/**
+	Notes that the head stopped at the origin instead of moving to
+	`attempted`, or fails the run if that is forbidden.
+	*/
+	fn clamped(&mut self, pc: uint, attempted: int) -> Result<(), RuntimeError> {
+		if self.forbid_clamp {
+			return Err(ClampedAtLeftEdge(attempted));
+		}
+		match self.clamps {
+			Some(ref mut events) => {
+				self.clamp_count += 1;
+				if events.len() < CLAMP_EVENT_LIMIT {
+					let event = ClampEvent { cycle: self.cycles + 1, attempted: attempted, pc: pc, span: None };
+					events.push(event);
+				}
+			}
+			None => {}
+		}
+		Ok(())
+	}
+
+	/**
+	Checks an offset from the head against the edges of the tape,
+	returning it clamped, or failing, if it goes past them. The left
+	edge is the tape's, see `Tape::left_edge`, and the right one that
+	of the semantics, or none if the tape wraps around.
+	*/
+	fn reach(&mut self, offset: int) -> Result<int, RuntimeError> {
+		// Tapes wrapping around have no edges to speak of.
+		if self.tape.wraps() { return Ok(offset) }
+		let pos = self.tape.position();
+		let first = self.tape.left_edge();
+		let size = self.semantics.tape_size.map(|size| size as int);
+		let mut target = pos + offset;
+		let too_far_left = first.map_or(false, |first| target < first);
+		if too_far_left || size.map_or(false, |size| target >= size) {
+			if self.semantics.bounds == FailAtEdges {
+				return Err(TapeOutOfBounds);
+			}
+			target = if too_far_left { first.unwrap() } else { size.unwrap() - 1 };
+		}
+		try!(self.check_memory(target));
+		Ok(target - pos)
+	}
+
+	/**
+	Checks the memory taken by the tape against the limit, if any,
+	when `target` is further than any cell reached before.
+	*/
+	fn check_memory(&mut self, target: int) -> Result<(), RuntimeError> {
+		let limit = match self.memory_limit {
+			Some(limit) => limit,
+			None => return Ok(()),
+		};
+		let (low, high) = self.reached;
+		if target >= low && target <= high { return Ok(()) }
+		self.reached = (min(low, target), max(high, target));
+		let bytes = self.tape.bytes_allocated();
+		if bytes > limit {
+			return Err(MemoryLimitExceeded(bytes, self.cycles));
+		}
+		Ok(())
+	}
+
+	/**
+	Accounts for a cycle, failing if it goes over the limit, or if
+	the run was cancelled. When cancelled, any output is flushed.
+	*/
+	fn tick(&mut self) -> Result<(), RuntimeError> {
+		self.cycles += 1;
+		let cycles = self.cycles;
+		match self.progress_hook {
+			Some((every, ref mut hook)) if cycles % every == 0 => (*hook)(cycles),
+			_ => {}
+		}
+		// Counting down is cheaper than dividing, on every cycle.
+		self.until_check -= 1;
+		if self.until_check == 0 {
+			self.until_check = self.cancel_granularity;
+			self.cancel.publish(self.cycles);
+			if self.cancel.take() {
+				let _ = self.output.flush();
+				return Err(Cancelled(self.cycles));
+			}
+			match self.limits.time {
+				Some(max) if self.clock.now() - self.started_at > max + self.paced_ms => {
+					return Err(LimitExceeded(TimeLimit));
+				}
+				_ => {}
+			}
+		}
+		match self.limits.cycles {
+			Some(max) if self.cycles > max => Err(LimitExceeded(CycleLimit)),
+			_ => Ok(()),
+		}
+	}
+
+	/**
+	Reads a byte from the input, or `None` at EOF, or if the input is
+	not there yet, which is told apart by `input_blocked` unless the
+	input mode takes it as EOF.
+	*/
+	fn read_byte(&mut self) -> Option<u8> {
+		if self.input_filters.is_empty() {
+			return self.read_line_byte();
+		}
+		// Reads on until a byte comes out of the filters, if any does.
+		while self.filtered_input.is_empty() && !self.input_filters.ended() {
+			let byte = match self.read_line_byte() {
+				Some(byte) => byte,
+				None => return None,
+			};
+			let mut passed = Vec::new();
+			self.input_filters.apply(byte, &mut passed);
+			self.filtered_input.extend(passed.move_iter());
+		}
+		let byte = self.filtered_input.pop_front();
+		// A filter ended the input, which is then not merely blocked.
+		if byte.is_none() {
+			self.input_blocked = false;
+		}
+		byte
+	}
+
+	/**
+	Reads a byte from the input, translating line endings if asked
+	to, see `NewlineMode`. A CR is read as a LF at once, and the LF
+	of a CRLF is skipped when it comes, however long after.
+	*/
+	fn read_line_byte(&mut self) -> Option<u8> {
+		loop {
+			let byte = self.read_raw_byte();
+			if self.newline_mode == RawNewlines || byte.is_none() {
+				return byte;
+			}
+			let after_cr = self.after_cr;
+			self.after_cr = byte == Some(13);
+			match byte {
+				Some(13) => return Some(10),
+				Some(10) if after_cr => continue,
+				other => return other,
+			}
+		}
+	}
+
+	/// Reads a byte from the input, as it is, see `read_byte`.
+	fn read_raw_byte(&mut self) -> Option<u8> {
+		match self.input_hook {
+			Some(ref mut hook) => {
+				let byte = (*hook)();
+				match byte {
+					Some(_) => self.read += 1,
+					None => self.input_blocked = false,
+				}
+				return byte;
+			}
+			None => {}
+		}
+		match self.input.read_u8() {
+			Ok(byte) => { self.read += 1; Some(byte) }
+			Err(err) => {
+				self.input_blocked = err.kind == ResourceUnavailable && self.input_mode == Blocking;
+				None
+			}
+		}
+	}
+
+	/**
+	Reads a decimal number from the input, modulo 256, or `None` at
+	EOF. Spaces, tabs and carriage returns before the number are
+	skipped. The number ends at the first character which is not a
+	digit, which is consumed too. A newline or any other character
+	found before a digit reads as zero, so an empty line is a zero.
+
+	If the input blocks midway, this is `None` too, and the digits read
+	so far are kept for the read tried again once resumed.
+	*/
+	fn read_number(&mut self) -> Option<u8> {
+		let mut number = self.pending_number.take();
+		loop {
+			let byte = match self.read_byte() {
+				Some(byte) => byte,
+				None if self.input_blocked => {
+					self.pending_number = number;
+					return None;
+				}
+				None => return number.map(|n| n as u8),
+			};
+			match (byte as char, number) {
+				(c, _) if c.is_digit() => {
+					let digit = (byte - '0' as u8) as uint;
+					number = Some((number.unwrap_or(0) * 10 + digit) % 256);
+				}
+				(' ', None) | ('\t', None) | ('\r', None) => {}
+				(_, None) => return Some(0),
+				(_, Some(_)) => return number.map(|n| n as u8),
+			}
+		}
+	}
+
+	/**
+	Prints the cell's contents to the output as char, unless output is
+	discarded, or the cell is zero and skipped. In numeric mode, prints
+	it as a decimal number instead, and in codepoint mode, as a UTF-8
+	encoded character.
+	*/
+	fn put_cell(&mut self) -> Result<(), RuntimeError> {
+		let byte_out = self.tape.cell().clone();
+		if self.discard_output || (self.skip_null_output && byte_out == 0) {
+			return Ok(());
+		}
+		if self.numeric_io {
+			for digit in format!("{} ", byte_out).bytes() {
+				try!(self.put(digit));
+			}
+		} else if self.output_mode == Codepoint {
+			let c = char::from_u32(byte_out as u32).unwrap_or('\ufffd');
+			for byte in c.to_str().bytes() {
+				try!(self.put(byte));
+			}
+		} else {
+			try!(self.put(byte_out));
+		}
+		Ok(())
+	}
+
+	/**
+	Outputs a byte, either capturing it or writing it to the output,
+	through the output filters, if any.
+	*/
+	fn put(&mut self, byte: u8) -> Result<(), RuntimeError> {
+		if self.output_filters.is_empty() {
+			return self.put_line_byte(byte);
+		}
+		let mut passed = Vec::new();
+		self.output_filters.apply(byte, &mut passed);
+		for &byte in passed.iter() {
+			try!(self.put_line_byte(byte));
+		}
+		Ok(())
+	}
+
+	/**
+	Outputs a byte, writing a LF as the platform spells newlines when
+	translating them on a console, see `set_output_newline_mode`.
+	*/
+	fn put_line_byte(&mut self, byte: u8) -> Result<(), RuntimeError> {
+		if byte != 10 || !self.output_console || self.output_newline_mode == RawNewlines {
+			return self.put_raw(byte);
+		}
+		for &byte in PLATFORM_NEWLINE.iter() {
+			try!(self.put_raw(byte));
+		}
+		Ok(())
+	}
+
+	/// Outputs a byte as it is, see `put`.
+	fn put_raw(&mut self, byte: u8) -> Result<(), RuntimeError> {
+		match self.limits.output {
+			Some(max) if self.written >= max => return Err(LimitExceeded(OutputLimit)),
+			_ => {}
+		}
+		self.written += 1;
+		if self.pacing != Unpaced {
+			self.pace();
+		}
+		if !self.conditions.is_empty() {
+			self.watch_output(byte);
+		}
+		match self.output_hook {
+			Some(ref mut hook) => (*hook)(byte),
+			None => {}
+		}
+		match self.tail {
+			Some((ref mut bytes, size)) => {
+				if size > 0 && bytes.len() == size {
+					bytes.pop_front();
+				}
+				if size > 0 {
+					bytes.push_back(byte);
+				}
+				return Ok(());
+			}
+			None => {}
+		}
+		match self.captured {
+			Some(ref mut bytes) => { bytes.push(byte); Ok(()) }
+			None if self.pacing != Unpaced => {
+				self.output.write_u8(byte).and_then(|_| self.output.flush()).map_err(|_| OutputError)
+			}
+			None => self.output.write_u8(byte).map_err(|_| OutputError),
+		}
+	}
+
+	/**
+	Sleeps for as long as the next output byte should wait, minding
+	the pacing. Bytes paced by rate are due evenly from the start of
+	the run, and only waited for if they come early.
+	*/
+	fn pace(&mut self) {
+		let wait = match self.pacing {
+			Unpaced => 0,
+			PerByte(ms) => ms,
+			BytesPerSecond(rate) => {
+				let due = self.started_at + self.paced as u64 * 1000 / max(rate, 1) as u64;
+				let now = self.clock.now();
+				if due > now { due - now } else { 0 }
+			}
+		};
+		self.paced += 1;
+		if wait > 0 {
+			self.clock.sleep(wait);
+			self.paced_ms += wait;
+		}
+	}
+}
+
+/**
+A generator of random values starting from a seed. Xorshift can not
+start from all zeros, which the constant half of the state rules out.
+*/
+fn rng_from_seed(seed: u64) -> XorShiftRng {
+	SeedableRng::from_seed([seed as u32, (seed >> 32) as u32, 0x9e3779b9, 0x243f6a88])
+}
+
+#[test]
+/// Output should be captured in memory, reading input from memory.
+fn test_machine_captures_output() {
+	let program = Ast::parse_str(",+.,+.").unwrap();
+	let mut machine = Machine::capturing(bytes!("HA"));
+	assert!(machine.run_program(&program).is_ok());
+	assert!(machine.captured_output() == bytes!("IB"));
+}
+
+#[cfg(test)]
+use filters::{Uppercase, StripCr, CrLf, Translate, TakeWhile};
+
+#[test]
+/// Input and output should go through their filters, in order.
+fn test_machine_filters() {
+	let mut machine = Machine::capturing(bytes!("Hello\r\nWorld\r\n"));
+	machine.add_input_filter(StripCr);
+	machine.add_input_filter(Uppercase);
+	assert!(machine.run_program(&Ast::parse_str(",[.,]").unwrap()).is_ok());
+	assert!(machine.captured_output() == bytes!("HELLO\nWORLD\n"));
+	assert!(machine.bytes_io() == (14, 12));
+
+	// Dropping the last byte, or ending the input, makes EOF come early.
+	let mut semantics = Semantics::common();
+	semantics.eof = EofMinusOne;
+	let program = Ast::parse_str(",.,.,.").unwrap();
+	let mut machine = Machine::capturing(bytes!("ab\r"));
+	machine.set_semantics(semantics.clone());
+	machine.add_input_filter(StripCr);
+	assert!(machine.run_program(&program).is_ok());
+	assert!(machine.captured_output() == bytes!("ab", 0xff));
+	let mut machine = Machine::capturing(bytes!("a\nbc"));
+	machine.set_semantics(semantics);
+	machine.add_input_filter(TakeWhile(10));
+	assert!(machine.run_program(&program).is_ok());
+	assert!(machine.captured_output() == bytes!("a", 0xff, 0xff));
+	assert!(machine.bytes_io().val0() == 2);
+
+	// Output limits count the bytes coming out of the filters.
+	let mut machine = Machine::capturing(bytes!("a\nb"));
+	machine.add_output_filter(CrLf);
+	assert!(machine.run_program(&program).is_ok());
+	assert!(machine.captured_output() == bytes!("a\r\nb"));
+	assert!(machine.bytes_io().val1() == 4);
+	let mut machine = Machine::capturing(bytes!("a\nb"));
+	machine.add_output_filter(CrLf);
+	machine.add_output_filter(Translate('a' as u8, 'A' as u8));
+	machine.set_limits(Limits { output: Some(3), ..Limits::none() });
+	assert!(machine.run_program(&program) == Err(LimitExceeded(OutputLimit)));
+	assert!(machine.captured_output() == bytes!("A\r\n"));
+}
+
+#[test]
+/// Only the last bytes of output should be kept in tail mode.
+fn test_machine_output_tail() {
+	// Outputs 1 to 1000, wrapping around at 256.
+	let program = Ast::parse_str("++++++++++[>++++++++++[>++++++++++[>+.<-]<-]<-]").unwrap();
+	let mut machine = Machine::capturing([]);
+	machine.set_output_tail(Some(10));
+	let mut seen = 0;
+	machine.on_output(|_| seen += 1);
+	assert!(machine.run_program(&program).is_ok());
+	assert!(machine.output_tail() == vec!(223, 224, 225, 226, 227, 228, 229, 230, 231, 232));
+	assert!(machine.captured_output().is_empty());
+	assert!(machine.bytes_io().val1() == 1000);
+	machine.set_output_tail(Some(0));
+	assert!(machine.run_program(&program).is_ok());
+	assert!(machine.output_tail().is_empty());
+	machine.set_output_tail(None);
+	assert!(machine.run_program(&Ast::parse_str("+.").unwrap()).is_ok());
+	assert!(machine.output_tail().is_empty() && machine.captured_output() == bytes!("\x01"));
+	drop(machine);
+	assert!(seen == 2001);
+}
+
+#[test]
+/// Runs should stop when going over the cycle limit, even in empty loops.
+fn test_machine_cycle_limit() {
+	let program = Ast::parse_str("+[]").unwrap();
+	let mut machine = Machine::capturing([]);
+	machine.set_limits(Limits { cycles: Some(1000), ..Limits::none() });
+	assert!(machine.run_program(&program) == Err(LimitExceeded(CycleLimit)));
+	assert!(machine.cycles() == 1001);
+}
+
+#[test]
+/// What is left of the cycle limit should be known after a run.
+fn test_machine_remaining_cycles() {
+	// `+++`, entering the loop, then `-` and a check three times.
+	let program = Ast::parse_str("+++[-]").unwrap();
+	let mut machine = Machine::capturing([]);
+	machine.run_program(&program).unwrap();
+	assert!(machine.remaining_cycles() == None);
+	machine.set_limits(Limits { cycles: Some(1000), ..Limits::none() });
+	assert!(machine.run_program(&program) == Ok(10));
+	assert!(machine.remaining_cycles() == Some(990));
+	machine.set_limits(Limits { cycles: Some(5), ..Limits::none() });
+	assert!(machine.run_program(&program).is_err());
+	assert!(machine.remaining_cycles() == Some(0));
+}
+
+#[test]
+/// Runs should stop before writing more than the output limit.
+fn test_machine_output_limit() {
+	let program = Ast::parse_str("+.....").unwrap();
+	let mut machine = Machine::capturing([]);
+	machine.set_limits(Limits { output: Some(3), ..Limits::none() });
+	assert!(machine.run_program(&program) == Err(LimitExceeded(OutputLimit)));
+	assert!(machine.captured_output().len() == 3);
+}
+
+#[test]
+/// The output hook should see every byte, which should still be output.
+fn test_machine_output_hook() {
+	let program = Ast::parse_str("+++...").unwrap();
+	let mut count = 0;
+	{
+		let mut machine = Machine::capturing([]);
+		machine.on_output(|byte| { assert!(byte == 3); count += 1; });
+		assert!(machine.run_program(&program).is_ok());
+		assert!(machine.captured_output() == &[3, 3, 3]);
+	}
+	assert!(count == 3);
+}
+
+#[test]
+/// Machines should run with closures for I/O alone.
+fn test_machine_callback_io() {
+	let program = Ast::parse_str(",[.+.,]").unwrap();
+	let mut input = bytes!("ab").iter().map(|&byte| byte);
+	let mut output = ~[];
+	{
+		let mut machine = Machine::with_callbacks(|| input.next(), |byte| output.push(byte));
+		assert!(machine.run_program(&program).is_ok());
+		assert!(machine.bytes_io() == (2, 4));
+	}
+	assert!(output.as_slice() == bytes!("abbc"));
+}
+
+#[cfg(test)]
+use analysis::{estimate_trip_counts, PrefixSummary};
+
+#[test]
+/// The progress hook should be called every so many cycles, the estimate keeping up.
+fn test_machine_progress_hook() {
+	let program = Ast::parse_str("++++++++[>++++[>+<-]<-]>>.").unwrap();
+	let estimate = estimate_trip_counts(&program, &PrefixSummary::of(&program));
+	let mut seen = ~[];
+	{
+		let mut machine = Machine::capturing([]);
+		machine.on_progress(10, |cycles| seen.push(estimate.percent(cycles).unwrap()));
+		assert!(machine.run_program(&program).is_ok());
+		assert!(machine.captured_output() == &[32]);
+	}
+	// 244 cycles, as estimated, and the last call comes at 240.
+	assert!(seen.len() == 24 && seen.last() == Some(&98));
+	assert!(seen.windows(2).all(|pair| pair[0] <= pair[1]));
+}
+
+#[cfg(test)]
+use std::cell::RefCell;
+
+#[test]
+/// The write hook should see every cell written, in order with output.
+fn test_machine_cell_write_hook() {
+	let writes = |source: &str, input: &[u8]| {
+		let mut seen = ~[];
+		{
+			let mut machine = Machine::capturing(input);
+			machine.on_cell_write(|position, value| seen.push((position, value)));
+			machine.run_program(&Ast::parse_str(source).unwrap()).unwrap();
+		}
+		seen
+	};
+	assert!(writes("+>++", []) == ~[(0, 1), (1, 1), (1, 2)]);
+	// Reading writes the cell, as EOF does with the common semantics.
+	assert!(writes("+>,<,", [7]) == ~[(0, 1), (1, 7), (0, 0)]);
+
+	let events = RefCell::new(~[]);
+	{
+		let mut machine = Machine::capturing([]);
+		machine.on_cell_write(|position, value| events.borrow_mut().push((position, value)));
+		machine.on_output(|byte| events.borrow_mut().push((-1, byte)));
+		machine.run_program(&Ast::parse_str("+.+").unwrap()).unwrap();
+	}
+	assert!(*events.borrow() == ~[(0, 1), (-1, 1), (0, 2)]);
+}
+
+#[cfg(test)]
+use semantics::{Semantics};
+
+#[cfg(test)]
+/// Runs a probe program on an in-memory machine with the given semantics.
+fn probe(semantics: Semantics, source: &str, input: &[u8]) -> Result<(~[u8], uint), RuntimeError> {
+	let program = Ast::parse_str(source).unwrap();
+	let mut machine = Machine::capturing(input);
+	machine.set_semantics(semantics);
+	let cycles = try!(machine.run_program(&program));
+	Ok((machine.captured_output().to_owned(), cycles))
+}
+
+#[test]
+/// Every preset should behave exactly as documented where they diverge.
+fn test_semantics_conformance() {
+	let presets = [Semantics::classic(), Semantics::common(), Semantics::strict()];
+	// Expected output of each probe under classic, common and strict.
+	let right_edge = ">".repeat(29999) + "+>+.";
+	let probes: ~[(&str, &[u8], [Result<~[u8], RuntimeError>, ..3])] = ~[
+		// Reading at EOF.
+		("+,.", &[], [Ok(~[1]), Ok(~[0]), Ok(~[1])]),
+		// Reading before EOF.
+		("+,.", &[7], [Ok(~[7]), Ok(~[7]), Ok(~[7])]),
+		// Cell underflow and overflow.
+		("-.", &[], [Ok(~[255]), Ok(~[255]), Err(CellUnderflow)]),
+		("-+.", &[], [Ok(~[0]), Ok(~[0]), Err(CellUnderflow)]),
+		(",+.", &[255], [Ok(~[0]), Ok(~[0]), Err(CellOverflow)]),
+		// Moving past the left edge.
+		("<+.", &[], [Ok(~[1]), Ok(~[1]), Err(TapeOutOfBounds)]),
+		// Moving past the right edge of a 30k tape.
+		(right_edge.as_slice(), &[], [Ok(~[2]), Ok(~[1]), Err(TapeOutOfBounds)]),
+	];
+	for &(source, input, ref expected) in probes.iter() {
+		for (semantics, expected) in presets.iter().zip(expected.iter()) {
+			let output = probe(semantics.clone(), source, input).map(|(output, _)| output);
+			assert!(output == *expected);
+		}
+	}
+
+	// Comments only cost cycles under the common semantics.
+	let cycles: ~[uint] = presets.iter().map(|semantics| {
+		probe(semantics.clone(), "a+b", &[]).unwrap().val1()
+	}).collect();
+	assert!(cycles == ~[1, 3, 1]);
+}
+
+#[test]
+/// A cell breakpoint should pause right after the cell reaches the value.
+fn test_machine_pauses_when_cell_equals() {
+	let program = Ast::parse_str("+++++>+<-").unwrap();
+	let mut machine = Machine::capturing([]);
+	machine.add_breakpoint(WhenCellEquals(5));
+	let mut execution = machine.start(&program);
+	assert!(machine.resume(&mut execution) == Ok(Paused));
+	assert!(machine.current_cell() == 5);
+	assert!(machine.cycles() == 5);
+	// Moving back onto the cell holding 5 should not pause again,
+	// as only changing a cell counts.
+	assert!(machine.resume(&mut execution) == Ok(Halted));
+	assert!(machine.current_cell() == 4);
+	assert!(machine.cycles() == 9);
+}
+
+#[cfg(test)]
+/// Where a program pauses on a condition, by cycle, until it ends.
+fn condition_hits(source: &str, input: &[u8], condition: Condition) -> ~[uint] {
+	let program = Ast::parse_str(source).unwrap();
+	let mut machine = Machine::capturing(input);
+	let id = machine.add_condition(condition);
+	let mut execution = machine.start(&program);
+	let mut hits = ~[];
+	loop {
+		match machine.resume(&mut execution).unwrap() {
+			ConditionHit(hit, cycle) => {
+				assert!(hit == id && cycle == machine.cycles());
+				hits.push(cycle);
+			}
+			Halted => return hits,
+			_ => fail!("Paused without a condition met."),
+		}
+	}
+}
+
+#[test]
+/// Each kind of condition should pause right after it is met, once.
+fn test_machine_conditions() {
+	assert!(condition_hits("+++++", [], CellUnderHeadEquals(3)) == ~[3]);
+	// Coming back to a cell above the bound meets it again.
+	assert!(condition_hits("+++>+<", [], CellUnderHeadAbove(2)) == ~[3, 6]);
+	// Wherever the head is, and only when written.
+	assert!(condition_hits("+>++<+>", [], AnyCellEquals(2)) == ~[4, 6]);
+	assert!(condition_hits(">>><<", [], HeadAt(2)) == ~[2, 4]);
+	assert!(condition_hits(",.,.,.,.,.", bytes!("hello"), OutputContains(bytes!("lo").to_owned())) == ~[10]);
+	assert!(condition_hits("+++++", [], CellUnderHeadEquals(9)).is_empty());
+}
+
+#[test]
+/// The first condition added should be told, and removed ones never.
+fn test_machine_conditions_several() {
+	let program = Ast::parse_str("++>+").unwrap();
+	let mut machine = Machine::capturing([]);
+	let above = machine.add_condition(CellUnderHeadAbove(0));
+	let equals = machine.add_condition(AnyCellEquals(2));
+	let mut execution = machine.start(&program);
+	assert!(machine.resume(&mut execution) == Ok(ConditionHit(above, 1)));
+	assert!(machine.resume(&mut execution) == Ok(ConditionHit(equals, 2)));
+	assert!(machine.resume(&mut execution) == Ok(ConditionHit(above, 4)));
+	assert!(machine.remove_condition(above));
+	assert!(!machine.remove_condition(above));
+	let mut execution = machine.start(&program);
+	assert!(machine.resume(&mut execution) == Ok(ConditionHit(equals, 2)));
+	assert!(machine.resume(&mut execution) == Ok(Halted));
+}
+
+#[test]
+/// Runs should pause right after each prompt, and go on from there.
+fn test_machine_run_until_output() {
+	let program = Ast::parse_str("++++++[>++++++++++<-]>++.>,.<.>,.").unwrap();
+	let mut machine = Machine::capturing(bytes!("ab"));
+	let (mut execution, state) = machine.run_until_output(&program, '>' as u8);
+	assert!(state == Ok(Paused));
+	assert!(machine.captured_output() == bytes!(">"));
+	assert!(machine.resume_until_output(&mut execution, '>' as u8) == Ok(Paused));
+	assert!(machine.captured_output() == bytes!(">a>"));
+	assert!(machine.conditions.is_empty());
+	assert!(machine.resume_until_output(&mut execution, '>' as u8) == Ok(Halted));
+	assert!(machine.captured_output() == bytes!(">a>b"));
+}
+
+#[test]
+/// Output should be matched across pauses, and chunks of streamed output.
+fn test_machine_output_condition_chunks() {
+	let program = Ast::parse_str(",.,.,.,.,.").unwrap();
+	let mut machine = Machine::capturing(bytes!("hello"));
+	let id = machine.add_condition(OutputContains(bytes!("lo").to_owned()));
+	let mut execution = machine.start(&program);
+	assert!(machine.resume_for(&mut execution, 8) == Ok(Paused));
+	assert!(machine.captured_output() == bytes!("hell"));
+	assert!(machine.resume(&mut execution) == Ok(ConditionHit(id, 10)));
+
+	// Chunks are handed over when the condition is met, the bytes
+	// looked for straddling the first chunk.
+	let mut machine = Machine::capturing(bytes!("hello"));
+	machine.add_condition(OutputContains(bytes!("lo").to_owned()));
+	let mut chunks = ~[];
+	let result = machine.run_streaming(&program, |chunk| { chunks.push(chunk.to_owned()); true },
+		StreamOptions { chunk_size: 3 });
+	assert!(result == Ok(10));
+	assert!(chunks == ~[bytes!("hel").to_owned(), bytes!("lo").to_owned()]);
+}
+
+#[test]
+/// Preloaded cells should be readable by the program.
+fn test_machine_preload_tape() {
+	let program = Ast::parse_str(".>.>.>.").unwrap();
+	let mut machine = Machine::capturing([]);
+	machine.preload_tape([1, 2, 3]);
+	assert!(machine.current_cell() == 1);
+	assert!(machine.run_program(&program).is_ok());
+	assert!(machine.captured_output() == &[1, 2, 3, 0]);
+}
+
+#[test]
+/// The time of a run should go to its heaviest top-level loop.
+fn test_machine_run_profiled() {
+	// A quick loop, one counting down 255 times over, and another quick one.
+	let program = Ast::parse_str("++[-]-[>-[-]<-]+[-]").unwrap();
+	let mut machine = Machine::capturing([]);
+	// A millisecond goes by on every cycle, as the time limit has the
+	// clock read on each of them.
+	machine.set_clock(~MockClock::stepping(1) as ~Clock:Send);
+	machine.set_cancel_granularity(1);
+	machine.set_limits(Limits { time: Some(1 << 40), ..Limits::none() });
+	let (times, result) = machine.run_profiled(&program);
+	assert!(result.is_ok());
+	let ids: ~[LoopId] = times.iter().map(|&(id, _)| id).collect();
+	assert!(ids == ~[LoopId(2), LoopId(4), LoopId(6)]);
+	let (quick, heavy) = (times.get(0).val1() + times.get(2).val1(), times.get(1).val1());
+	assert!(heavy > 10 * quick);
+
+	// Loops nested in others are timed as part of them.
+	let extended = ::ast::ParseOptions { extensions: true, ..::ast::ParseOptions::strict() };
+	let (program, _) = Ast::parse_with("+[[-]]{-}", extended).unwrap();
+	machine.set_extensions(ExtensionSet::all());
+	let (times, _) = machine.run_profiled(&program);
+	assert!(times.len() == 1);
+}
+
+#[test]
+/// Zero bytes should not be output when skipped, but still cost a cycle.
+fn test_machine_skip_null_output() {
+	let program = Ast::parse_str("++++++++[>++++++++<-]>+.[-].++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++.").unwrap();
+	let mut machine = Machine::capturing([]);
+	assert!(machine.run_program(&program).is_ok());
+	assert!(machine.captured_output() == bytes!("A\x00B"));
+	let cycles = machine.cycles();
+	machine.reset();
+	machine.set_skip_null_output(true);
+	assert!(machine.run_program(&program).is_ok());
+	assert!(machine.captured_output() == bytes!("AB"));
+	assert!(machine.cycles() == cycles);
+	assert!(machine.bytes_io().val1() == 2);
+}
+
+#[cfg(test)]
+use pacing::MockClock;
+
+#[test]
+/// Every byte output should be paced by one sleep, but those refused.
+fn test_machine_output_pacing() {
+	let program = Ast::parse_str("+.+.+.+.+.").unwrap();
+	let mut machine = Machine::capturing([]);
+	let clock = MockClock::new();
+	machine.set_clock(~clock.clone() as ~Clock:Send);
+	machine.set_output_pacing(PerByte(40));
+	assert!(machine.run_program(&program).is_ok());
+	assert!(clock.sleeps() == vec!(40, 40, 40, 40, 40));
+	assert!(machine.paced_ms() == 200);
+
+	machine.set_limits(Limits { output: Some(3), ..Limits::none() });
+	assert!(machine.run_program(&program) == Err(LimitExceeded(OutputLimit)));
+	assert!(clock.sleeps().len() == 8);
+	assert!(machine.paced_ms() == 120);
+
+	// At 4 bytes a second, after the first byte, one every 250ms.
+	let clock = MockClock::new();
+	machine.set_clock(~clock.clone() as ~Clock:Send);
+	machine.set_limits(Limits::none());
+	machine.set_output_pacing(BytesPerSecond(4));
+	assert!(machine.run_program(&program).is_ok());
+	assert!(clock.sleeps() == vec!(250, 250, 250, 250));
+}
+
+#[test]
+/// The time limit should count the time running, but not pacing.
+fn test_machine_time_limit_pacing() {
+	// Outputs 10 bytes, then spends about 255 * 255 * 4 cycles.
+	let program = Ast::parse_str("++++++++++[.-]-[>-[-]<-]").unwrap();
+	let mut machine = Machine::capturing([]);
+	machine.set_cancel_granularity(1);
+	machine.set_limits(Limits { time: Some(50), ..Limits::none() });
+	let clock = MockClock::new();
+	machine.set_clock(~clock.clone() as ~Clock:Send);
+	machine.set_output_pacing(PerByte(1000));
+	assert!(machine.run_program(&program).is_ok());
+	assert!(machine.paced_ms() == 10000);
+
+	// A millisecond goes by whenever the time is told.
+	machine.set_clock(~MockClock::stepping(1) as ~Clock:Send);
+	assert!(machine.run_program(&program) == Err(LimitExceeded(TimeLimit)));
+	assert!(machine.cycles() < 100);
+}
+
+#[test]
+/// Arguments should be laid out on the tape, for the program to walk.
+fn test_machine_args() {
+	// Skips the count and the first argument, then echoes the second.
+	let program = Ast::parse_str(">[>]>[.>]").unwrap();
+	let mut machine = Machine::capturing([]);
+	assert!(machine.set_args([bytes!("foo"), bytes!("ab")]).is_ok());
+	assert!(machine.current_cell() == 2);
+	assert!(machine.args_cells() == 8);
+	assert!(machine.run_program(&program).is_ok());
+	assert!(machine.captured_output() == bytes!("ab"));
+
+	assert!(machine.set_args([]).is_ok());
+	assert!(machine.current_cell() == 0);
+	assert!(machine.args_cells() == 1);
+	machine.reset();
+	assert!(machine.args_cells() == 0);
+}
+
+#[test]
+/// Arguments which do not fit the layout should be refused up front.
+fn test_machine_args_refused() {
+	let mut machine = Machine::capturing([]);
+	machine.set_semantics(::semantics::Semantics::strict());
+	let many = Vec::from_elem(256, bytes!("x"));
+	assert!(machine.set_args(many.as_slice()) == Err(~"Too many arguments for a cell: 256, over 255."));
+	assert!(machine.set_args(Vec::from_elem(255, bytes!("x")).as_slice()).is_ok());
+	assert!(machine.current_cell() == 255);
+	let zero = [bytes!("ok"), bytes!("a\x00b")];
+	assert!(machine.set_args(zero) == Err(~"Argument 2 holds a zero byte, which would end it early."));
+	assert!(machine.args_cells() == 255 * 2 + 1);
+}
+
+#[cfg(test)]
+use test::BenchHarness;
+
+#[cfg(test)]
+static STRING_PRINTER: &'static str = "++++++++[>+++++++++<-]>.<+++[>++++++++++<-]>-.+++++++..+++.[-]++++++++++.";
+
+#[test]
+/// The tail of a program should run in a sprint, as it would otherwise.
+fn test_machine_tail_sprint() {
+	let program = Ast::parse_str(STRING_PRINTER).unwrap();
+	let mut traced = Machine::capturing([]);
+	traced.on_step(|_| {});
+	let cycles = traced.run_program(&program);
+	assert!(!traced.sprinted);
+
+	let mut machine = Machine::capturing([]);
+	assert!(machine.run_program(&program) == cycles);
+	assert!(machine.sprinted);
+	assert!(machine.captured_output() == traced.captured_output());
+	assert!(machine.captured_output() == bytes!("Hello\n"));
+	assert!(machine.op_counts() == traced.op_counts());
+
+	// Limits are minded within the sprint, which starts after `[-]`.
+	for &limits in [Limits { cycles: Some(cycles.unwrap() - 3), output: None, time: None },
+	                Limits { cycles: None, output: Some(5), time: None }].iter() {
+		let (mut sprinting, mut stepping) = (Machine::capturing([]), Machine::capturing([]));
+		sprinting.set_limits(limits);
+		stepping.set_limits(limits);
+		stepping.on_step(|_| {});
+		assert!(sprinting.run_program(&program) == stepping.run_program(&program));
+		assert!(sprinting.cycles() == stepping.cycles() && sprinting.captured_output() == stepping.captured_output());
+		assert!(sprinting.sprinted && !stepping.sprinted);
+	}
+
+	// Nor is there any sprint while conditions are watched.
+	let mut watched = Machine::capturing([]);
+	watched.add_condition(OutputContains(bytes!("o\n").to_owned()));
+	let mut execution = watched.start(&program);
+	assert!(match watched.resume(&mut execution) { Ok(ConditionHit(..)) => true, _ => false });
+	assert!(!watched.sprinted);
+}
+
+#[bench]
+/// Running a long program made only of output, all of it sprinted through.
+fn bench_machine_tail_sprint(b: &mut BenchHarness) {
+	let program = Ast::parse_str("+.".repeat(10000)).unwrap();
+	b.iter(|| { Machine::capturing([]).run_program(&program).unwrap(); });
+}
+
+#[bench]
+/// Running a compute-heavy program, dominated by dispatch.
+fn bench_machine_compute_heavy(b: &mut BenchHarness) {
+	// Counts down from 255 to zero, 255 times over.
+	let program = Ast::parse_str("-[>-[-]<-]").unwrap();
+	b.iter(|| { Machine::capturing([]).run_program(&program).unwrap(); });
+}
+
+#[test]
+/// Cancelling from another task should stop a run, and keep the machine usable.
+fn test_machine_cancel_from_another_task() {
+	let (tokens, token) = channel();
+	let (results, result) = channel();
+	spawn(proc() {
+		let mut machine = Machine::capturing([]);
+		machine.set_cancel_granularity(100);
+		tokens.send(machine.cancel_token());
+		let endless = Ast::parse_str("+[]").unwrap();
+		let cancelled = machine.run_program(&endless);
+		let cycles = machine.cycles();
+		machine.reset();
+		let again = machine.run_program(&Ast::parse_str("+.").unwrap());
+		results.send((cancelled, cycles, again, machine.captured_output().to_owned()));
+	});
+
+	// Cancel once the run is under way, not before it starts.
+	let token = token.recv();
+	while token.cycles() < 1000 {}
+	let seen = token.cycles();
+	token.cancel();
+	let (cancelled, cycles, again, output) = result.recv();
+	assert!(cycles >= seen);
+	assert!(cancelled == Err(Cancelled(cycles)));
+	assert!(again == Ok(2));
+	assert!(output == ~[1]);
+}
+
+#[cfg(test)]
+use time::precise_time_ns;
+
+#[test]
+/// Other tasks should see a run progress, and stop it promptly.
+fn test_machine_progress_from_another_task() {
+	let (tokens, token) = channel();
+	let (results, result) = channel();
+	spawn(proc() {
+		let mut machine = Machine::capturing([]);
+		machine.set_cancel_granularity(100);
+		tokens.send(machine.cancel_token());
+		let cancelled = machine.run_program(&Ast::parse_str("+[]").unwrap());
+		results.send((cancelled, machine.cycles()));
+	});
+
+	let token = token.recv();
+	// Wait for the run to be well under way.
+	while token.cycles() < 10000 {}
+	let seen = token.cycles();
+	let asked = precise_time_ns();
+	token.cancel();
+	let (cancelled, cycles) = result.recv();
+	assert!(precise_time_ns() - asked < 1000000000);
+	assert!(cancelled == Err(Cancelled(cycles)));
+	assert!(cycles >= seen);
+	// Once cancelled, the count is exact.
+	assert!(token.cycles() == cycles);
+}
+
+#[test]
+/// Numeric I/O should read and write decimal numbers.
+fn test_machine_numeric_io() {
+	let program = Ast::parse_str(",.,.,.,.").unwrap();
+	let mut machine = Machine::capturing(bytes!("42\n 7x\n"));
+	machine.set_numeric_io(true);
+	assert!(machine.run_program(&program).is_ok());
+	// The last two are an empty line, and EOF.
+	assert!(machine.captured_output() == bytes!("42 7 0 0 "));
+}
+
+#[test]
+/// In codepoint mode, cells should be written as UTF-8 characters.
+fn test_machine_codepoint_output() {
+	let program = Ast::parse_str(",.,.").unwrap();
+	let mut machine = Machine::capturing([65, 0xe9]);
+	machine.set_output_mode(Codepoint);
+	machine.run_program(&program).unwrap();
+	assert!(machine.captured_output() == "Aé".as_bytes());
+}
+
+#[cfg(test)]
+/// An input with nothing to give for a few reads before each byte.
+struct TrickleReader {
+	bytes: ~[u8],
+	/// How many reads find nothing before each byte.
+	waits: uint,
+	waited: uint,
+}
+
+#[cfg(test)]
+impl Reader for TrickleReader {
+	fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+		if self.bytes.is_empty() {
+			return Err(standard_error(EndOfFile));
+		}
+		if self.waited < self.waits {
+			self.waited += 1;
+			return Err(standard_error(ResourceUnavailable));
+		}
+		self.waited = 0;
+		buf[0] = self.bytes.shift().unwrap();
+		Ok(1)
+	}
+}
+
+#[cfg(test)]
+/// A capturing machine reading from a trickle of bytes.
+fn trickling<'a>(bytes: ~[u8], waits: uint) -> Machine<'a> {
+	let mut machine = Machine::with_io(~TrickleReader { bytes: bytes, waits: waits, waited: 0 }, ~NullWriter);
+	machine.captured = Some(Vec::new());
+	machine
+}
+
+#[test]
+/// Blocking reads should wait for input, stopping resumable runs.
+fn test_machine_input_blocking() {
+	let program = Ast::parse_str(",.,.").unwrap();
+	let mut machine = trickling(~[7, 8], 2);
+	assert!(machine.run_program(&program) == Ok(4));
+	assert!(machine.captured_output() == &[7, 8]);
+
+	let mut machine = trickling(~[7], 1);
+	let mut execution = machine.start(&program);
+	assert!(machine.resume(&mut execution) == Ok(Blocked));
+	assert!(machine.cycles() == 0);
+	// The second read finds the input over.
+	assert!(machine.resume(&mut execution) == Ok(Halted));
+	assert!(machine.captured_output() == &[7, 0]);
+
+	// Numbers go on from the digits read before the input blocked.
+	let mut machine = trickling(bytes!("12\n3\n").to_owned(), 1);
+	machine.set_numeric_io(true);
+	let mut execution = machine.start(&program);
+	let mut blocked = 0;
+	loop {
+		match machine.resume(&mut execution) {
+			Ok(Blocked) => blocked += 1,
+			Ok(Halted) => break,
+			_ => fail!("Expected the run to block or end."),
+		}
+	}
+	assert!(blocked == 5);
+	assert!(machine.captured_output() == bytes!("12 3 "));
+}
+
+#[test]
+/// Reads finding nothing should be EOF, as the semantics say, without waiting.
+fn test_machine_input_eof_on_empty() {
+	let program = Ast::parse_str("+,.,.").unwrap();
+	let mut machine = trickling(~[7], 1);
+	machine.set_input_mode(EofOnEmpty);
+	assert!(machine.run_program(&program) == Ok(5));
+	// The byte is still there for the next read.
+	assert!(machine.captured_output() == &[0, 7]);
+
+	let mut machine = trickling(~[7], 1);
+	machine.set_input_mode(EofOnEmpty);
+	machine.set_semantics(Semantics::classic());
+	assert!(machine.run_program(&program) == Ok(5));
+	assert!(machine.captured_output() == &[1, 7]);
+}
+
+#[test]
+/// Traces should list the instructions executed, in order, once each.
+fn test_machine_trace_collect() {
+	let mut machine = Machine::capturing([]);
+	machine.run_program(&Ast::parse_str("+.").unwrap()).unwrap();
+	assert!(machine.collected_trace().is_empty());
+	machine.set_trace_collect(true);
+	machine.run_program(&Ast::parse_str("+.").unwrap()).unwrap();
+	assert!(machine.collected_trace() == &[OpAdd, OpPut]);
+	assert!(!machine.trace_truncated());
+
+	// Reads tried again are only collected once.
+	let mut machine = trickling(~[7, 8], 2);
+	machine.set_trace_collect(true);
+	machine.run_program(&Ast::parse_str(",.,.").unwrap()).unwrap();
+	assert!(machine.collected_trace() == &[OpGet, OpPut, OpGet, OpPut]);
+}
+
+#[cfg(test)]
+/// Prints 10.000 bytes.
+static TEN_KB: &'static str = "++++++++++[>++++++++++[>++++++++++[>++++++++++[>+.<-]<-]<-]<-]";
+
+#[test]
+/// Streamed chunks should add up to the output, and be as large as asked.
+fn test_machine_run_streaming() {
+	let program = Ast::parse_str(TEN_KB).unwrap();
+	let mut expected = Machine::capturing([]);
+	expected.run_program(&program).unwrap();
+	assert!(expected.captured_output().len() == 10000);
+
+	let mut chunks: ~[~[u8]] = ~[];
+	let mut machine = Machine::capturing([]);
+	let result = machine.run_streaming(&program, |chunk| {
+		chunks.push(chunk.to_owned());
+		true
+	}, StreamOptions { chunk_size: 1024 });
+	assert!(result == Ok(expected.cycles()));
+	assert!(chunks.concat_vec().as_slice() == expected.captured_output());
+	assert!(chunks.init().iter().all(|chunk| chunk.len() >= 1024));
+	assert!(chunks.last().unwrap().len() < 1024);
+	// Nothing should have been captured the usual way.
+	assert!(machine.captured_output().is_empty());
+}
+
+#[test]
+/// The consumer should be able to stop a streaming run.
+fn test_machine_run_streaming_abort() {
+	let program = Ast::parse_str(TEN_KB).unwrap();
+	let mut calls = 0;
+	let mut machine = Machine::capturing([]);
+	let result = machine.run_streaming(&program, |_| {
+		calls += 1;
+		calls < 2
+	}, StreamOptions { chunk_size: 1024 });
+	assert!(result == Err(Aborted));
+	assert!(calls == 2);
+}
+
+#[cfg(test)]
+use storage::{tape_from_name, tape_eq};
+
+#[test]
+/// Dumps should go by position, open ends going to the edges of the touched range.
+fn test_machine_dump_tape() {
+	let mut machine = Machine::capturing([]);
+	machine.run_program(&Ast::parse_str("+>++>>+++<").unwrap()).unwrap();
+	assert!(machine.touched_range() == (0, 4));
+	assert!(machine.dump_tape((None, None)) == vec!(1, 2, 0, 3));
+	assert!(machine.dump_tape((Some(2), None)) == vec!(0, 3));
+	assert!(machine.dump_tape((None, Some(2))) == vec!(1, 2));
+	assert!(machine.dump_tape((Some(-2), Some(1))) == vec!(0, 0, 1));
+}
+
+#[test]
+/// Every tape backend should run programs the same.
+fn test_machine_tape_backends() {
+	let (_, source, input) = ::corpus::PROGRAMS[0];
+	let program = Ast::parse_str(source).unwrap();
+	let outputs: ~[~[u8]] = ["vector", "sparse", "two-way", "chunked", "ring:30000"].iter().map(|name| {
+		let mut machine = Machine::capturing(input);
+		machine.set_tape(tape_from_name(*name).unwrap());
+		machine.run_program(&program).unwrap();
+		machine.captured_output().to_owned()
+	}).collect();
+	assert!(outputs[0] == bytes!("Hello World!\n").to_owned());
+	assert!(outputs.iter().all(|output| *output == outputs[0]));
+}
+
+#[test]
+/// The head stopping at the origin should be noted, or fail the run.
+fn test_machine_clamp_events() {
+	// Moves left of the origin twice on each of two runs of the loop.
+	let program = Ast::parse_str("++[<<-]").unwrap();
+	let map = SourceMap::of(&program);
+	let events = |tape: &str| {
+		let mut machine = Machine::capturing([]);
+		machine.set_tape(tape_from_name(tape).unwrap());
+		machine.set_track_clamps(true);
+		assert!(machine.run_mapped(&program, &map).is_ok());
+		assert!(machine.clamp_count() == machine.clamp_events().len());
+		machine.clamp_events().iter().map(|event| {
+			assert!(event.attempted == -1);
+			(event.cycle, event.span.unwrap().start, event.span.unwrap().end)
+		}).collect::<~[(uint, uint, uint)]>()
+	};
+	assert!(events("vector") == ~[(4, 3, 4), (5, 4, 5), (8, 3, 4), (9, 4, 5)]);
+	assert!(events("chunked") == events("vector"));
+	// Tapes with room on the left, or wrapping around, never stop it.
+	assert!(events("sparse").is_empty() && events("two-way").is_empty());
+	assert!(events("ring:16").is_empty());
+
+	let mut machine = Machine::capturing([]);
+	machine.set_forbid_clamp(true);
+	assert!(machine.run_program(&program) == Err(ClampedAtLeftEdge(-1)));
+	assert!(machine.cycles() == 3);
+	machine.set_forbid_clamp(false);
+	assert!(machine.run_program(&program).is_ok());
+	assert!(machine.clamp_events().is_empty() && machine.clamp_count() == 0);
+}
+
+#[test]
+/// Tapes with room on the left should let the head go there, up to their edge.
+fn test_machine_left_of_origin() {
+	let program = Ast::parse_str("+<<<++>>>.<<<.").unwrap();
+	let run = |tape: ~Tape<Unit>, semantics: Semantics| {
+		let mut machine = Machine::capturing([]);
+		machine.set_semantics(semantics);
+		machine.set_tape(tape);
+		let result = machine.run_program(&program).map(|_| machine.tape().position());
+		(result, machine.captured_output().to_owned())
+	};
+	let common = Semantics::common();
+	assert!(run(tape_from_name("vector").unwrap(), common.clone()) == (Ok(0), ~[0, 3]));
+	assert!(run(tape_from_name("two-way").unwrap(), common.clone()) == (Ok(-3), ~[1, 2]));
+	assert!(run(~VectorTape::centered(2) as ~Tape<Unit>, common) == (Ok(-2), ~[0, 2]));
+	// Edges fail strict runs where the tape has them, and only there.
+	assert!(run(tape_from_name("two-way").unwrap(), Semantics::strict()) == (Ok(-3), ~[1, 2]));
+	assert!(run(~VectorTape::centered(2) as ~Tape<Unit>, Semantics::strict()).val0() == Err(TapeOutOfBounds));
+}
+
+#[test]
+/// Vector and sparse tapes should hold the same cells after the same run.
+fn test_machine_tape_eq() {
+	let run = |source: &str, tape: &str| {
+		let mut machine = Machine::capturing([]);
+		machine.set_tape(tape_from_name(tape).unwrap());
+		machine.run_program(&Ast::parse_str(source).unwrap()).unwrap();
+		machine
+	};
+	let source = "++++++++[>++++++++<-]>+>>-<<<";
+	let (vector, sparse) = (run(source, "vector"), run(source, "sparse"));
+	let range = vector.touched_range();
+	assert!(range == (0, 4));
+	assert!(tape_eq(vector.tape(), sparse.tape(), range));
+	assert!(tape_eq(vector.tape(), sparse.tape(), (-10, 10)));
+	let other = run("++++++++[>++++++++<-]>+>>+<<<", "sparse");
+	assert!(!tape_eq(vector.tape(), other.tape(), range));
+}
+
+#[test]
+/// A chunked tape should run every corpus program like a vector tape does.
+fn test_machine_chunked_tape_corpus() {
+	for &(name, source, input) in ::corpus::PROGRAMS.iter() {
+		let program = Ast::parse_str(source).unwrap();
+		let mut machine = Machine::capturing(input);
+		machine.set_tape(tape_from_name("chunked").unwrap());
+		machine.run_program(&program).unwrap();
+		let (output, tape) = ::corpus::run(&program, input);
+		let range = machine.touched_range();
+		if machine.captured_output() != output.as_slice()
+		|| machine.tape_report(range).render_ascii(64) != tape {
+			fail!("{} behaves differently on a chunked tape", name);
+		}
+	}
+}
+
+#[test]
+/// Scratch cells left over should be counted, and none when cleared.
+fn test_machine_nonzero_cell_count() {
+	let mut machine = Machine::capturing([]);
+	machine.run_program(&Ast::parse_str("++++++++[>++++++++<-]>+.[-]").unwrap()).unwrap();
+	assert!(machine.captured_output() == bytes!("A"));
+	assert!(machine.nonzero_cell_count() == 0);
+	let mut machine = Machine::capturing([]);
+	machine.run_program(&Ast::parse_str("+>++++++++[>++++++++<-]>+.<<").unwrap()).unwrap();
+	assert!(machine.nonzero_cell_count() == 2);
+}
+
+#[test]
+/// Sweeping programs should stop soon after their tape takes more memory than allowed.
+fn test_machine_memory_limit() {
+	let program = Ast::parse_str("+[>+]").unwrap();
+	let cap = 100000;
+	for &name in ["vector", "sparse", "chunked"].iter() {
+		let mut machine = Machine::capturing([]);
+		machine.set_tape(tape_from_name(name).unwrap());
+		machine.set_memory_limit(Some(cap));
+		match machine.run_program(&program) {
+			Err(MemoryLimitExceeded(bytes, cycles)) => {
+				assert!(bytes > cap && bytes <= 2 * cap);
+				assert!(cycles == machine.cycles());
+			}
+			_ => fail!("{} went on past the memory limit", name),
+		}
+		assert!(machine.tape.bytes_allocated() <= 2 * cap);
+	}
+}
+
+#[test]
+/// Programs within a generous memory limit should not notice it.
+fn test_machine_memory_limit_corpus() {
+	for &(name, source, input) in ::corpus::PROGRAMS.iter() {
+		let program = Ast::parse_str(source).unwrap();
+		let (output, _) = ::corpus::run(&program, input);
+		for &tape in ["vector", "sparse", "chunked"].iter() {
+			let mut machine = Machine::capturing(input);
+			machine.set_tape(tape_from_name(tape).unwrap());
+			machine.set_memory_limit(Some(16 << 20));
+			if machine.run_program(&program).is_err() || machine.captured_output() != output.as_slice() {
+				fail!("{} behaves differently under a memory limit on a {} tape", name, tape);
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+use operators::{Incr, Decr};
+
+#[cfg(test)]
+/// A program nesting loops as deep as asked, each of them entered.
+fn nested(depth: uint) -> Ast {
+	let mut program = Ast(Vec::from_elem(depth, Decr).move_iter().collect());
+	for _ in range(0, depth) {
+		program = Ast(~[Incr, Sub(program)]);
+	}
+	program
+}
+
+#[test]
+/// Runs going too deep in loops should fail, however deep the program.
+fn test_machine_max_exec_depth() {
+	let mut machine = ::builder::MachineBuilder::new()
+		.input(~MemReader::new(~[]) as ~Reader)
+		.output(~NullWriter as ~Writer)
+		.build()
+		.unwrap();
+	// The first loop too deep opens after a `+[` for each loop around it.
+	let first = 2 * DEFAULT_MAX_EXEC_DEPTH + 1;
+	assert!(machine.run_program(&nested(5000)) == Err(NestingTooDeep(5000, Span { start: first, end: first + 1 })));
+	// Nothing was run.
+	assert!(machine.cycles() == 0 && machine.max_depth_reached() == 0);
+	let results = machine.run_batch(&nested(5000), [bytes!("")], Limits::none());
+	assert!(results.get(0).error == Some(NestingTooDeep(5000, Span { start: first, end: first + 1 })));
+
+	assert!(machine.run_program(&nested(50)) == Ok(4 * 50));
+	assert!(machine.max_depth_reached() == 50);
+	machine.set_max_exec_depth(49);
+	assert!(machine.run_program(&nested(50)) == Err(NestingTooDeep(50, Span { start: 99, end: 100 })));
+	// Nor may programs nest deeper than anything parsed may.
+	machine.set_max_exec_depth(5000);
+	assert!(machine.run_program(&nested(5000)).is_err());
+}
+
+#[test]
+/// A do-while loop should run its body once, even on a zero cell.
+fn test_machine_do_while() {
+	let extended = ::ast::ParseOptions { extensions: true, ..::ast::ParseOptions::strict() };
+	let parse = |source: &str| Ast::parse_with(source, extended).map(|(ast, _)| ast).unwrap();
+	let mut machine = Machine::capturing([]);
+	machine.set_extensions(ExtensionSet::all());
+	machine.run_program(&parse("{.}[.]")).unwrap();
+	assert!(machine.captured_output() == bytes!("\x00"));
+	// The `.` and the check of the cell, then skipping over `[.]`.
+	assert!(machine.cycles() == 3);
+
+	// On a zero cell, `[-]` does nothing, while `{-}` goes below zero.
+	let mut machine = Machine::with_semantics(Semantics::strict());
+	machine.set_extensions(ExtensionSet::all());
+	assert!(machine.run_program(&parse("[-]")).is_ok());
+	assert!(machine.run_program(&parse("{-}")) == Err(CellUnderflow));
+}
+
+#[test]
+/// Every kind of run should refuse extensions not enabled, before running anything.
+fn test_machine_extensions_disabled() {
+	let extended = ::ast::ParseOptions { extensions: true, ..::ast::ParseOptions::strict() };
+	let (program, _) = Ast::parse_with("+?.", extended).unwrap();
+	let (optimized, map) = program.optimize_mapped();
+	let expected = ExtensionDisabled(ValidationError { operator: ::operators::Random, extension: ::features::RandomValues });
+	let mut machine = Machine::capturing([]);
+	assert!(machine.run_mapped(&optimized, &map).map_err(|err| err.error) == Err(expected.clone()));
+	let mut execution = machine.start(&program);
+	assert!(machine.resume(&mut execution) == Err(expected.clone()));
+	assert!(machine.replay(::bytecode::instruction_ops(&program), []) == Err(expected.clone()));
+	assert!(machine.cycles() == 0 && machine.captured_output().is_empty());
+
+	machine.set_extensions(ExtensionSet::none().with(::features::RandomValues));
+	assert!(machine.run_mapped(&optimized, &map).is_ok());
+}
+
+#[test]
+/// Errors in optimized programs should span what the failing operator was folded from.
+fn test_machine_run_mapped() {
+	let (program, map) = Ast::parse_str("+> -----").unwrap().optimize_mapped();
+	let mut machine = Machine::with_semantics(Semantics::strict());
+	let expected = LocatedError { error: CellUnderflow, span: Span { start: 3, end: 8 } };
+	assert!(machine.run_mapped(&program, &map) == Err(expected.clone()));
+	assert!(format!("{}", expected) == ~"At 3..8: Cell decremented past zero.");
+}
+
+#[test]
+/// Cells should wrap around by default, both ways, even once optimized.
+fn test_machine_cells_wrap() {
+	let increments = Ast::parse_str("+".repeat(256)).unwrap();
+	for program in [increments.clone(), increments.optimize()].iter() {
+		let mut machine = Machine::capturing([]);
+		assert!(machine.run_program(program).is_ok());
+		assert!(machine.current_cell() == 0);
+	}
+	let decrement = Ast::parse_str("-").unwrap();
+	for program in [decrement.clone(), decrement.optimize()].iter() {
+		let mut machine = Machine::capturing([]);
+		assert!(machine.run_program(program).is_ok());
+		assert!(machine.current_cell() == 255);
+	}
+}
+
+#[test]
+/// Every program of a corpus should run against its own input.
+fn test_machine_run_corpus() {
+	let programs = [
+		(Ast::parse_str(",[.,]").unwrap(), ~"echo"),
+		(Ast::parse_str("++++++++[>++++++++<-]>+.").unwrap(), ~""),
+		(Ast::parse_str(",+[-.,+]").unwrap(), ~"cat"),
+	];
+	let mut machine = Machine::capturing(bytes!("own"));
+	let results = machine.run_corpus(programs);
+	assert!(results == vec!(Ok(Vec::from_slice(bytes!("echo"))), Ok(vec!(65)), Ok(Vec::from_slice(bytes!("cat")))));
+	machine.set_limits(Limits { cycles: Some(1000), ..Limits::none() });
+	let results = machine.run_corpus([(Ast::parse_str("+[]").unwrap(), ~""), programs[1].clone()]);
+	assert!(results == vec!(Err(LimitExceeded(CycleLimit)), Ok(vec!(65))));
+	// The machine's own input and output are back.
+	assert!(machine.run_program(&Ast::parse_str(",.").unwrap()).is_ok());
+	assert!(machine.captured_output() == bytes!("o"));
+}
+
+#[test]
+/// Every input of a batch should run, whether or not the others fail.
+fn test_machine_run_batch() {
+	let program = Ast::parse_str(",[.,]").unwrap();
+	let long = "x".repeat(100);
+	let inputs = [bytes!("hello"), bytes!(""), long.as_bytes(), bytes!("a"), bytes!("bye")];
+	let mut machine = Machine::capturing([]);
+	let results = machine.run_batch(&program, inputs.as_slice(), Limits { cycles: Some(50), ..Limits::none() });
+	assert!(results.len() == 5);
+	for (i, result) in results.iter().enumerate() {
+		if i == 2 {
+			assert!(result.error == Some(LimitExceeded(CycleLimit)));
+			assert!(result.cycles == 51);
+		} else {
+			assert!(result.error.is_none());
+			assert!(result.output.as_slice() == inputs[i]);
+		}
+	}
+	// `,` and entering the loop, then `.`, `,` and checking for each byte.
+	assert!(results.get(0).cycles == 2 + 3 * 5);
+	// The machine's own limits are back.
+	assert!(machine.run_program(&Ast::parse_str("+[+]").unwrap()).is_ok());
+}
+
+#[test]
+/// Pure runs should have no output, and always end up the same.
+fn test_machine_pure() {
+	let program = Ast::parse_str("++++[>,+++.>++<<-]>>[<+>-]").unwrap();
+	let run = || {
+		let mut machine = Machine::pure();
+		let cycles = machine.run_program(&program).unwrap();
+		let range = machine.touched_range();
+		(cycles, machine.bytes_io(), machine.tape_report(range).render_ascii(64))
+	};
+	let (cycles, (read, written), tape) = run();
+	assert!(read == 4);
+	assert!(written == 0);
+	assert!(run() == (cycles, (read, written), tape));
+}
+
+#[test]
+/// Random values should only depend on the seed, and cover every value.
+fn test_machine_random() {
+	let extended = ::ast::ParseOptions { extensions: true, ..::ast::ParseOptions::strict() };
+	let parse = |source: &str| Ast::parse_with(source, extended).map(|(ast, _)| ast).unwrap();
+	let program = parse("?.>?.>?.>?.");
+	let output = |program: &Ast, seed: u64| {
+		let mut machine = Machine::capturing([]);
+		machine.set_extensions(ExtensionSet::all());
+		machine.seed_rng(seed);
+		machine.run_program(program).unwrap();
+		machine.captured_output().to_owned()
+	};
+	assert!(output(&program, 42) == output(&program, 42));
+	assert!(output(&program, 42) != output(&program, 43));
+	// Without seeding, every run is the same too.
+	let mut machine = Machine::capturing([]);
+	machine.set_extensions(ExtensionSet::all());
+	machine.run_program(&program).unwrap();
+	machine.run_program(&program).unwrap();
+	let captured = machine.captured_output();
+	assert!(captured.slice_to(4) == captured.slice_from(4));
+	assert!(captured.slice_to(4) == output(&program, DEFAULT_SEED).as_slice());
+	// Without extensions, `?` is a comment.
+	assert!(Ast::parse_str("?") == Ok(Ast(~[::operators::Nop(~"?")])));
+
+	let draws = output(&parse("?.".repeat(10000).as_slice()), 7);
+	let mut seen = [false, ..256];
+	for &value in draws.iter() {
+		seen[value as uint] = true;
+	}
+	assert!(seen.iter().all(|&seen| seen));
+}
+
+#[test]
+/// Checkpoints should be collected with the cycles before them in debug mode only.
+fn test_machine_checkpoints() {
+	let extended = ::ast::ParseOptions { extensions: true, ..::ast::ParseOptions::strict() };
+	let (program, _) = Ast::parse_with("+#start++[-#loop]#end", extended).unwrap();
+	let mut machine = Machine::capturing([]);
+	machine.set_extensions(ExtensionSet::all());
+	assert!(machine.run_program(&program) == Ok(12));
+	assert!(machine.checkpoints().is_empty());
+
+	machine.set_debug(true);
+	assert!(machine.run_program(&program) == Ok(12));
+	assert!(machine.checkpoints() == [(~"start", 1), (~"loop", 6), (~"loop", 9), (~"end", 11)].as_slice());
+	// Where comments are free, so are checkpoints.
+	machine.set_semantics(Semantics::classic());
+	assert!(machine.run_program(&program) == Ok(8));
+	assert!(machine.checkpoints() == [(~"start", 1), (~"loop", 5), (~"loop", 7), (~"end", 8)].as_slice());
+}
+
+#[test]
+/// `@` should hand the machine over to the host, which reads and writes the current cell.
+fn test_machine_host_call() {
+	let extended = ::ast::ParseOptions { extensions: true, ..::ast::ParseOptions::strict() };
+	let (program, _) = Ast::parse_with("+++@.>@", extended).unwrap();
+	assert!(format!("{}", program) == ~"+++@.>@");
+	let mut seen = Vec::new();
+	{
+		let mut machine = Machine::capturing([]);
+		machine.set_extensions(ExtensionSet::all());
+		machine.on_host_call(|machine| {
+			let cell = machine.current_cell();
+			seen.push(cell);
+			machine.set_current_cell(cell + 62);
+		});
+		assert!(machine.run_program(&program) == Ok(7));
+		assert!(machine.captured_output() == bytes!("A"));
+		assert!(machine.current_cell() == 62);
+	}
+	assert!(seen == vec!(3, 0));
+	// Without a hook, `@` only costs a cycle, and without extensions, it is a comment.
+	let mut machine = Machine::capturing([]);
+	machine.set_extensions(ExtensionSet::all());
+	assert!(machine.run_program(&program) == Ok(7));
+	assert!(machine.captured_output() == bytes!(3));
+	assert!(Ast::parse_str("@") == Ok(Ast(~[::operators::Nop(~"@")])));
+}
+
+#[test]
+/// Every line ending should read as a single LF when translating, and as it is otherwise.
+fn test_machine_newline_translation() {
+	let program = Ast::parse_str(",[.,]").unwrap();
+	let echo = |input: &[u8], mode: NewlineMode| {
+		let mut machine = Machine::capturing(input);
+		machine.set_newline_mode(mode);
+		machine.run_program(&program).unwrap();
+		machine.captured_output().to_owned()
+	};
+	let cases = [
+		(bytes!("one\r\ntwo\r\n"), bytes!("one\ntwo\n")),
+		(bytes!("one\rtwo"), bytes!("one\ntwo")),
+		// A CR right before EOF is still a newline.
+		(bytes!("one\r"), bytes!("one\n")),
+		(bytes!("a\r\nb\rc\nd\r\r\n\n"), bytes!("a\nb\nc\nd\n\n\n")),
+	];
+	for &(input, expected) in cases.iter() {
+		assert!(echo(input, TranslateNewlines).as_slice() == expected);
+		assert!(echo(input, RawNewlines).as_slice() == input);
+	}
+	// Filters only see what the translation left.
+	let mut machine = Machine::capturing(bytes!("a\r\nb"));
+	machine.set_newline_mode(TranslateNewlines);
+	machine.add_input_filter(TakeWhile('\r' as u8));
+	machine.run_program(&program).unwrap();
+	assert!(machine.captured_output() == bytes!("a\nb"));
+
+	// Output is only translated on consoles, or where told it is one.
+	let newline = Ast::parse_str("++++++++++.").unwrap();
+	let mut machine = Machine::capturing([]);
+	machine.set_output_newline_mode(TranslateNewlines);
+	machine.run_program(&newline).unwrap();
+	assert!(machine.captured_output() == bytes!("\n"));
+	let mut machine = Machine::capturing([]);
+	machine.set_output_newline_mode(TranslateNewlines);
+	machine.set_output_console(true);
+	machine.run_program(&newline).unwrap();
+	assert!(machine.captured_output() == PLATFORM_NEWLINE);
+}
+
+#[cfg(test)]
+static LOOPS: &'static str = "++++++++[>++++++++<-]>+.[>+>+<<-]>>+.<[-<+>]<.+++[>+++<-]>.";
+
+#[test]
+/// Runs resumed from a snapshot should end as if never interrupted.
+fn test_machine_snapshots() {
+	let program = Ast::parse_str(LOOPS).unwrap();
+	let mut machine = Machine::capturing([]);
+	let cycles = machine.run_program(&program).unwrap();
+	let expected = machine.captured_output().to_owned();
+
+	let mut machine = Machine::capturing([]);
+	let (snapshots, result) = machine.run_with_snapshots(&program, 1);
+	assert!(result == Ok(cycles) && machine.captured_output() == expected.as_slice());
+	assert!(snapshots.iter().map(|snapshot| snapshot.exit).collect::<Vec<uint>>() == vec!(1, 2, 3, 4));
+	assert!(snapshots.iter().all(|snapshot| snapshot.is_resumable()));
+	let middle = snapshots.get(1);
+	assert!(middle.head == 1 && middle.output_len == 1);
+	assert!(middle.recent == vec!((1, 0), (3, 65), (2, 65), (0, 0)));
+
+	let mut resumed = Machine::capturing([]);
+	assert!(resumed.resume_from(middle, &program) == Ok(cycles));
+	assert!(resumed.captured_output() == expected.slice_from(middle.output_len));
+	assert!(resumed.dump_tape((None, None)) == machine.dump_tape((None, None)));
+
+	// The first checksum to differ tells the loop which changed.
+	let changed = Ast::parse_str(LOOPS.replace("[-<+>]", "[-<++>]").as_slice()).unwrap();
+	let (others, _) = Machine::capturing([]).run_with_snapshots(&changed, 1);
+	assert!(snapshots.iter().zip(others.iter()).position(|(a, b)| a.checksum != b.checksum) == Some(2));
+}
+
+#[test]
+/// Long runs should keep their snapshots, and the resumable ones, within bounds.
+fn test_machine_snapshot_thinning() {
+	let program = Ast::parse_str("+[-]".repeat(1000).as_slice()).unwrap();
+	let (snapshots, _) = Machine::capturing([]).run_with_snapshots(&program, 10);
+	assert!(snapshots.len() == 100 && snapshots.iter().all(|snapshot| snapshot.exit % 10 == 0));
+
+	// Going over the bound halves how often snapshots are taken, twice here.
+	let (snapshots, result) = Machine::capturing([]).run_with_snapshots(&program, 1);
+	assert!(snapshots.len() == 250 && snapshots.len() <= MAX_SNAPSHOTS);
+	assert!(snapshots.iter().all(|snapshot| snapshot.exit % 4 == 0));
+	let resumable: Vec<&Snapshot> = snapshots.iter().filter(|snapshot| snapshot.is_resumable()).collect();
+	assert!(resumable.len() > 0 && resumable.len() <= MAX_RESUMABLE_SNAPSHOTS);
+	let last = snapshots.last().unwrap();
+	assert!(last.exit == 1000 && last.is_resumable());
+	assert!(Machine::capturing([]).resume_from(last, &program) == result);
+
+	let thinned = snapshots.iter().find(|snapshot| !snapshot.is_resumable()).unwrap();
+	let mut machine = Machine::capturing([]);
+	assert!(machine.resume_from(thinned, &program) == Err(SnapshotNotResumable(thinned.exit)));
+	assert!(machine.cycles() == 0);
+}
+
+#[test]
+/// Resuming a snapshot of another program should fail, running nothing.
+fn test_machine_snapshot_of_another_program() {
+	let program = Ast::parse_str(LOOPS).unwrap();
+	let (snapshots, _) = Machine::capturing([]).run_with_snapshots(&program, 1);
+	let other = Ast::parse_str(LOOPS.replace("[-<+>]", "[-<++>]").as_slice()).unwrap();
+	let mut machine = Machine::capturing([]);
+	assert!(machine.resume_from(snapshots.get(1), &other) == Err(SnapshotOfAnotherProgram(2)));
+	assert!(machine.cycles() == 0 && machine.captured_output().len() == 0);
+}
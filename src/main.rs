@@ -5,19 +5,124 @@
 #[phase(syntax, link)] extern crate log;
 extern crate brainfuck;
 extern crate getopts;
+extern crate time;
 
 use std::os;
+use std::io;
 use std::io::File;
-use getopts::getopts;
-use brainfuck::{Ast,Machine};
+use std::io::signal::{Listener, Interrupt};
+use std::libc;
+use std::rand;
+use getopts::{getopts, optflag, optflagopt, optopt, optmulti, OptGroup};
+use brainfuck::{Ast,Semantics,RunReport,CancelToken,ParseOptions,ExtractOptions};
+use brainfuck::analysis::{find_repeats, eof_sensitivity};
+use brainfuck::semantics::{EofZero, EofMinusOne, EofUnchanged};
+use brainfuck::dialect::{translate, BRAINFUCK};
+use brainfuck::optimizer::emit_optimized_source;
+use brainfuck::runner::{run_files, RunOptions, RunPlan, Summary, FileRunError, FileParseError};
+use brainfuck::heatmap::{HeatCounts, HeatIntensity};
+use brainfuck::machine::{DEFAULT_SEED, Blocking, EofOnEmpty, Raw, Codepoint, Limits};
+use brainfuck::machine::{RawNewlines, TranslateNewlines};
+use brainfuck::conformance::check_all;
+use brainfuck::trace::diff_lockstep;
+use brainfuck::pacing::{Unpaced, PerByte};
+use brainfuck::filters::{Filter, filter_from_name};
+use brainfuck::SourceMap;
+
+/// The command line options.
+fn opts() -> ~[OptGroup] {
+    ~[
+        optflagopt("", "analyze-repeats",
+            "Report repeated fragments instead of running (default 8 ops)", "MIN_LEN"),
+        optflag("v", "verbose", "Print a banner before running each file, and its cycles and duration after"),
+        optflag("", "version", "Print the name and version of the interpreter, and with --verbose the features it was built with"),
+        optflag("", "fail-fast", "Stop at the first file failing to parse or to run"),
+        optflag("", "pure", "Refuse programs doing any I/O, which can then only compute on their tape"),
+        optflag("", "no-input", "Run without any input, refusing programs which read some"),
+        optflag("", "eof-on-input", "With --no-input, run programs reading input, feeding them EOF"),
+        optflag("", "args-on-tape",
+            "Preload the tape with the arguments after `--`: their count, then the bytes of each one followed by a zero"),
+        optopt("", "input-mode",
+            "When input has nothing to give yet: block (default) waits, eof-on-empty takes it as EOF", "MODE"),
+        optopt("", "output-mode",
+            "What writing a cell outputs: byte (default), or codepoint for its UTF-8 character", "MODE"),
+        optopt("", "newline",
+            "How line endings are read, and written to a terminal: raw keeps them, translate reads CRLF and CR as LF, and writes LF as the platform does (default on Windows)", "MODE"),
+        optmulti("", "input-filter",
+            "Pass input through a filter before programs read it, in the order given: uppercase, strip-cr, crlf, translate:FROM:TO or take-while:BYTE", "NAME[:ARGS]"),
+        optmulti("", "output-filter", "Pass output through a filter before writing it, in the order given, named like with --input-filter", "NAME[:ARGS]"),
+        optopt("", "output-delay", "Wait MS milliseconds before writing each byte of output, e.g. for animations", "MS"),
+        optflag("", "extensions", "Parse the extended operators: `{...}` do-while loops, `?` random values, `!` negation, `#name` checkpoints, `=N` cell values and `@` host calls"),
+        optflag("", "recover", "Close unmatched `[` at the end and drop unmatched `]`, with warnings"),
+        optflag("", "extract", "List the programs found in each file, e.g. a post or a README, instead of running it"),
+        optopt("", "extract-run", "Run the Nth program found in each file, from 1, instead of the file itself", "N"),
+        optopt("", "extract-min-ops", "Only find programs of at least N operators (default 16)", "N"),
+        optflag("", "self-check", "Run each file on every backend instead, with the input from stdin, reporting any difference, within 100000000 cycles"),
+        optopt("", "diff-against",
+            "Run each file side by side with another program instead, with the input from stdin, reporting where they first differ, within 100000000 cycles", "FILE"),
+        optflag("", "warn-clamp", "Warn every time the head stops at the origin, moving left of it, on stderr"),
+        optflag("", "forbid-clamp", "Fail programs whose head stops at the origin, moving left of it"),
+        optflag("", "dump-tape", "Print a picture of the tape to stderr after running"),
+        optflag("", "debug", "Print the `#name` checkpoints reached to stderr, with --extensions"),
+        optflagopt("", "heatmap",
+            "Print how many times each source character ran to stderr, as counts (default) or intensity", "STYLE"),
+        optflag("", "explain", "Narrate what each instruction did to stderr, as it runs"),
+        optopt("", "explain-limit", "Stop narrating after N lines, the run going on (default 200)", "N"),
+        optflagopt("", "progress",
+            "Print how far each run is to stderr every so many cycles (default 10000000), against an estimate if any", "CYCLES"),
+        optopt("", "semantics", "Dialect to follow: classic, common (default) or strict", "NAME"),
+        optopt("", "seed", "Seed of the random values of `?`: a number, or random", "SEED"),
+        optopt("", "max-memory", "Fail programs whose tape takes more than BYTES, e.g. 64M", "BYTES"),
+        optopt("", "tape", "Tape to use: vector (default), sparse, two-way, chunked, or ring:N for a ring of N cells", "KIND"),
+        optopt("O", "opt-level", "Optimize the program: 0 (default), 1 folds runs, 2 also offsets, 3 balanced writes instead", "LEVEL"),
+        optopt("", "cache-dir", "Cache programs optimized with -O2 and up in DIR, loading them from there next time", "DIR"),
+        optflag("", "no-cache", "Optimize programs every time, without caching them (the default)"),
+        optflag("", "time", "Print how long the run took to stderr"),
+        optopt("", "report", "Print a summary of the run to stderr, in the given format: json", "FORMAT"),
+        optopt("", "report-file", "Write the summary to a file instead of stderr", "PATH"),
+    ]
+}
+
+/// Parses a number of bytes, maybe followed by K, M or G.
+fn parse_bytes(text: &str) -> Option<uint> {
+    if text.is_empty() { return None }
+    let (digits, unit) = match text.char_at_reverse(text.len()) {
+        'K' | 'k' => (text.slice_to(text.len() - 1), 1 << 10),
+        'M' | 'm' => (text.slice_to(text.len() - 1), 1 << 20),
+        'G' | 'g' => (text.slice_to(text.len() - 1), 1 << 30),
+        _ => (text, 1),
+    };
+    from_str::<uint>(digits).map(|n| n * unit)
+}
+
+/// Makes the filters named, in order.
+fn parse_filters(names: &[~str]) -> Result<~[Filter], ~str> {
+    let mut filters = ~[];
+    for name in names.iter() {
+        match filter_from_name(*name) {
+            Some(filter) => filters.push(filter),
+            None => return Err(format!("Unknown filter `{}`.", *name)),
+        }
+    }
+    Ok(filters)
+}
 
 /// Prints a simple help screen.
 fn usage(reason: &str) {
     let cmd = os::args()[0];
-    println!("Usage: {} <source.bf>", cmd);
+    let brief = format!("Usage: {} [options] <source.bf>... [-- <args>...]\n       {} translate [--from DIALECT] [--to DIALECT] <source>\n       {} check [--semantics NAME] <source.bf>...", cmd, cmd, cmd);
+    println!("{}", getopts::usage(brief, opts()));
     if !reason.is_empty() { println!("{}", reason); }
 }
 
+/// The exit status when a program could not be run, e.g. unreadable.
+static EXIT_RUN_ERROR: int = 1;
+/// The exit status when a program could not be parsed.
+static EXIT_PARSE_ERROR: int = 2;
+
+/// How many cycles a run may take when comparing runs, before giving up.
+static CHECK_CYCLES: uint = 100000000;
+
 /// Reads the contents of a file into a string.
 fn read_file(filename: &~str) -> Result<~str, ~str> {
     let mut file = match File::open( &Path::new(filename.as_bytes()) ) {
@@ -30,36 +135,632 @@ fn read_file(filename: &~str) -> Result<~str, ~str> {
     }
 }
 
-/// Interpretes the given program, piping from STDIN
-/// and to STDOUT.
+/// The source of the Nth program found in a text, from 1.
+fn extracted(text: ~str, n: uint, options: ExtractOptions) -> Result<~str, ~str> {
+    let found = Ast::extract_from_text(text, options);
+    if n > found.len() {
+        return Err(format!("No program {} found, only {}.", n, found.len()));
+    }
+    let &(ref span, _) = found.get(n - 1);
+    Ok(text.chars().skip(span.start).take(span.end - span.start).collect())
+}
+
+/// Prints the fragments repeated in a program, and where they are.
+fn print_repeats(program: &Ast, min_len: uint) {
+    for repeat in find_repeats(program, min_len).iter() {
+        let fragment: ~[~str] = repeat.fragment.iter()
+            .map(|op| format!("{}", op)).collect();
+        println!("{} times, {} ops, saves {}: {}",
+            repeat.count, repeat.fragment.len(), repeat.saved, fragment.concat());
+        for span in repeat.spans.iter() {
+            println!("    at {}", span);
+        }
+    }
+}
+
+/// Cancels runs on Ctrl-C. A second Ctrl-C, while the run has yet
+/// to notice the first, e.g. because it is waiting for input, exits.
+/// Stops listening once the returned sender is dropped.
+fn cancel_on_interrupt(token: CancelToken) -> Sender<()> {
+    let (done, finished) = channel::<()>();
+    let mut listener = Listener::new();
+    if listener.register(Interrupt).is_err() { return done }
+    spawn(proc() {
+        loop {
+            select! (
+                _ = listener.rx.recv() => {
+                    if token.is_cancelled() {
+                        unsafe { libc::exit(130) };
+                    }
+                    token.cancel();
+                },
+                _ = finished.recv_opt() => break
+            )
+        }
+    });
+    done
+}
+
+/// The command line options of the `translate` subcommand.
+fn translate_opts() -> ~[OptGroup] {
+    ~[
+        optopt("", "from", "Dialect of the source: bf (default) or ook", "DIALECT"),
+        optopt("", "to", "Dialect to translate to: bf (default) or ook", "DIALECT"),
+        optflag("", "annotate-loops", "Mark the start and end of each loop with a comment line"),
+    ]
+}
+
+/// Translates a program between Brainfuck and its derivatives,
+/// writing it to STDOUT.
+fn translate_main(args: &[~str]) {
+    let usage = |reason: &str| {
+        let brief = format!("Usage: {} translate [options] <source>", os::args()[0]);
+        println!("{}", getopts::usage(brief, translate_opts()));
+        if !reason.is_empty() { println!("{}", reason); }
+    };
+    let matches = match getopts(args, translate_opts()) {
+        Ok(m) => m,
+        Err(err) => return usage(err.to_err_msg()),
+    };
+    let filename = match matches.free.as_slice() {
+        [ref filename] => filename.clone(),
+        _ => return usage("Expected a single source file."),
+    };
+    let source = match read_file(&filename) {
+        Ok(source) => source,
+        Err(msg) => return usage(msg),
+    };
+    let from = matches.opt_str("from").unwrap_or(~"bf");
+    let to = matches.opt_str("to").unwrap_or(~"bf");
+    match translate(source, from, to, matches.opt_present("annotate-loops")) {
+        Ok(translated) => {
+            let _ = writeln!(&mut io::stderr(), "note: comments are dropped when translating.");
+            print!("{}", translated);
+        }
+        Err(msg) => return usage(msg),
+    }
+}
+
+/// The command line options of the `check` subcommand.
+fn check_opts() -> ~[OptGroup] {
+    ~[
+        optopt("", "semantics", "Dialect the programs will run with: classic, common (default) or strict", "NAME"),
+        optflag("", "extensions", "Parse the extended operators: `{...}` do-while loops, `?` random values, `!` negation, `#name` checkpoints, `=N` cell values and `@` host calls"),
+    ]
+}
+
+/// Checks the given programs without running them, reporting the
+/// reads whose outcome at the end of input may change control flow.
+fn check_main(args: &[~str]) {
+    let usage = |reason: &str| {
+        let brief = format!("Usage: {} check [options] <source>...", os::args()[0]);
+        println!("{}", getopts::usage(brief, check_opts()));
+        if !reason.is_empty() { println!("{}", reason); }
+    };
+    let matches = match getopts(args, check_opts()) {
+        Ok(m) => m,
+        Err(err) => return usage(err.to_err_msg()),
+    };
+    if matches.free.is_empty() {
+        return usage("Expected at least one source file.");
+    }
+    let name = matches.opt_str("semantics").unwrap_or(~"common");
+    let semantics = match Semantics::from_name(name) {
+        Some(semantics) => semantics,
+        None => return usage(format!("Unknown semantics `{}`.", name)),
+    };
+    let options = ParseOptions { extensions: matches.opt_present("extensions"), ..ParseOptions::strict() };
+
+    let mut flagged = false;
+    for filename in matches.free.iter() {
+        let parsed = match read_file(filename) {
+            Ok(source) => Ast::parse_with(source, options).map_err(|msg| (msg, EXIT_PARSE_ERROR)),
+            Err(msg) => Err((msg, EXIT_RUN_ERROR)),
+        };
+        let program = match parsed {
+            Ok((program, _)) => program,
+            Err((msg, status)) => {
+                println!("{}: {}", filename, msg);
+                os::set_exit_status(status);
+                continue;
+            }
+        };
+        for span in eof_sensitivity(&program).reads.iter() {
+            println!("{}:{}: A loop may check this read, so that what it does at the end of input matters.", filename, span);
+            flagged = true;
+        }
+    }
+    if flagged {
+        let eof = match semantics.eof {
+            EofZero => "sets the cell to 0",
+            EofMinusOne => "sets the cell to -1",
+            EofUnchanged => "leaves the cell unchanged",
+        };
+        println!("note: at the end of input, `,` {} with the {} semantics.", eof, name);
+    }
+}
+
+/// The command line options of the `hash` subcommand.
+fn hash_opts() -> ~[OptGroup] {
+    ~[
+        optflag("", "extensions", "Parse the extended operators: `{...}` do-while loops, `?` random values, `!` negation, `#name` checkpoints, `=N` cell values and `@` host calls"),
+    ]
+}
+
+/// Prints the canonical hash of each given program, which stays the
+/// same however the program is commented or formatted.
+fn hash_main(args: &[~str]) {
+    let usage = |reason: &str| {
+        let brief = format!("Usage: {} hash [options] <source>...", os::args()[0]);
+        println!("{}", getopts::usage(brief, hash_opts()));
+        if !reason.is_empty() { println!("{}", reason); }
+    };
+    let matches = match getopts(args, hash_opts()) {
+        Ok(m) => m,
+        Err(err) => return usage(err.to_err_msg()),
+    };
+    if matches.free.is_empty() {
+        return usage("Expected at least one source file.");
+    }
+    let options = ParseOptions { extensions: matches.opt_present("extensions"), ..ParseOptions::strict() };
+    for filename in matches.free.iter() {
+        let parsed = match read_file(filename) {
+            Ok(source) => Ast::parse_with(source, options).map_err(|msg| (msg, EXIT_PARSE_ERROR)),
+            Err(msg) => Err((msg, EXIT_RUN_ERROR)),
+        };
+        match parsed {
+            Ok((program, _)) => println!("{}  {}", program.canonical_hash_hex(), filename),
+            Err((msg, status)) => {
+                println!("{}: {}", filename, msg);
+                os::set_exit_status(status);
+            }
+        }
+    }
+}
+
+/// The command line options of the `optimize` subcommand.
+fn optimize_opts() -> ~[OptGroup] {
+    ~[
+        optopt("o", "output", "Write the optimized program to a file instead of stdout", "PATH"),
+        optflag("", "annotate", "Tell what each optimized operator was made of, in comments"),
+        optflag("", "extensions", "Parse the extended operators: `{...}` do-while loops, `?` random values, `!` negation, `#name` checkpoints, `=N` cell values and `@` host calls"),
+    ]
+}
+
+/// Prints a program as optimized, in standard Brainfuck, to show
+/// what the optimizer does to it.
+fn optimize_main(args: &[~str]) {
+    let usage = |reason: &str| {
+        let brief = format!("Usage: {} optimize [options] <source>", os::args()[0]);
+        println!("{}", getopts::usage(brief, optimize_opts()));
+        if !reason.is_empty() { println!("{}", reason); }
+    };
+    let matches = match getopts(args, optimize_opts()) {
+        Ok(m) => m,
+        Err(err) => return usage(err.to_err_msg()),
+    };
+    let filename = match matches.free.as_slice() {
+        [ref filename] => filename.clone(),
+        _ => return usage("Expected a single source file."),
+    };
+    let source = match read_file(&filename) {
+        Ok(source) => source,
+        Err(msg) => return usage(msg),
+    };
+    let options = ParseOptions { extensions: matches.opt_present("extensions"), ..ParseOptions::strict() };
+    let program = match Ast::parse_with(source, options) {
+        Ok((program, _)) => program,
+        Err(msg) => {
+            println!("{}: {}", filename, msg);
+            return os::set_exit_status(EXIT_PARSE_ERROR);
+        }
+    };
+    let (optimized, map) = program.optimize_mapped();
+    let emitted = if matches.opt_present("annotate") {
+        emit_optimized_source(&optimized, &map, source)
+    } else {
+        BRAINFUCK.render(&optimized, false)
+    };
+    match matches.opt_str("output") {
+        Some(path) => if File::create(&Path::new(path.as_slice())).and_then(|mut f| f.write_str(emitted)).is_err() {
+            println!("Cannot write to `{}`.", path);
+            os::set_exit_status(EXIT_RUN_ERROR);
+        },
+        None => print!("{}", emitted),
+    }
+}
+
+/// Whether an argument is merely the interpreter's own name, as some
+/// systems pass it along when running a `#!` script, rather than a file.
+fn is_own_name(arg: &str) -> bool {
+    let own = os::args()[0];
+    let named = arg == own || Some(arg) == Path::new(own.as_slice()).filename_str();
+    named && !Path::new(arg).exists()
+}
+
+/// Interpretes the given programs one after the other, piping
+/// from STDIN and to STDOUT.
 fn main() {
     let args = os::args();
-    let matches = match getopts(args.tail(), []) {
+    if args.len() > 1 && args[1] == ~"translate" {
+        return translate_main(args.slice_from(2));
+    }
+    if args.len() > 1 && args[1] == ~"check" {
+        return check_main(args.slice_from(2));
+    }
+    if args.len() > 1 && args[1] == ~"hash" {
+        return hash_main(args.slice_from(2));
+    }
+    if args.len() > 1 && args[1] == ~"optimize" {
+        return optimize_main(args.slice_from(2));
+    }
+    // With --args-on-tape, what follows `--` is for the programs.
+    let (args, program_args) = match args.iter().position(|arg| *arg == ~"--") {
+        Some(i) if args.slice_to(i).contains(&~"--args-on-tape") => (args.slice_to(i), args.slice_from(i + 1)),
+        _ => (args.as_slice(), &[]),
+    };
+    let mut matches = match getopts(args.tail(), opts()) {
         Ok(m) => m,
         Err(err) => return usage(err.to_err_msg()),
     };
+    matches.free.retain(|arg| !is_own_name(*arg));
+    if matches.opt_present("version") {
+        println!("bf {}", brainfuck::VERSION);
+        if matches.opt_present("verbose") {
+            println!("features: {}", brainfuck::features());
+        }
+        return;
+    }
     if matches.free.is_empty() {
         return usage("No source file given.");
     };
+    let semantics = match matches.opt_str("semantics") {
+        Some(name) => match Semantics::from_name(name) {
+            Some(semantics) => semantics,
+            None => return usage(format!("Unknown semantics `{}`.", name)),
+        },
+        None => Semantics::common(),
+    };
+    let opt_level = match matches.opt_str("opt-level") {
+        Some(level) => match from_str::<uint>(level) {
+            Some(level) if level <= 3 => level,
+            _ => return usage("Invalid optimization level."),
+        },
+        None => 0,
+    };
+    if matches.opt_present("no-cache") && matches.opt_present("cache-dir") {
+        return usage("--no-cache and --cache-dir are contradictory.");
+    }
+    let cache_dir = matches.opt_str("cache-dir").map(|dir| Path::new(dir));
+    match matches.opt_str("report") {
+        Some(ref format) if *format != ~"json" => {
+            return usage(format!("Unknown report format `{}`.", format));
+        }
+        _ => {}
+    }
+    let tape = matches.opt_str("tape").unwrap_or(~"vector");
+    let max_memory = match matches.opt_str("max-memory") {
+        None => None,
+        Some(bytes) => match parse_bytes(bytes) {
+            Some(bytes) => Some(bytes),
+            None => return usage(format!("Invalid memory limit `{}`.", bytes)),
+        },
+    };
+    let input_mode = match matches.opt_str("input-mode") {
+        None => Blocking,
+        Some(ref mode) if *mode == ~"block" => Blocking,
+        Some(ref mode) if *mode == ~"eof-on-empty" => EofOnEmpty,
+        Some(mode) => return usage(format!("Unknown input mode `{}`.", mode)),
+    };
+    let output_mode = match matches.opt_str("output-mode") {
+        None => Raw,
+        Some(ref mode) if *mode == ~"byte" => Raw,
+        Some(ref mode) if *mode == ~"codepoint" => Codepoint,
+        Some(mode) => return usage(format!("Unknown output mode `{}`.", mode)),
+    };
+    let newline_mode = match matches.opt_str("newline") {
+        None if cfg!(windows) => TranslateNewlines,
+        None => RawNewlines,
+        Some(ref mode) if *mode == ~"raw" => RawNewlines,
+        Some(ref mode) if *mode == ~"translate" => TranslateNewlines,
+        Some(mode) => return usage(format!("Unknown newline mode `{}`.", mode)),
+    };
+    let output_pacing = match matches.opt_str("output-delay") {
+        None => Unpaced,
+        Some(ms) => match from_str::<u64>(ms) {
+            Some(ms) => PerByte(ms),
+            None => return usage("Invalid output delay."),
+        },
+    };
+    let input_filters = match parse_filters(matches.opt_strs("input-filter")) {
+        Ok(filters) => filters,
+        Err(reason) => return usage(reason),
+    };
+    let output_filters = match parse_filters(matches.opt_strs("output-filter")) {
+        Ok(filters) => filters,
+        Err(reason) => return usage(reason),
+    };
+    if matches.opt_present("eof-on-input") && !matches.opt_present("no-input") {
+        return usage("--eof-on-input only makes sense with --no-input.");
+    }
+    let seed = match matches.opt_str("seed") {
+        Some(ref seed) if *seed == ~"random" => {
+            let seed = rand::random::<u64>();
+            let _ = writeln!(&mut io::stderr(), "Seeded with {}.", seed);
+            seed
+        }
+        Some(seed) => match from_str::<u64>(seed) {
+            Some(seed) => seed,
+            None => return usage("Invalid seed."),
+        },
+        None => DEFAULT_SEED,
+    };
+    let heatmap = match matches.opt_default("heatmap", "counts") {
+        Some(ref style) if *style == ~"counts" => Some(HeatCounts),
+        Some(ref style) if *style == ~"intensity" => Some(HeatIntensity),
+        Some(style) => return usage(format!("Unknown heatmap style `{}`.", style)),
+        None => None,
+    };
+    if heatmap.is_some() && opt_level > 1 {
+        return usage("--heatmap only works up to -O1.");
+    }
+    let explain = match matches.opt_str("explain-limit") {
+        None if !matches.opt_present("explain") => None,
+        None => Some(200),
+        Some(limit) => match from_str::<uint>(limit) {
+            Some(limit) => Some(limit),
+            None => return usage("Invalid narration limit."),
+        },
+    };
+    if explain.is_some() && opt_level > 1 {
+        return usage("--explain only works up to -O1.");
+    }
+    let progress = match matches.opt_default("progress", "10000000") {
+        None => None,
+        Some(every) => match from_str::<uint>(every) {
+            Some(every) if every > 0 => Some(every),
+            _ => return usage("Invalid progress interval."),
+        },
+    };
+    let extract_options = match matches.opt_str("extract-min-ops") {
+        None => ExtractOptions::default(),
+        Some(min_ops) => match from_str::<uint>(min_ops) {
+            Some(min_ops) => ExtractOptions { min_ops: min_ops, ..ExtractOptions::default() },
+            None => return usage("Invalid minimum of operators."),
+        },
+    };
+    let extract_run = match matches.opt_str("extract-run") {
+        None => None,
+        Some(n) => match from_str::<uint>(n) {
+            Some(n) if n > 0 => Some(n),
+            _ => return usage("Invalid program number, expected 1 or more."),
+        },
+    };
+    let report = matches.opt_present("report") || matches.opt_present("report-file");
+    let cancel = CancelToken::new();
+    let _listening = cancel_on_interrupt(cancel.clone());
+
+    // Only report the repeated fragments, if asked to.
+    match matches.opt_default("analyze-repeats", "8") {
+        Some(min_len) => {
+            let min_len = match from_str::<uint>(min_len) {
+                Some(min_len) => min_len,
+                None => return usage("Invalid minimum fragment length."),
+            };
+            for filename in matches.free.iter() {
+                let parsed = match read_file(filename) {
+                    Ok(source) => Ast::parse_str(source).map_err(|msg| (msg, EXIT_PARSE_ERROR)),
+                    Err(msg) => Err((msg, EXIT_RUN_ERROR)),
+                };
+                match parsed {
+                    Ok(program) => print_repeats(&program, min_len),
+                    Err((msg, status)) => {
+                        let _ = writeln!(&mut io::stderr(), "{}: {}", filename, msg);
+                        os::set_exit_status(status);
+                    }
+                }
+            }
+            return;
+        }
+        None => {}
+    }
+
+    // Only list the programs found in each file, if asked to.
+    if matches.opt_present("extract") {
+        for filename in matches.free.iter() {
+            match read_file(filename) {
+                Ok(source) => {
+                    let found = Ast::extract_from_text(source, extract_options.clone());
+                    for (i, &(ref span, ref program)) in found.iter().enumerate() {
+                        println!("{}: {} at {}: {}", filename, i + 1, *span, program.display_truncated(60));
+                    }
+                }
+                Err(msg) => {
+                    let _ = writeln!(&mut io::stderr(), "{}: {}", filename, msg);
+                    os::set_exit_status(EXIT_RUN_ERROR);
+                }
+            }
+        }
+        return;
+    }
 
+    // Comparing runs parses programs like running them, within a limit.
+    let parse_options = ParseOptions {
+        recover_brackets: matches.opt_present("recover"),
+        extensions: matches.opt_present("extensions"),
+        ..ParseOptions::strict()
+    };
+    let check_limits = Limits { cycles: Some(CHECK_CYCLES), ..Limits::none() };
+
+    // Only check that every backend runs the programs alike, if asked to.
+    if matches.opt_present("self-check") {
+        let input = io::stdin().read_to_end().unwrap_or(~[]);
+        for filename in matches.free.iter() {
+            let parsed = match read_file(filename) {
+                Ok(source) => Ast::parse_with(source, parse_options).map_err(|msg| (msg, EXIT_PARSE_ERROR)),
+                Err(msg) => Err((msg, EXIT_RUN_ERROR)),
+            };
+            match parsed {
+                Ok((program, _)) => {
+                    let report = check_all(&program, input, check_limits.clone(), semantics.clone());
+                    if !report.is_ok() {
+                        let _ = writeln!(&mut io::stderr(), "{}: backends disagree\n{}", filename, report);
+                        os::set_exit_status(EXIT_RUN_ERROR);
+                    }
+                }
+                Err((msg, status)) => {
+                    let _ = writeln!(&mut io::stderr(), "{}: {}", filename, msg);
+                    os::set_exit_status(status);
+                }
+            }
+        }
+        return;
+    }
+
+    // Only compare the runs of each file with another program's, if asked to.
+    match matches.opt_str("diff-against") {
+        Some(other) => {
+            let input = io::stdin().read_to_end().unwrap_or(~[]);
+            let against = match read_file(&other).and_then(|source| Ast::parse_with(source, parse_options)) {
+                Ok((program, _)) => program,
+                Err(msg) => {
+                    let _ = writeln!(&mut io::stderr(), "{}: {}", other, msg);
+                    os::set_exit_status(EXIT_PARSE_ERROR);
+                    return;
+                }
+            };
+            let against_map = SourceMap::of(&against);
+            for filename in matches.free.iter() {
+                let parsed = match read_file(filename) {
+                    Ok(source) => Ast::parse_with(source, parse_options).map_err(|msg| (msg, EXIT_PARSE_ERROR)),
+                    Err(msg) => Err((msg, EXIT_RUN_ERROR)),
+                };
+                match parsed {
+                    Ok((program, _)) => {
+                        let map = SourceMap::of(&program);
+                        match diff_lockstep(&program, &map, &against, &against_map, input,
+                                            check_limits.clone(), semantics.clone(), max_memory, 8) {
+                            Some(divergence) => {
+                                let _ = writeln!(&mut io::stderr(), "{} (a) against {} (b):\n{}", filename, other, divergence);
+                                os::set_exit_status(EXIT_RUN_ERROR);
+                            }
+                            None => {}
+                        }
+                    }
+                    Err((msg, status)) => {
+                        let _ = writeln!(&mut io::stderr(), "{}: {}", filename, msg);
+                        os::set_exit_status(status);
+                    }
+                }
+            }
+            return;
+        }
+        None => {}
+    }
+
+    let options = RunOptions {
+        verbose: matches.opt_present("verbose"),
+        fail_fast: matches.opt_present("fail-fast"),
+        recover: matches.opt_present("recover"),
+        extensions: matches.opt_present("extensions"),
+        opt_level: opt_level,
+        cache_dir: cache_dir,
+        semantics: semantics,
+        tape: tape.clone(),
+        max_memory: max_memory,
+        cancel: Some(cancel),
+        pure: matches.opt_present("pure"),
+        no_input: matches.opt_present("no-input"),
+        eof_on_input: matches.opt_present("eof-on-input"),
+        input_mode: input_mode,
+        output_mode: output_mode,
+        newline_mode: newline_mode,
+        console_output: io::stdio::stdout_raw().isatty(),
+        output_pacing: output_pacing,
+        input_filters: input_filters,
+        output_filters: output_filters,
+        warn_clamp: matches.opt_present("warn-clamp"),
+        forbid_clamp: matches.opt_present("forbid-clamp"),
+        heatmap: heatmap,
+        seed: seed,
+        debug: matches.opt_present("debug"),
+        explain: explain,
+        progress: progress,
+        args: if matches.opt_present("args-on-tape") {
+            Some(program_args.iter().map(|arg| arg.as_bytes().to_owned()).collect())
+        } else {
+            None
+        },
+    };
+    // Settings which do not make sense together are refused up front.
+    match options.builder().build() {
+        Ok(_) => {}
+        Err(err) => return usage(format!("{}", err)),
+    }
+
+    // Read every program source, piping them all from STDIN.
+    let mut plans = Vec::new();
+    let mut unreadable = 0;
     for filename in matches.free.iter() {
-        // Read the program source.
-        let source = match read_file(filename) {
-            Ok(source) => source,
-            Err(msg) => return usage(msg),
+        let read = match extract_run {
+            Some(n) => read_file(filename).and_then(|text| extracted(text, n, extract_options.clone())),
+            None => read_file(filename),
         };
+        match read {
+            Ok(source) => plans.push(RunPlan { name: filename.clone(), source: source, input: ~io::stdin_raw() as ~Reader }),
+            Err(msg) => {
+                let _ = writeln!(&mut io::stderr(), "{}: {}", filename, msg);
+                unreadable += 1;
+                if options.fail_fast { break }
+            }
+        }
+    }
+    if unreadable > 0 && options.fail_fast { return os::set_exit_status(EXIT_RUN_ERROR) }
 
-        // Parse the source code into an AST.
-        let program = match Ast::parse_str(source) {
-            Ok(program) => program,
-            Err(msg) => fail!(msg),
-        };
+    let mut report_failed = false;
+    let results = run_files(plans, &options, &mut io::stdout(), &mut io::stderr(), |file, machine| {
+        // Show the tape even after errors, for post-mortems.
+        if matches.opt_present("dump-tape") {
+            let report = machine.tape_report(machine.touched_range());
+            let _ = io::stderr().write_str(report.render_ascii(64));
+        }
 
-        // Create a machine and run the AST.
-        let mut machine = Machine::new();
-        match machine.run_program(&program) {
-            Ok(_) => { /* nop */ },
-            Err(msg) => fail!(msg),
+        let result = match file.outcome {
+            FileRunError(ref err) => Err(err.clone()),
+            _ => Ok(file.cycles),
         };
+
+        if matches.opt_present("time") {
+            let outcome = match result { Ok(_) => ~"ok", Err(ref err) => format!("{}", err) };
+            let _ = writeln!(&mut io::stderr(), "{}: {} cycles in {:.3f} ms, {}",
+                file.name, file.cycles, file.duration_ns as f64 / 1e6, outcome);
+        }
+
+        if report {
+            let mut summary = RunReport::new(machine, &result, file.duration_ns, opt_level);
+            summary.tape_backend = tape.clone();
+            let json = format!("{}", summary.to_json());
+            let written = match matches.opt_str("report-file") {
+                Some(path) => File::create(&Path::new(path)).and_then(|mut f| f.write_line(json)),
+                None => io::stderr().write_line(json),
+            };
+            if written.is_err() { report_failed = true }
+        }
+    });
+    if report_failed { return usage("Cannot write the report."); }
+
+    let summary = Summary::of(results.as_slice());
+    if options.verbose || matches.free.len() > 1 {
+        let _ = writeln!(&mut io::stderr(), "{}", summary);
+    }
+    // Parse errors take precedence, as nothing of those programs ran.
+    let unparsed = results.iter().any(|file| match file.outcome {
+        FileParseError(_) => true,
+        _ => false,
+    });
+    if unparsed {
+        os::set_exit_status(EXIT_PARSE_ERROR);
+    } else if summary.failures + unreadable > 0 {
+        os::set_exit_status(EXIT_RUN_ERROR);
     }
 }
@@ -1,4 +1,6 @@
 use std::fmt;
+use std::str;
+use std::io::{IoResult, MemWriter};
 use std::from_str::FromStr;
 use ast::Ast;
 
@@ -6,7 +8,7 @@ use ast::Ast;
 /**
 Internal representations for actual language operators.
 */
-
+#[deriving(Eq, TotalEq, Hash, Clone)]
 pub enum Operator {
 
 	// Operators from the spec:
@@ -30,13 +32,167 @@ pub enum Operator {
 	Loop,
 	/// An ignored, extraneous character.
 	Nop(~str),
+	/// A `#!` line starting the source, newline included, which is
+	/// skipped as a whole: it compiles to nothing, and costs nothing.
+	Shebang(~str),
+
+	// Extended operators, only parsed when asked to:
+
+	/// Starts a do-while loop, whose body always runs at least once.
+	DoSkip,
+	/// If the cell under head is non-zero, jump back to matching `DoSkip`.
+	DoLoop,
+	/// Sets the contents of the cell to a pseudo-random value.
+	Random,
+	/// A named checkpoint, `#name`, reported when reached in debug
+	/// mode and ignored otherwise.
+	Checkpoint(~str),
+	/// Sets the contents of the cell to a literal value, `=N`.
+	SetCell(u8),
+	/// Negates the contents of the cell, `!`, as `0 - v` wrapping
+	/// around: 1 becomes 255, and 0 stays 0.
+	Negate,
+	/// Calls the host back, `@`, which may inspect and change the
+	/// tape, see `Machine::on_host_call`.
+	HostCall,
 
 	// Internal operators:
 
 	/// Contains the parsed Ast for a nested block of code.
 	/// Used for containing the code inside `[...]` loops.
 	Sub(Ast),
+	/// Contains the parsed Ast for the body of a `{...}` do-while loop.
+	DoSub(Ast),
+
+	// Optimized operators:
+
+	/// Increments the contents of the cell by N.
+	IncrN(uint),
+	/// Decrements the contents of the cell by N.
+	DecrN(uint),
+	/// Moves the tape head N cells to the left.
+	PrevN(uint),
+	/// Moves the tape head N cells to the right.
+	NextN(uint),
+	/// Adds to the contents of the cell at an offset from the head,
+	/// without moving it.
+	AddAt(int, int),
+	/// Sets the contents of the cell at an offset from the head,
+	/// without moving it.
+	SetAt(int, u8),
+	/// Moves the tape head N cells to the left (-) or to the right (+).
+	Move(int),
+	/// Adds to the contents of several cells at offsets from the head,
+	/// without moving it: what to add, by offset.
+	OffsetWrite(~[(int, int)]),
+
+}
+
+impl Operator {
+	/**
+	The number of source characters this operator was parsed from.
+	Sub-blocks account for their brackets and their whole body.
+	*/
+	pub fn source_len(&self) -> uint {
+		match *self {
+			Nop(ref c) | Shebang(ref c) => c.char_len(),
+			Checkpoint(ref name) => name.char_len() + 1,
+			SetCell(value) => value.to_str().len() + 1,
+			Sub(ref ast) | DoSub(ref ast) => ast.source_len() + 2,
+			IncrN(n) | DecrN(n) | PrevN(n) | NextN(n) => n,
+			_ => 1,
+		}
+	}
+
+	/**
+	The name of the operator, with what it holds, or how much of it
+	for loops and multiple writes, e.g. `AddAt(2, -1)`.
+	*/
+	pub fn name(&self) -> ~str {
+		match *self {
+			Incr => ~"Incr",
+			Decr => ~"Decr",
+			Prev => ~"Prev",
+			Next => ~"Next",
+			Put => ~"Put",
+			Get => ~"Get",
+			Skip => ~"Skip",
+			Loop => ~"Loop",
+			DoSkip => ~"DoSkip",
+			DoLoop => ~"DoLoop",
+			Random => ~"Random",
+			Negate => ~"Negate",
+			HostCall => ~"HostCall",
+			Nop(ref c) => format!("Nop(\"{}\")", c.escape_default()),
+			Shebang(ref line) => format!("Shebang(\"{}\")", line.escape_default()),
+			Checkpoint(ref name) => format!("Checkpoint({})", *name),
+			SetCell(value) => format!("SetCell({})", value),
+			Sub(Ast(ref body)) => format!("Sub({} ops)", body.len()),
+			DoSub(Ast(ref body)) => format!("DoSub({} ops)", body.len()),
+			IncrN(n) => format!("IncrN({})", n),
+			DecrN(n) => format!("DecrN({})", n),
+			PrevN(n) => format!("PrevN({})", n),
+			NextN(n) => format!("NextN({})", n),
+			AddAt(offset, delta) => format!("AddAt({}, {})", offset, delta),
+			SetAt(offset, value) => format!("SetAt({}, {})", offset, value),
+			Move(offset) => format!("Move({})", offset),
+			OffsetWrite(ref writes) => format!("OffsetWrite({} cells)", writes.len()),
+		}
+	}
 
+	/**
+	Writes the source of the operator, spelling out optimized ones.
+	*/
+	fn write_source(&self, out: &mut Writer) -> IoResult<()> {
+		match *self {
+			Incr => out.write_str("+"),
+			Decr => out.write_str("-"),
+			Prev => out.write_str("<"),
+			Next => out.write_str(">"),
+			Skip => out.write_str("["),
+			Loop => out.write_str("]"),
+			Put  => out.write_str("."),
+			Get  => out.write_str(","),
+			DoSkip => out.write_str("{"),
+			DoLoop => out.write_str("}"),
+			Random => out.write_str("?"),
+			Negate => out.write_str("!"),
+			HostCall => out.write_str("@"),
+			Nop(ref c) | Shebang(ref c) => out.write_str(*c),
+			Checkpoint(ref name) => {
+				try!(out.write_str("#"));
+				out.write_str(*name)
+			}
+			SetCell(value) => write!(out, "={}", value),
+			Sub(ref ast) => write!(out, "[{}]", *ast),
+			DoSub(ref ast) => write!(out, "\\{{}\\}", *ast),
+			IncrN(n) => repeat(out, "+", n),
+			DecrN(n) => repeat(out, "-", n),
+			PrevN(n) => repeat(out, "<", n),
+			NextN(n) => repeat(out, ">", n),
+			AddAt(offset, delta) => {
+				try!(moves(out, offset));
+				try!(repeat(out, if delta < 0 { "-" } else { "+" }, delta.abs() as uint));
+				moves(out, -offset)
+			}
+			SetAt(offset, value) => {
+				try!(moves(out, offset));
+				try!(out.write_str("[-]"));
+				try!(repeat(out, "+", value as uint));
+				moves(out, -offset)
+			}
+			Move(offset) => moves(out, offset),
+			OffsetWrite(ref writes) => {
+				let mut head = 0;
+				for &(offset, delta) in writes.iter() {
+					try!(moves(out, offset - head));
+					try!(repeat(out, if delta < 0 { "-" } else { "+" }, delta.abs() as uint));
+					head = offset;
+				}
+				moves(out, -head)
+			}
+		}
+	}
 }
 
 impl FromStr for Operator {
@@ -53,6 +209,11 @@ impl FromStr for Operator {
 			"]" => Some(Loop),
 			"." => Some(Put),
 			"," => Some(Get),
+			"{" => Some(DoSkip),
+			"}" => Some(DoLoop),
+			"?" => Some(Random),
+			"!" => Some(Negate),
+			"@" => Some(HostCall),
 			c => Some(Nop(c.to_owned())),
 		}
 	}
@@ -60,20 +221,39 @@ impl FromStr for Operator {
 
 impl fmt::Show for Operator {
 	/**
-	Parses a string into the matching operator.
+	Writes the source of the operator, spelling out optimized ones,
+	or its name with the `#` flag, e.g. `Incr` or `Sub(12 ops)`.
+	Either is padded to the width asked for, if any.
 	*/
 	fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
-		f.buf.write((match *self {
-			Incr => ~"+",
-			Decr => ~"-",
-			Prev => ~"<",
-			Next => ~">",
-			Skip => ~"[",
-			Loop => ~"]",
-			Put  => ~".",
-			Get  => ~",",
-			Nop(ref c) => c.to_owned(),
-			Sub(ref ast) => format!("[{}]", ast),
-		}).as_bytes())
+		if f.flags & (1 << (fmt::parse::FlagAlternate as uint)) != 0 {
+			return f.pad(self.name().as_slice());
+		}
+		match f.width {
+			// Sources are written as they go, unless they need measuring.
+			None => self.write_source(f.buf),
+			Some(_) => {
+				let mut source = MemWriter::new();
+				try!(self.write_source(&mut source));
+				f.pad(str::from_utf8(source.get_ref()).unwrap())
+			}
+		}
+	}
+}
+
+/**
+Writes `source` `n` times over.
+*/
+fn repeat(out: &mut Writer, source: &str, n: uint) -> IoResult<()> {
+	for _ in range(0, n) {
+		try!(out.write_str(source));
 	}
+	Ok(())
+}
+
+/**
+Writes the source moving the tape head by `offset` cells.
+*/
+fn moves(out: &mut Writer, offset: int) -> IoResult<()> {
+	if offset < 0 { repeat(out, "<", (-offset) as uint) } else { repeat(out, ">", offset as uint) }
 }
@@ -0,0 +1,175 @@
+/*!
+Showing the work of the optimizer in plain Brainfuck: optimized
+programs spelled with the eight standard operators, along with
+comments telling what each optimized operator was made of.
+
+Comments are made of characters which are not operators, so the
+result is still a program, doing what the optimized one does.
+*/
+
+use std::str::StrBuf;
+use ast::{Ast, Span};
+use operators::{Operator, Sub, DoSub, Incr, Decr, Prev, Next, Put, Get, IncrN, DecrN, PrevN, NextN};
+use operators::{SetCell, SetAt, AddAt, Move, OffsetWrite};
+use sourcemap::SourceMap;
+use dialect::BRAINFUCK;
+
+
+/// Characters which are operators, in standard or extended programs.
+static OPERATOR_CHARS: &'static str = "+-<>.,[]{}?!#=";
+
+/**
+Spells an optimized program in standard Brainfuck, like
+`Dialect::render` does, with a comment before each operator made of
+more than one of the source, or standing for something else, e.g.
+`(( was 5 ops, now add 5 ))` before the `+++++` of an `IncrN(5)`,
+and where operators of the source were dropped altogether, e.g.
+`(( dropped 3 ops ))` where `[-][-]` became `[-]`.
+
+The map tells where each operator came from in the source, e.g. as
+returned by `Ast::optimize_mapped` along with the program. Comments
+never hold operator characters, any such being replaced with `_`.
+*/
+pub fn emit_optimized_source(optimized: &Ast, map: &SourceMap, source: &str) -> ~str {
+	let chars: ~[char] = source.chars().collect();
+	let mut out = StrBuf::new();
+	emit_ops(optimized, map, chars, Span { start: 0, end: chars.len() }, &mut out);
+	out.into_owned()
+}
+
+/**
+Spells a sequence of operators standing for the given span of the
+source, see `emit_optimized_source`.
+*/
+fn emit_ops(ast: &Ast, map: &SourceMap, source: &[char], span: Span, out: &mut StrBuf) {
+	let (&Ast(ref ops), &SourceMap(ref nodes)) = (ast, map);
+	let mut covered = span.start;
+	for (op, node) in ops.iter().zip(nodes.iter()) {
+		annotate_dropped(out, source, covered, node.span.start);
+		covered = node.span.end;
+		match *op {
+			Sub(ref body) | DoSub(ref body) => {
+				let inside = Span { start: node.span.start + 1, end: node.span.end - 1 };
+				// Do-while loops run their body once before the loop.
+				match *op { DoSub(_) => emit_ops(body, &node.body, source, inside, out), _ => {} }
+				out.push_str("[");
+				emit_ops(body, &node.body, source, inside, out);
+				out.push_str("]");
+			}
+			ref other => {
+				let spelled = BRAINFUCK.render(&Ast(~[other.clone()]), false);
+				let was = op_count(source, node.span);
+				let plain = was == 1 && match *other { Incr | Decr | Prev | Next | Put | Get => true, _ => false };
+				if !plain && !spelled.is_empty() {
+					annotate(out, format!("was {} ops, now {}", was, describe(other)));
+				}
+				out.push_str(spelled);
+			}
+		}
+	}
+	annotate_dropped(out, source, covered, span.end);
+}
+
+/// Tells of the operators between two positions of the source, if any.
+fn annotate_dropped(out: &mut StrBuf, source: &[char], start: uint, end: uint) {
+	if start >= end { return }
+	let dropped = op_count(source, Span { start: start, end: end });
+	if dropped > 0 {
+		annotate(out, format!("dropped {} ops", dropped));
+	}
+}
+
+/// How many operators of standard programs a span of the source holds.
+fn op_count(source: &[char], span: Span) -> uint {
+	source.slice(span.start, span.end).iter().filter(|&&c| "+-<>.,[]".contains_char(c)).count()
+}
+
+/// Writes a comment on its own line, with operator characters replaced.
+fn annotate(out: &mut StrBuf, text: ~str) {
+	if out.len() > 0 && !out.as_slice().ends_with("\n") { out.push_char('\n') }
+	out.push_str("(( ");
+	for c in text.chars() {
+		out.push_char(if OPERATOR_CHARS.contains_char(c) { '_' } else { c });
+	}
+	out.push_str(" ))\n");
+}
+
+/// Tells what an operator does, in words.
+fn describe(op: &Operator) -> ~str {
+	let side = |offset: int| if offset < 0 { format!("{} left", -offset) } else { format!("{} right", offset) };
+	match *op {
+		Incr => ~"add 1",
+		Decr => ~"subtract 1",
+		Prev => ~"left 1",
+		Next => ~"right 1",
+		IncrN(n) => format!("add {}", n),
+		DecrN(n) => format!("subtract {}", n),
+		PrevN(n) => format!("left {}", n),
+		NextN(n) => format!("right {}", n),
+		SetCell(0) => ~"clear",
+		SetCell(value) => format!("set to {}", value),
+		Move(offset) if offset < 0 => format!("left {}", -offset),
+		Move(offset) => format!("right {}", offset),
+		AddAt(offset, delta) if delta < 0 => format!("subtract {} at {}", -delta, side(offset)),
+		AddAt(offset, delta) => format!("add {} at {}", delta, side(offset)),
+		SetAt(offset, value) => format!("set to {} at {}", value, side(offset)),
+		OffsetWrite(ref writes) => format!("write to {} cells", writes.len()),
+		ref other => other.name(),
+	}
+}
+
+#[cfg(test)]
+use sourcemap::SourceNode;
+
+#[cfg(test)]
+/// The comments of an emitted program.
+fn annotations(emitted: &str) -> ~[~str] {
+	emitted.split_str("((").skip(1).map(|rest| rest.split_str("))").next().unwrap().to_owned()).collect()
+}
+
+#[test]
+/// Emitted programs should parse, and run like the original ones.
+fn test_optimizer_emit_corpus() {
+	for &(name, source, input) in ::corpus::PROGRAMS.iter() {
+		let original = Ast::parse_str(source).unwrap();
+		let (optimized, map) = original.optimize_mapped();
+		let emitted = emit_optimized_source(&optimized, &map, source);
+		let reparsed = match Ast::parse_str(emitted) {
+			Ok(ast) => ast,
+			Err(msg) => fail!("{} does not parse once emitted: {}", name, msg),
+		};
+		if ::corpus::run(&reparsed, input) != ::corpus::run(&original, input) {
+			fail!("{} runs differently once emitted", name);
+		}
+		for annotation in annotations(emitted).iter() {
+			assert!(!annotation.chars().any(|c| OPERATOR_CHARS.contains_char(c)));
+		}
+	}
+}
+
+#[test]
+/// Folded runs and dropped clears should be told where they were.
+fn test_optimizer_emit_annotations() {
+	let source = "+++++>> --<<. [-][-]";
+	let (optimized, map) = Ast::parse_str(source).unwrap().optimize_mapped();
+	let emitted = emit_optimized_source(&optimized, &map, source);
+	assert!(annotations(emitted) == ~[
+		~" was 5 ops, now add 5 ", ~" was 2 ops, now right 2 ", ~" was 2 ops, now subtract 2 ",
+		~" was 2 ops, now left 2 ", ~" dropped 3 ops ",
+	]);
+	assert!(emitted.starts_with("(( was 5 ops, now add 5 ))\n+++++\n"));
+	assert!(emitted.ends_with(".[-]\n(( dropped 3 ops ))\n"));
+	// Operators dropped from loop bodies are told inside the loop.
+	let (optimized, map) = Ast::parse_str("+[[-][-]]").unwrap().optimize_mapped();
+	assert!(emit_optimized_source(&optimized, &map, "+[[-][-]]") == ~"+[[-]\n(( dropped 3 ops ))\n]");
+
+	// Offsets, and operators from extensions, are told in words too.
+	let optimized = Ast(~[AddAt(2, 3), AddAt(-1, -1), SetCell(7)]);
+	let node = |start: uint, end: uint| SourceNode { span: Span { start: start, end: end }, body: SourceMap(~[]) };
+	let map = SourceMap(~[node(0, 10), node(0, 10), node(11, 13)]);
+	let emitted = emit_optimized_source(&optimized, &map, ">>+++<<<-> =7");
+	assert!(annotations(emitted) == ~[
+		~" was 10 ops, now add 3 at 2 right ", ~" was 10 ops, now subtract 1 at 1 left ", ~" was 0 ops, now set to 7 ",
+	]);
+	assert!(emitted.ends_with("[-]+++++++") && Ast::parse_str(emitted).is_ok());
+}
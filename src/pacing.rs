@@ -0,0 +1,127 @@
+/*!
+Pacing output like a slow device would, e.g. for programs drawing
+animations, which are lost when run at full speed.
+
+Pacing sleeps between output bytes, on a `Clock`, so that tests can
+tell how long runs would have slept without sleeping at all.
+*/
+
+use std::io::timer::sleep;
+use sync::{Arc, Mutex};
+use time::precise_time_ns;
+
+
+/**
+How fast output is written, see `Machine::set_output_pacing`.
+*/
+#[deriving(Clone, Eq, Show)]
+pub enum Pacing {
+	/// As fast as the program outputs, the default.
+	Unpaced,
+	/// Waiting the given milliseconds before each byte.
+	PerByte(u64),
+	/**
+	At most the given number of bytes per second, spread evenly:
+	each byte is due a second divided by the rate after the one
+	before it, and only waited for if it comes sooner. A rate of
+	zero is taken as one.
+	*/
+	BytesPerSecond(uint),
+}
+
+/**
+Tells the time, and sleeps, in milliseconds. Machines pace their
+output, and time runs, on one, see `Machine::set_clock`.
+*/
+pub trait Clock {
+	/// The time now, from some arbitrary start.
+	fn now(&self) -> u64;
+	/// Sleeps for the given time.
+	fn sleep(&mut self, ms: u64);
+	/**
+	The time now in nanoseconds, for timing runs finely. Clocks only
+	telling milliseconds give them in nanoseconds.
+	*/
+	fn now_ns(&self) -> u64 {
+		self.now() * 1000000
+	}
+}
+
+/**
+The clock of the system, actually sleeping.
+*/
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+	fn now(&self) -> u64 {
+		precise_time_ns() / 1000000
+	}
+	fn sleep(&mut self, ms: u64) {
+		sleep(ms);
+	}
+	fn now_ns(&self) -> u64 {
+		precise_time_ns()
+	}
+}
+
+/**
+A clock which never sleeps, only noting how long it was asked to,
+and whose time only goes by as it sleeps, or as it is read if told
+so. Clones share their time, so that one can be given to a machine
+and another kept to look at.
+*/
+#[deriving(Clone)]
+pub struct MockClock {
+	state: Arc<Mutex<MockState>>,
+}
+
+/// The time of a mock clock, shared by its clones.
+struct MockState {
+	now: u64,
+	/// How much time goes by whenever the clock is read.
+	step: u64,
+	/// How long each sleep was for, in order.
+	sleeps: Vec<u64>,
+}
+
+impl MockClock {
+	/// A clock standing still, but for sleeping.
+	pub fn new() -> MockClock {
+		MockClock::stepping(0)
+	}
+
+	/// A clock whose time goes by the given milliseconds after every reading.
+	pub fn stepping(step: u64) -> MockClock {
+		MockClock { state: Arc::new(Mutex::new(MockState { now: 0, step: step, sleeps: Vec::new() })) }
+	}
+
+	/// How long each sleep so far was for, in order.
+	pub fn sleeps(&self) -> Vec<u64> {
+		self.state.lock().sleeps.clone()
+	}
+}
+
+impl Clock for MockClock {
+	fn now(&self) -> u64 {
+		let mut guard = self.state.lock();
+		let now = guard.now;
+		guard.now += guard.step;
+		now
+	}
+	fn sleep(&mut self, ms: u64) {
+		let mut guard = self.state.lock();
+		guard.now += ms;
+		guard.sleeps.push(ms);
+	}
+}
+
+#[test]
+/// A mock clock should note sleeps, and go by when asked to.
+fn test_pacing_mock_clock() {
+	let clock = MockClock::stepping(5);
+	let mut shared = clock.clone();
+	assert!(shared.now() == 0);
+	shared.sleep(100);
+	assert!(clock.now() == 105);
+	assert!(clock.sleeps() == vec!(100));
+}
@@ -0,0 +1,579 @@
+/*!
+Parsing in two phases: first into a tree where every character of the
+source has its place, for tools which need the source as it was, such
+as formatters and highlighters, then lowering the tree into the `Ast`
+which machines run. `Ast::parse_with` does both at once.
+
+The tree never fails to build, but for sources going over the size
+asked for: unmatched brackets and out of range values are kept in it,
+and only fail lowering.
+*/
+
+use std::fmt;
+use std::mem::replace;
+use std::str::StrBuf;
+use ast::{Ast, Span, ParseOptions, ParseWarning, ClosedAtEnd, DroppedClose, MAX_NESTING, split_shebang};
+use operators::{Operator, Sub, DoSub, Nop, Shebang, Checkpoint, SetCell};
+use operators::{Incr, Decr, IncrN, DecrN, Prev, Next, Put, Get, Random, Negate, HostCall};
+
+
+/**
+A program as written, down to its comments and whitespace. See
+`ParseTree::parse`.
+*/
+#[deriving(Clone, Eq, Show)]
+pub struct ParseTree {
+	/// The nodes of the top level, in the order of the source.
+	pub nodes: ~[Node],
+}
+
+/**
+A piece of the source, along with its span, in chars.
+*/
+#[deriving(Clone, Eq, Show)]
+pub enum Node {
+	/// An operator of a single character, e.g. `+`, or `?` with extensions.
+	OpNode(Operator, Span),
+	/// A checkpoint, `#name`, with its name.
+	CheckpointNode(~str, Span),
+	/// A cell value, `=N`, with its digits, checked when lowering.
+	ValueNode(~str, Span),
+	/// A run of characters which are neither operators nor whitespace.
+	CommentNode(~str, Span),
+	/// A run of whitespace.
+	WhitespaceNode(~str, Span),
+	/// A `#!` line starting the source, newline included.
+	ShebangNode(~str, Span),
+	/// A `!` and everything after it, meant as input for the program.
+	/// See `ParseOptions::input_section`.
+	InputNode(~str, Span),
+	/// A loop, brackets included.
+	BlockNode(Block),
+	/// A closing bracket which closes no loop, `]`, or `}` with extensions.
+	StrayNode(char, Span),
+}
+
+/**
+A loop, `[...]`, or a do-while loop, `{...}`, as written.
+*/
+#[deriving(Clone, Eq, Show)]
+pub struct Block {
+	/// Whether this is a do-while loop.
+	pub do_while: bool,
+	/// The span of the opening bracket.
+	pub open: Span,
+	/// The nodes between the brackets.
+	pub body: ~[Node],
+	/// The span of the closing bracket, unless the source lacks it.
+	pub close: Option<Span>,
+}
+
+/**
+Why a tree could not be lowered, or built at all for programs too
+large. Written as `Ast::parse_with` words its errors.
+*/
+#[deriving(Clone, Eq)]
+pub enum ParseError {
+	/// A bracket left unmatched, and where it is.
+	Unmatched(char, uint),
+	/// A cell value past those of a cell, with its digits and where it is.
+	OutOfRange(~str, uint),
+	/// More operators than `ParseOptions::max_ops`.
+	TooLarge,
+	/// A loop nested deeper than `MAX_NESTING`, and where it opens.
+	TooDeep(uint),
+}
+
+impl fmt::Show for ParseError {
+	fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			Unmatched(bracket, _) => write!(f.buf, "Unmatched `{}`.", bracket),
+			OutOfRange(ref digits, _) => write!(f.buf, "Cell value `={}` is out of range.", *digits),
+			TooLarge => write!(f.buf, "program too large"),
+			TooDeep(_) => write!(f.buf, "Loops nested deeper than {} levels.", MAX_NESTING),
+		}
+	}
+}
+
+impl Node {
+	/// The span of the node, brackets and body included for loops.
+	pub fn span(&self) -> Span {
+		match *self {
+			OpNode(_, span) | CheckpointNode(_, span) | ValueNode(_, span) => span,
+			CommentNode(_, span) | WhitespaceNode(_, span) => span,
+			ShebangNode(_, span) | InputNode(_, span) | StrayNode(_, span) => span,
+			BlockNode(ref block) => {
+				let end = match block.close {
+					Some(close) => close.end,
+					// Unclosed loops go on until the end of the source.
+					None => block.body.last().map_or(block.open.end, |node| node.span().end),
+				};
+				Span { start: block.open.start, end: end }
+			}
+		}
+	}
+}
+
+impl ParseTree {
+	/**
+	Produce a tree from a source string. Only `extensions`, which
+	tells what the extended operators are, `input_section` and
+	`max_ops` matter here.
+
+	Fails as soon as the source goes over `max_ops` operators, as
+	the program lowered from it would, see `ParseOptions::max_ops`,
+	or nests loops deeper than `MAX_NESTING`.
+	*/
+	pub fn parse(source: &str, options: ParseOptions) -> Result<ParseTree, ParseError> {
+		let mut builder = Builder { stack: ~[], nodes: ~[], run: None, token: None, emitted: 0 };
+		let too_large = |emitted: uint| options.max_ops.map_or(false, |max| emitted > max);
+
+		let (shebang, rest) = split_shebang(source);
+		let skipped = match shebang {
+			Some(line) => {
+				let span = Span { start: 0, end: line.char_len() };
+				builder.nodes.push(ShebangNode(line.to_owned(), span));
+				builder.emitted += 1;
+				span.end
+			}
+			None => 0,
+		};
+		for (i, (offset, token)) in rest.char_indices().enumerate() {
+			if too_large(builder.emitted) {
+				return Err(TooLarge);
+			}
+			let position = i + skipped;
+			// A checkpoint's name goes on until the first character
+			// which can not be part of it, and a cell value's digits
+			// likewise, but for leading zeros.
+			let goes_on = match builder.token {
+				Some(('#', _, _)) => token.is_alphanumeric() || token == '_',
+				Some((_, ref digits, _)) => token.is_digit() && digits.as_slice() != "0",
+				None => false,
+			};
+			if goes_on {
+				match builder.token {
+					Some((_, ref mut text, _)) => text.push_char(token),
+					None => {}
+				}
+				continue;
+			}
+			builder.end_token(position);
+			let opens = token == '[' || (token == '{' && options.extensions);
+			if opens && builder.stack.len() == MAX_NESTING {
+				return Err(TooDeep(position));
+			}
+			match token {
+				'#' | '=' if options.extensions => {
+					builder.end_run(position);
+					builder.token = Some((token, StrBuf::new(), position));
+				}
+				'[' => builder.open(false, position),
+				'{' if options.extensions => builder.open(true, position),
+				']' => builder.close(token, position),
+				'}' if options.extensions => builder.close(token, position),
+				'!' if options.input_section && !options.extensions => {
+					builder.end_run(position);
+					let input = rest.slice_from(offset);
+					let span = Span { start: position, end: position + input.char_len() };
+					builder.nodes.push(InputNode(input.to_owned(), span));
+					builder.emitted += 1;
+					break;
+				}
+				_ => match single_operator(token, options.extensions) {
+					Some(op) => {
+						builder.end_run(position);
+						builder.nodes.push(OpNode(op, Span { start: position, end: position + 1 }));
+						builder.emitted += 1;
+					}
+					None => builder.extend_run(token, position),
+				}
+			}
+		}
+		let end = skipped + rest.char_len();
+		builder.end_token(end);
+		builder.end_run(end);
+		if too_large(builder.emitted) {
+			return Err(TooLarge);
+		}
+		Ok(builder.finish())
+	}
+
+	/**
+	Lowers the tree into the program it stands for, with the given
+	options, see `ParseOptions`. Comments and whitespace are kept,
+	as `Nop` operators, one per character.
+	*/
+	pub fn lower(&self, options: ParseOptions) -> Result<Ast, ParseError> {
+		self.lower_with_warnings(options).map(|(ast, _)| ast)
+	}
+
+	/**
+	Lowers the tree, like `lower`, returning the warnings about what
+	was recovered from, if anything, alongside the program.
+	*/
+	pub fn lower_with_warnings(&self, options: ParseOptions) -> Result<(Ast, Vec<ParseWarning>), ParseError> {
+		let mut lowering = Lowering { recover: options.recover_brackets, compress: options.compress_runs, warnings: Vec::new(), closed: Vec::new() };
+		let ast = Ast(try!(lowering.nodes(self.nodes, None, None)));
+		if options.max_ops.map_or(false, |max| ast.node_count() > max) {
+			return Err(TooLarge);
+		}
+		// Loops closed at the end are told outermost first.
+		let Lowering { mut warnings, mut closed, .. } = lowering;
+		closed.reverse();
+		warnings.push_all_move(closed);
+		Ok((ast, warnings))
+	}
+
+	/// Gives back the exact source the tree was parsed from.
+	pub fn source(&self) -> ~str {
+		let mut out = StrBuf::new();
+		write_nodes(self.nodes, &mut out);
+		out.into_owned()
+	}
+
+	/**
+	Calls a function with every node of the tree, in the order of the
+	source: loops come before the nodes of their body.
+	*/
+	pub fn visit(&self, mut f: |&Node|) {
+		visit_nodes(self.nodes, &mut f);
+	}
+
+	/// The input after a `!` ending the program, if the source has one.
+	pub fn input<'a>(&'a self) -> Option<&'a str> {
+		self.nodes.iter().filter_map(|node| match *node {
+			InputNode(ref text, _) => Some(text.slice_from(1)),
+			_ => None,
+		}).next()
+	}
+}
+
+/**
+The state of a tree being built: the nodes of the loops being read,
+and the characters making a single node being read.
+*/
+struct Builder {
+	/// The nodes of each enclosing loop, with its opening bracket and
+	/// whether it is a do-while loop.
+	stack: ~[(~[Node], Span, bool)],
+	/// The nodes of the innermost loop being read, or of the top level.
+	nodes: ~[Node],
+	/// The comment or whitespace being read, where it started, and
+	/// whether it is whitespace.
+	run: Option<(StrBuf, uint, bool)>,
+	/// The checkpoint or cell value being read: its `#` or `=`, what
+	/// follows it, and where it started.
+	token: Option<(char, StrBuf, uint)>,
+	/// How many operators the program lowered so far would have.
+	emitted: uint,
+}
+
+impl Builder {
+	/// Adds a character to the comment or whitespace being read,
+	/// starting another if it is of the other kind.
+	fn extend_run(&mut self, c: char, position: uint) {
+		let space = c.is_whitespace();
+		let other = match self.run { Some((_, _, was)) => was != space, None => false };
+		if other {
+			self.end_run(position);
+		}
+		if self.run.is_none() {
+			self.run = Some((StrBuf::new(), position, space));
+		}
+		match self.run {
+			Some((ref mut text, _, _)) => text.push_char(c),
+			None => {}
+		}
+		self.emitted += 1;
+	}
+
+	/// Ends the comment or whitespace being read, if any.
+	fn end_run(&mut self, position: uint) {
+		match self.run.take() {
+			Some((text, start, space)) => {
+				let span = Span { start: start, end: position };
+				let text = text.into_owned();
+				self.nodes.push(if space { WhitespaceNode(text, span) } else { CommentNode(text, span) });
+			}
+			None => {}
+		}
+	}
+
+	/// Ends the checkpoint or cell value being read, if any. A `#` or
+	/// `=` alone is a comment.
+	fn end_token(&mut self, position: uint) {
+		match self.token.take() {
+			Some((sigil, text, start)) => {
+				if text.len() == 0 {
+					return self.extend_run(sigil, start);
+				}
+				let span = Span { start: start, end: position };
+				let text = text.into_owned();
+				self.nodes.push(if sigil == '#' { CheckpointNode(text, span) } else { ValueNode(text, span) });
+				self.emitted += 1;
+			}
+			None => {}
+		}
+	}
+
+	/// Starts reading the body of a loop, which counts as an operator
+	/// from then on, so that limits hold while it is being read.
+	fn open(&mut self, do_while: bool, position: uint) {
+		self.end_run(position);
+		let outer = replace(&mut self.nodes, ~[]);
+		self.stack.push((outer, Span { start: position, end: position + 1 }, do_while));
+		self.emitted += 1;
+	}
+
+	/// Ends the loop being read, if the bracket closes it.
+	fn close(&mut self, bracket: char, position: uint) {
+		self.end_run(position);
+		let span = Span { start: position, end: position + 1 };
+		let closes = match self.stack.last() {
+			Some(&(_, _, do_while)) => do_while == (bracket == '}'),
+			None => false,
+		};
+		if !closes {
+			return self.nodes.push(StrayNode(bracket, span));
+		}
+		let (outer, open, do_while) = self.stack.pop().unwrap();
+		let body = replace(&mut self.nodes, outer);
+		self.nodes.push(BlockNode(Block { do_while: do_while, open: open, body: body, close: Some(span) }));
+	}
+
+	/// The tree, with loops still open at the end left unclosed.
+	fn finish(mut self) -> ParseTree {
+		while !self.stack.is_empty() {
+			let (outer, open, do_while) = self.stack.pop().unwrap();
+			let body = replace(&mut self.nodes, outer);
+			self.nodes.push(BlockNode(Block { do_while: do_while, open: open, body: body, close: None }));
+		}
+		ParseTree { nodes: self.nodes }
+	}
+}
+
+/// The operator a single character stands for, if any.
+fn single_operator(c: char, extensions: bool) -> Option<Operator> {
+	match c {
+		'+' => Some(Incr),
+		'-' => Some(Decr),
+		'<' => Some(Prev),
+		'>' => Some(Next),
+		'.' => Some(Put),
+		',' => Some(Get),
+		'?' if extensions => Some(Random),
+		'!' if extensions => Some(Negate),
+		'@' if extensions => Some(HostCall),
+		_ => None,
+	}
+}
+
+/**
+The state of a tree being lowered: what was recovered from so far.
+*/
+struct Lowering {
+	/// Whether to recover from unmatched brackets.
+	recover: bool,
+	/// Whether to fold runs of `+` and `-`.
+	compress: bool,
+	/// The stray brackets dropped.
+	warnings: Vec<ParseWarning>,
+	/// The loops closed at the end, innermost first.
+	closed: Vec<ParseWarning>,
+}
+
+impl Lowering {
+	/**
+	Lowers a sequence of nodes, in the given loop if any, itself in
+	an unclosed do-while loop starting at the given position if any,
+	which fails unclosed loops inside it too.
+	*/
+	fn nodes(&mut self, nodes: &[Node], enclosing: Option<&Block>, unclosed_do_while: Option<uint>) -> Result<~[Operator], ParseError> {
+		let mut ops = ~[];
+		for node in nodes.iter() {
+			match *node {
+				OpNode(ref op, _) if self.compress => push_compressed(&mut ops, op),
+				OpNode(ref op, _) => ops.push(op.clone()),
+				CheckpointNode(ref name, _) => ops.push(Checkpoint(name.clone())),
+				ValueNode(ref digits, span) => match from_str::<u8>(*digits) {
+					Some(value) => ops.push(SetCell(value)),
+					None => return Err(OutOfRange(digits.clone(), span.start)),
+				},
+				CommentNode(ref text, _) | WhitespaceNode(ref text, _) => {
+					for c in text.chars() {
+						ops.push(Nop(c.to_str()));
+					}
+				}
+				ShebangNode(ref line, _) => ops.push(Shebang(line.clone())),
+				// The input is none of the program's business.
+				InputNode(ref text, _) => ops.push(Nop(text.clone())),
+				BlockNode(ref block) => {
+					let unclosed = block.close.is_none();
+					let do_while = match unclosed_do_while {
+						None if unclosed && block.do_while => Some(block.open.start),
+						other => other,
+					};
+					let body = Ast(try!(self.nodes(block.body, Some(block), do_while)));
+					if unclosed {
+						match do_while {
+							Some(position) => return Err(Unmatched('{', position)),
+							None if !self.recover => return Err(Unmatched('[', block.open.start)),
+							None => self.closed.push(ParseWarning { position: block.open.start, kind: ClosedAtEnd }),
+						}
+					}
+					ops.push(if block.do_while { DoSub(body) } else { Sub(body) });
+				}
+				StrayNode(bracket, span) => match enclosing {
+					Some(block) => return Err(Unmatched(if block.do_while { '{' } else { '[' }, block.open.start)),
+					None if bracket == ']' && self.recover => {
+						self.warnings.push(ParseWarning { position: span.start, kind: DroppedClose });
+					}
+					None => return Err(Unmatched(bracket, span.start)),
+				},
+			}
+		}
+		Ok(ops)
+	}
+}
+
+/// Pushes an operator, folding it into the run of `+` or `-` it continues, if any.
+fn push_compressed(ops: &mut ~[Operator], op: &Operator) {
+	let folded = match (ops.last(), op) {
+		(Some(&Incr), &Incr) => Some(IncrN(2)),
+		(Some(&IncrN(n)), &Incr) => Some(IncrN(n + 1)),
+		(Some(&Decr), &Decr) => Some(DecrN(2)),
+		(Some(&DecrN(n)), &Decr) => Some(DecrN(n + 1)),
+		_ => None,
+	};
+	match folded {
+		Some(folded) => *ops.mut_last().unwrap() = folded,
+		None => ops.push(op.clone()),
+	}
+}
+
+/// Writes the source of a sequence of nodes.
+fn write_nodes(nodes: &[Node], out: &mut StrBuf) {
+	for node in nodes.iter() {
+		match *node {
+			OpNode(ref op, _) => out.push_str(format!("{}", *op)),
+			CheckpointNode(ref name, _) => { out.push_char('#'); out.push_str(*name) }
+			ValueNode(ref digits, _) => { out.push_char('='); out.push_str(*digits) }
+			CommentNode(ref text, _) | WhitespaceNode(ref text, _) => out.push_str(*text),
+			ShebangNode(ref text, _) | InputNode(ref text, _) => out.push_str(*text),
+			BlockNode(ref block) => {
+				out.push_char(if block.do_while { '{' } else { '[' });
+				write_nodes(block.body, out);
+				if block.close.is_some() {
+					out.push_char(if block.do_while { '}' } else { ']' });
+				}
+			}
+			StrayNode(bracket, _) => out.push_char(bracket),
+		}
+	}
+}
+
+/// Calls a function with a sequence of nodes and those inside them.
+fn visit_nodes(nodes: &[Node], f: &mut |&Node|) {
+	for node in nodes.iter() {
+		(*f)(node);
+		match *node {
+			BlockNode(ref block) => visit_nodes(block.body, f),
+			_ => {}
+		}
+	}
+}
+
+#[cfg(test)]
+use std::io::MemReader;
+
+#[cfg(test)]
+/// The options of standard programs followed by input.
+fn with_input() -> ParseOptions {
+	ParseOptions { input_section: true, ..ParseOptions::strict() }
+}
+
+#[test]
+/// Trees should give back their source, byte for byte.
+fn test_parse_tree_source() {
+	let extended = ParseOptions { extensions: true, ..ParseOptions::strict() };
+	let fixtures = [
+		("#!/usr/bin/env bf\n+[->+<] comment, then .\n", ParseOptions::strict()),
+		("#!/usr/bin/env bf\n,[.,]!input, with [brackets]\n", with_input()),
+		("\t{-}?=42 #end! =0=07 # = [=256]", extended),
+		("]+[>+[>+ unmatched é", ParseOptions::strict()),
+		("{-] and [-}", extended),
+		("", ParseOptions::strict()),
+	];
+	for &(source, options) in fixtures.iter() {
+		let tree = ParseTree::parse(source, options).unwrap();
+		assert!(tree.source() == source.to_owned());
+		assert!(tree.nodes.last().map_or(0, |node| node.span().end) == source.char_len());
+	}
+	for &(_, source, _) in ::corpus::PROGRAMS.iter() {
+		assert!(ParseTree::parse(source, ParseOptions::strict()).unwrap().source() == source.to_owned());
+	}
+
+	let tree = ParseTree::parse("+.!abc\n", with_input()).unwrap();
+	assert!(tree.input() == Some("abc\n"));
+	assert!(tree.lower(with_input()) == Ok(Ast(~[Incr, Put, Nop(~"!abc\n")])));
+	assert!(ParseTree::parse("+.!abc\n", ParseOptions::strict()).unwrap().input() == None);
+}
+
+#[test]
+/// Lowering should give what parsing always did, errors and warnings included.
+fn test_parse_tree_lower() {
+	let strict = ParseOptions::strict();
+	for &(name, source, _) in ::corpus::PROGRAMS.iter() {
+		let lowered = ParseTree::parse(source, strict).and_then(|tree| tree.lower(strict));
+		// The streaming parser was written apart, and parses likewise.
+		let streamed = Ast::parse_reader(&mut MemReader::new(source.as_bytes().to_owned()));
+		if lowered.ok() != streamed.ok() {
+			fail!("{} lowers to another program", name);
+		}
+	}
+
+	let recover = ParseOptions { recover_brackets: true, ..strict };
+	let tree = ParseTree::parse("]+[>+[>+", recover).unwrap();
+	assert!(tree.lower(strict) == Err(Unmatched(']', 0)));
+	let tree = ParseTree::parse("+[>+[>+", strict).unwrap();
+	assert!(tree.lower(strict) == Err(Unmatched('[', 4)));
+	let tree = ParseTree::parse("]+[>+[>+", strict).unwrap();
+	assert!(tree.lower_with_warnings(recover) == Ok((Ast::parse_str("+[>+[>+]]").unwrap(), vec!(
+		ParseWarning { position: 0, kind: DroppedClose },
+		ParseWarning { position: 2, kind: ClosedAtEnd },
+		ParseWarning { position: 5, kind: ClosedAtEnd },
+	))));
+	let extended = ParseOptions { extensions: true, ..strict };
+	let lower = |source: &str| ParseTree::parse(source, extended).unwrap().lower(extended);
+	assert!(lower("{-]") == Err(Unmatched('{', 0)));
+	assert!(lower("[-}") == Err(Unmatched('[', 0)));
+	assert!(lower("{[-") == Err(Unmatched('{', 0)));
+	assert!(lower("+=256") == Err(OutOfRange(~"256", 1)));
+	assert!(format!("{}", OutOfRange(~"256", 1)) == ~"Cell value `=256` is out of range.");
+	let limited = ParseOptions { max_ops: Some(2), ..strict };
+	assert!(ParseTree::parse("++++]", limited) == Err(TooLarge));
+	// Loops count as they open, however long their body.
+	assert!(ParseTree::parse("[[[", limited) == Err(TooLarge));
+	assert!(ParseTree::parse("[[[-", limited) == Err(TooLarge));
+	assert!(ParseTree::parse("[+]", limited).is_ok());
+	assert!(ParseTree::parse("[".repeat(MAX_NESTING + 1).as_slice(), strict) == Err(TooDeep(MAX_NESTING)));
+	assert!(ParseTree::parse("[".repeat(MAX_NESTING).as_slice(), strict).is_ok());
+}
+
+#[test]
+/// Brackets and comments inside loops should have their own spans.
+fn test_parse_tree_spans() {
+	let tree = ParseTree::parse("+[>[-] note <]", ParseOptions::strict()).unwrap();
+	let mut blocks = ~[];
+	let mut comments = ~[];
+	tree.visit(|node| match *node {
+		BlockNode(ref block) => blocks.push((block.open, block.close)),
+		CommentNode(ref text, span) => comments.push((text.clone(), span)),
+		_ => {}
+	});
+	let span = |start: uint, end: uint| Span { start: start, end: end };
+	assert!(blocks == ~[(span(1, 2), Some(span(13, 14))), (span(3, 4), Some(span(5, 6)))]);
+	assert!(comments == ~[(~"note", span(7, 11))]);
+	assert!(tree.nodes.get(1).unwrap().span() == span(1, 14));
+}
@@ -0,0 +1,265 @@
+use std::cmp::max;
+use std::io::MemReader;
+use sync::{Arc, Mutex};
+use machine::{Machine, Limits, Limit, LimitExceeded, BatchResult, RuntimeError};
+use ast::Ast;
+
+
+/**
+A program to be run by a `MachinePool`, along with its input.
+*/
+pub struct Job {
+	/// Identifies the job among its results.
+	pub id: uint,
+	/// The program to run. Shared, as the same one is often run many times.
+	pub program: Arc<Ast>,
+	/// The bytes to feed to the program's input.
+	pub input: ~[u8],
+}
+
+/**
+The outcome of running a `Job`.
+*/
+pub struct JobResult {
+	/// The id of the job this is the result of.
+	pub id: uint,
+	/// Everything the program wrote to its output.
+	pub output: Vec<u8>,
+	/// The cycles executed, up to the limit if one was hit.
+	pub cycles: uint,
+	/// The limit which stopped the program, if any.
+	pub limit: Option<Limit>,
+	/// Why the program stopped, if it did not end, a limit included.
+	pub error: Option<RuntimeError>,
+}
+
+
+/**
+A pool of reusable machines, running jobs concurrently.
+
+Each of the pool's workers runs in its own task, and owns a machine
+which is reset between jobs rather than being built anew, sparing
+the allocation of a fresh tape for every program. Output is always
+captured in memory.
+
+Jobs are distributed among the workers as they become free, so
+results may complete in any order; match them by id.
+*/
+pub struct MachinePool {
+	/// Feeds jobs to the workers, along with where to send results.
+	jobs: Sender<(Job, Sender<JobResult>)>,
+}
+
+impl MachinePool {
+	/**
+	Produces a pool with `workers` machines, each applying `limits`
+	to every job it runs.
+	*/
+	pub fn new(workers: uint, limits: Limits) -> MachinePool {
+		let (jobs, queue) = channel::<(Job, Sender<JobResult>)>();
+		let queue = Arc::new(Mutex::new(queue));
+
+		for _ in range(0, workers) {
+			let queue = queue.clone();
+			let limits = limits.clone();
+			spawn(proc() {
+				let mut machine = Machine::capturing([]);
+				machine.set_limits(limits);
+				loop {
+					// Only hold the lock while waiting for a job, so
+					// that other workers can pick up jobs meanwhile.
+					let next = queue.lock().recv_opt();
+					match next {
+						Some((job, results)) => results.send(run_job(&mut machine, job)),
+						// The pool is gone.
+						None => break,
+					}
+				}
+			});
+		}
+
+		MachinePool { jobs: jobs }
+	}
+
+	/**
+	Submits a job to the pool. Its result will be sent to the
+	returned receiver once a worker has run it.
+	*/
+	pub fn run(&self, job: Job) -> Receiver<JobResult> {
+		let (results, result) = channel();
+		self.jobs.send((job, results));
+		result
+	}
+}
+
+/**
+Runs a job on a machine, after bringing it back to a pristine state.
+*/
+fn run_job(machine: &mut Machine, job: Job) -> JobResult {
+	machine.reset();
+	machine.set_input(~MemReader::new(job.input));
+	let error = machine.run_program(&*job.program).err();
+	let limit = match error {
+		Some(LimitExceeded(limit)) => Some(limit),
+		_ => None,
+	};
+	JobResult {
+		id: job.id,
+		output: machine.take_captured_output(),
+		cycles: machine.cycles(),
+		limit: limit,
+		error: error,
+	}
+}
+
+/**
+Runs a program against each of many inputs like `Machine::run_batch`
+does, spreading them over `tasks` tasks, each with a machine of its
+own running a contiguous share of the inputs. Machines are as from
+`Machine::capturing`. The results are still in the order of the
+inputs.
+*/
+pub fn run_batch_parallel(program: &Ast, inputs: &[&[u8]], limits: Limits, tasks: uint) -> Vec<BatchResult> {
+	let program = Arc::new(program.clone());
+	let tasks = max(tasks, 1);
+	let share = max((inputs.len() + tasks - 1) / tasks, 1);
+	let shares: ~[Receiver<Vec<BatchResult>>] = inputs.chunks(share).map(|chunk| {
+		let (results, result) = channel();
+		let program = program.clone();
+		let limits = limits.clone();
+		let chunk: ~[~[u8]] = chunk.iter().map(|input| input.to_owned()).collect();
+		spawn(proc() {
+			let inputs: ~[&[u8]] = chunk.iter().map(|input| input.as_slice()).collect();
+			let mut machine = Machine::capturing([]);
+			results.send(machine.run_batch(&*program, inputs.as_slice(), limits));
+		});
+		result
+	}).collect();
+
+	let mut results = Vec::with_capacity(inputs.len());
+	for share in shares.iter() {
+		results.push_all_move(share.recv());
+	}
+	results
+}
+
+#[cfg(test)]
+use test::BenchHarness;
+#[cfg(test)]
+use machine::{CycleLimit, ExtensionDisabled};
+#[cfg(test)]
+use ast::ParseOptions;
+
+#[test]
+/// Every job should get exactly one result, with the right outcome.
+fn test_machine_pool_runs_every_job() {
+	let echo = Arc::new(Ast::parse_str(",[.,]").unwrap());
+	let spin = Arc::new(Ast::parse_str("+[]").unwrap());
+	let pool = MachinePool::new(4, Limits { cycles: Some(10000), ..Limits::none() });
+
+	// Mix terminating and endless jobs.
+	let results: ~[(uint, Receiver<JobResult>)] = range(0u, 100).map(|id| {
+		let program = if id % 3 == 0 { spin.clone() } else { echo.clone() };
+		let input = format!("job {}", id).into_bytes();
+		(id, pool.run(Job { id: id, program: program, input: input }))
+	}).collect();
+
+	for &(id, ref result) in results.iter() {
+		let res = result.recv();
+		assert!(res.id == id);
+		if id % 3 == 0 {
+			assert!(res.limit == Some(CycleLimit));
+			assert!(res.error == Some(LimitExceeded(CycleLimit)));
+			assert!(res.output.is_empty());
+		} else {
+			assert!(res.limit.is_none() && res.error.is_none());
+			assert!(res.output.as_slice() == format!("job {}", id).as_bytes());
+		}
+		// No other result should come for the same job.
+		assert!(result.recv_opt().is_none());
+	}
+
+	// Jobs failing otherwise should tell why, rather than look like they ended.
+	let extended = ParseOptions { extensions: true, ..ParseOptions::strict() };
+	let (random, _) = Ast::parse_with(".?", extended).unwrap();
+	let res = pool.run(Job { id: 100, program: Arc::new(random), input: ~[] }).recv();
+	assert!(res.limit.is_none());
+	match res.error {
+		Some(ExtensionDisabled(_)) => {}
+		_ => fail!("Expected the job to be refused."),
+	}
+	assert!(res.output.is_empty() && res.cycles == 0);
+}
+
+#[cfg(test)]
+static BENCH_PROGRAM: &'static str = "++++++++[>++++++++<-]>+.";
+
+#[bench]
+/// Running jobs on a pool, reusing its machines.
+fn bench_machine_pool_reuse(b: &mut BenchHarness) {
+	let program = Arc::new(Ast::parse_str(BENCH_PROGRAM).unwrap());
+	let pool = MachinePool::new(1, Limits::none());
+	b.iter(|| {
+		pool.run(Job { id: 0, program: program.clone(), input: ~[] }).recv();
+	});
+}
+
+#[bench]
+/// Running jobs naively, building a fresh machine for each of them.
+fn bench_machine_per_job(b: &mut BenchHarness) {
+	let program = Arc::new(Ast::parse_str(BENCH_PROGRAM).unwrap());
+	let (jobs, queue) = channel::<(Job, Sender<JobResult>)>();
+	spawn(proc() {
+		for (job, results) in queue.iter() {
+			let mut machine = Machine::capturing([]);
+			results.send(run_job(&mut machine, job));
+		}
+	});
+	b.iter(|| {
+		let (results, result) = channel();
+		jobs.send((Job { id: 0, program: program.clone(), input: ~[] }, results));
+		result.recv();
+	});
+}
+
+#[cfg(test)]
+/// A thousand short inputs, for batches.
+fn thousand_inputs() -> ~[~[u8]] {
+	range(0u, 1000).map(|i| format!("input {}", i).into_bytes()).collect()
+}
+
+#[test]
+/// Spreading a batch over tasks should not change its results, nor their order.
+fn test_run_batch_parallel() {
+	let program = Ast::parse_str(",[.,]").unwrap();
+	let owned = thousand_inputs();
+	let inputs: ~[&[u8]] = owned.iter().map(|input| input.as_slice()).collect();
+	let limits = Limits { cycles: Some(20), ..Limits::none() };
+	let expected = Machine::capturing([]).run_batch(&program, inputs.as_slice(), limits.clone());
+	for &tasks in [1u, 3, 8].iter() {
+		assert!(run_batch_parallel(&program, inputs.as_slice(), limits.clone(), tasks) == expected);
+	}
+}
+
+#[bench]
+/// Running a thousand inputs as a batch, compiling the program once.
+fn bench_run_batch(b: &mut BenchHarness) {
+	let program = Ast::parse_str(",[.,]").unwrap();
+	let owned = thousand_inputs();
+	let inputs: ~[&[u8]] = owned.iter().map(|input| input.as_slice()).collect();
+	let mut machine = Machine::capturing([]);
+	b.iter(|| { machine.run_batch(&program, inputs.as_slice(), Limits::none()); });
+}
+
+#[bench]
+/// Running a thousand inputs naively, parsing and building a machine for each.
+fn bench_run_batch_naive(b: &mut BenchHarness) {
+	let owned = thousand_inputs();
+	b.iter(|| {
+		for input in owned.iter() {
+			let program = Ast::parse_str(",[.,]").unwrap();
+			let mut machine = Machine::capturing(input.as_slice());
+			let _ = machine.run_program(&program);
+		}
+	});
+}
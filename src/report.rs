@@ -0,0 +1,270 @@
+/*!
+Machine-readable summaries of runs, for scripts driving the
+interpreter. Reports are emitted as JSON by a tiny serializer, as
+they only need to be written, never read back.
+*/
+
+use std::fmt;
+use std::mem::size_of;
+use storage::Unit;
+use machine::{Machine, RuntimeError, LimitExceeded, CycleLimit, OutputLimit, TimeLimit};
+use machine::{OutputError, CellOverflow, CellUnderflow, TapeOutOfBounds, Cancelled, Aborted};
+use machine::{MemoryLimitExceeded, NestingTooDeep, ClampedAtLeftEdge, ExtensionDisabled};
+use machine::{SnapshotNotResumable, SnapshotOfAnotherProgram, LoopInTrace};
+use bytecode::OPCODES;
+
+
+/**
+A JSON value.
+*/
+#[deriving(Clone, Eq)]
+pub enum Json {
+	JsonNull,
+	JsonBool(bool),
+	JsonInt(i64),
+	JsonString(~str),
+	JsonArray(~[Json]),
+	/// Keys are written in the given order.
+	JsonObject(~[(~str, Json)]),
+}
+
+impl fmt::Show for Json {
+	/**
+	Writes the value as compact JSON, with no whitespace at all.
+	*/
+	fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			JsonNull => write!(f.buf, "null"),
+			JsonBool(b) => write!(f.buf, "{}", b),
+			JsonInt(n) => write!(f.buf, "{}", n),
+			JsonString(ref s) => write!(f.buf, "{}", escape(s.as_slice())),
+			JsonArray(ref values) => {
+				try!(write!(f.buf, "["));
+				for (i, value) in values.iter().enumerate() {
+					if i > 0 { try!(write!(f.buf, ",")); }
+					try!(write!(f.buf, "{}", *value));
+				}
+				write!(f.buf, "]")
+			}
+			JsonObject(ref fields) => {
+				try!(write!(f.buf, "\\{"));
+				for (i, &(ref key, ref value)) in fields.iter().enumerate() {
+					if i > 0 { try!(write!(f.buf, ",")); }
+					try!(write!(f.buf, "{}:{}", escape(key.as_slice()), *value));
+				}
+				write!(f.buf, "\\}")
+			}
+		}
+	}
+}
+
+/**
+Quotes a string as a JSON string literal, escaping quotes, backslashes
+and control characters. Anything else is valid as it is.
+*/
+pub fn escape(s: &str) -> ~str {
+	let mut out = StrBuf::new();
+	out.push_char('"');
+	for c in s.chars() {
+		match c {
+			'"' => out.push_str("\\\""),
+			'\\' => out.push_str("\\\\"),
+			'\n' => out.push_str("\\n"),
+			'\r' => out.push_str("\\r"),
+			'\t' => out.push_str("\\t"),
+			// Backslashes are escaped in format strings too.
+			c if (c as u32) < 0x20 => out.push_str(format!("\\\\u{:04x}", c as u32)),
+			c => out.push_char(c),
+		}
+	}
+	out.push_char('"');
+	out.into_owned()
+}
+
+
+/**
+How a run ended.
+*/
+#[deriving(Clone, Eq, Show)]
+pub enum Termination {
+	/// The program ran to its end.
+	Completed,
+	/// The run failed, with the kind of error and its message.
+	Failed(~str, ~str),
+	/// The run went over a limit, with the kind of limit and its message.
+	LimitHit(~str, ~str),
+}
+
+impl Termination {
+	/**
+	Tells how a run ended from its result.
+	*/
+	pub fn of<T>(result: &Result<T, RuntimeError>) -> Termination {
+		let err = match *result {
+			Ok(_) => return Completed,
+			Err(ref err) => err,
+		};
+		let message = format!("{}", *err);
+		match *err {
+			LimitExceeded(CycleLimit) => LimitHit(~"cycles", message),
+			LimitExceeded(OutputLimit) => LimitHit(~"output", message),
+			LimitExceeded(TimeLimit) => LimitHit(~"time", message),
+			MemoryLimitExceeded(..) => LimitHit(~"memory", message),
+			NestingTooDeep(..) => LimitHit(~"nesting", message),
+			OutputError => Failed(~"output_error", message),
+			CellOverflow => Failed(~"cell_overflow", message),
+			CellUnderflow => Failed(~"cell_underflow", message),
+			TapeOutOfBounds => Failed(~"tape_out_of_bounds", message),
+			Cancelled(_) => Failed(~"cancelled", message),
+			Aborted => Failed(~"aborted", message),
+			ClampedAtLeftEdge(_) => Failed(~"clamped_at_left_edge", message),
+			ExtensionDisabled(_) => Failed(~"extension_disabled", message),
+			SnapshotNotResumable(_) => Failed(~"snapshot_not_resumable", message),
+			SnapshotOfAnotherProgram(_) => Failed(~"snapshot_of_another_program", message),
+			LoopInTrace(_) => Failed(~"loop_in_trace", message),
+		}
+	}
+}
+
+/**
+A summary of a finished run: how long it took, what it executed,
+what it did to its I/O and tape, how it was configured, and how it
+ended.
+*/
+#[deriving(Clone, Eq)]
+pub struct RunReport {
+	/// Wall-clock duration of the run, in nanoseconds.
+	pub duration_ns: u64,
+	/// Cycles executed.
+	pub cycles: uint,
+	/// Instructions executed, by opcode name.
+	pub op_counts: ~[(~str, uint)],
+	/// Bytes read from the input.
+	pub bytes_in: uint,
+	/// Bytes written to the output.
+	pub bytes_out: uint,
+	/// How many loops deep the run went.
+	pub max_depth_reached: uint,
+	/// The range of cells touched, see `Machine::touched_range`.
+	pub tape_extent: (int, int),
+	/// How many cells the tape allocated.
+	pub peak_cells: uint,
+	/// How many cells from the origin held arguments, see `Machine::set_args`.
+	pub args_cells: uint,
+	/// The kind of tape used, named as by `storage::tape_from_name`.
+	pub tape_backend: ~str,
+	/// The size of each cell, in bits.
+	pub cell_bits: uint,
+	/// How much the program was optimized before running.
+	pub opt_level: uint,
+	/// The extensions enabled, in their string form, see `ExtensionSet`.
+	pub extensions: ~str,
+	/// How the run ended.
+	pub termination: Termination,
+}
+
+impl RunReport {
+	/**
+	Summarizes the last run of a machine, given its result, how long
+	it took, and the optimization level of the program it ran. The
+	tape is assumed to be the default `vector` one.
+	*/
+	pub fn new<T>(machine: &Machine, result: &Result<T, RuntimeError>,
+	              duration_ns: u64, opt_level: uint) -> RunReport {
+		let (bytes_in, bytes_out) = machine.bytes_io();
+		RunReport {
+			duration_ns: duration_ns,
+			cycles: machine.cycles(),
+			op_counts: OPCODES.iter().zip(machine.op_counts().iter())
+				.map(|(opcode, &count)| (opcode.name().to_owned(), count))
+				.collect(),
+			bytes_in: bytes_in,
+			bytes_out: bytes_out,
+			max_depth_reached: machine.max_depth_reached(),
+			tape_extent: machine.touched_range(),
+			peak_cells: machine.allocated_cells(),
+			args_cells: machine.args_cells(),
+			tape_backend: ~"vector",
+			cell_bits: size_of::<Unit>() * 8,
+			opt_level: opt_level,
+			extensions: format!("{}", machine.active_extensions()),
+			termination: Termination::of(result),
+		}
+	}
+
+	/**
+	The report as a JSON object.
+	*/
+	pub fn to_json(&self) -> Json {
+		let int = |n: uint| JsonInt(n as i64);
+		let (start, end) = self.tape_extent;
+		let termination = match self.termination {
+			Completed => ~[(~"reason", JsonString(~"ok"))],
+			Failed(ref kind, ref message) => ~[
+				(~"reason", JsonString(~"error")),
+				(~"kind", JsonString(kind.clone())),
+				(~"message", JsonString(message.clone())),
+			],
+			LimitHit(ref kind, ref message) => ~[
+				(~"reason", JsonString(~"limit")),
+				(~"kind", JsonString(kind.clone())),
+				(~"message", JsonString(message.clone())),
+			],
+		};
+		JsonObject(~[
+			(~"duration_ns", JsonInt(self.duration_ns as i64)),
+			(~"cycles", int(self.cycles)),
+			(~"ops", JsonObject(self.op_counts.iter().map(|&(ref name, count)| {
+				(name.clone(), int(count))
+			}).collect())),
+			(~"bytes_in", int(self.bytes_in)),
+			(~"bytes_out", int(self.bytes_out)),
+			(~"max_depth", int(self.max_depth_reached)),
+			(~"tape", JsonObject(~[
+				(~"start", JsonInt(start as i64)),
+				(~"end", JsonInt(end as i64)),
+				(~"peak_cells", int(self.peak_cells)),
+				(~"args_cells", int(self.args_cells)),
+			])),
+			(~"config", JsonObject(~[
+				(~"tape_backend", JsonString(self.tape_backend.clone())),
+				(~"cell_bits", int(self.cell_bits)),
+				(~"opt_level", int(self.opt_level)),
+				(~"extensions", JsonString(self.extensions.clone())),
+			])),
+			(~"termination", JsonObject(termination)),
+		])
+	}
+}
+
+#[cfg(test)]
+use ast::Ast;
+
+#[test]
+/// Quotes, backslashes and control characters should be escaped.
+fn test_json_escape() {
+	assert!(escape("say \"hi\"\\\n\x01") == ~"\"say \\\"hi\\\"\\\\\\n\\u0001\"");
+	assert!(format!("{}", JsonArray(~[JsonNull, JsonBool(true), JsonInt(-3)])) == ~"[null,true,-3]");
+}
+
+#[test]
+/// A report should describe a run exactly, escaping its error message.
+fn test_run_report_json() {
+	let program = Ast::parse_str(",.-").unwrap();
+	let mut machine = Machine::capturing(bytes!("a"));
+	let result = machine.run_program(&program);
+	let mut report = RunReport::new(&machine, &result, 1500, 0);
+	assert!(report.termination == Completed);
+	report.termination = Failed(~"cell_underflow", ~"Cell \"x\" went below zero.");
+	let expected = [
+		"{\"duration_ns\":1500,\"cycles\":3,",
+		"\"ops\":{\"move\":0,\"add\":1,\"jnz\":0,\"jz\":0,\"add_at\":0,\"set_at\":0,\"offset_write\":0,",
+		"\"put\":1,\"get\":1,\"random\":0,\"negate\":0,\"checkpoint\":0,\"comment\":0,\"nop\":0,\"host_call\":0},",
+		"\"bytes_in\":1,\"bytes_out\":1,\"max_depth\":0,",
+		"\"tape\":{\"start\":0,\"end\":1,\"peak_cells\":30000,\"args_cells\":0},",
+		"\"config\":{\"tape_backend\":\"vector\",\"cell_bits\":8,\"opt_level\":0,\"extensions\":\"none\"},",
+		"\"termination\":{\"reason\":\"error\",\"kind\":\"cell_underflow\",",
+		"\"message\":\"Cell \\\"x\\\" went below zero.\"}}",
+	].concat();
+	assert!(format!("{}", report.to_json()) == expected);
+}
@@ -0,0 +1,666 @@
+/*!
+A small peephole rewriting engine.
+
+Optimizations are expressed as rules, each made of a pattern and of
+a function producing the replacement for whatever the pattern
+matched. Patterns are sequences of `Matcher`s, matching one operator
+each, so rules can not match across loop boundaries, but are applied
+inside loop bodies too.
+
+Rules are applied by a `Ruleset`, see `Ruleset::apply` for the exact
+order in which it does so.
+
+The same matchers make up the patterns of `OpPattern`, which are only
+looked for, see `Ast::find`, and can match runs of operators and the
+bodies of loops too.
+*/
+
+use operators::{Operator, Sub, Incr, Decr, Prev, Next, Nop};
+use operators::{IncrN, DecrN, PrevN, NextN, Move, DoSub, Shebang};
+use ast::{Ast, Span};
+use sourcemap::{SourceMap, SourceNode};
+use collections::hashmap::HashMap;
+
+
+/**
+Matches a single operator in a pattern.
+*/
+#[deriving(Clone, Eq, Show)]
+pub enum Matcher {
+	/// Exactly the given operator.
+	Exactly(Operator),
+	/// Any operator adding to the current cell: `+`, `-`, or their
+	/// counted forms. See `amount` for what it adds.
+	AnyAdd,
+	/// Any operator adding to the current cell going up: `+` or `IncrN`.
+	AnyIncr,
+	/// Any operator adding to the current cell going down: `-` or `DecrN`.
+	AnyDecr,
+	/// Any operator moving the head: `<`, `>`, their counted forms, or
+	/// `Move`. See `amount` for how far it moves.
+	AnyMove,
+	/// Any operator moving the head to the right: `>`, `NextN`, or
+	/// `Move` by a positive offset.
+	AnyNext,
+	/// Any operator moving the head to the left: `<`, `PrevN`, or
+	/// `Move` by a negative offset.
+	AnyPrev,
+	/// A loop clearing the current cell, that is `[-]`.
+	AnyClear,
+	/// Any operator at all.
+	AnyOp,
+}
+
+impl Matcher {
+	/**
+	Whether an operator is matched.
+	*/
+	pub fn matches(&self, op: &Operator) -> bool {
+		match *self {
+			Exactly(ref expected) => op == expected,
+			AnyAdd => match *op { Incr | Decr | IncrN(_) | DecrN(_) => true, _ => false },
+			AnyIncr => match *op { Incr | IncrN(_) => true, _ => false },
+			AnyDecr => match *op { Decr | DecrN(_) => true, _ => false },
+			AnyMove => match *op { Prev | Next | PrevN(_) | NextN(_) | Move(_) => true, _ => false },
+			AnyNext => match *op { Next | NextN(_) => true, Move(n) => n > 0, _ => false },
+			AnyPrev => match *op { Prev | PrevN(_) => true, Move(n) => n < 0, _ => false },
+			AnyClear => is_clear(op),
+			AnyOp => true,
+		}
+	}
+}
+
+/**
+An element of an `OpPattern`, matching one or more operators.
+*/
+#[deriving(Clone, Eq, Show)]
+pub enum Element {
+	/// A single operator, matched by the matcher.
+	One(Matcher),
+	/**
+	Operators in a row, at least one, each matched by the matcher,
+	whose amounts add up to the given total, see `amount`. With no
+	total, any total goes, and is captured: `Counted(AnyAdd, None)`
+	is the `Add(any)` of rule descriptions.
+	*/
+	Counted(Matcher, Option<int>),
+	/// A loop whose whole body, comments aside, matches the pattern.
+	LoopOf(OpPattern),
+	/// The pattern, once or more in a row.
+	Repeated(OpPattern),
+}
+
+/**
+A pattern to look for in programs, see `Ast::find`.
+*/
+#[deriving(Clone, Eq, Show)]
+pub struct OpPattern(pub ~[Element]);
+
+/**
+Where a pattern matched, as found by `Ast::find`.
+*/
+#[deriving(Clone, Eq, Show)]
+pub struct Match {
+	/// The source of the matched operators, comments between them included.
+	pub span: Span,
+	/// The totals of the `Counted` elements without one, in the order
+	/// they matched, loop bodies included.
+	pub captures: ~[int],
+}
+
+/**
+A rewrite rule: wherever its pattern matches a sequence of operators,
+they are replaced by what its `replace` function makes of them.
+*/
+pub struct Rule {
+	/// A short name describing the rule.
+	pub name: &'static str,
+	/// What to look for, one matcher per operator.
+	pub pattern: ~[Matcher],
+	/// Makes the replacement, given the matched operators.
+	pub replace: fn(&[Operator]) -> ~[Operator],
+}
+
+/**
+An ordered collection of rewrite rules.
+*/
+pub struct Ruleset {
+	rules: ~[Rule],
+}
+
+impl Ruleset {
+	/**
+	The built-in rules, in this order:
+
+	- `fold-adds`: `Add(a) Add(b) => Add(a+b)`, where both add in
+	  the same direction.
+	- `fold-moves`: `Move(a) Move(b) => Move(a+b)`, likewise.
+	- `double-clear`: `Clear Clear => Clear`.
+
+	Where `Add` is any of `AnyAdd`, and `Move` any of `AnyMove`.
+	Comments are dropped before applying them.
+
+	These keep what programs do under any semantics: additions
+	which cancel out are kept, as they may go out of the cell's
+	range on the way, and so are moves going back and forth, as
+	they may stop at the edge of the tape. See `wrapping` for more.
+	*/
+	pub fn builtin() -> Ruleset {
+		let mut rules = Ruleset::custom();
+		rules.push(Rule { name: "fold-adds", pattern: ~[AnyIncr, AnyIncr], replace: fold_adds });
+		rules.push(Rule { name: "fold-adds", pattern: ~[AnyDecr, AnyDecr], replace: fold_adds });
+		rules.push(Rule { name: "fold-moves", pattern: ~[AnyNext, AnyNext], replace: fold_moves });
+		rules.push(Rule { name: "fold-moves", pattern: ~[AnyPrev, AnyPrev], replace: fold_moves });
+		rules.push(Rule { name: "double-clear", pattern: ~[AnyClear, AnyClear], replace: keep_last });
+		rules
+	}
+
+	/**
+	The built-in rules, then those which only keep what programs do
+	when cells wrap around, see `semantics::WrapAround`:
+
+	- `cancel-adds`: `Add(a) Add(b) => Add(a+b)`, whichever the
+	  directions, dropping both when they cancel out.
+	- `dead-add`: `Add(_) Clear => Clear`.
+
+	Moves going back and forth are still kept, as the head may stop
+	at the edge of the tape whichever the cells.
+	*/
+	pub fn wrapping() -> Ruleset {
+		let mut rules = Ruleset::builtin();
+		rules.push(Rule { name: "cancel-adds", pattern: ~[AnyAdd, AnyAdd], replace: fold_adds });
+		rules.push(Rule { name: "dead-add", pattern: ~[AnyAdd, AnyClear], replace: keep_last });
+		rules
+	}
+
+	/**
+	An empty ruleset, to which custom rules can be added with `push`.
+	Start from `Ruleset::builtin()` instead to extend the built-in
+	rules.
+	*/
+	pub fn custom() -> Ruleset {
+		Ruleset { rules: ~[] }
+	}
+
+	/**
+	Adds a rule, to be tried after every rule added before it.
+	Rules with an empty pattern are never applied.
+	*/
+	pub fn push(&mut self, rule: Rule) {
+		self.rules.push(rule);
+	}
+
+	/**
+	Rewrites a program until no rule applies anymore.
+
+	Comments are dropped first. Loop bodies are rewritten before the
+	loops themselves are looked at, innermost first. Each sequence of
+	operators is then rewritten in passes, until a pass changes
+	nothing: a pass goes from left to right, trying the rules in the
+	order they were added at each position. The first rule matching
+	there is applied, and the pass goes on right after its
+	replacement, which is only looked at again by the next pass.
+
+	Rules whose replacement could be rewritten back into what they
+	matched would never reach a fixpoint, and should be avoided.
+	*/
+	pub fn apply(&self, ast: &Ast) -> Ast {
+		let (ast, _) = self.apply_mapped(ast, &SourceMap::of(ast));
+		ast
+	}
+
+	/**
+	Rewrites a program like `apply` does, keeping track of where in
+	the source each operator came from, given a map of the program.
+
+	Operators a rule kept as they were keep their span, while those
+	it made up span everything the rule matched.
+	*/
+	pub fn apply_mapped(&self, ast: &Ast, map: &SourceMap) -> (Ast, SourceMap) {
+		let (&Ast(ref ops), &SourceMap(ref nodes)) = (ast, map);
+		let mut ops: ~[(Operator, SourceNode)] = ops.iter().zip(nodes.iter()).filter_map(|(op, node)| {
+			let rewrite_body = |body: &Ast| self.apply_mapped(body, &node.body);
+			match *op {
+				Nop(_) | Shebang(_) => None,
+				Sub(ref body) => {
+					let (body, map) = rewrite_body(body);
+					Some((Sub(body), SourceNode { span: node.span, body: map }))
+				}
+				DoSub(ref body) => {
+					let (body, map) = rewrite_body(body);
+					Some((DoSub(body), SourceNode { span: node.span, body: map }))
+				}
+				ref other => Some((other.clone(), node.clone())),
+			}
+		}).collect();
+		loop {
+			let (rewritten, changed) = self.pass(ops);
+			ops = rewritten;
+			if !changed { break }
+		}
+
+		let mut rewritten = ~[];
+		let mut nodes = ~[];
+		for (op, node) in ops.move_iter() {
+			rewritten.push(op);
+			nodes.push(node);
+		}
+		(Ast(rewritten), SourceMap(nodes))
+	}
+
+	/**
+	Makes a single pass over a sequence of mapped operators, telling
+	whether anything was rewritten.
+	*/
+	fn pass(&self, ops: ~[(Operator, SourceNode)]) -> (~[(Operator, SourceNode)], bool) {
+		let mut out = ~[];
+		let mut changed = false;
+		let mut i = 0;
+		while i < ops.len() {
+			let rest = ops.slice_from(i);
+			let rule = self.rules.iter().find(|rule| {
+				!rule.pattern.is_empty() && rule.pattern.len() <= rest.len()
+				&& rule.pattern.iter().zip(rest.iter()).all(|(m, &(ref op, _))| m.matches(op))
+			});
+			match rule {
+				Some(rule) => {
+					let len = rule.pattern.len();
+					let matched = rest.slice_to(len);
+					let matched_ops: ~[Operator] = matched.iter().map(|&(ref op, _)| op.clone()).collect();
+					let (&(_, ref first), &(_, ref last)) = (&matched[0], &matched[len - 1]);
+					let span = Span { start: first.span.start, end: last.span.end };
+					for op in (rule.replace)(matched_ops.as_slice()).move_iter() {
+						let node = match matched.iter().find(|&&(ref kept, _)| *kept == op) {
+							Some(&(_, ref node)) => node.clone(),
+							None => SourceNode::spanning(&op, span),
+						};
+						out.push((op, node));
+					}
+					changed = true;
+					i += len;
+				}
+				None => {
+					out.push(rest[0].clone());
+					i += 1;
+				}
+			}
+		}
+		(out, changed)
+	}
+}
+
+/**
+Finds where a pattern matches a program, given a map of it. See
+`Ast::find`.
+*/
+pub fn find(ast: &Ast, map: &SourceMap, pattern: &OpPattern) -> Vec<Match> {
+	let mut found = Vec::new();
+	search(ast, map, pattern, &mut found);
+	found
+}
+
+/// Looks for the matches of a pattern in a sequence of operators and
+/// the loops which are not part of a match, see `find`.
+fn search(ast: &Ast, map: &SourceMap, pattern: &OpPattern, found: &mut Vec<Match>) {
+	let (&Ast(ref ops), &SourceMap(ref nodes)) = (ast, map);
+	let &OpPattern(ref elements) = pattern;
+	let mapped: ~[(&Operator, &SourceNode)] = ops.iter().zip(nodes.iter())
+		.filter(|&(op, _)| !is_comment(op)).collect();
+	let code: ~[&Operator] = mapped.iter().map(|&(op, _)| op).collect();
+	let mut i = 0;
+	while i < code.len() {
+		// The longest match starting here, the first found if several.
+		let mut longest: Option<(uint, ~[int])> = None;
+		for (end, captures) in ends(elements.as_slice(), code.as_slice(), i).move_iter() {
+			if end > longest.as_ref().map_or(i, |&(longest, _)| longest) {
+				longest = Some((end, captures));
+			}
+		}
+		match longest {
+			Some((end, captures)) => {
+				let (&(_, first), &(_, last)) = (&mapped[i], &mapped[end - 1]);
+				let span = Span { start: first.span.start, end: last.span.end };
+				found.push(Match { span: span, captures: captures });
+				i = end;
+			}
+			None => {
+				let (op, node) = mapped[i];
+				match *op {
+					Sub(ref body) | DoSub(ref body) => search(body, &node.body, pattern, found),
+					_ => {}
+				}
+				i += 1;
+			}
+		}
+	}
+}
+
+/// Whether an operator is left out of matching: comments, and the
+/// shebang line.
+fn is_comment(op: &Operator) -> bool {
+	match *op {
+		Nop(_) | Shebang(_) => true,
+		_ => false,
+	}
+}
+
+/**
+Every way a sequence of elements can match operators from `at` on:
+where the match ends, and what it captured, only the first found
+for each end.
+*/
+fn ends(elements: &[Element], ops: &[&Operator], at: uint) -> Vec<(uint, ~[int])> {
+	ends_from(elements, 0, ops, at, &mut HashMap::new())
+}
+
+/// Every way the elements from `first` on can match operators from
+/// `at` on, like `ends`. What each element matched at each position
+/// is kept in `seen`, so that it is only ever looked for once.
+fn ends_from(elements: &[Element], first: uint, ops: &[&Operator], at: uint,
+             seen: &mut HashMap<(uint, uint), Vec<(uint, ~[int])>>) -> Vec<(uint, ~[int])> {
+	if first == elements.len() {
+		return vec!((at, ~[]));
+	}
+	match seen.find(&(first, at)) {
+		Some(found) => return found.clone(),
+		None => {}
+	}
+	let mut found: Vec<(uint, ~[int])> = Vec::new();
+	for (end, captures) in heads(&elements[first], ops, at).move_iter() {
+		for (last, more) in ends_from(elements, first + 1, ops, end, seen).move_iter() {
+			if found.iter().any(|&(other, _)| other == last) { continue }
+			let mut all = captures.clone();
+			all.push_all(more);
+			found.push((last, all));
+		}
+	}
+	seen.insert((first, at), found.clone());
+	found
+}
+
+/// Every way a single element can match operators from `at` on, like
+/// `ends`.
+fn heads(element: &Element, ops: &[&Operator], at: uint) -> Vec<(uint, ~[int])> {
+	let mut found = Vec::new();
+	match *element {
+		One(ref matcher) => {
+			if at < ops.len() && matcher.matches(ops[at]) {
+				found.push((at + 1, ~[]));
+			}
+		}
+		Counted(ref matcher, total) => {
+			let (mut end, mut sum) = (at, 0);
+			while end < ops.len() && matcher.matches(ops[end]) {
+				sum += amount(ops[end]);
+				end += 1;
+				match total {
+					None => found.push((end, ~[sum])),
+					Some(total) if total == sum => found.push((end, ~[])),
+					Some(_) => {}
+				}
+			}
+		}
+		LoopOf(OpPattern(ref elements)) => {
+			if at < ops.len() {
+				match *ops[at] {
+					Sub(Ast(ref body)) => {
+						let body: ~[&Operator] = body.iter().filter(|op| !is_comment(*op)).collect();
+						let whole = ends(elements.as_slice(), body.as_slice(), 0).move_iter().find(|&(end, _)| end == body.len());
+						match whole {
+							Some((_, captures)) => found.push((at + 1, captures)),
+							None => {}
+						}
+					}
+					_ => {}
+				}
+			}
+		}
+		Repeated(OpPattern(ref elements)) => {
+			// Each round goes on from where the previous one ended,
+			// and must match something, else it would go on forever.
+			let mut round = vec!((at, ~[]));
+			let mut seen = HashMap::new();
+			while !round.is_empty() {
+				let mut next: Vec<(uint, ~[int])> = Vec::new();
+				for &(start, ref captures) in round.iter() {
+					for (end, more) in ends_from(elements.as_slice(), 0, ops, start, &mut seen).move_iter() {
+						if end > start && !next.iter().any(|&(other, _)| other == end) {
+							let mut all = captures.clone();
+							all.push_all(more);
+							next.push((end, all));
+						}
+					}
+				}
+				found.push_all(next.as_slice());
+				round = next;
+			}
+		}
+	}
+	found
+}
+
+/**
+How much an operator adds to the cell, or moves the head by.
+Anything else counts as zero.
+*/
+pub fn amount(op: &Operator) -> int {
+	match *op {
+		Incr | Next => 1,
+		Decr | Prev => -1,
+		IncrN(n) | NextN(n) => n as int,
+		DecrN(n) | PrevN(n) => -(n as int),
+		Move(n) => n,
+		_ => 0,
+	}
+}
+
+/**
+The shortest operators adding `n` to the cell: none, a single `+` or
+`-`, or a counted operator.
+*/
+pub fn add_ops(n: int) -> ~[Operator] {
+	match n {
+		0 => ~[],
+		1 => ~[Incr],
+		-1 => ~[Decr],
+		n if n > 0 => ~[IncrN(n as uint)],
+		n => ~[DecrN((-n) as uint)],
+	}
+}
+
+/**
+The shortest operators moving the head by `n` cells, like `add_ops`.
+*/
+pub fn move_ops(n: int) -> ~[Operator] {
+	match n {
+		0 => ~[],
+		1 => ~[Next],
+		-1 => ~[Prev],
+		n if n > 0 => ~[NextN(n as uint)],
+		n => ~[PrevN((-n) as uint)],
+	}
+}
+
+/**
+Whether an operator is a loop clearing the current cell, that is
+a loop whose body, comments aside, is a single `-`.
+*/
+pub fn is_clear(op: &Operator) -> bool {
+	match *op {
+		Sub(Ast(ref body)) => {
+			let mut ops = body.iter().filter(|op| match **op { Nop(_) => false, _ => true });
+			ops.next() == Some(&Decr) && ops.next().is_none()
+		}
+		_ => false,
+	}
+}
+
+/// Replaces two additions by their sum.
+fn fold_adds(ops: &[Operator]) -> ~[Operator] {
+	add_ops(amount(&ops[0]) + amount(&ops[1]))
+}
+
+/// Replaces two moves by their sum.
+fn fold_moves(ops: &[Operator]) -> ~[Operator] {
+	move_ops(amount(&ops[0]) + amount(&ops[1]))
+}
+
+/// Keeps the last operator only, the others being overridden by it.
+fn keep_last(ops: &[Operator]) -> ~[Operator] {
+	~[ops[ops.len() - 1].clone()]
+}
+
+#[test]
+/// Each built-in rule should do what it says.
+fn test_rewrite_builtin_rules() {
+	let rules = Ruleset::builtin();
+	let rewrite = |source: &str| rules.apply(&Ast::parse_str(source).unwrap());
+	assert!(rewrite("++-") == Ast(~[IncrN(2), Decr]));
+	assert!(rewrite(">> <") == Ast(~[NextN(2), Prev]));
+	assert!(rewrite("[-][-]") == Ast(~[Sub(Ast(~[Decr]))]));
+	// Loop bodies are rewritten too.
+	assert!(rewrite("[--]>[-][-]") == Ast(~[Sub(Ast(~[DecrN(2)])), Next, Sub(Ast(~[Decr]))]));
+	// What could go past the cell's range or the tape's edge is kept.
+	assert!(rewrite("+-<>+++[-]") == Ast(~[Incr, Decr, Prev, Next, IncrN(3), Sub(Ast(~[Decr]))]));
+	assert!(rewrite("<>+") == Ast(~[Prev, Next, Incr]));
+}
+
+#[test]
+/// The wrapping rules should also cancel additions, and drop dead ones.
+fn test_rewrite_wrapping_rules() {
+	let rules = Ruleset::wrapping();
+	let rewrite = |source: &str| rules.apply(&Ast::parse_str(source).unwrap());
+	assert!(rewrite("++-") == Ast(~[Incr]));
+	assert!(rewrite("+++[-]") == Ast(~[Sub(Ast(~[Decr]))]));
+	// Loop bodies are rewritten too, and can become clears.
+	assert!(rewrite("[-+-]+[-]") == Ast(~[Sub(Ast(~[Decr]))]));
+	assert!(rewrite("<>+") == Ast(~[Prev, Next, Incr]));
+}
+
+#[test]
+/// Rewriting should go on until nothing changes.
+fn test_rewrite_reaches_fixpoint() {
+	let rules = Ruleset::wrapping();
+	assert!(rules.apply(&Ast::parse_str("+-+-").unwrap()) == Ast(~[]));
+	// The first pass only gets as far as `++` and `--`.
+	assert!(rules.apply(&Ast::parse_str("++--").unwrap()) == Ast(~[]));
+	assert!(rules.apply(&Ast::parse_str("+++>+<--").unwrap()) == Ast(~[IncrN(3), Next, Incr, Prev, DecrN(2)]));
+	assert!(Ruleset::builtin().apply(&Ast::parse_str("+ + >> > <").unwrap()) == Ast(~[IncrN(2), NextN(3), Prev]));
+}
+
+#[cfg(test)]
+/// Rewrites `<>` into nothing.
+fn cancel_back_and_forth(_: &[Operator]) -> ~[Operator] {
+	~[]
+}
+
+#[test]
+/// User rules should be applied in the order they were added.
+fn test_rewrite_custom_rule() {
+	let mut rules = Ruleset::custom();
+	rules.push(Rule { name: "back-and-forth", pattern: ~[Exactly(Prev), Exactly(Next)], replace: cancel_back_and_forth });
+	let ast = rules.apply(&Ast::parse_str("+<>+<<").unwrap());
+	assert!(ast == Ast(~[Incr, Incr, Prev, Prev]));
+}
+
+#[test]
+/// The built-in rules should not change what the corpus programs do.
+fn test_rewrite_corpus() {
+	for rules in [Ruleset::builtin(), Ruleset::wrapping()].iter() {
+		for &(name, source, input) in ::corpus::PROGRAMS.iter() {
+			let ast = Ast::parse_str(source).unwrap();
+			if ::corpus::run(&rules.apply(&ast), input) != ::corpus::run(&ast, input) {
+				fail!("{} behaves differently once rewritten", name);
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+use machine::Machine;
+#[cfg(test)]
+use semantics::Semantics;
+
+#[test]
+/// The built-in rules should keep what programs do even where cells fail and the head stops at the edge.
+fn test_rewrite_builtin_sound() {
+	let rules = Ruleset::builtin();
+	let run = |source: &str, semantics: Semantics| {
+		let ast = Ast::parse_str(source).unwrap();
+		let outcome = |program: &Ast| {
+			let mut machine = Machine::capturing([]);
+			machine.set_semantics(semantics);
+			let result = machine.run_program(program).map(|_| ());
+			(result, machine.dump_tape((Some(0), Some(3))))
+		};
+		assert!(outcome(&rules.apply(&ast)) == outcome(&ast));
+	};
+	for source in ["<>+", "-+", "+++-[-]", "->+<<>>"].iter() {
+		run(*source, Semantics::common());
+		run(*source, Semantics::strict());
+	}
+}
+
+#[cfg(test)]
+/// Matches copy loops, e.g. `[->+>++<<]`, capturing each move and add.
+fn copy_loop() -> OpPattern {
+	let step = OpPattern(~[Counted(AnyMove, None), Counted(AnyAdd, None)]);
+	OpPattern(~[LoopOf(OpPattern(~[One(Exactly(Decr)), Repeated(step), Counted(AnyMove, None)]))])
+}
+
+#[test]
+/// Runs of operators should be matched whole, capturing their totals.
+fn test_rewrite_find_sequence() {
+	let pattern = OpPattern(~[Counted(AnyAdd, None), Counted(AnyMove, None), One(Exactly(Put))]);
+	let found = Ast::parse_str("++ +>>.[-]+++>.").unwrap().find(&pattern);
+	assert!(found == vec!(
+		Match { span: Span { start: 0, end: 7 }, captures: ~[3, 2] },
+		Match { span: Span { start: 10, end: 15 }, captures: ~[3, 1] },
+	));
+}
+
+#[test]
+/// Loops should be matched by the shape of their body, and searched.
+fn test_rewrite_find_loop() {
+	let found = Ast::parse_str("[->+<]").unwrap().find(&copy_loop());
+	assert!(found == vec!(Match { span: Span { start: 0, end: 6 }, captures: ~[1, 1, -1] }));
+	assert!(Ast::parse_str("[->+<.]").unwrap().find(&copy_loop()).is_empty());
+	let found = Ast::parse_str("+[[->+>++<<]]").unwrap().find(&copy_loop());
+	assert!(found == vec!(Match { span: Span { start: 2, end: 12 }, captures: ~[1, 1, 1, 2, -2] }));
+}
+
+#[test]
+/// Matches should not overlap.
+fn test_rewrite_find_no_overlap() {
+	let found = Ast::parse_str("++++").unwrap().find(&OpPattern(~[Counted(AnyAdd, Some(2))]));
+	assert!(found == vec!(
+		Match { span: Span { start: 0, end: 2 }, captures: ~[] },
+		Match { span: Span { start: 2, end: 4 }, captures: ~[] },
+	));
+}
+
+#[test]
+/// Patterns splitting long runs many ways should still be matched quickly.
+fn test_rewrite_find_many_splits() {
+	let pattern = OpPattern(::std::slice::from_elem(12, Counted(AnyAdd, None)));
+	let found = Ast::parse_str("+".repeat(40).as_slice()).unwrap().find(&pattern);
+	let mut captures = ~[1, ..12];
+	captures[11] = 29;
+	assert!(found == vec!(Match { span: Span { start: 0, end: 40 }, captures: captures }));
+
+	let repeated = OpPattern(~[Repeated(OpPattern(~[Counted(AnyAdd, None)]))]);
+	let found = Ast::parse_str("+".repeat(40).as_slice()).unwrap().find(&repeated);
+	assert!(found == vec!(Match { span: Span { start: 0, end: 40 }, captures: ~[40] }));
+}
+
+#[test]
+/// Spans should point at the matched source, comments and shebang counted.
+fn test_rewrite_find_spans() {
+	let source = "#!/usr/bin/env bf\ncopy: [->>+<<] then clear: [-]\n>>[-<<+>>]";
+	let chars: ~[char] = source.chars().collect();
+	let found = Ast::parse_str(source).unwrap().find(&copy_loop());
+	let matched: ~[~str] = found.iter().map(|m| {
+		chars.slice(m.span.start, m.span.end).iter().map(|&c| c).collect()
+	}).collect();
+	assert!(matched == ~[~"[->>+<<]", ~"[-<<+>>]"]);
+}
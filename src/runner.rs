@@ -0,0 +1,662 @@
+/*!
+Running a batch of programs one after the other, as the interpreter
+does with the files it is given, reporting on each of them.
+
+I/O is injected, so that batches can be run without a terminal.
+*/
+
+use std::fmt;
+use std::cell::Cell;
+use std::io::{Writer, stderr};
+use std::io::util::NullWriter;
+use time::precise_time_ns;
+use ast::{Ast, ParseOptions};
+use machine::{Machine, RuntimeError, CancelToken, ClosedInput, InputMode, Blocking, OutputMode, Raw, DEFAULT_SEED};
+use machine::{NewlineMode, RawNewlines};
+use semantics::Semantics;
+use builder::MachineBuilder;
+use features::ExtensionSet;
+use sourcemap::SourceMap;
+use heatmap::HeatmapStyle;
+use pacing::{Pacing, Unpaced};
+use filters::Filter;
+use explain::ExplainTracer;
+use analysis::{estimate_trip_counts, PrefixSummary};
+use cache::{CompileOptions, lookup, save, optimize};
+
+
+/**
+A program to run, and the input to give it.
+*/
+pub struct RunPlan {
+	/// What to call the program in messages, e.g. its file name.
+	pub name: ~str,
+	/// The source of the program.
+	pub source: ~str,
+	/// Where the program reads its input from.
+	pub input: ~Reader,
+}
+
+/**
+How to run a batch of programs.
+*/
+#[deriving(Clone)]
+pub struct RunOptions {
+	/// Whether to print a banner before each run, and a footer after it.
+	pub verbose: bool,
+	/// Whether to stop at the first failure, skipping the other programs.
+	pub fail_fast: bool,
+	/// Whether to recover from unmatched brackets, see `ParseOptions`.
+	pub recover: bool,
+	/// Whether to parse the extended operators, see `ParseOptions`.
+	pub extensions: bool,
+	/**
+	0 runs programs as they are, 1 folds runs, 2 also uses offsets,
+	and 3 folds balanced writes instead.
+	*/
+	pub opt_level: uint,
+	/**
+	Where to cache optimized programs, if anywhere, see `cache`. Only
+	programs optimized past level 1, which keep no source map, are.
+	Programs found there are run without parsing them again, so any
+	parse warnings are only told the first time.
+	*/
+	pub cache_dir: Option<Path>,
+	/// The semantics to run programs with.
+	pub semantics: Semantics,
+	/// The tape to run programs on, as named by `storage::tape_from_name`.
+	pub tape: ~str,
+	/// How many bytes the tape can take, see `Machine::set_memory_limit`.
+	pub max_memory: Option<uint>,
+	/// A token with which runs can be cancelled, if any.
+	pub cancel: Option<CancelToken>,
+	/**
+	Whether to only run programs without any I/O, see `Ast::has_io`,
+	so that they can only compute on their tape. Others are refused.
+	*/
+	pub pure: bool,
+	/**
+	Whether to run programs without any input, ignoring the planned
+	one. Programs reading input are then refused, unless feeding
+	them EOF.
+	*/
+	pub no_input: bool,
+	/// Whether programs run without input get EOF whenever they read.
+	pub eof_on_input: bool,
+	/// What reading does when input is not there yet, see `InputMode`.
+	pub input_mode: InputMode,
+	/// What writing a cell outputs, see `OutputMode`.
+	pub output_mode: OutputMode,
+	/// How line endings are read, and written to consoles, see `NewlineMode`.
+	pub newline_mode: NewlineMode,
+	/// Whether the output given to `run_files` is a console, see `Machine::set_output_console`.
+	pub console_output: bool,
+	/// How fast output is written, see `Machine::set_output_pacing`.
+	pub output_pacing: Pacing,
+	/// What input goes through before programs read it, in order, see `Machine::add_input_filter`.
+	pub input_filters: ~[Filter],
+	/// What output goes through before it is written, in order, see `Machine::add_output_filter`.
+	pub output_filters: ~[Filter],
+	/**
+	Whether to log every time the head stops at the origin, moving
+	left of it, see `Machine::set_track_clamps`.
+	*/
+	pub warn_clamp: bool,
+	/// Whether the head stopping at the origin fails runs, see `Machine::set_forbid_clamp`.
+	pub forbid_clamp: bool,
+	/**
+	How to log a heatmap of each program, if at all. See `Heatmap`;
+	there is none for programs run without a source map.
+	*/
+	pub heatmap: Option<HeatmapStyle>,
+	/// The seed of the random values of `?`, see `Machine::seed_rng`.
+	pub seed: u64,
+	/// Whether to log the checkpoints reached, see `Machine::set_debug`.
+	pub debug: bool,
+	/**
+	Whether to narrate each run on stderr as it goes, and how many
+	lines of it at most, see `ExplainTracer`. Programs run without a
+	source map are not narrated.
+	*/
+	pub explain: Option<uint>,
+	/**
+	How many cycles apart to print how far each run is on stderr, if
+	at all, against an estimate of its cycles when there is one, see
+	`analysis::estimate_trip_counts`.
+	*/
+	pub progress: Option<uint>,
+	/**
+	The arguments to preload the tape of every program with, if any,
+	see `Machine::set_args`. Programs whose arguments do not fit are
+	refused.
+	*/
+	pub args: Option<~[~[u8]]>,
+}
+
+impl RunOptions {
+	/**
+	Quiet, carrying on after failures, strictly parsing, without
+	optimizing, on the common semantics and a vector tape.
+	*/
+	pub fn new() -> RunOptions {
+		RunOptions {
+			verbose: false,
+			fail_fast: false,
+			recover: false,
+			extensions: false,
+			opt_level: 0,
+			cache_dir: None,
+			semantics: Semantics::common(),
+			tape: ~"vector",
+			max_memory: None,
+			cancel: None,
+			pure: false,
+			no_input: false,
+			eof_on_input: false,
+			input_mode: Blocking,
+			output_mode: Raw,
+			newline_mode: RawNewlines,
+			console_output: false,
+			output_pacing: Unpaced,
+			input_filters: ~[],
+			output_filters: ~[],
+			warn_clamp: false,
+			forbid_clamp: false,
+			heatmap: None,
+			seed: DEFAULT_SEED,
+			debug: false,
+			explain: None,
+			progress: None,
+			args: None,
+		}
+	}
+
+	/**
+	A builder for the machines running programs with these options,
+	given their I/O, so that the options can be checked up front.
+	*/
+	pub fn builder(&self) -> MachineBuilder {
+		let mut builder = MachineBuilder::new()
+			.semantics(self.semantics.clone())
+			.tape_named(self.tape)
+			.seed(self.seed)
+			.debug(self.debug)
+			.extensions(if self.extensions { ExtensionSet::all() } else { ExtensionSet::none() })
+			.input_mode(self.input_mode.clone())
+			.output_mode(self.output_mode.clone())
+			.newline_mode(self.newline_mode.clone())
+			.output_console(self.console_output)
+			.output_pacing(self.output_pacing.clone())
+			.track_clamps(self.warn_clamp)
+			.forbid_clamp(self.forbid_clamp);
+		for filter in self.input_filters.iter() {
+			builder = builder.input_filter(filter.clone());
+		}
+		for filter in self.output_filters.iter() {
+			builder = builder.output_filter(filter.clone());
+		}
+		match self.max_memory {
+			Some(bytes) => builder = builder.memory_limit(bytes),
+			None => {}
+		}
+		match self.cancel {
+			Some(ref token) => builder = builder.cancel_token(token.clone()),
+			None => {}
+		}
+		builder
+	}
+}
+
+/**
+How running a program went.
+*/
+#[deriving(Clone, Eq, Show)]
+pub enum FileOutcome {
+	/// The program ran to its end.
+	FileOk,
+	/// The program could not be parsed, for the given reason.
+	FileParseError(~str),
+	/// The program failed while running.
+	FileRunError(RuntimeError),
+	/// The program was not run, for the given reason.
+	FileRefused(~str),
+	/// The program was not run, as an earlier one failed.
+	FileSkipped,
+}
+
+/**
+The result of running a program of a batch.
+*/
+#[deriving(Clone, Eq, Show)]
+pub struct FileResult {
+	/// The name of the program, as planned.
+	pub name: ~str,
+	pub outcome: FileOutcome,
+	/// The cycles executed, even if the run failed.
+	pub cycles: uint,
+	/// How long the run took, in nanoseconds.
+	pub duration_ns: u64,
+}
+
+impl FileResult {
+	/// Whether the program failed to parse or to run.
+	pub fn failed(&self) -> bool {
+		match self.outcome {
+			FileParseError(_) | FileRunError(_) | FileRefused(_) => true,
+			FileOk | FileSkipped => false,
+		}
+	}
+}
+
+/**
+Totals over the results of a batch.
+*/
+#[deriving(Clone, Eq)]
+pub struct Summary {
+	/// The programs which were not skipped.
+	pub files: uint,
+	pub cycles: uint,
+	pub duration_ns: u64,
+	/// The programs which failed to parse or to run, or were refused.
+	pub failures: uint,
+	/// The programs skipped because of an earlier failure.
+	pub skipped: uint,
+}
+
+impl Summary {
+	/**
+	Adds up the results of a batch.
+	*/
+	pub fn of(results: &[FileResult]) -> Summary {
+		let mut summary = Summary { files: 0, cycles: 0, duration_ns: 0, failures: 0, skipped: 0 };
+		for result in results.iter() {
+			if result.outcome == FileSkipped {
+				summary.skipped += 1;
+				continue;
+			}
+			summary.files += 1;
+			summary.cycles += result.cycles;
+			summary.duration_ns += result.duration_ns;
+			if result.failed() { summary.failures += 1 }
+		}
+		summary
+	}
+}
+
+impl fmt::Show for Summary {
+	fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+		try!(write!(f.buf, "{} files run, {} cycles in {:.3f} ms, {} failed",
+			self.files, self.cycles, self.duration_ns as f64 / 1e6, self.failures));
+		if self.skipped > 0 {
+			try!(write!(f.buf, ", {} skipped", self.skipped));
+		}
+		Ok(())
+	}
+}
+
+
+/**
+Runs a batch of programs in order, writing their output to `out`, and
+their errors, along with banners when verbose, to `log`.
+
+A program failing to parse or to run is reported with its name, and
+where in its source it failed when known, which does not stop the
+others from running, unless failing fast. So is a program refused
+for reading input when there is none. Once a program has run,
+`after` is called with its result and the machine it ran on, e.g.
+to inspect the tape.
+*/
+pub fn run_files(plans: Vec<RunPlan>, options: &RunOptions, out: &mut Writer, log: &mut Writer,
+                 after: |&FileResult, &Machine|) -> Vec<FileResult> {
+	let mut results = Vec::new();
+	let mut failed = false;
+	for plan in plans.move_iter() {
+		let RunPlan { name, source, input } = plan;
+		if failed && options.fail_fast {
+			results.push(FileResult { name: name, outcome: FileSkipped, cycles: 0, duration_ns: 0 });
+			continue;
+		}
+		if options.verbose {
+			let _ = writeln!(log, "=== running {} ===", name);
+		}
+
+		// Load programs optimized past level 1 from the cache if they
+		// are there, which skips parsing and optimizing them again.
+		let parse_options = ParseOptions { recover_brackets: options.recover, extensions: options.extensions, ..ParseOptions::strict() };
+		let compile = CompileOptions { parse: parse_options.clone(), opt_level: options.opt_level };
+		let cached = match options.cache_dir {
+			Some(ref dir) if options.opt_level > 1 => lookup(source.as_bytes(), &compile, dir),
+			_ => None,
+		};
+		let loaded = cached.is_some();
+
+		// Otherwise parse the source code into an AST, maybe recovering
+		// from unmatched brackets.
+		let parsed = match cached {
+			Some(program) => Ok((program, Vec::new())),
+			None => Ast::parse_with(source, parse_options),
+		};
+		let (program, recovered) = match parsed {
+			Ok((program, warnings)) => {
+				for warning in warnings.iter() {
+					let _ = writeln!(log, "{}: {}", name, warning);
+				}
+				(program, !warnings.is_empty())
+			}
+			Err(msg) => {
+				let _ = writeln!(log, "{}: {}", name, msg);
+				results.push(FileResult { name: name, outcome: FileParseError(msg), cycles: 0, duration_ns: 0 });
+				failed = true;
+				continue;
+			}
+		};
+		if options.pure && program.has_io() {
+			let reason = ~"Refusing to run a program doing I/O.";
+			let _ = writeln!(log, "{}: {}", name, reason);
+			results.push(FileResult { name: name, outcome: FileRefused(reason), cycles: 0, duration_ns: 0 });
+			failed = true;
+			continue;
+		}
+		// Programs waiting for input which will never come would hang.
+		let input = if !options.no_input {
+			input
+		} else if options.eof_on_input || !program.uses_input() {
+			~ClosedInput as ~Reader
+		} else {
+			let reason = ~"Refusing to run a program reading input without any input.";
+			let _ = writeln!(log, "{}: {}", name, reason);
+			results.push(FileResult { name: name, outcome: FileRefused(reason), cycles: 0, duration_ns: 0 });
+			failed = true;
+			continue;
+		};
+
+		// Keep track of where operators came from, to locate errors,
+		// unless recovering from brackets moved them around.
+		let (program, map) = match options.opt_level {
+			_ if loaded => (program, None),
+			0 => {
+				let map = SourceMap::of(&program);
+				(program, Some(map))
+			}
+			1 => {
+				let (program, map) = program.optimize_mapped();
+				(program, Some(map))
+			}
+			level => {
+				let program = optimize(&program, level);
+				match options.cache_dir {
+					Some(ref dir) => save(source.as_bytes(), &compile, dir, &program),
+					None => {}
+				}
+				(program, None)
+			}
+		};
+		let map = if recovered { None } else { map };
+
+		// Narrating needs to know where each instruction came from.
+		let mut tracer = match (options.explain, &map) {
+			(Some(limit), &Some(ref map)) => Some(ExplainTracer::new(source, map.instruction_spans(&program), limit)),
+			_ => None,
+		};
+
+		// Progress is told against an estimate of the cycles, if any.
+		let estimate = options.progress.map(|_| estimate_trip_counts(&program, &PrefixSummary::of(&program)));
+		// Hooks tell which file they are about, as it runs.
+		let hook_name = name.clone();
+
+		// Run it, passing its output along as it comes.
+		let write_failed = Cell::new(false);
+		let machine = match options.builder().input(input).output(~NullWriter as ~Writer).build() {
+			Ok(machine) => machine,
+			Err(err) => {
+				let reason = format!("{}", err);
+				let _ = writeln!(log, "{}: {}", name, reason);
+				results.push(FileResult { name: name, outcome: FileRefused(reason), cycles: 0, duration_ns: 0 });
+				failed = true;
+				continue;
+			}
+		};
+		let (result, span) = {
+			let mut machine = machine;
+			match options.args {
+				Some(ref args) => {
+					let args: ~[&[u8]] = args.iter().map(|arg| arg.as_slice()).collect();
+					match machine.set_args(args) {
+						Ok(()) => {}
+						Err(reason) => {
+							let _ = writeln!(log, "{}: {}", name, reason);
+							results.push(FileResult { name: name, outcome: FileRefused(reason), cycles: 0, duration_ns: 0 });
+							failed = true;
+							continue;
+						}
+					}
+				}
+				None => {}
+			}
+			// Paced output is only paced if seen as it comes.
+			let paced = options.output_pacing != Unpaced;
+			machine.on_output(|byte| {
+				if out.write_u8(byte).is_err() || (paced && out.flush().is_err()) { write_failed.set(true) }
+			});
+			// Narration goes straight to stderr too, a step at a time.
+			match tracer {
+				Some(ref mut tracer) => machine.on_step(|step| tracer.step(step, |line| {
+					let _ = writeln!(&mut stderr(), "{}: {}", hook_name, line);
+				})),
+				None => {}
+			}
+			// Progress goes straight to stderr, as it comes.
+			match (options.progress, &estimate) {
+				(Some(every), &Some(ref estimate)) => machine.on_progress(every, |cycles| {
+					let _ = writeln!(&mut stderr(), "{}: {}", hook_name, estimate.progress(cycles));
+				}),
+				_ => {}
+			}
+
+			let started = precise_time_ns();
+			let run = match (&map, options.heatmap) {
+				(&Some(ref map), Some(style)) => {
+					let (heatmap, run) = machine.run_heatmap(&program, map, source);
+					let _ = write!(log, "{}", heatmap.render(source, style));
+					run.map_err(|err| (err, None))
+				}
+				(&Some(ref map), None) => machine.run_mapped(&program, map).map_err(|err| (err.error, Some(err.span))),
+				(&None, _) => machine.run_program(&program).map_err(|err| (err, None)),
+			};
+			let duration_ns = precise_time_ns() - started;
+			for &(ref checkpoint, cycles) in machine.checkpoints().iter() {
+				let _ = writeln!(log, "{}: reached `{}` after {} cycles", name, *checkpoint, cycles);
+			}
+			for event in machine.clamp_events().iter() {
+				let _ = match event.span {
+					Some(span) => write!(log, "{}:{}: ", name, span),
+					None => write!(log, "{}: ", name),
+				};
+				let _ = writeln!(log, "head stopped at the origin, moving to {}, after {} cycles",
+					event.attempted, event.cycle);
+			}
+			let unlisted = machine.clamp_count() - machine.clamp_events().len();
+			if unlisted > 0 {
+				let _ = writeln!(log, "{}: head stopped at the origin {} more times", name, unlisted);
+			}
+			let (outcome, span) = match run {
+				Ok(_) if write_failed.get() => (FileRunError(::machine::OutputError), None),
+				Ok(_) => (FileOk, None),
+				Err((err, span)) => (FileRunError(err), span),
+			};
+			let result = FileResult { name: name, outcome: outcome, cycles: machine.cycles(), duration_ns: duration_ns };
+			after(&result, &machine);
+			(result, span)
+		};
+		let _ = out.flush();
+
+		match result.outcome {
+			FileRunError(ref err) => {
+				let _ = match span {
+					Some(span) => writeln!(log, "{}:{}: {}", result.name, span, *err),
+					None => writeln!(log, "{}: {}", result.name, *err),
+				};
+				failed = true;
+			}
+			_ => {}
+		}
+		if options.verbose {
+			let _ = writeln!(log, "=== {}: {} cycles in {:.3f} ms ===",
+				result.name, result.cycles, result.duration_ns as f64 / 1e6);
+		}
+		results.push(result);
+	}
+	results
+}
+
+#[cfg(test)]
+use std::io::{MemReader, MemWriter, TempDir};
+#[cfg(test)]
+use std::str::from_utf8;
+
+#[cfg(test)]
+/// Three programs, the second of which does not parse.
+fn three_plans() -> Vec<RunPlan> {
+	vec!(
+		RunPlan { name: ~"a.bf", source: ~",+.", input: ~MemReader::new(~[64]) as ~Reader },
+		RunPlan { name: ~"b.bf", source: ~"+[", input: ~MemReader::new(~[]) as ~Reader },
+		RunPlan { name: ~"c.bf", source: ~"++++++++[>++++++++<-]>++.", input: ~MemReader::new(~[]) as ~Reader },
+	)
+}
+
+#[test]
+/// A parse error should be reported, and not stop the other programs.
+fn test_run_files_carries_on() {
+	let (mut out, mut log) = (MemWriter::new(), MemWriter::new());
+	let results = run_files(three_plans(), &RunOptions::new(), &mut out, &mut log, |_, _| {});
+	let outcomes: ~[FileOutcome] = results.iter().map(|r| r.outcome.clone()).collect();
+	assert!(outcomes == ~[FileOk, FileParseError(~"Unmatched `[`."), FileOk]);
+	assert!(out.get_ref() == bytes!("AB"));
+	assert!(from_utf8(log.get_ref()) == Some("b.bf: Unmatched `[`.\n"));
+
+	let summary = Summary::of(results.as_slice());
+	assert!(summary.files == 3);
+	assert!(summary.failures == 1);
+	assert!(summary.skipped == 0);
+	assert!(summary.cycles == results.iter().fold(0, |sum, r| sum + r.cycles));
+}
+
+#[test]
+/// Failing fast should skip every program after the first failure.
+fn test_run_files_fails_fast() {
+	let (mut out, mut log) = (MemWriter::new(), MemWriter::new());
+	let options = RunOptions { fail_fast: true, verbose: true, ..RunOptions::new() };
+	let mut ran = 0;
+	let results = run_files(three_plans(), &options, &mut out, &mut log, |_, _| ran += 1);
+	let outcomes: ~[FileOutcome] = results.iter().map(|r| r.outcome.clone()).collect();
+	assert!(outcomes == ~[FileOk, FileParseError(~"Unmatched `[`."), FileSkipped]);
+	assert!(ran == 1);
+	assert!(out.get_ref() == bytes!("A"));
+	let log = from_utf8(log.get_ref()).unwrap().to_owned();
+	assert!(log.starts_with("=== running a.bf ===\n=== a.bf: 3 cycles in "));
+	assert!(log.ends_with("=== running b.bf ===\nb.bf: Unmatched `[`.\n"));
+
+	let summary = Summary::of(results.as_slice());
+	assert!(summary.files == 2);
+	assert!(summary.failures == 1);
+	assert!(summary.skipped == 1);
+}
+
+#[test]
+/// Without input, programs reading it should be refused, or get EOF.
+fn test_run_files_without_input() {
+	let plans = || vec!(
+		RunPlan { name: ~"cat.bf", source: ~",[.,]", input: ~MemReader::new(~[1, 2]) as ~Reader },
+		RunPlan { name: ~"out.bf", source: ~"+.", input: ~MemReader::new(~[]) as ~Reader },
+	);
+	let options = RunOptions { no_input: true, ..RunOptions::new() };
+	let (mut out, mut log) = (MemWriter::new(), MemWriter::new());
+	let results = run_files(plans(), &options, &mut out, &mut log, |_, _| {});
+	let reason = ~"Refusing to run a program reading input without any input.";
+	assert!(results.get(0).outcome == FileRefused(reason.clone()));
+	assert!(results.get(1).outcome == FileOk);
+	assert!(out.get_ref() == bytes!("\x01"));
+	assert!(from_utf8(log.get_ref()).unwrap() == format!("cat.bf: {}\n", reason));
+
+	// Fed EOF, the first read clears the cell, and the loop never runs.
+	let options = RunOptions { no_input: true, eof_on_input: true, ..RunOptions::new() };
+	let (mut out, mut log) = (MemWriter::new(), MemWriter::new());
+	let results = run_files(plans(), &options, &mut out, &mut log, |_, _| {});
+	assert!(results.get(0).outcome == FileOk);
+	assert!(results.get(0).cycles == 2);
+	assert!(out.get_ref() == bytes!("\x01"));
+}
+
+#[test]
+/// Pure runs should refuse programs doing any I/O, even only output.
+fn test_run_files_pure() {
+	let plans = vec!(
+		RunPlan { name: ~"out.bf", source: ~"+.", input: ~MemReader::new(~[]) as ~Reader },
+		RunPlan { name: ~"clear.bf", source: ~"+[-]", input: ~MemReader::new(~[]) as ~Reader },
+	);
+	let options = RunOptions { pure: true, ..RunOptions::new() };
+	let (mut out, mut log) = (MemWriter::new(), MemWriter::new());
+	let results = run_files(plans, &options, &mut out, &mut log, |_, _| {});
+	assert!(results.get(0).outcome == FileRefused(~"Refusing to run a program doing I/O."));
+	assert!(results.get(1).outcome == FileOk);
+	assert!(out.get_ref().is_empty());
+
+	// Calling the host back could do anything, so it is refused too.
+	let options = RunOptions { pure: true, extensions: true, ..RunOptions::new() };
+	let plans = vec!(RunPlan { name: ~"host.bf", source: ~"+@", input: ~MemReader::new(~[]) as ~Reader });
+	let results = run_files(plans, &options, &mut out, &mut log, |_, _| {});
+	assert!(results.get(0).outcome == FileRefused(~"Refusing to run a program doing I/O."));
+}
+
+#[test]
+/// Every program should find the arguments on its tape, unless refused.
+fn test_run_files_args() {
+	let plans = vec!(
+		RunPlan { name: ~"second.bf", source: ~">[>]>[.>]", input: ~MemReader::new(~[]) as ~Reader },
+		RunPlan { name: ~"count.bf", source: format!("{}.", "+".repeat(48)), input: ~MemReader::new(~[]) as ~Reader },
+	);
+	let options = RunOptions { args: Some(~[~[102, 111, 111], ~[98, 97, 114]]), ..RunOptions::new() };
+	let (mut out, mut log) = (MemWriter::new(), MemWriter::new());
+	let results = run_files(plans, &options, &mut out, &mut log, |_, _| {});
+	assert!(results.iter().all(|result| result.outcome == FileOk));
+	assert!(out.get_ref() == bytes!("bar2"));
+
+	let options = RunOptions { args: Some(~[~[0]]), ..RunOptions::new() };
+	let plans = vec!(RunPlan { name: ~"a.bf", source: ~"+", input: ~MemReader::new(~[]) as ~Reader });
+	let results = run_files(plans, &options, &mut out, &mut log, |_, _| {});
+	assert!(results.get(0).outcome == FileRefused(~"Argument 1 holds a zero byte, which would end it early."));
+}
+
+#[test]
+/// The head stopping at the origin should be warned about, or fail the run.
+fn test_run_files_clamp() {
+	let plan = || vec!(RunPlan { name: ~"drift.bf", source: ~"+[<-]", input: ~MemReader::new(~[]) as ~Reader });
+	let options = RunOptions { warn_clamp: true, ..RunOptions::new() };
+	let (mut out, mut log) = (MemWriter::new(), MemWriter::new());
+	let results = run_files(plan(), &options, &mut out, &mut log, |_, _| {});
+	assert!(results.get(0).outcome == FileOk);
+	assert!(from_utf8(log.get_ref()) == Some("drift.bf:2..3: head stopped at the origin, moving to -1, after 3 cycles\n"));
+
+	let options = RunOptions { forbid_clamp: true, ..RunOptions::new() };
+	let results = run_files(plan(), &options, &mut out, &mut log, |_, _| {});
+	assert!(results.get(0).outcome == FileRunError(::machine::ClampedAtLeftEdge(-1)));
+}
+
+#[test]
+/// Programs in the cache should be run from there, without parsing them again.
+fn test_run_files_cache() {
+	let dir = TempDir::new("bf-cache").unwrap();
+	let options = RunOptions { opt_level: 2, cache_dir: Some(dir.path().clone()), ..RunOptions::new() };
+	let compile = CompileOptions { parse: ParseOptions::strict(), opt_level: 2 };
+	// Unless loaded, the source would fail to parse.
+	save(bytes!("+["), &compile, dir.path(), &Ast::parse_str("+++.").unwrap());
+	let plans = vec!(
+		RunPlan { name: ~"cached.bf", source: ~"+[", input: ~MemReader::new(~[]) as ~Reader },
+		RunPlan { name: ~"fresh.bf", source: ~"++.", input: ~MemReader::new(~[]) as ~Reader },
+	);
+	let (mut out, mut log) = (MemWriter::new(), MemWriter::new());
+	let results = run_files(plans, &options, &mut out, &mut log, |_, _| {});
+	assert!(results.iter().all(|result| result.outcome == FileOk));
+	assert!(out.get_ref() == bytes!("\x03\x02"));
+	assert!(lookup(bytes!("++."), &compile, dir.path()).is_some());
+}
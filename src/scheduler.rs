@@ -0,0 +1,409 @@
+/*!
+Running several programs as concurrent processes, deterministically.
+
+A `Scheduler` shares time between its processes round-robin, in the
+order they were spawned, each running for a quantum of cycles at a
+time. Processes can talk through a mailbox of numbered channels: the
+output of a process can go to a channel, from which others read
+their input. A process reading from an empty channel is blocked, and
+left out of the rounds until something is written to it, or every
+process writing to it is over, at which point it reads EOF.
+
+Nothing depends on timing, so the same programs run with the same
+quanta always interleave the same way.
+*/
+
+use std::cmp::min;
+use std::io::{IoResult, MemReader, EndOfFile, ResourceUnavailable, standard_error};
+use collections::{RingBuf, Deque};
+use collections::hashmap::HashMap;
+use sync::{Arc, Mutex};
+use ast::Ast;
+use machine::{Machine, Execution, RuntimeError, Halted, Paused, Blocked, ConditionHit};
+
+
+/**
+Identifies a process of a scheduler, by the order it was spawned in.
+*/
+#[deriving(Clone, Eq, TotalEq, Hash, Show)]
+pub struct Pid(pub uint);
+
+/**
+Where a process reads from and writes to.
+*/
+#[deriving(Clone, Eq, Show)]
+pub struct ProcIo {
+	/// The bytes to read, unless reading from a channel.
+	pub input: ~[u8],
+	/// The channel of the mailbox to read from instead, if any.
+	pub input_channel: Option<uint>,
+	/// The channel of the mailbox to write to, if any. Otherwise the
+	/// output is captured, see `ProcResult`.
+	pub output_channel: Option<uint>,
+}
+
+impl ProcIo {
+	/// Reads from the given bytes, capturing the output.
+	pub fn bytes(input: &[u8]) -> ProcIo {
+		ProcIo { input: input.to_owned(), input_channel: None, output_channel: None }
+	}
+}
+
+/**
+Where a process is at.
+*/
+#[deriving(Clone, Eq, Show)]
+pub enum ProcState {
+	/// The process can run.
+	ProcReady,
+	/// The process is waiting for its input channel to be written to.
+	ProcBlocked,
+	/// The program of the process has ended.
+	ProcHalted,
+	/// The program of the process failed.
+	ProcFailed(RuntimeError),
+}
+
+/**
+How a scheduler run ended.
+*/
+#[deriving(Clone, Eq, Show)]
+pub enum SchedulerOutcome {
+	/// Every process is over, whether it halted or failed.
+	SchedHalted,
+	/// The processes ran for as many cycles as the scheduler allows.
+	SchedLimitReached,
+	/// Every process left is blocked, waiting for another one.
+	SchedDeadlocked,
+}
+
+/**
+How a process was doing when a scheduler run ended.
+*/
+#[deriving(Clone, Eq, Show)]
+pub struct ProcResult {
+	pub state: ProcState,
+	/// The cycles executed by the process.
+	pub cycles: uint,
+	/// The output of the process, unless it went to a channel.
+	pub output: Vec<u8>,
+}
+
+/**
+The result of a scheduler run.
+*/
+#[deriving(Clone, Eq, Show)]
+pub struct SchedulerResult {
+	pub outcome: SchedulerOutcome,
+	/// The cycles executed by every process together.
+	pub cycles: uint,
+	/// How each process was doing, by pid.
+	pub procs: ~[ProcResult],
+	/// The process given each quantum, in order.
+	pub schedule: ~[Pid],
+}
+
+/**
+A channel of the mailbox, shared by the processes using it.
+*/
+struct Channel {
+	/// Written and not yet read.
+	bytes: RingBuf<u8>,
+	/// How many processes writing to it are not over yet.
+	writers: uint,
+	/// Whether reading past the written bytes is EOF, rather than
+	/// waiting for more.
+	closed: bool,
+}
+
+/// Reads from a channel, see `Channel`.
+struct ChannelReader(Arc<Mutex<Channel>>);
+
+/// Writes to a channel, see `Channel`.
+struct ChannelWriter(Arc<Mutex<Channel>>);
+
+impl Reader for ChannelReader {
+	fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+		let &ChannelReader(ref channel) = self;
+		let mut guard = channel.lock();
+		let channel = &mut *guard;
+		if channel.bytes.is_empty() {
+			return Err(standard_error(if channel.closed { EndOfFile } else { ResourceUnavailable }));
+		}
+		let mut read = 0;
+		while read < buf.len() {
+			match channel.bytes.pop_front() {
+				Some(byte) => { buf[read] = byte; read += 1 }
+				None => break,
+			}
+		}
+		Ok(read)
+	}
+}
+
+impl Writer for ChannelWriter {
+	fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+		let &ChannelWriter(ref channel) = self;
+		let mut guard = channel.lock();
+		for &byte in buf.iter() {
+			guard.bytes.push_back(byte);
+		}
+		Ok(())
+	}
+}
+
+/// A process, and the channels it uses.
+struct Proc {
+	machine: Machine<'static>,
+	execution: Execution,
+	state: ProcState,
+	input: Option<Arc<Mutex<Channel>>>,
+	output: Option<Arc<Mutex<Channel>>>,
+}
+
+impl Proc {
+	/**
+	Whether the process can be given a quantum: it is ready, or
+	blocked on a channel which can now be read from.
+	*/
+	fn runnable(&self) -> bool {
+		match (&self.state, &self.input) {
+			(&ProcReady, _) => true,
+			(&ProcBlocked, &Some(ref channel)) => {
+				let guard = channel.lock();
+				!guard.bytes.is_empty() || guard.closed
+			}
+			_ => false,
+		}
+	}
+}
+
+/**
+Runs programs as processes sharing time, see the module docs.
+
+```rust
+use brainfuck::Ast;
+use brainfuck::scheduler::{Scheduler, ProcIo};
+
+let mut scheduler = Scheduler::new();
+let producer = ProcIo { output_channel: Some(0), ..ProcIo::bytes([]) };
+let consumer = ProcIo { input_channel: Some(0), ..ProcIo::bytes([]) };
+scheduler.spawn(Ast::parse_str("+++++[>+++++++++++++<-]>.+.").unwrap(), producer);
+scheduler.spawn(Ast::parse_str(",[.,]").unwrap(), consumer);
+let result = scheduler.run(10);
+assert!(result.procs[1].output.as_slice() == bytes!("AB"));
+```
+*/
+pub struct Scheduler {
+	procs: Vec<Proc>,
+	/// The channels of the mailbox, made as processes use them.
+	channels: HashMap<uint, Arc<Mutex<Channel>>>,
+	/// How many cycles all processes may run for together, if limited.
+	cycle_limit: Option<uint>,
+	/// How many cycles all processes ran for together.
+	cycles: uint,
+	/// The process given each quantum so far.
+	schedule: Vec<Pid>,
+}
+
+impl Scheduler {
+	/// A scheduler without any process, nor limit.
+	pub fn new() -> Scheduler {
+		Scheduler {
+			procs: Vec::new(),
+			channels: HashMap::new(),
+			cycle_limit: None,
+			cycles: 0,
+			schedule: Vec::new(),
+		}
+	}
+
+	/**
+	Limits how many cycles all processes may run for together, over
+	every run of the scheduler.
+	*/
+	pub fn set_cycle_limit(&mut self, cycles: uint) {
+		self.cycle_limit = Some(cycles);
+	}
+
+	/**
+	Loads a program as a new process, on a machine of its own, which
+	will run when the scheduler does.
+	*/
+	pub fn spawn(&mut self, program: Ast, io: ProcIo) -> Pid {
+		let ProcIo { input, input_channel, output_channel } = io;
+		let input_channel = input_channel.map(|channel| self.channel(channel));
+		let output_channel = output_channel.map(|channel| self.channel(channel));
+		let input = match input_channel {
+			Some(ref channel) => ~ChannelReader(channel.clone()) as ~Reader,
+			None => ~MemReader::new(input) as ~Reader,
+		};
+		let mut machine = match output_channel {
+			Some(ref channel) => {
+				channel.lock().writers += 1;
+				Machine::with_io(input, ~ChannelWriter(channel.clone()) as ~Writer)
+			}
+			None => {
+				let mut machine = Machine::capturing([]);
+				machine.set_input(input);
+				machine
+			}
+		};
+		let execution = machine.start(&program);
+		self.procs.push(Proc {
+			machine: machine,
+			execution: execution,
+			state: ProcReady,
+			input: input_channel,
+			output: output_channel,
+		});
+		Pid(self.procs.len() - 1)
+	}
+
+	/**
+	Runs the processes round-robin, giving each runnable one up to
+	`quantum` cycles in turn, until every one is over, the cycle
+	limit is reached, or the processes left are all blocked.
+	*/
+	pub fn run(&mut self, quantum: uint) -> SchedulerResult {
+		assert!(quantum > 0, "Quanta can not be empty.");
+		// Nothing will ever be written to channels without writers.
+		for channel in self.channels.values() {
+			let mut guard = channel.lock();
+			if guard.writers == 0 { guard.closed = true }
+		}
+
+		let outcome = self.rounds(quantum);
+
+		SchedulerResult {
+			outcome: outcome,
+			cycles: self.cycles,
+			procs: self.procs.iter().map(|proc_| ProcResult {
+				state: proc_.state.clone(),
+				cycles: proc_.machine.cycles(),
+				output: Vec::from_slice(proc_.machine.captured_output()),
+			}).collect(),
+			schedule: self.schedule.as_slice().to_owned(),
+		}
+	}
+
+	/**
+	Gives quanta to the runnable processes in turn, until none is
+	left, or the cycle limit is reached.
+	*/
+	fn rounds(&mut self, quantum: uint) -> SchedulerOutcome {
+		loop {
+			let mut ran = false;
+			for pid in range(0, self.procs.len()) {
+				if !self.procs.get(pid).runnable() { continue }
+				let slice = match self.cycle_limit {
+					Some(max) if self.cycles >= max => return SchedLimitReached,
+					Some(max) => min(quantum, max - self.cycles),
+					None => quantum,
+				};
+				ran = true;
+				self.schedule.push(Pid(pid));
+				let proc_ = self.procs.get_mut(pid);
+				let before = proc_.machine.cycles();
+				proc_.state = match proc_.machine.resume_for(&mut proc_.execution, slice) {
+					Ok(Halted) => ProcHalted,
+					Ok(Paused) | Ok(ConditionHit(..)) => ProcReady,
+					Ok(Blocked) => ProcBlocked,
+					Err(err) => ProcFailed(err),
+				};
+				self.cycles += proc_.machine.cycles() - before;
+				// Readers see EOF once every writer is over.
+				match (&proc_.state, &proc_.output) {
+					(&ProcHalted, &Some(ref channel)) | (&ProcFailed(_), &Some(ref channel)) => {
+						let mut guard = channel.lock();
+						guard.writers -= 1;
+						if guard.writers == 0 { guard.closed = true }
+					}
+					_ => {}
+				}
+			}
+			if !ran { break }
+		}
+		let over = self.procs.iter().all(|proc_| match proc_.state {
+			ProcHalted | ProcFailed(_) => true,
+			ProcReady | ProcBlocked => false,
+		});
+		if over { SchedHalted } else { SchedDeadlocked }
+	}
+
+	/// The channel of the mailbox with the given number, made if need be.
+	fn channel(&mut self, channel: uint) -> Arc<Mutex<Channel>> {
+		self.channels.find_or_insert_with(channel, |_| {
+			Arc::new(Mutex::new(Channel { bytes: RingBuf::new(), writers: 0, closed: false }))
+		}).clone()
+	}
+}
+
+#[cfg(test)]
+/// A producer writing `ABC` to channel 0 after counting up to 65,
+/// and a consumer echoing channel 0, spawned in the given order.
+fn producer_consumer(consumer_first: bool) -> Scheduler {
+	let producer = Ast::parse_str("+++++[>+++++++++++++<-]>.+.+.").unwrap();
+	let consumer = Ast::parse_str(",[.,]").unwrap();
+	let mut scheduler = Scheduler::new();
+	if consumer_first {
+		scheduler.spawn(consumer.clone(), ProcIo { input_channel: Some(0), ..ProcIo::bytes([]) });
+	}
+	scheduler.spawn(producer, ProcIo { output_channel: Some(0), ..ProcIo::bytes([]) });
+	if !consumer_first {
+		scheduler.spawn(consumer, ProcIo { input_channel: Some(0), ..ProcIo::bytes([]) });
+	}
+	scheduler
+}
+
+#[test]
+/// The consumer should echo everything the producer wrote, then read EOF.
+fn test_scheduler_producer_consumer() {
+	let result = producer_consumer(false).run(7);
+	assert!(result.outcome == SchedHalted);
+	assert!(result.procs[0].state == ProcHalted);
+	assert!(result.procs[0].output.is_empty());
+	assert!(result.procs[1].state == ProcHalted);
+	assert!(result.procs[1].output.as_slice() == bytes!("ABC"));
+	assert!(result.cycles == result.procs[0].cycles + result.procs[1].cycles);
+	// Same programs, same quanta: the same interleaving.
+	for quantum in [1u, 7, 100].iter() {
+		let result = producer_consumer(true).run(*quantum);
+		assert!(result == producer_consumer(true).run(*quantum));
+	}
+}
+
+#[test]
+/// A blocked consumer should not be given quanta until it can read.
+fn test_scheduler_deschedules_blocked() {
+	let result = producer_consumer(true).run(1);
+	let consumer_quanta = result.schedule.iter().filter(|&pid| *pid == Pid(0)).count();
+	// Blocked right away, then given no quantum until the producer
+	// wrote, and then one per cycle of echoing: the first read and
+	// check, then a write, read and check per byte.
+	assert!(result.procs[0].cycles == 2 + 3 * 3);
+	assert!(consumer_quanta == 1 + result.procs[0].cycles);
+	assert!(result.schedule[0] == Pid(0) && result.schedule[1] == Pid(1));
+	assert!(result.procs[0].output.as_slice() == bytes!("ABC"));
+}
+
+#[test]
+/// Processes waiting on each other, or running forever, should be stopped.
+fn test_scheduler_deadlock_and_limit() {
+	let mut scheduler = Scheduler::new();
+	let echo = Ast::parse_str(",.").unwrap();
+	scheduler.spawn(echo.clone(), ProcIo { input_channel: Some(0), output_channel: Some(1), ..ProcIo::bytes([]) });
+	scheduler.spawn(echo, ProcIo { input_channel: Some(1), output_channel: Some(0), ..ProcIo::bytes([]) });
+	let result = scheduler.run(5);
+	assert!(result.outcome == SchedDeadlocked);
+	assert!(result.procs.iter().all(|proc_| proc_.state == ProcBlocked));
+
+	let mut scheduler = Scheduler::new();
+	scheduler.spawn(Ast::parse_str("+[]").unwrap(), ProcIo::bytes([]));
+	scheduler.spawn(Ast::parse_str("+[]").unwrap(), ProcIo::bytes([]));
+	scheduler.set_cycle_limit(25);
+	let result = scheduler.run(10);
+	assert!(result.outcome == SchedLimitReached);
+	assert!(result.cycles == 25);
+	assert!(result.schedule == ~[Pid(0), Pid(1), Pid(0)]);
+}
@@ -0,0 +1,128 @@
+/*!
+The dialect decisions a Brainfuck machine has to make.
+
+The language leaves a few behaviors unspecified, and interpreters
+disagree on them. `Semantics` bundles every such decision, and comes
+with three presets:
+
+| Knob                  | `classic()`   | `common()`    | `strict()`    |
+|-----------------------|---------------|---------------|---------------|
+| `,` at end of input   | cell kept     | cell set to 0 | cell kept     |
+| cell over/underflow   | wraps         | wraps         | error         |
+| tape size             | 30.000 cells  | growable      | 30.000 cells  |
+| moving past the edges | clamps        | clamps        | error         |
+| comments cost cycles  | no            | yes           | no            |
+
+`common()` is what machines use by default.
+*/
+
+
+/**
+What `Get` does to the cell when there is no more input. Input which
+merely has nothing to give yet only counts as over with the
+`EofOnEmpty` input mode, see `Machine::set_input_mode`.
+*/
+#[deriving(Clone, Eq, Show)]
+pub enum Eof {
+	/// The cell is set to zero.
+	EofZero,
+	/// The cell is set to minus one, that is its largest value.
+	EofMinusOne,
+	/// The cell is left as it was.
+	EofUnchanged,
+}
+
+/**
+What happens when a cell goes over its largest value, or under zero.
+*/
+#[deriving(Clone, Eq, Show)]
+pub enum Overflow {
+	/// The cell wraps around to zero, or to its largest value.
+	WrapAround,
+	/// The run fails with an error.
+	FailOnOverflow,
+}
+
+/**
+What happens when the head is moved past the edges of the tape.
+*/
+#[deriving(Clone, Eq, Show)]
+pub enum Bounds {
+	/// The head stops at the edge.
+	ClampAtEdges,
+	/// The run fails with an error.
+	FailAtEdges,
+}
+
+
+/**
+Every dialect decision a machine makes while running a program.
+*/
+#[deriving(Clone, Eq, Show)]
+pub struct Semantics {
+	/// What `Get` does at the end of input.
+	pub eof: Eof,
+	/// What happens when a cell is incremented or decremented past its range.
+	pub overflow: Overflow,
+	/// How many cells the tape has, or `None` if it grows as needed.
+	pub tape_size: Option<uint>,
+	/// What happens when the head moves past the first or last cell.
+	pub bounds: Bounds,
+	/// Whether comments cost a cycle, like the operators do.
+	pub comments_cost_cycles: bool,
+}
+
+impl Semantics {
+	/**
+	The semantics of the original implementation: a tape of 30.000
+	cells wrapping around, leaving the cell untouched at EOF.
+	*/
+	pub fn classic() -> Semantics {
+		Semantics {
+			eof: EofUnchanged,
+			overflow: WrapAround,
+			tape_size: Some(30000),
+			bounds: ClampAtEdges,
+			comments_cost_cycles: false,
+		}
+	}
+
+	/**
+	The semantics most programs expect, and the default for machines:
+	a growable tape of wrapping cells, and EOF read as zero.
+	*/
+	pub fn common() -> Semantics {
+		Semantics {
+			eof: EofZero,
+			overflow: WrapAround,
+			tape_size: None,
+			bounds: ClampAtEdges,
+			comments_cost_cycles: true,
+		}
+	}
+
+	/**
+	Like `classic()`, but failing on anything a portable program
+	should not rely on: cells going out of range, and the head going
+	past the edges of the tape.
+	*/
+	pub fn strict() -> Semantics {
+		Semantics {
+			overflow: FailOnOverflow,
+			bounds: FailAtEdges,
+			..Semantics::classic()
+		}
+	}
+
+	/**
+	Looks up a preset by name: `classic`, `common` or `strict`.
+	*/
+	pub fn from_name(name: &str) -> Option<Semantics> {
+		match name {
+			"classic" => Some(Semantics::classic()),
+			"common" => Some(Semantics::common()),
+			"strict" => Some(Semantics::strict()),
+			_ => None,
+		}
+	}
+}
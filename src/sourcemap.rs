@@ -0,0 +1,132 @@
+/*!
+Source maps, telling where in the source each operator of a program
+came from, even after it was rewritten by the optimizer.
+*/
+
+use operators::{Operator, Sub, DoSub, Shebang};
+use ast::{Ast, Span};
+
+
+/**
+Where each operator of a program came from, shaped like the program:
+one node per operator, in order.
+*/
+#[deriving(Eq, Clone, Show)]
+pub struct SourceMap(pub ~[SourceNode]);
+
+/**
+Where an operator came from, and the operators of its body too for
+loops.
+*/
+#[deriving(Eq, Clone, Show)]
+pub struct SourceNode {
+	/// The source the operator stands for, brackets included for loops.
+	pub span: Span,
+	/// The nodes of the loop's body, empty for anything else.
+	pub body: SourceMap,
+}
+
+impl SourceMap {
+	/**
+	Maps a program as it was parsed, where every operator stands for
+	as many characters as it has in the source.
+	*/
+	pub fn of(ast: &Ast) -> SourceMap {
+		SourceMap::starting_at(ast, 0)
+	}
+
+	/// Maps a program found at some offset of the source.
+	fn starting_at(ast: &Ast, start: uint) -> SourceMap {
+		let &Ast(ref ops) = ast;
+		let mut pos = start;
+		SourceMap(ops.iter().map(|op| {
+			let span = Span { start: pos, end: pos + op.source_len() };
+			pos = span.end;
+			let body = match *op {
+				Sub(ref body) | DoSub(ref body) => SourceMap::starting_at(body, span.start + 1),
+				_ => SourceMap(~[]),
+			};
+			SourceNode { span: span, body: body }
+		}).collect())
+	}
+
+	/**
+	The span of each instruction the program compiles to, see
+	`Bytecode::compile`. A loop's opening bracket is the span of its
+	`OpJz`, and its closing bracket that of its `OpJnz`.
+
+	The map must be one of the program, else this fails.
+	*/
+	pub fn instruction_spans(&self, ast: &Ast) -> ~[Span] {
+		let mut spans = Vec::new();
+		self.flatten(ast, &mut spans);
+		spans.move_iter().collect()
+	}
+
+	/// Lays out the spans of a program's instructions.
+	fn flatten(&self, ast: &Ast, spans: &mut Vec<Span>) {
+		let (&SourceMap(ref nodes), &Ast(ref ops)) = (self, ast);
+		assert!(nodes.len() == ops.len(), "Source map does not match the program.");
+		for (op, node) in ops.iter().zip(nodes.iter()) {
+			let span = node.span;
+			let closing = Span { start: span.end - 1, end: span.end };
+			match *op {
+				Sub(ref body) => {
+					spans.push(Span { start: span.start, end: span.start + 1 });
+					node.body.flatten(body, spans);
+					spans.push(closing);
+				}
+				DoSub(ref body) => {
+					node.body.flatten(body, spans);
+					spans.push(closing);
+				}
+				Shebang(_) => {}
+				_ => spans.push(span),
+			}
+		}
+	}
+}
+
+impl SourceNode {
+	/**
+	Maps an operator which stands for a whole span of source, as do
+	the operators of its body, if any.
+	*/
+	pub fn spanning(op: &Operator, span: Span) -> SourceNode {
+		let body = match *op {
+			Sub(Ast(ref body)) | DoSub(Ast(ref body)) => {
+				SourceMap(body.iter().map(|op| SourceNode::spanning(op, span)).collect())
+			}
+			_ => SourceMap(~[]),
+		};
+		SourceNode { span: span, body: body }
+	}
+}
+
+#[cfg(test)]
+use bytecode::Bytecode;
+
+#[test]
+/// Optimized operators should map to the whole run they were folded from.
+fn test_source_map_optimized() {
+	let (ast, map) = Ast::parse_str("++>+++++ [-][-]").unwrap().optimize_mapped();
+	let spans: ~[(uint, uint)] = map.instruction_spans(&ast).iter().map(|s| (s.start, s.end)).collect();
+	// `IncrN(2)`, `Next` and `IncrN(5)`, then the loop, its body, and
+	// its check. The second loop is dropped, and the first keeps its
+	// own span.
+	assert!(spans == ~[(0, 2), (2, 3), (3, 8), (9, 10), (10, 11), (11, 12)]);
+	let (ast, map) = Ast::parse_str("+++>.<").unwrap().optimize_mapped();
+	let spans: ~[(uint, uint)] = map.instruction_spans(&ast).iter().map(|s| (s.start, s.end)).collect();
+	assert!(spans == ~[(0, 3), (3, 4), (4, 5), (5, 6)]);
+}
+
+#[test]
+/// There should be a span for every instruction of the corpus programs.
+fn test_source_map_corpus() {
+	for &(_, source, _) in ::corpus::PROGRAMS.iter() {
+		let ast = Ast::parse_str(source).unwrap();
+		let spans = SourceMap::of(&ast).instruction_spans(&ast);
+		assert!(spans.len() == Bytecode::compile(&ast).code.len());
+		assert!(spans.iter().all(|span| span.end > span.start && span.end <= source.char_len()));
+	}
+}
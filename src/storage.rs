@@ -2,7 +2,9 @@ extern crate collections;
 use collections::hashmap::HashMap;
 use std::num::Zero;
 use std::vec::Vec;
-use std::cmp::max;
+use std::slice;
+use std::cmp::{min, max};
+use std::mem::size_of;
 
 /**
 Common data-type used for cells.
@@ -31,6 +33,12 @@ negative indexing.
 
 If the tape is being wound over its bounds, it should stop at
 the edge and silently ignore subsequent seeks in that direction.
+
+Cells are always told apart by their position: how far they are from
+where the head started, the origin, negative on its left. Whatever
+the tape keeps them in, positions mean the same cells on every tape,
+so that tools inspecting tapes work on any of them. Where the origin
+is kept is for the tape to know, see `Tape::origin_offset`.
 */
 pub trait Tape<T:Int + Clone> {
 
@@ -46,6 +54,31 @@ pub trait Tape<T:Int + Clone> {
 	*/
 	fn cell<'a>(&'a mut self) -> &'a mut T;
 
+	/**
+	Returns a mutable reference to the contents of the cell `offset`
+	cells away from the head, without moving it. The cell is found
+	as if the tape was wound there, so offsets past the bounds stop
+	at the edge.
+	*/
+	fn at_offset<'a>(&'a mut self, offset: int) -> &'a mut T;
+
+	/**
+	The position of the tape head, relative to the origin.
+	*/
+	fn position(&self) -> int;
+
+	/**
+	Returns the contents of the cell at the given position, without
+	moving the head nor allocating anything.
+	*/
+	fn peek(&self, pos: int) -> T;
+
+	/**
+	Returns every cell the tape has allocated, with its position,
+	sorted by position. Cells which were never allocated are zero.
+	*/
+	fn cells(&self) -> Vec<(int, T)>;
+
 	/**
 	A shortcut for manipulating the cell using a function.
 	*/
@@ -53,6 +86,97 @@ pub trait Tape<T:Int + Clone> {
 		f(self.cell())
 	}
 
+	/**
+	Zeroes every cell and rewinds the tape to its origin, so that
+	it can be reused for another program without reallocating.
+	*/
+	fn clear(&mut self);
+
+	/**
+	Whether winding past the edges of the tape wraps around to the
+	other side, rather than stopping at the edge.
+	*/
+	fn wraps(&self) -> bool {
+		false
+	}
+
+	/**
+	Whether winding past the edges of the tape should fail the run,
+	rather than stop at the edge. Only tapes of their own size, which
+	do not wrap, decide on this, see `core::FixedTape`; machines go
+	by their semantics instead.
+	*/
+	fn fails_at_edges(&self) -> bool {
+		false
+	}
+
+	/**
+	The leftmost position the tape holds, where winding further left
+	stops, or `None` for tapes with room on the left for as many cells
+	as needed. Machines stop the head there, or fail, as their
+	semantics say. Tapes wrapping around have no edge to speak of.
+	*/
+	fn left_edge(&self) -> Option<int> {
+		Some(0)
+	}
+
+	/**
+	An estimate of the memory holding the cells, in bytes, for
+	limiting how much a program can take. It is within twice what is
+	actually allocated, and never decreases as the tape is used,
+	though it may once the tape is cleared. Each tape documents how
+	it makes its estimate.
+	*/
+	fn bytes_allocated(&self) -> uint;
+
+	/**
+	Where the origin is in the tape's own storage, for tapes keeping
+	their cells in order: the cell at a position is kept at index
+	`position + origin_offset()`. Only tapes which grow on the left
+	move their origin, as they make room for more cells there.
+	*/
+	fn origin_offset(&self) -> int {
+		0
+	}
+
+}
+
+
+/**
+Whether two tapes hold the same values from `start` inclusive to
+`end` exclusive, wherever their heads are. Neither head is moved,
+nor anything allocated, see `Tape::peek`.
+*/
+pub fn tape_eq<T: Int + Clone>(a: &Tape<T>, b: &Tape<T>, (start, end): (int, int)) -> bool {
+	range(start, end).all(|pos| a.peek(pos) == b.peek(pos))
+}
+
+/**
+The values of a tape from `start` inclusive to `end` exclusive, by
+position, without moving the head nor allocating anything.
+*/
+pub fn dump<T: Int + Clone>(tape: &Tape<T>, (start, end): (int, int)) -> Vec<T> {
+	range(start, end).map(|pos| tape.peek(pos)).collect()
+}
+
+/**
+Makes a tape of bytes from its name: `vector`, `sparse`, `two-way`,
+`chunked`, or `ring:N` for a ring of N cells.
+*/
+pub fn tape_from_name(name: &str) -> Option<~Tape<Unit>> {
+	match name {
+		"vector" => Some(~VectorTape::new() as ~Tape<Unit>),
+		"sparse" => Some(~SparseTape::new() as ~Tape<Unit>),
+		"two-way" => Some(~TwoWayTape::new() as ~Tape<Unit>),
+		"chunked" => Some(~ChunkedTape::new() as ~Tape<Unit>),
+		_ if name.starts_with("ring:") => {
+			match from_str::<uint>(name.slice_from(5)) {
+				Some(size) if size > 0 => Some(~RingTape::new(size) as ~Tape<Unit>),
+				_ => None,
+			}
+		}
+		_ => None,
+	}
 }
 
 
@@ -65,12 +189,20 @@ indexing and preallocated cells.
 The tape is bounded on the left, but unbounded on the right as
 it has the ability to grow on necessity. It comes pre-grown with
 30.000 cells, initialized to zero.
+
+Optionally, the left edge can wrap instead of clamping: see
+`VectorTape::with_left_wrap`. Or the tape can start in its middle,
+to leave room on the left of the origin: see `VectorTape::centered`.
 */
 pub struct VectorTape<T> {
 	/// The actual underlying vector.
 	storage: Vec<T>,
+	/// The index of the origin in the vector.
+	origin: uint,
 	/// Keeps track of the reading head.
 	cur: int,
+	/// Whether winding left past the first cell wraps around.
+	wrap_left: bool,
 }
 
 impl<T:Int> VectorTape<T> {
@@ -82,8 +214,78 @@ impl<T:Int> VectorTape<T> {
 		VectorTape {
 			// Make space for at least 30k zero cells.
 			storage: Vec::from_elem(30000, Zero::zero()),
+			origin: 0,
 			cur: 0,
+			wrap_left: false,
+		}
+	}
+
+	/**
+	Produces a new, empty tape whose left edge wraps around.
+
+	Winding left past the first cell lands on the last cell of
+	the tape's current logical size, that is, its high-water mark
+	(30.000 cells, or more if the tape has grown since). The right
+	side is still growable, so winding right past the last cell
+	grows the tape instead of wrapping back to the first one.
+
+	Note that this makes the wrap target move as the tape grows:
+	a program that wraps left after having touched cell 40.000
+	lands on cell 40.000, not on cell 29.999.
+	*/
+	pub fn with_left_wrap() -> VectorTape<T> {
+		VectorTape { wrap_left: true, ..VectorTape::new() }
+	}
+
+	/**
+	Produces a new, empty tape whose origin is in its middle, e.g. for
+	programs doing most of their work left of it, without the cost of
+	a `SparseTape`. Comes pre-grown with `2 * half_size` zero-ed cells,
+	the origin being the first of the right half, so that the head can
+	go as far as `half_size` cells left of the origin, where it stops.
+	Positions are still relative to the origin, negative on its left.
+	Machines stop the head at that edge too, see `Tape::left_edge`.
+	*/
+	pub fn centered(half_size: uint) -> VectorTape<T> {
+		VectorTape {
+			storage: Vec::from_elem(2 * half_size, Zero::zero()),
+			origin: half_size,
+			..VectorTape::new()
+		}
+	}
+
+	/// The leftmost position the tape holds.
+	fn first(&self) -> int {
+		-(self.origin as int)
+	}
+
+	/**
+	The position the head would land on, if wound by `offset`.
+	*/
+	fn landing(&self, offset: int) -> int {
+		let pos = self.cur + offset;
+		if pos >= self.first() {
+			pos
+		} else if self.wrap_left {
+			// Wrap modulo the logical size, keeping it positive.
+			let size = self.storage.len() as int;
+			((pos % size) + size) % size
+		} else {
+			self.first()
+		}
+	}
+
+	/**
+	Returns a mutable reference to the cell at a position, growing
+	the tape with zero cells if it lies past its end.
+	*/
+	fn grown_to<'a>(&'a mut self, pos: int) -> &'a mut T {
+		let pos = (pos + self.origin as int).to_uint().unwrap_or( Zero::zero() );
+		let size = self.storage.len();
+		if pos >= size {
+			self.storage.grow(pos - size + 1, &Zero::zero());
 		}
+		self.storage.get_mut(pos)
 	}
 }
 
@@ -91,17 +293,84 @@ impl<T:Int> Tape<T> for VectorTape<T> {
 	/**
 	Seeks the tape `offset` cells to the left (-) or to the
 	right (+). Tape is bounded on the left, so it's not possible
-	to seek before that.
+	to seek before that, unless it was built to wrap around.
 	*/
 	fn wind(&mut self, offset: int) {
-		self.cur = max(0, self.cur + offset)
+		self.cur = self.landing(offset);
 	}
 	/**
 	Returns a mutable reference to the contents of a cell.
+	The tape is grown with zero cells if the head went past its end.
 	*/
 	fn cell<'a>(&'a mut self) -> &'a mut T {
-		self.storage.get_mut( self.cur.to_uint().unwrap_or( Zero::zero() ))
+		let pos = self.cur;
+		self.grown_to(pos)
 	}
+	fn at_offset<'a>(&'a mut self, offset: int) -> &'a mut T {
+		let pos = self.landing(offset);
+		self.grown_to(pos)
+	}
+	fn position(&self) -> int {
+		self.cur
+	}
+	fn peek(&self, pos: int) -> T {
+		let index = pos + self.origin as int;
+		if index < 0 || index >= self.storage.len() as int {
+			Zero::zero()
+		} else {
+			*self.storage.get(index as uint)
+		}
+	}
+	fn cells(&self) -> Vec<(int, T)> {
+		let origin = self.origin as int;
+		self.storage.iter().enumerate().map(|(index, &v)| (index as int - origin, v)).collect()
+	}
+	/**
+	Zeroes every cell in place, keeping the grown storage around.
+	*/
+	fn clear(&mut self) {
+		for cell in self.storage.mut_iter() {
+			*cell = Zero::zero();
+		}
+		self.cur = 0;
+	}
+	fn wraps(&self) -> bool {
+		self.wrap_left
+	}
+	/// The first cell, which is the origin unless the tape is centered.
+	fn left_edge(&self) -> Option<int> {
+		Some(self.first())
+	}
+	/**
+	The capacity of the vector, which is exactly what it allocated.
+	*/
+	fn bytes_allocated(&self) -> uint {
+		self.storage.capacity() * size_of::<T>()
+	}
+	fn origin_offset(&self) -> int {
+		self.origin as int
+	}
+}
+
+#[cfg(test)]
+/**
+Asserts that the cells of a tape, from the origin on, hold the
+expected values, winding the head over each of them, then winds it
+back where it was. Cells are read with `peek`, so that sparse tapes
+allocate nothing.
+*/
+pub fn assert_tape_prefix(tape: &mut Tape<Unit>, expected: &[Unit]) {
+	let start = tape.position();
+	tape.wind(-start);
+	for (i, &value) in expected.iter().enumerate() {
+		let found = tape.peek(tape.position());
+		if found != value {
+			fail!("Cell {} holds {}, expected {}.", i, found, value);
+		}
+		if i + 1 < expected.len() { tape.wind(1) }
+	}
+	let end = tape.position();
+	tape.wind(start - end);
 }
 
 #[test]
@@ -143,6 +412,107 @@ fn test_vector_tape_has_at_least_30k_cells() {
 	}
 }
 
+#[test]
+/// A wrapping tape should land on its last cell when winding left from 0.
+fn test_vector_tape_wraps_left_to_logical_size() {
+	let mut t : VectorTape<Unit> = VectorTape::with_left_wrap();
+	// Wind left from the first cell, and mark where we landed.
+	t.wind(-1);
+	t.mutate( |v|{ *v = 1; } );
+	// Going back to the first cell should not find the mark.
+	t.wind(-29999);
+	assert!(*t.cell() == 0);
+	// The mark should be on the last of the 30k preallocated cells.
+	t.wind(29999);
+	assert!(*t.cell() == 1);
+}
+
+#[test]
+/// A non-wrapping tape should clamp at its left edge.
+fn test_vector_tape_clamps_left_by_default() {
+	let mut t : VectorTape<Unit> = VectorTape::new();
+	t.mutate( |v|{ *v = 1; } );
+	t.wind(-5);
+	assert!(*t.cell() == 1);
+}
+
+#[test]
+/// Clearing the tape should zero its cells and rewind it.
+fn test_vector_tape_clear_zeroes_and_rewinds() {
+	let mut t : VectorTape<Unit> = VectorTape::new();
+	t.mutate( |v|{ *v = 1; } );
+	t.wind(2);
+	t.mutate( |v|{ *v = 2; } );
+	t.clear();
+	// We should be back at the first cell, which is now zero.
+	assert!(*t.cell() == 0);
+	t.wind(2);
+	assert!(*t.cell() == 0);
+}
+
+#[test]
+/// Peeking should read cells anywhere without moving the head.
+fn test_vector_tape_peek() {
+	let mut t : VectorTape<Unit> = VectorTape::new();
+	t.wind(3);
+	t.mutate( |v|{ *v = 3; } );
+	t.wind(-1);
+	assert!(t.peek(3) == 3);
+	assert!(t.peek(-1) == 0);
+	assert!(t.peek(50000) == 0);
+	assert!(t.position() == 2);
+}
+
+#[test]
+/// A centered tape should hold cells left of the origin, up to its edge.
+fn test_vector_tape_centered() {
+	let mut t : VectorTape<Unit> = VectorTape::centered(10);
+	t.mutate( |v|{ *v = 1; } );
+	t.wind(-4);
+	assert!(t.position() == -4);
+	t.mutate( |v|{ *v = 2; } );
+	assert!(*t.at_offset(4) == 1);
+	assert!(t.peek(-4) == 2 && t.peek(0) == 1);
+	assert!(t.origin_offset() == 10);
+	// The left edge is `half_size` cells away, and the right one grows.
+	t.wind(-20);
+	assert!(t.position() == -10);
+	*t.at_offset(40) = 3;
+	assert!(t.peek(30) == 3);
+	assert!(t.cells().iter().filter(|&&(_, v)| v != 0).map(|&(pos, _)| pos).collect::<~[int]>() == ~[-4, 0, 30]);
+}
+
+#[test]
+/// Cells at an offset should be reachable without moving the head.
+fn test_vector_tape_at_offset() {
+	let mut t : VectorTape<Unit> = VectorTape::new();
+	t.wind(2);
+	*t.at_offset(3) = 5;
+	*t.at_offset(-5) = 1;
+	assert!(t.position() == 2);
+	assert!(t.peek(5) == 5);
+	// Offsets past the left edge should stop there.
+	assert!(t.peek(0) == 1);
+}
+
+#[test]
+/// A pattern written going right, then overwritten going left, should be kept whole.
+fn test_vector_tape_pattern() {
+	let mut t : VectorTape<Unit> = VectorTape::new();
+	for i in range(0u8, 6) {
+		t.mutate( |v|{ *v = i * 2; } );
+		t.wind(1);
+	}
+	t.wind(-2);
+	t.mutate( |v|{ *v -= 1; } );
+	t.wind(-2);
+	*t.at_offset(-1) = 255;
+	assert_tape_prefix(&mut t, [0, 255, 4, 6, 7, 10, 0]);
+	// The head is back where it was.
+	assert!(t.position() == 2);
+	assert!(*t.cell() == 4);
+}
+
 
 /**
 A tape which provides sparse allocation.
@@ -152,16 +522,20 @@ allocation. Cells are created on first access.
 
 The tape is unbounded on both directions, and can be safely
 considered infinite, though it will actually contain at most
-`MAX_INT` cells, centered around zero.
+`MAX_INT` cells, centered around zero. As that could take all the
+memory there is, the number of cells can be limited, see
+`SparseTape::with_max_cells`.
 */
 pub struct SparseTape<T> {
 	/// The actual underlying hashmap.
 	storage: HashMap<int, T>,
 	/// Keeps track of the reading head.
 	cur: int,
+	/// How many cells can be created, if limited.
+	max_cells: Option<uint>,
 }
 
-impl<T> SparseTape<T> {
+impl<T:Int> SparseTape<T> {
 	/**
 	Produces a new, empty tape.
 	Cells will be lazily initialized to zero on access.
@@ -170,7 +544,40 @@ impl<T> SparseTape<T> {
 		SparseTape {
 			storage: HashMap::new(),
 			cur: 0,
+			max_cells: None,
+		}
+	}
+
+	/**
+	Produces a new, empty tape which will create at most `max` cells.
+
+	Cells which were already created can be accessed as usual, and
+	winding is not limited, but accessing yet another cell once `max`
+	cells exist fails the task. There is no way for `cell` to refuse
+	otherwise, so tapes running untrusted programs should be used in
+	a task of their own, or checked with `is_full` beforehand.
+	Peeking never creates cells, so it is always allowed.
+	*/
+	pub fn with_max_cells(max: uint) -> SparseTape<T> {
+		SparseTape { max_cells: Some(max), ..SparseTape::new() }
+	}
+
+	/**
+	Whether the tape has created as many cells as it can.
+	*/
+	pub fn is_full(&self) -> bool {
+		self.max_cells.map_or(false, |max| self.storage.len() >= max)
+	}
+
+	/**
+	Returns a mutable reference to the cell at a position, creating it
+	if needed, and failing if that would go over the limit.
+	*/
+	fn cell_at<'a>(&'a mut self, pos: int) -> &'a mut T {
+		if self.is_full() && !self.storage.contains_key(&pos) {
+			fail!("Sparse tape is full: cannot create more than {} cells.", self.max_cells.unwrap());
 		}
+		self.storage.find_or_insert(pos, Zero::zero())
 	}
 }
 
@@ -188,7 +595,41 @@ impl<T:Int> Tape<T> for SparseTape<T> {
 	created on first access, and its uninitialized value is zero.
 	*/
 	fn cell<'a>(&'a mut self) -> &'a mut T {
-		self.storage.find_or_insert(self.cur, Zero::zero())
+		let pos = self.cur;
+		self.cell_at(pos)
+	}
+	fn at_offset<'a>(&'a mut self, offset: int) -> &'a mut T {
+		let pos = self.cur + offset;
+		self.cell_at(pos)
+	}
+	fn position(&self) -> int {
+		self.cur
+	}
+	fn peek(&self, pos: int) -> T {
+		self.storage.find(&pos).map_or(Zero::zero(), |&v| v)
+	}
+	fn cells(&self) -> Vec<(int, T)> {
+		let mut cells: Vec<(int, T)> = self.storage.iter().map(|(&pos, &v)| (pos, v)).collect();
+		cells.as_mut_slice().sort_by(|&(a, _), &(b, _)| a.cmp(&b));
+		cells
+	}
+	/**
+	Forgets every cell, as they will be lazily created again.
+	*/
+	fn clear(&mut self) {
+		self.storage.clear();
+		self.cur = 0;
+	}
+	fn left_edge(&self) -> Option<int> {
+		None
+	}
+	/**
+	Twice the size of an entry, with its hash, for each cell created:
+	the table keeps between a third and twice as many slots as it
+	has entries, to keep lookups fast.
+	*/
+	fn bytes_allocated(&self) -> uint {
+		self.storage.len() * 2 * size_of::<(u64, int, T)>()
 	}
 }
 
@@ -230,3 +671,627 @@ fn test_sparse_tape_has_at_least_30k_cells() {
 		t.wind(1);
 	}
 }
+
+#[test]
+/// Clearing the tape should zero its cells and rewind it.
+fn test_sparse_tape_clear_zeroes_and_rewinds() {
+	let mut t : SparseTape<Unit> = SparseTape::new();
+	t.mutate( |v|{ *v = 1; } );
+	t.wind(-2);
+	t.mutate( |v|{ *v = 2; } );
+	t.clear();
+	// We should be back at the first cell, which is now zero.
+	assert!(*t.cell() == 0);
+	t.wind(-2);
+	assert!(*t.cell() == 0);
+}
+
+#[test]
+/// Cells should be listed in order, however they were allocated.
+fn test_sparse_tape_cells_are_sorted() {
+	let mut t : SparseTape<Unit> = SparseTape::new();
+	t.wind(5);
+	t.mutate( |v|{ *v = 5; } );
+	t.wind(-10);
+	t.mutate( |v|{ *v = 1; } );
+	assert!(t.cells() == vec!((-5, 1), (5, 5)));
+	assert!(t.peek(5) == 5);
+	assert!(t.position() == -5);
+}
+
+#[test]
+/// A pattern with gaps should read back whole, and checking it should allocate nothing.
+fn test_sparse_tape_pattern() {
+	let mut t : SparseTape<Unit> = SparseTape::new();
+	for &(offset, value) in [(0, 1), (2, 3), (3, 4), (-2, 9)].iter() {
+		*t.at_offset(offset) = value;
+	}
+	t.wind(3);
+	t.mutate( |v|{ *v += 1; } );
+	assert_tape_prefix(&mut t, [1, 0, 3, 5, 0]);
+	assert!(t.position() == 3);
+	assert!(t.cells() == vec!((-2, 9), (0, 1), (2, 3), (3, 5)));
+}
+
+#[test]
+#[should_fail]
+/// Creating more cells than allowed should fail, but not revisiting them.
+fn test_sparse_tape_max_cells() {
+	let mut t : SparseTape<Unit> = SparseTape::with_max_cells(3);
+	for _ in range(0, 3) {
+		t.mutate( |v|{ *v = 1; } );
+		t.wind(1);
+	}
+	assert!(t.is_full());
+	t.wind(-3);
+	assert!(*t.cell() == 1);
+	assert!(t.peek(10) == 0);
+	t.wind(10);
+	t.cell();
+}
+
+
+/**
+A dense tape unbounded in both directions.
+
+Like a `VectorTape`, its cells are kept in order in a vector, which
+comes pre-grown with 30.000 cells, from the origin on. The vector
+grows on the right as usual, and on the left by moving every cell
+right to make room, at least doubling its size, so that growing
+left is as cheap as growing right in the long run. Positions keep
+meaning the same cells as the tape grows: only their index in the
+vector changes, see `origin_offset`.
+*/
+pub struct TwoWayTape<T> {
+	/// The actual underlying vector.
+	storage: Vec<T>,
+	/// The index of the origin in the vector.
+	origin: uint,
+	/// Keeps track of the reading head.
+	cur: int,
+}
+
+impl<T:Int> TwoWayTape<T> {
+	/**
+	Produces a new, empty tape.
+	Comes pre-grown with 30.000 zero-ed cells right of the origin.
+	*/
+	pub fn new() -> TwoWayTape<T> {
+		TwoWayTape {
+			storage: Vec::from_elem(30000, Zero::zero()),
+			origin: 0,
+			cur: 0,
+		}
+	}
+
+	/**
+	Returns a mutable reference to the cell at a position, growing
+	the tape with zero cells on either side if it lies past its ends.
+	*/
+	fn cell_at<'a>(&'a mut self, pos: int) -> &'a mut T {
+		let index = pos + self.origin as int;
+		if index < 0 {
+			let room = max((-index) as uint, self.storage.len());
+			let mut grown = Vec::with_capacity(room + self.storage.len());
+			grown.grow(room, &Zero::zero());
+			grown.push_all(self.storage.as_slice());
+			self.storage = grown;
+			self.origin += room;
+		}
+		let index = (pos + self.origin as int) as uint;
+		let size = self.storage.len();
+		if index >= size {
+			self.storage.grow(index - size + 1, &Zero::zero());
+		}
+		self.storage.get_mut(index)
+	}
+}
+
+impl<T:Int> Tape<T> for TwoWayTape<T> {
+	/**
+	Seeks the tape `offset` cells to the left (-) or to the
+	right (+). The tape is unbounded in both direction, and only
+	grows once a cell is accessed.
+	*/
+	fn wind(&mut self, offset: int) {
+		self.cur += offset;
+	}
+	fn cell<'a>(&'a mut self) -> &'a mut T {
+		let pos = self.cur;
+		self.cell_at(pos)
+	}
+	fn at_offset<'a>(&'a mut self, offset: int) -> &'a mut T {
+		let pos = self.cur + offset;
+		self.cell_at(pos)
+	}
+	fn position(&self) -> int {
+		self.cur
+	}
+	fn peek(&self, pos: int) -> T {
+		let index = pos + self.origin as int;
+		if index < 0 || index >= self.storage.len() as int {
+			Zero::zero()
+		} else {
+			*self.storage.get(index as uint)
+		}
+	}
+	fn cells(&self) -> Vec<(int, T)> {
+		let origin = self.origin as int;
+		self.storage.iter().enumerate().map(|(index, &v)| (index as int - origin, v)).collect()
+	}
+	/**
+	Zeroes every cell in place, keeping the grown storage, and the
+	origin, where they are.
+	*/
+	fn clear(&mut self) {
+		for cell in self.storage.mut_iter() {
+			*cell = Zero::zero();
+		}
+		self.cur = 0;
+	}
+	fn left_edge(&self) -> Option<int> {
+		None
+	}
+	/**
+	The capacity of the vector, which is exactly what it allocated.
+	*/
+	fn bytes_allocated(&self) -> uint {
+		self.storage.capacity() * size_of::<T>()
+	}
+	fn origin_offset(&self) -> int {
+		self.origin as int
+	}
+}
+
+#[test]
+/// The tape should grow on the left, keeping positions as they were.
+fn test_two_way_tape_grows_left() {
+	let mut t : TwoWayTape<Unit> = TwoWayTape::new();
+	t.wind(2);
+	t.mutate( |v|{ *v = 2; } );
+	t.wind(-5);
+	assert!(*t.cell() == 0);
+	t.mutate( |v|{ *v = 3; } );
+	assert!(t.origin_offset() == 30000);
+	assert!(t.position() == -3);
+	assert!(t.peek(2) == 2 && t.peek(-3) == 3 && t.peek(-30001) == 0);
+	*t.at_offset(-40000) = 4;
+	assert!(t.peek(-40003) == 4);
+	assert!(t.cells().iter().filter(|&&(_, v)| v != 0).map(|&(pos, _)| pos).collect::<~[int]>() == ~[-40003, -3, 2]);
+	t.clear();
+	assert!(t.position() == 0 && t.peek(-3) == 0);
+}
+
+#[cfg(test)]
+use ast::Ast;
+#[cfg(test)]
+use core::run_core;
+
+#[test]
+/// Tapes unbounded on the left should hold the same cells at the same positions.
+fn test_tape_positions_agree() {
+	let Ast(ops) = Ast::parse_str("+<<++<+++>>>>->").unwrap();
+	let run = |tape: &mut Tape<Unit>| assert!(run_core(ops.as_slice(), tape, &mut range(0u8, 0), |_| true).is_ok());
+	let mut sparse: SparseTape<Unit> = SparseTape::new();
+	let mut two_way: TwoWayTape<Unit> = TwoWayTape::new();
+	run(&mut sparse);
+	run(&mut two_way);
+	assert!(dump(&sparse as &Tape<Unit>, (-3, 3)) == vec!(3, 2, 0, 1, 255, 0));
+	assert!(dump(&sparse as &Tape<Unit>, (-3, 3)) == dump(&two_way as &Tape<Unit>, (-3, 3)));
+	assert!(sparse.position() == two_way.position());
+	assert!(sparse.origin_offset() == 0 && two_way.origin_offset() > 0);
+
+	// Wrappers leave positions as they are.
+	let mut tracked = TrackingTape::new(TwoWayTape::<Unit>::new());
+	run(&mut tracked);
+	assert!(dump(&tracked as &Tape<Unit>, (-3, 3)) == dump(&two_way as &Tape<Unit>, (-3, 3)));
+	assert!(tracked.origin_offset() == two_way.origin_offset());
+	assert!(tracked.extent() == Some((-3, 1)));
+}
+
+/**
+A tape keeping track of the cells accessed on another tape, which it
+wraps: the lowest and highest positions whose cells were accessed,
+or written to. Positions, and everything else, are those of the
+tape wrapped.
+*/
+pub struct TrackingTape<U> {
+	/// The tape wrapped.
+	inner: U,
+	/// The lowest and highest positions accessed, if any.
+	extent: Option<(int, int)>,
+}
+
+impl<U> TrackingTape<U> {
+	/**
+	Wraps a tape, with nothing accessed yet.
+	*/
+	pub fn new(inner: U) -> TrackingTape<U> {
+		TrackingTape { inner: inner, extent: None }
+	}
+
+	/**
+	The lowest and highest positions whose cells were accessed since
+	the tape was wrapped, or last cleared, if any.
+	*/
+	pub fn extent(&self) -> Option<(int, int)> {
+		self.extent
+	}
+
+	/**
+	Unwraps the tape.
+	*/
+	pub fn unwrap(self) -> U {
+		self.inner
+	}
+
+	/// Notes that the cell at a position was accessed.
+	fn track(&mut self, pos: int) {
+		self.extent = Some(match self.extent {
+			Some((low, high)) => (min(low, pos), max(high, pos)),
+			None => (pos, pos),
+		});
+	}
+}
+
+impl<T:Int, U:Tape<T>> Tape<T> for TrackingTape<U> {
+	fn wind(&mut self, offset: int) {
+		self.inner.wind(offset);
+	}
+	fn cell<'a>(&'a mut self) -> &'a mut T {
+		let pos = self.inner.position();
+		self.track(pos);
+		self.inner.cell()
+	}
+	/**
+	Accesses a cell like the tape wrapped does, noting the position
+	it lands on, which for offsets past the edges is not the position
+	asked for.
+	*/
+	fn at_offset<'a>(&'a mut self, offset: int) -> &'a mut T {
+		let pos = self.inner.position();
+		self.inner.wind(offset);
+		let reached = self.inner.position();
+		self.inner.wind(pos - reached);
+		self.track(reached);
+		self.inner.at_offset(offset)
+	}
+	fn position(&self) -> int {
+		self.inner.position()
+	}
+	fn peek(&self, pos: int) -> T {
+		self.inner.peek(pos)
+	}
+	fn cells(&self) -> Vec<(int, T)> {
+		self.inner.cells()
+	}
+	fn clear(&mut self) {
+		self.inner.clear();
+		self.extent = None;
+	}
+	fn wraps(&self) -> bool {
+		self.inner.wraps()
+	}
+	fn fails_at_edges(&self) -> bool {
+		self.inner.fails_at_edges()
+	}
+	fn left_edge(&self) -> Option<int> {
+		self.inner.left_edge()
+	}
+	fn bytes_allocated(&self) -> uint {
+		self.inner.bytes_allocated()
+	}
+	fn origin_offset(&self) -> int {
+		self.inner.origin_offset()
+	}
+}
+
+
+/// How many cells each chunk of a `ChunkedTape` holds.
+pub static CHUNK_SIZE: uint = 65536;
+
+/**
+A dense tape made of fixed-size chunks, for programs using millions
+of cells.
+
+A `VectorTape` copies all of its cells whenever it grows, and needs
+twice their memory meanwhile. This tape instead keeps chunks of
+`CHUNK_SIZE` cells, each allocated on its own the first time one of
+its cells is accessed, so growing never moves cells around, and
+chunks which were never touched take no memory but a pointer.
+
+Like a `VectorTape`, the tape is bounded on the left and unbounded
+on the right.
+*/
+pub struct ChunkedTape<T> {
+	/// The chunks, by position on the tape, if allocated.
+	chunks: Vec<Option<~[T]>>,
+	/// How many of the chunks are allocated.
+	allocated: uint,
+	/// Keeps track of the reading head.
+	cur: int,
+}
+
+impl<T:Int> ChunkedTape<T> {
+	/**
+	Produces a new, empty tape, without any chunk allocated.
+	*/
+	pub fn new() -> ChunkedTape<T> {
+		ChunkedTape { chunks: Vec::new(), allocated: 0, cur: 0 }
+	}
+
+	/**
+	How many chunks were allocated so far.
+	*/
+	pub fn allocated_chunks(&self) -> uint {
+		self.allocated
+	}
+
+	/**
+	Returns a mutable reference to the cell at a position, allocating
+	its chunk if needed. Positions past the left edge stop there.
+	*/
+	fn cell_at<'a>(&'a mut self, pos: int) -> &'a mut T {
+		let pos = max(pos, 0) as uint;
+		let (chunk, index) = (pos / CHUNK_SIZE, pos % CHUNK_SIZE);
+		let count = self.chunks.len();
+		if chunk >= count {
+			self.chunks.grow_fn(chunk - count + 1, |_| None);
+		}
+		let slot = self.chunks.get_mut(chunk);
+		if slot.is_none() {
+			*slot = Some(slice::from_elem(CHUNK_SIZE, Zero::zero()));
+			self.allocated += 1;
+		}
+		&mut slot.get_mut_ref()[index]
+	}
+}
+
+impl<T:Int> Tape<T> for ChunkedTape<T> {
+	/**
+	Seeks the tape `offset` cells to the left (-) or to the
+	right (+), stopping at the left edge. Nothing is allocated.
+	*/
+	fn wind(&mut self, offset: int) {
+		self.cur = max(self.cur + offset, 0);
+	}
+	/**
+	Returns a mutable reference to the contents of a cell, allocating
+	its chunk on first access.
+	*/
+	fn cell<'a>(&'a mut self) -> &'a mut T {
+		let pos = self.cur;
+		self.cell_at(pos)
+	}
+	fn at_offset<'a>(&'a mut self, offset: int) -> &'a mut T {
+		let pos = self.cur + offset;
+		self.cell_at(pos)
+	}
+	fn position(&self) -> int {
+		self.cur
+	}
+	fn peek(&self, pos: int) -> T {
+		if pos < 0 { return Zero::zero() }
+		let (chunk, index) = (pos as uint / CHUNK_SIZE, pos as uint % CHUNK_SIZE);
+		match self.chunks.as_slice().get(chunk) {
+			Some(&Some(ref cells)) => cells[index],
+			_ => Zero::zero(),
+		}
+	}
+	fn cells(&self) -> Vec<(int, T)> {
+		let mut cells = Vec::new();
+		for (chunk, slot) in self.chunks.iter().enumerate() {
+			match *slot {
+				Some(ref chunk_cells) => {
+					let start = (chunk * CHUNK_SIZE) as int;
+					for (index, &v) in chunk_cells.iter().enumerate() {
+						cells.push((start + index as int, v));
+					}
+				}
+				None => {}
+			}
+		}
+		cells
+	}
+	/**
+	Zeroes the cells of every allocated chunk in place, keeping them
+	around.
+	*/
+	fn clear(&mut self) {
+		for slot in self.chunks.mut_iter() {
+			match *slot {
+				Some(ref mut cells) => {
+					for cell in cells.mut_iter() {
+						*cell = Zero::zero();
+					}
+				}
+				None => {}
+			}
+		}
+		self.cur = 0;
+	}
+	/**
+	The cells of the allocated chunks, and a pointer for each chunk
+	there is room for.
+	*/
+	fn bytes_allocated(&self) -> uint {
+		self.allocated * CHUNK_SIZE * size_of::<T>() + self.chunks.capacity() * size_of::<Option<~[T]>>()
+	}
+}
+
+#[test]
+/// Cells far apart, on either side of chunk boundaries, should keep their values.
+fn test_chunked_tape_far_cells() {
+	let mut t : ChunkedTape<Unit> = ChunkedTape::new();
+	let positions = [0, 65535, 65536, 10000000];
+	for (i, &pos) in positions.iter().enumerate() {
+		let offset = pos - t.position();
+		t.wind(offset);
+		t.mutate( |v|{ *v = i as u8 + 1; } );
+	}
+	let origin = -t.position();
+	t.wind(origin);
+	for (i, &pos) in positions.iter().enumerate() {
+		assert!(*t.at_offset(pos) == i as u8 + 1);
+		assert!(t.peek(pos) == i as u8 + 1);
+	}
+	// The first chunk, the second one, and the one of the last cell.
+	assert!(t.allocated_chunks() == 3);
+	assert!(t.cells().len() == 3 * CHUNK_SIZE);
+	assert!(t.cells().get(65536) == &(65536, 3));
+}
+
+#[test]
+/// Chunks should only be allocated when their cells are accessed.
+fn test_chunked_tape_allocates_on_touch() {
+	let mut t : ChunkedTape<Unit> = ChunkedTape::new();
+	t.wind(5000000);
+	t.wind(-10);
+	assert!(t.peek(4999990) == 0);
+	assert!(t.allocated_chunks() == 0);
+	assert!(*t.cell() == 0);
+	assert!(t.allocated_chunks() == 1);
+	// Clamped at the left edge, like a vector tape.
+	t.wind(-6000000);
+	assert!(t.position() == 0);
+	t.mutate( |v|{ *v = 1; } );
+	t.clear();
+	assert!(t.allocated_chunks() == 2);
+	assert!(t.peek(0) == 0);
+}
+
+#[cfg(test)]
+use test::BenchHarness;
+
+#[cfg(test)]
+/// Writes each of the first ten million cells, going right.
+fn sweep<T: Tape<Unit>>(t: &mut T) {
+	for _ in range(0, 10000000) {
+		t.mutate( |v|{ *v = 1; } );
+		t.wind(1);
+	}
+}
+
+#[bench]
+/// Sweeping a chunked tape, which never copies cells.
+fn bench_sweep_chunked(b: &mut BenchHarness) {
+	b.iter(|| {
+		let mut t : ChunkedTape<Unit> = ChunkedTape::new();
+		sweep(&mut t);
+	});
+}
+
+#[bench]
+/// Sweeping a vector tape, which copies its cells as it grows.
+fn bench_sweep_vector(b: &mut BenchHarness) {
+	b.iter(|| {
+		let mut t : VectorTape<Unit> = VectorTape::new();
+		sweep(&mut t);
+	});
+}
+
+#[bench]
+/// Sweeping a sparse tape, which hashes every position.
+fn bench_sweep_sparse(b: &mut BenchHarness) {
+	b.iter(|| {
+		let mut t : SparseTape<Unit> = SparseTape::new();
+		sweep(&mut t);
+	});
+}
+
+
+/**
+A tape of a fixed number of cells, arranged in a ring.
+
+Winding past either edge wraps around to the other one, so the
+tape has no bounds, but only as many cells as it was made with.
+The head's position is always between zero and the size.
+*/
+pub struct RingTape<T> {
+	/// The actual underlying vector, never grown.
+	storage: Vec<T>,
+	/// Keeps track of the reading head.
+	cur: int,
+}
+
+impl<T:Int> RingTape<T> {
+	/**
+	Produces a new ring of `size` zeroed cells, which can not be zero.
+	*/
+	pub fn new(size: uint) -> RingTape<T> {
+		if size == 0 { fail!("A ring tape needs at least one cell.") }
+		RingTape {
+			storage: Vec::from_elem(size, Zero::zero()),
+			cur: 0,
+		}
+	}
+
+	/**
+	The index of the cell at a position, wrapping around.
+	*/
+	fn index(&self, pos: int) -> uint {
+		let size = self.storage.len() as int;
+		(((pos % size) + size) % size) as uint
+	}
+}
+
+impl<T:Int> Tape<T> for RingTape<T> {
+	fn wind(&mut self, offset: int) {
+		self.cur = self.index(self.cur + offset) as int;
+	}
+	fn cell<'a>(&'a mut self) -> &'a mut T {
+		self.storage.get_mut(self.cur as uint)
+	}
+	fn at_offset<'a>(&'a mut self, offset: int) -> &'a mut T {
+		let index = self.index(self.cur + offset);
+		self.storage.get_mut(index)
+	}
+	fn position(&self) -> int {
+		self.cur
+	}
+	fn peek(&self, pos: int) -> T {
+		*self.storage.get(self.index(pos))
+	}
+	fn cells(&self) -> Vec<(int, T)> {
+		self.storage.iter().enumerate().map(|(pos, &v)| (pos as int, v)).collect()
+	}
+	fn clear(&mut self) {
+		for cell in self.storage.mut_iter() {
+			*cell = Zero::zero();
+		}
+		self.cur = 0;
+	}
+	fn wraps(&self) -> bool {
+		true
+	}
+	/// The cells of the ring, allocated once and for all.
+	fn bytes_allocated(&self) -> uint {
+		self.storage.len() * size_of::<T>()
+	}
+}
+
+#[test]
+/// A ring should wrap around both ways.
+fn test_ring_tape_wraps_both_ways() {
+	let mut t : RingTape<Unit> = RingTape::new(3);
+	t.wind(-1);
+	assert!(t.position() == 2);
+	t.mutate( |v|{ *v = 1; } );
+	t.wind(4);
+	assert!(t.position() == 0);
+	assert!(*t.at_offset(-1) == 1);
+	assert!(t.peek(5) == 1);
+}
+
+#[test]
+/// Tapes should be made from their names.
+fn test_tape_from_name() {
+	assert!(tape_from_name("vector").is_some());
+	assert!(tape_from_name("sparse").is_some());
+	assert!(tape_from_name("two-way").is_some());
+	assert!(tape_from_name("chunked").is_some());
+	assert!(tape_from_name("ring:10").map(|t| t.cells().len()) == Some(10));
+	assert!(tape_from_name("ring:0").is_none());
+	assert!(tape_from_name("ring").is_none());
+	assert!(tape_from_name("tree").is_none());
+}
@@ -0,0 +1,60 @@
+/*!
+Searching for the shortest program doing something, by running every
+small program in turn, see `enumerate`.
+*/
+
+use ast::Ast;
+use enumerate::{programs, OpKind, KindIncr, KindDecr, KindPrev, KindNext, KindLoop};
+use machine::{Machine, Limits};
+use storage::Unit;
+
+
+/// The kinds of operators searched programs are made of, those without I/O.
+pub static SEARCH_KINDS: [OpKind, ..5] = [KindIncr, KindDecr, KindPrev, KindNext, KindLoop];
+
+/**
+Finds the first program of at most `max_len` operators, shortest
+first, whose tape satisfies `spec` once it halted. The tape is given
+from the origin to the last touched cell, see
+`Machine::touched_range`.
+
+Programs are run without input, and their output is discarded.
+Programs failing, or going over the limits, are passed over, so
+the limits should bound cycles, as some programs never halt.
+Programs with loops which can never run are not tried, as they do
+what shorter ones do.
+*/
+pub fn search(spec: |&[Unit]| -> bool, max_len: uint, limits: Limits) -> Option<Ast> {
+	let mut machine = Machine::capturing([]);
+	machine.set_limits(limits);
+	for source in programs(max_len, SEARCH_KINDS, true) {
+		let program = match Ast::parse_str(source.as_slice()) {
+			Ok(program) => program,
+			Err(_) => continue,
+		};
+		machine.reset();
+		if machine.run_program(&program).is_err() {
+			continue;
+		}
+		let (_, end) = machine.touched_range();
+		let cells: ~[Unit] = range(0, end).map(|pos| machine.tape().peek(pos)).collect();
+		if spec(cells.as_slice()) {
+			return Some(program);
+		}
+	}
+	None
+}
+
+#[test]
+/// The shortest program leaving 3 in the first cell should be found.
+fn test_superopt_search() {
+	let limits = Limits { cycles: Some(1000), output: None, time: None };
+	let found = search(|cells| cells[0] == 3, 4, limits);
+	assert!(found.and_then(|program| program.source()) == Some(~"+++"));
+	let found = search(|cells| cells.len() == 2 && cells[1] == 255, 4, limits);
+	assert!(found.and_then(|program| program.source()) == Some(~">-"));
+	assert!(search(|cells| cells[0] == 10, 4, limits).is_none());
+	// Programs like `+[]` never halt, and are passed over.
+	let found = search(|cells| cells[0] == 4, 4, limits);
+	assert!(found.and_then(|program| program.source()) == Some(~"++++"));
+}
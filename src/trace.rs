@@ -0,0 +1,482 @@
+/*!
+Recording what runs do, event by event, and finding where two runs
+first part ways, e.g. a program and an edited or optimized copy.
+
+Runs are compared by what can be seen of them: where the head is,
+what the cell under it holds, and what is written out. Comments,
+checkpoints and no-ops leave no event, so that they can differ
+between the two programs.
+*/
+
+use std::fmt;
+use std::cell::RefCell;
+use collections::{RingBuf, Deque};
+use ast::{Ast, Span};
+use sourcemap::SourceMap;
+use operators::Operator;
+use features::ExtensionSet;
+use bytecode::{OpPut, OpCheckpoint, OpComment, OpNop, instruction_ops};
+use semantics::Semantics;
+use machine::{Machine, Execution, Limits, RuntimeError, Step, Halted, Paused, Blocked, ConditionHit};
+
+
+/**
+What an instruction did, as far as comparing runs goes. Every event
+is the same size, so that long traces stay compact.
+*/
+#[deriving(Clone, Eq, Show)]
+pub struct TraceEvent {
+	/// The cycles spent so far, this instruction included.
+	pub cycle: uint,
+	/// Where the instruction is in the bytecode.
+	pub pc: uint,
+	/// Where the tape head is after the instruction.
+	pub head: int,
+	/// The contents of the cell under the head after it.
+	pub cell: u8,
+	/// The byte written out, if the instruction wrote one.
+	pub output: Option<u8>,
+}
+
+impl TraceEvent {
+	/// Makes the event of a step, unless it is not worth comparing.
+	pub fn of(step: &Step) -> Option<TraceEvent> {
+		match step.instr.opcode {
+			OpCheckpoint | OpComment | OpNop => None,
+			opcode => Some(TraceEvent {
+				cycle: step.cycle,
+				pc: step.pc,
+				head: step.to,
+				cell: step.after,
+				output: if opcode == OpPut { Some(step.after) } else { None },
+			}),
+		}
+	}
+
+	/// Whether two events look the same from outside, cycles aside.
+	pub fn same_as(&self, other: &TraceEvent) -> bool {
+		self.head == other.head && self.cell == other.cell && self.output == other.output
+	}
+}
+
+/**
+The events of a run, in order, and the source of each instruction of
+the program, to tell where events come from.
+*/
+#[deriving(Clone, Eq, Show)]
+pub struct TraceLog {
+	/// The events so far.
+	pub events: Vec<TraceEvent>,
+	/// The span of each instruction, see `SourceMap::instruction_spans`.
+	pub spans: ~[Span],
+	/// Why the run failed after these events, if it did.
+	pub failure: Option<RuntimeError>,
+}
+
+impl TraceLog {
+	/// An empty log, for a program whose instructions have these spans.
+	pub fn new(spans: ~[Span]) -> TraceLog {
+		TraceLog { events: Vec::new(), spans: spans, failure: None }
+	}
+
+	/// Records a step, see `Machine::on_step`.
+	pub fn step(&mut self, step: &Step) {
+		match TraceEvent::of(step) {
+			Some(event) => self.events.push(event),
+			None => {}
+		}
+	}
+}
+
+/**
+Where two runs first did something different.
+*/
+#[deriving(Clone, Eq)]
+pub struct Divergence {
+	/// How many events both runs had alike before.
+	pub matched: uint,
+	/// The first event of each run to differ, `None` for a run which had ended.
+	pub first: (Option<TraceEvent>, Option<TraceEvent>),
+	/// Why each run which had ended failed, if it did rather than halting.
+	pub failures: (Option<RuntimeError>, Option<RuntimeError>),
+	/// The source of the instruction behind each of these events, when known.
+	pub spans: (Option<Span>, Option<Span>),
+	/// The events of each run right before, oldest first, up to the window asked for.
+	pub window: (~[TraceEvent], ~[TraceEvent]),
+}
+
+impl Divergence {
+	/**
+	The cycle at which the runs diverge, as counted by the first run
+	to get there. When both runs ended, but not alike, it is the
+	cycle of the last event they had alike, if any.
+	*/
+	pub fn cycle(&self) -> uint {
+		match self.first {
+			(Some(ref event), _) | (None, Some(ref event)) => event.cycle,
+			(None, None) => {
+				let (ref before, _) = self.window;
+				before.last().map_or(0, |event| event.cycle)
+			}
+		}
+	}
+}
+
+impl fmt::Show for Divergence {
+	/**
+	Tells what each run did when they diverged, and where, then what
+	they did before.
+	*/
+	fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+		try!(writeln!(f.buf, "Runs diverge at cycle {}, after {} matching events:", self.cycle(), self.matched));
+		let (ref first_a, ref first_b) = self.first;
+		let (ref span_a, ref span_b) = self.spans;
+		let (ref failure_a, ref failure_b) = self.failures;
+		for &(name, event, span, failure) in [("a", first_a, span_a, failure_a), ("b", first_b, span_b, failure_b)].iter() {
+			let place = match *span {
+				Some(ref span) => format!(" at {}", *span),
+				None => ~"",
+			};
+			match (event, failure) {
+				(&Some(ref event), _) => try!(writeln!(f.buf, "  {}{}: {}", name, place, describe(event))),
+				(&None, &Some(ref err)) => try!(writeln!(f.buf, "  {}: failed: {}", name, *err)),
+				(&None, &None) => try!(writeln!(f.buf, "  {}: ended", name)),
+			}
+		}
+		let (ref before_a, ref before_b) = self.window;
+		try!(write!(f.buf, "Before:"));
+		for (a, b) in before_a.iter().zip(before_b.iter()) {
+			try!(write!(f.buf, "\n  {} | {}", describe(a), describe(b)));
+		}
+		Ok(())
+	}
+}
+
+/// Tells in a few words what an event shows, e.g. `cycle 4: head 1, cell 65, wrote 65`.
+fn describe(event: &TraceEvent) -> ~str {
+	match event.output {
+		Some(byte) => format!("cycle {}: head {}, cell {}, wrote {}", event.cycle, event.head, event.cell, byte),
+		None => format!("cycle {}: head {}, cell {}", event.cycle, event.head, event.cell),
+	}
+}
+
+/// Whether two runs did the same at some point, one of them having maybe ended.
+fn alike(a: &Option<TraceEvent>, b: &Option<TraceEvent>) -> bool {
+	match (a, b) {
+		(&Some(ref a), &Some(ref b)) => a.same_as(b),
+		(&None, &None) => true,
+		_ => false,
+	}
+}
+
+/// The span of the instruction behind an event, when known.
+fn span_of(spans: &[Span], event: &Option<TraceEvent>) -> Option<Span> {
+	event.as_ref().and_then(|event| spans.get(event.pc)).map(|span| span.clone())
+}
+
+/**
+Runs a program on the given input, within the given limits, and
+records what it did. The map tells the source of the instructions,
+see `SourceMap::of`. Every extension is enabled. Runs which fail
+are recorded up to the failure, which is kept along.
+*/
+pub fn record(program: &Ast, map: &SourceMap, input: &[u8], limits: Limits) -> TraceLog {
+	let mut log = TraceLog::new(map.instruction_spans(program));
+	let failure;
+	{
+		let mut machine = Machine::capturing(input);
+		machine.set_limits(limits);
+		machine.set_extensions(ExtensionSet::all());
+		machine.on_step(|step| log.step(step));
+		failure = machine.run_program(program).err();
+	}
+	log.failure = failure;
+	log
+}
+
+/**
+Runs a program on the given input, within the given limits, and
+records the operator behind every instruction it executed, in order,
+see `bytecode::instruction_ops`. The trace can be replayed without
+the program, see `Machine::replay`. Every extension is enabled.
+Runs which fail are recorded up to the failure, the instruction
+failing included.
+*/
+pub fn record_ops(program: &Ast, input: &[u8], limits: Limits) -> ~[Operator] {
+	let ops = instruction_ops(program);
+	let mut trace = Vec::new();
+	{
+		let mut machine = Machine::capturing(input);
+		machine.set_limits(limits);
+		machine.set_extensions(ExtensionSet::all());
+		machine.on_step(|step| trace.push(ops[step.pc].clone()));
+		let _ = machine.run_program(program);
+	}
+	trace.move_iter().collect()
+}
+
+/**
+Finds the first event at which two recorded runs differ, if any,
+along with up to `window` events of each run before it. Runs ending
+alike, but for one failing, or failing otherwise, differ at their end.
+*/
+pub fn diff(a: &TraceLog, b: &TraceLog, window: uint) -> Option<Divergence> {
+	let mut i = 0;
+	loop {
+		let event_a = a.events.as_slice().get(i).map(|event| event.clone());
+		let event_b = b.events.as_slice().get(i).map(|event| event.clone());
+		if event_a.is_none() && event_b.is_none() && a.failure == b.failure {
+			return None;
+		}
+		if !alike(&event_a, &event_b) || (event_a.is_none() && event_b.is_none()) {
+			let start = if i > window { i - window } else { 0 };
+			let ended = |log: &TraceLog, event: &Option<TraceEvent>| if event.is_none() { log.failure.clone() } else { None };
+			return Some(Divergence {
+				matched: i,
+				spans: (span_of(a.spans, &event_a), span_of(b.spans, &event_b)),
+				failures: (ended(a, &event_a), ended(b, &event_b)),
+				first: (event_a, event_b),
+				window: (a.events.slice(start, i).to_owned(), b.events.slice(start, i).to_owned()),
+			});
+		}
+		i += 1;
+	}
+}
+
+/**
+One of the runs compared by `diff_lockstep`: a machine going a cycle
+at a time, the events it made and which were not compared yet, and
+the last few which were.
+*/
+struct Side<'a> {
+	machine: Machine<'a>,
+	execution: Execution,
+	/// Filled by the step hook of the machine.
+	pending: &'a RefCell<RingBuf<TraceEvent>>,
+	spans: ~[Span],
+	recent: RingBuf<TraceEvent>,
+	window: uint,
+	ended: bool,
+	/// Why the run failed, once it ended, if it did.
+	failure: Option<RuntimeError>,
+}
+
+impl<'a> Side<'a> {
+	/// Takes over a machine, whose step hook fills `pending`, to run a program.
+	fn new(mut machine: Machine<'a>, pending: &'a RefCell<RingBuf<TraceEvent>>,
+	       program: &Ast, map: &SourceMap, window: uint) -> Side<'a> {
+		let execution = machine.start(program);
+		Side {
+			machine: machine,
+			execution: execution,
+			pending: pending,
+			spans: map.instruction_spans(program),
+			recent: RingBuf::new(),
+			window: window,
+			ended: false,
+			failure: None,
+		}
+	}
+
+	/**
+	Runs until the next event, unless the run ended first, keeping
+	why it failed if it did.
+	*/
+	fn next(&mut self) -> Option<TraceEvent> {
+		while self.pending.borrow().is_empty() && !self.ended {
+			match self.machine.resume_for(&mut self.execution, 1) {
+				Ok(Paused) | Ok(Blocked) | Ok(ConditionHit(..)) => {}
+				Ok(Halted) => self.ended = true,
+				Err(err) => {
+					self.ended = true;
+					self.failure = Some(err);
+				}
+			}
+		}
+		self.pending.borrow_mut().pop_front()
+	}
+
+	/// Why the run failed, if it ended with no event left, and failed.
+	fn failure_at(&self, event: &Option<TraceEvent>) -> Option<RuntimeError> {
+		if event.is_none() { self.failure.clone() } else { None }
+	}
+
+	/// Keeps an event compared alike, forgetting those out of the window.
+	fn keep(&mut self, event: TraceEvent) {
+		self.recent.push_back(event);
+		if self.recent.len() > self.window {
+			self.recent.pop_front();
+		}
+	}
+}
+
+/**
+Like `diff`, but runs both programs side by side, a cycle at a time,
+stopping at the first divergence. Only the window of events is kept,
+so that long runs can be compared without recording them whole, and
+the tape of each run is kept within `memory_limit`, if any, see
+`Machine::set_memory_limit`. The maps tell the source of the
+instructions, see `SourceMap::of`. Every extension is enabled.
+*/
+pub fn diff_lockstep(a: &Ast, a_map: &SourceMap, b: &Ast, b_map: &SourceMap, input: &[u8],
+                     limits: Limits, semantics: Semantics, memory_limit: Option<uint>, window: uint) -> Option<Divergence> {
+	let pending_a = RefCell::new(RingBuf::new());
+	let pending_b = RefCell::new(RingBuf::new());
+	let mut machine_a = Machine::capturing(input);
+	machine_a.set_limits(limits.clone());
+	machine_a.set_semantics(semantics.clone());
+	machine_a.set_memory_limit(memory_limit);
+	machine_a.set_extensions(ExtensionSet::all());
+	machine_a.on_step(|step| for event in TraceEvent::of(step).move_iter() { pending_a.borrow_mut().push_back(event) });
+	let mut machine_b = Machine::capturing(input);
+	machine_b.set_limits(limits);
+	machine_b.set_semantics(semantics);
+	machine_b.set_memory_limit(memory_limit);
+	machine_b.set_extensions(ExtensionSet::all());
+	machine_b.on_step(|step| for event in TraceEvent::of(step).move_iter() { pending_b.borrow_mut().push_back(event) });
+	let mut side_a = Side::new(machine_a, &pending_a, a, a_map, window);
+	let mut side_b = Side::new(machine_b, &pending_b, b, b_map, window);
+
+	let mut matched = 0;
+	loop {
+		let (event_a, event_b) = (side_a.next(), side_b.next());
+		if event_a.is_none() && event_b.is_none() && side_a.failure == side_b.failure {
+			return None;
+		}
+		if !alike(&event_a, &event_b) || (event_a.is_none() && event_b.is_none()) {
+			return Some(Divergence {
+				matched: matched,
+				spans: (span_of(side_a.spans, &event_a), span_of(side_b.spans, &event_b)),
+				failures: (side_a.failure_at(&event_a), side_b.failure_at(&event_b)),
+				first: (event_a, event_b),
+				window: (side_a.recent.iter().map(|event| event.clone()).collect(),
+				         side_b.recent.iter().map(|event| event.clone()).collect()),
+			});
+		}
+		side_a.keep(event_a.unwrap());
+		side_b.keep(event_b.unwrap());
+		matched += 1;
+	}
+}
+
+#[cfg(test)]
+/// Parses a program along with its source map.
+fn parsed(source: &str) -> (Ast, SourceMap) {
+	let ast = Ast::parse_str(source).unwrap();
+	let map = SourceMap::of(&ast);
+	(ast, map)
+}
+
+#[cfg(test)]
+/// The event of an instruction which wrote nothing.
+fn event(cycle: uint, pc: uint, head: int, cell: u8) -> TraceEvent {
+	TraceEvent { cycle: cycle, pc: pc, head: head, cell: cell, output: None }
+}
+
+#[test]
+/// A single operator changed should be found, with what came before.
+fn test_trace_diff_single_operator() {
+	let (a, a_map) = parsed("++>+<.");
+	let (b, b_map) = parsed("++>-<.");
+	let log_a = record(&a, &a_map, [], Limits::none());
+	let log_b = record(&b, &b_map, [], Limits::none());
+	assert!(log_a.events.len() == 6);
+	assert!(diff(&log_a, &log_a.clone(), 2).is_none());
+
+	let divergence = diff(&log_a, &log_b, 2).unwrap();
+	assert!(divergence.cycle() == 4);
+	assert!(divergence.matched == 3);
+	assert!(divergence.first == (Some(event(4, 3, 1, 1)), Some(event(4, 3, 1, 255))));
+	assert!(divergence.spans == (Some(Span { start: 3, end: 4 }), Some(Span { start: 3, end: 4 })));
+	let before = ~[event(2, 1, 0, 2), event(3, 2, 1, 0)];
+	assert!(divergence.window == (before.clone(), before));
+	assert!(diff_lockstep(&a, &a_map, &b, &b_map, [], Limits::none(), Semantics::common(), None, 2) == Some(divergence));
+}
+
+#[test]
+/// A run ending early should diverge from one going on.
+fn test_trace_diff_ended() {
+	let (a, a_map) = parsed("+.");
+	let (b, b_map) = parsed("+. comment");
+	let (c, c_map) = parsed("+.+");
+	assert!(diff_lockstep(&a, &a_map, &b, &b_map, [], Limits::none(), Semantics::common(), None, 4).is_none());
+	let divergence = diff_lockstep(&a, &a_map, &c, &c_map, [], Limits::none(), Semantics::common(), None, 4).unwrap();
+	assert!(divergence.first == (None, Some(event(3, 2, 0, 2))));
+	assert!(divergence.spans == (None, Some(Span { start: 2, end: 3 })));
+	assert!(divergence.window.val0().len() == 2);
+}
+
+#[test]
+/// A run failing should diverge from one halting, unless both failed alike.
+fn test_trace_diff_failed() {
+	let (a, a_map) = parsed("+.");
+	let (b, b_map) = parsed("+.<");
+	let strict = Semantics::strict();
+	assert!(diff_lockstep(&a, &a_map, &b, &b_map, [], Limits::none(), Semantics::common(), None, 4).is_none());
+	assert!(diff_lockstep(&b, &b_map, &b, &b_map, [], Limits::none(), strict.clone(), None, 4).is_none());
+	let divergence = diff_lockstep(&a, &a_map, &b, &b_map, [], Limits::none(), strict, None, 4).unwrap();
+	assert!(divergence.first == (None, None));
+	assert!(divergence.failures == (None, Some(::machine::TapeOutOfBounds)));
+	assert!(divergence.matched == 2 && divergence.cycle() == 2);
+	assert!(format!("{}", divergence).contains("b: failed: Head moved past the edges of the tape."));
+
+	let limits = Limits { cycles: Some(1), ..Limits::none() };
+	let log_a = record(&a, &a_map, [], Limits::none());
+	let log_b = record(&a, &a_map, [], limits.clone());
+	assert!(log_b.failure == Some(::machine::LimitExceeded(::machine::CycleLimit)));
+	let divergence = diff(&log_a, &log_b, 4).unwrap();
+	assert!(divergence.first == (Some(TraceEvent { output: Some(1), ..event(2, 1, 0, 1) }), None));
+	assert!(divergence.failures == (None, log_b.failure.clone()));
+}
+
+#[test]
+/// Runs diverging late should be compared keeping only the window.
+fn test_trace_diff_lockstep_long() {
+	// Some 135k cycles of nested loops, then a single differing operator.
+	let prefix = "++++++++++++[>++++++++++++[>++++++++++++[>++++++++++++[>+<-]<-]<-]<-]";
+	let (a, a_map) = parsed(format!("{}>+.", prefix));
+	let (b, b_map) = parsed(format!("{}>-.", prefix));
+	// Recording either run whole would take more than the ceiling by
+	// far, while the tapes of both fit within it.
+	let ceiling = 64 << 10;
+	assert!(135722 * ::std::mem::size_of::<TraceEvent>() > 10 * ceiling);
+	let divergence = diff_lockstep(&a, &a_map, &b, &b_map, [], Limits::none(), Semantics::common(), Some(ceiling), 4).unwrap();
+	assert!(divergence.failures == (None, None));
+	assert!(divergence.cycle() == 135723);
+	assert!(divergence.matched == 135722);
+	let at = prefix.len() + 1;
+	assert!(divergence.spans == (Some(Span { start: at, end: at + 1 }), Some(Span { start: at, end: at + 1 })));
+	assert!(divergence.window.val0().len() == 4 && divergence.window.val1().len() == 4);
+	assert!(divergence.window.val0().last().unwrap().cycle == 135722);
+}
+
+#[cfg(test)]
+use operators::{Get, Skip, Next, Incr};
+
+#[test]
+/// Replaying the trace of a run should leave the tape as the run did.
+fn test_trace_replay() {
+	let program = Ast::parse_str(",[>+>++<<-]>>[-<+>]<.,").unwrap();
+	let input = [3u8, 9];
+	let trace = record_ops(&program, input, Limits::none());
+	assert!(trace.slice_to(4) == [Get, Skip, Next, Incr]);
+
+	let mut original = Machine::capturing(input);
+	let cycles = original.run_program(&program);
+	let mut replayed = Machine::capturing([]);
+	assert!(replayed.replay(trace, input) == cycles);
+	assert!(replayed.tape().cells() == original.tape().cells());
+	assert!(replayed.captured_output() == original.captured_output());
+
+	// The trace goes the same way whatever the input, ending up with
+	// what its reads got from this one, then EOF.
+	let mut replayed = Machine::capturing([]);
+	assert!(replayed.replay(trace, [5]).is_ok());
+	assert!(replayed.tape().peek(0) == 2 && replayed.tape().peek(2) == 0);
+
+	// Traces holding whole loops are refused, running nothing.
+	let Ast(ops) = Ast::parse_str("+.[-]").unwrap();
+	let mut replayed = Machine::capturing([]);
+	assert!(replayed.replay(ops, []) == Err(::machine::LoopInTrace(2)));
+	assert!(replayed.cycles() == 0 && replayed.captured_output().len() == 0);
+}
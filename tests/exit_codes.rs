@@ -0,0 +1,40 @@
+/*!
+Checks the exit status of the interpreter, as scripts see it.
+
+Run from the root of the repository once the interpreter is built,
+see `make test`.
+*/
+
+#![crate_id = "exit_codes"]
+
+use std::io::process::{Process, ProcessExit, ExitStatus};
+
+
+/// Where `make` builds the interpreter.
+static INTERPRETER: &'static str = "dist/bf";
+
+/// Runs the interpreter on the given arguments, returning its exit status.
+fn exit_status(args: &[&str]) -> ProcessExit {
+	let args: ~[~str] = args.iter().map(|arg| arg.to_owned()).collect();
+	match Process::output(INTERPRETER, args) {
+		Ok(output) => output.status,
+		Err(err) => fail!("Cannot run `{}`: {}", INTERPRETER, err),
+	}
+}
+
+#[test]
+/// Programs which do not parse should exit with 2.
+fn test_exit_parse_error() {
+	assert!(exit_status(["tests/unmatched-l.bf"]) == ExitStatus(2));
+	assert!(exit_status(["tests/unmatched-r.bf"]) == ExitStatus(2));
+	assert!(exit_status(["--fail-fast", "tests/unmatched-l.bf", "tests/long.bf"]) == ExitStatus(2));
+}
+
+#[test]
+/// Programs which fail while running should exit with 1, others with 0.
+fn test_exit_run_error() {
+	// Moving left of the first cell is an error in strict semantics.
+	assert!(exit_status(["--semantics", "strict", "tests/tape-bound.bf"]) == ExitStatus(1));
+	assert!(exit_status(["tests/tape-bound.bf"]) == ExitStatus(0));
+	assert!(exit_status(["examples/helloworld.bf"]) == ExitStatus(0));
+}